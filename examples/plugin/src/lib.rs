@@ -0,0 +1,66 @@
+//! An example lsp-fiddle plugin. Build it with `cargo build --release`
+//! and run lsp-fiddle with
+//! `LSP_PLUGIN=target/release/liblsp_fiddle_example_plugin.so`.
+//!
+//! It drops `telemetry/event` notifications from the server, and turns
+//! `window/showMessage` popups into `window/logMessage` notifications.
+
+use serde_json::Value;
+
+const ABI_VERSION: u32 = 1;
+
+const DIRECTION_TO_EDITOR: u8 = 1;
+
+const ACTION_FORWARD: i32 = 0;
+const ACTION_DROP: i32 = 1;
+const ACTION_REPLACE: i32 = 2;
+
+#[no_mangle]
+pub extern "C" fn lsp_plugin_abi_version() -> u32 {
+    ABI_VERSION
+}
+
+/// # Safety
+///
+/// `json` must point to `len` bytes, and `replacement` and
+/// `replacement_len` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn process_message(
+    direction: u8,
+    json: *const u8,
+    len: usize,
+    replacement: *mut *mut u8,
+    replacement_len: *mut usize,
+) -> i32 {
+    if direction != DIRECTION_TO_EDITOR {
+        return ACTION_FORWARD;
+    }
+
+    let json = std::slice::from_raw_parts(json, len);
+    let Ok(mut message) = serde_json::from_slice::<Value>(json) else {
+        return ACTION_FORWARD;
+    };
+
+    match message.get("method").and_then(|m| m.as_str()) {
+        Some("telemetry/event") => ACTION_DROP,
+        Some("window/showMessage") => {
+            message["method"] = "window/logMessage".into();
+
+            let bytes = message.to_string().into_bytes().into_boxed_slice();
+            *replacement_len = bytes.len();
+            *replacement = Box::into_raw(bytes) as *mut u8;
+            ACTION_REPLACE
+        }
+        _ => ACTION_FORWARD,
+    }
+}
+
+/// Frees a replacement returned by `process_message`.
+///
+/// # Safety
+///
+/// `ptr` and `len` must come from a single `process_message` call.
+#[no_mangle]
+pub unsafe extern "C" fn free_message(ptr: *mut u8, len: usize) {
+    drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len)));
+}
@@ -0,0 +1,145 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::config::AlertThresholds;
+use crate::stream::Direction;
+
+/// Watches for traffic patterns that suggest a misbehaving editor or
+/// server, such as an editor flooding the server with changes
+pub(crate) struct TrafficMonitor {
+    thresholds: AlertThresholds,
+    /// When each didChange in the last second was sent
+    recent_did_changes: VecDeque<Instant>,
+    did_change_alerting: bool,
+    outstanding_alerting: bool,
+    /// Payloads must be larger than this to alert again, so we only
+    /// report sizes that keep growing
+    payload_alert_size: Option<usize>,
+    /// Directions whose destination is currently falling behind
+    backlogged: HashSet<Direction>,
+    /// Most complete messages waiting at once in each direction
+    peak_backlog: HashMap<Direction, usize>,
+    /// Kinds of alert raised during the session
+    triggered: BTreeSet<&'static str>,
+}
+
+impl TrafficMonitor {
+    pub(crate) fn new(thresholds: AlertThresholds) -> Self {
+        Self {
+            payload_alert_size: thresholds.payload_bytes,
+            thresholds,
+            recent_did_changes: VecDeque::new(),
+            did_change_alerting: false,
+            outstanding_alerting: false,
+            backlogged: HashSet::new(),
+            peak_backlog: HashMap::new(),
+            triggered: BTreeSet::new(),
+        }
+    }
+
+    /// Record a didChange notification sent by the editor
+    pub(crate) fn did_change(&mut self, now: Instant) -> Option<String> {
+        let limit = self.thresholds.did_change_per_sec?;
+
+        self.recent_did_changes.push_back(now);
+        while let Some(&sent) = self.recent_did_changes.front() {
+            if now.duration_since(sent) < Duration::from_secs(1) {
+                break;
+            }
+            self.recent_did_changes.pop_front();
+        }
+
+        let rate = self.recent_did_changes.len();
+        if rate <= limit {
+            self.did_change_alerting = false;
+            return None;
+        }
+        if std::mem::replace(&mut self.did_change_alerting, true) {
+            return None;
+        }
+
+        self.triggered.insert("did_change_rate");
+        Some(format!(
+            "editor sent more than {} didChange notifications in one second",
+            limit
+        ))
+    }
+
+    /// Check the number of editor requests the server hasn't answered
+    pub(crate) fn outstanding_requests(&mut self, count: usize) -> Option<String> {
+        let limit = self.thresholds.outstanding_requests?;
+
+        if count <= limit {
+            self.outstanding_alerting = false;
+            return None;
+        }
+        if std::mem::replace(&mut self.outstanding_alerting, true) {
+            return None;
+        }
+
+        self.triggered.insert("outstanding_requests");
+        Some(format!(
+            "{} editor requests are waiting for a response from the server",
+            count
+        ))
+    }
+
+    /// Check the size of a message body
+    pub(crate) fn payload(&mut self, stream: &str, size: usize) -> Option<String> {
+        let alert_size = self.payload_alert_size?;
+        if size <= alert_size {
+            return None;
+        }
+
+        // Only alert again once payloads have doubled in size.
+        self.payload_alert_size = Some(size.saturating_mul(2));
+        self.triggered.insert("payload_size");
+        Some(format!("{} message body is {} bytes", stream, size))
+    }
+
+    /// Check how many complete messages were waiting to be forwarded
+    /// at once. Lots of them means data piled up while we were writing
+    /// the previous messages, so the destination isn't keeping up.
+    pub(crate) fn backlog(&mut self, direction: Direction, messages: usize) -> Option<String> {
+        let limit = self.thresholds.backlog_messages?;
+
+        let peak = self.peak_backlog.entry(direction).or_default();
+        *peak = (*peak).max(messages);
+
+        if messages <= limit {
+            self.backlogged.remove(&direction);
+            return None;
+        }
+        if !self.backlogged.insert(direction) {
+            return None;
+        }
+
+        self.triggered.insert("backlog");
+        Some(format!(
+            "{} messages were waiting to be sent to the {}, it may not be keeping up",
+            messages,
+            match direction {
+                Direction::ToServer => "server",
+                Direction::ToEditor => "editor",
+            }
+        ))
+    }
+
+    /// The largest backlog seen in each direction, if we're tracking
+    /// backlogs
+    pub(crate) fn peak_backlog_json(&self) -> Option<serde_json::Value> {
+        self.thresholds.backlog_messages?;
+
+        let peak = |direction| self.peak_backlog.get(&direction).copied().unwrap_or(0);
+        Some(serde_json::json!({
+            "stdin": peak(Direction::ToServer),
+            "stdout": peak(Direction::ToEditor),
+        }))
+    }
+
+    /// Names of the alerts raised during the session
+    pub(crate) fn triggered(&self) -> Vec<&'static str> {
+        self.triggered.iter().copied().collect()
+    }
+}
@@ -0,0 +1,107 @@
+use serde_json::Value;
+use std::path::PathBuf;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+
+use crate::clock::SharedClock;
+
+/// Writes user annotations to the JSON logs of both streams, to mark
+/// moments in a capture like where a bug was triggered
+#[derive(Clone)]
+pub(crate) struct Annotations {
+    clock: SharedClock,
+    /// The stream tasks, which write annotations to their logs
+    logs: Vec<mpsc::UnboundedSender<Value>>,
+}
+
+impl Annotations {
+    pub(crate) fn new(clock: SharedClock, logs: Vec<mpsc::UnboundedSender<Value>>) -> Self {
+        Self { clock, logs }
+    }
+
+    fn annotate(&self, text: &str) {
+        eprintln!("Annotation: {}", text);
+        let annotation = serde_json::json!({
+            "_annotation": text,
+            "_timestamp": self.clock.now().to_rfc3339(),
+        });
+        for log in &self.logs {
+            let _ = log.send(annotation.clone());
+        }
+    }
+}
+
+/// Writes an annotation for each line written to the FIFO at `path`,
+/// e.g. `echo triggered completion here > annotations.fifo`. The FIFO is
+/// opened again whenever its writer closes it.
+pub(crate) async fn read_annotation_fifo(path: PathBuf, annotations: Annotations) {
+    // A regular file would be read from the start again at each EOF.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) if metadata.file_type().is_fifo() => {}
+            Ok(_) => {
+                eprintln!(
+                    "Ignoring LSP_ANNOTATION_FIFO: {} isn't a FIFO, create one with mkfifo",
+                    path.display()
+                );
+                return;
+            }
+            Err(e) => {
+                eprintln!("Ignoring LSP_ANNOTATION_FIFO: {}: {}", path.display(), e);
+                return;
+            }
+        }
+    }
+    loop {
+        let file = match tokio::fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!(
+                    "Failed to open LSP_ANNOTATION_FIFO {}: {}",
+                    path.display(),
+                    e
+                );
+                return;
+            }
+        };
+        let mut lines = BufReader::new(file).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let text = line.trim();
+            if !text.is_empty() {
+                annotations.annotate(text);
+            }
+        }
+    }
+}
+
+/// Writes an annotation on each SIGUSR1, holding the contents of the
+/// file at `path` at that moment, or `SIGUSR1` if it's empty
+#[cfg(unix)]
+pub(crate) async fn annotate_on_sigusr1(path: PathBuf, annotations: Annotations) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigusr1 = match signal(SignalKind::user_defined1()) {
+        Ok(sigusr1) => sigusr1,
+        Err(e) => {
+            eprintln!("Failed to listen for SIGUSR1: {}", e);
+            return;
+        }
+    };
+    while sigusr1.recv().await.is_some() {
+        let text = match tokio::fs::read_to_string(&path).await {
+            Ok(text) => text.trim().to_string(),
+            Err(e) => {
+                eprintln!(
+                    "Failed to read LSP_ANNOTATION_FILE {}: {}",
+                    path.display(),
+                    e
+                );
+                String::new()
+            }
+        };
+        annotations.annotate(if text.is_empty() { "SIGUSR1" } else { &text });
+    }
+}
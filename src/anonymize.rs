@@ -0,0 +1,36 @@
+use serde_json::Value;
+
+/// Replaces the source text in `textDocument/didOpen` and
+/// `textDocument/didChange` notifications with `x`s, keeping line
+/// breaks so lengths and positions still line up
+pub(crate) fn anonymize_text(message: &mut Value) {
+    let method = message.get("method").and_then(|m| m.as_str());
+
+    match method {
+        Some("textDocument/didOpen") => {
+            if let Some(text) = message.pointer_mut("/params/textDocument/text") {
+                mask(text);
+            }
+        }
+        Some("textDocument/didChange") => {
+            let changes = message
+                .pointer_mut("/params/contentChanges")
+                .and_then(|changes| changes.as_array_mut());
+            for change in changes.into_iter().flatten() {
+                if let Some(text) = change.get_mut("text") {
+                    mask(text);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn mask(text: &mut Value) {
+    if let Value::String(text) = text {
+        *text = text
+            .chars()
+            .map(|c| if c == '\n' || c == '\r' { c } else { 'x' })
+            .collect();
+    }
+}
@@ -0,0 +1,252 @@
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+use crate::config::LogFormat;
+use crate::{format_lsp_message, LspMessageParser};
+
+/// One entry in a stdin or stdout log, in any format
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Entry {
+    /// A parsed message, including any fields lsp-fiddle added such as
+    /// `_empty`, `_coalesced` or a `_meta` header
+    Message(Value),
+    /// A message body that wasn't valid JSON, kept as it was sent
+    Unparsed(String),
+}
+
+/// Reads every entry from a stdin or stdout log
+pub(crate) fn read_log(path: &Path, format: LogFormat) -> Result<Vec<Entry>> {
+    let contents = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let entries = match format {
+        LogFormat::Raw => {
+            let (entries, rest) = parse_raw(&contents);
+            if !rest.is_empty() {
+                eprintln!(
+                    "Ignoring incomplete message at the end of {}",
+                    path.display()
+                );
+            }
+            entries
+        }
+        LogFormat::JsonLines => String::from_utf8_lossy(&contents)
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| parse_entry(line.to_string()))
+            .collect(),
+        LogFormat::JsonArray => {
+            let values: Vec<Value> = serde_json::from_slice(&contents).with_context(|| {
+                format!(
+                    "{} isn't a JSON array, try `lsp-fiddle repair` first",
+                    path.display()
+                )
+            })?;
+            // Unparseable bodies are logged as strings, to keep the
+            // array valid.
+            values
+                .into_iter()
+                .map(|value| match value {
+                    Value::String(payload) => Entry::Unparsed(payload),
+                    value => Entry::Message(value),
+                })
+                .collect()
+        }
+    };
+    Ok(entries)
+}
+
+/// Parses framed messages from raw bytes, returning the entries and
+/// any bytes at the end that aren't a complete message
+pub(crate) fn parse_raw(contents: &[u8]) -> (Vec<Entry>, Vec<u8>) {
+    let mut parser = LspMessageParser::new();
+    parser.add_data(contents);
+    let mut entries = vec![];
+    while let Some((_, payload)) = parser.try_parse_message() {
+        entries.push(if payload.is_empty() {
+            Entry::Message(serde_json::json!({ "_empty": true }))
+        } else {
+            parse_entry(payload)
+        });
+    }
+    (entries, parser.partial_message().to_vec())
+}
+
+fn parse_entry(payload: String) -> Entry {
+    match serde_json::from_str(&payload) {
+        Ok(value) => Entry::Message(value),
+        Err(_) => Entry::Unparsed(payload),
+    }
+}
+
+/// The stream and message of a JSON log entry, which may be wrapped
+/// with the stream it was sent on. Capture headers are skipped.
+pub(crate) fn unwrap_entry(
+    stream: &'static str,
+    mut entry: Value,
+) -> Option<(&'static str, Value)> {
+    if entry.get("_meta").is_some() {
+        return None;
+    }
+    let wrapped_stream = match entry.get("stream").and_then(|s| s.as_str()) {
+        Some("stdin") => "stdin",
+        Some(_) => "stdout",
+        None => return Some((stream, entry)),
+    };
+    match entry.get_mut("message") {
+        Some(message) => Some((wrapped_stream, message.take())),
+        None => Some((stream, entry)),
+    }
+}
+
+/// Which stream a log holds, from names like `<timestamp>_stdin.jsonl`.
+/// Assumes stdout if the name doesn't say.
+pub(crate) fn stream_from_file_name(path: &Path) -> &'static str {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    if name.contains("_stdin") {
+        "stdin"
+    } else {
+        "stdout"
+    }
+}
+
+/// Writes entries as a log in `format`, the same way a proxy session
+/// would have logged them
+pub(crate) fn write_log(path: &Path, format: LogFormat, entries: &[Entry]) -> Result<()> {
+    let mut output = String::new();
+    match format {
+        LogFormat::Raw => {
+            for entry in entries {
+                match entry {
+                    Entry::Message(value) if value.get("_meta").is_some() => {
+                        eprintln!("Dropping capture header, raw logs can't hold it");
+                    }
+                    Entry::Message(value) if value.get("_empty").is_some() => {
+                        output.push_str(&format_lsp_message(""));
+                    }
+                    Entry::Message(value) => {
+                        output.push_str(&format_lsp_message(&value.to_string()));
+                    }
+                    Entry::Unparsed(payload) => output.push_str(&format_lsp_message(payload)),
+                }
+            }
+        }
+        LogFormat::JsonLines => {
+            for entry in entries {
+                match entry {
+                    Entry::Message(value) => output.push_str(&value.to_string()),
+                    Entry::Unparsed(payload) => output.push_str(payload),
+                }
+                output.push('\n');
+            }
+        }
+        LogFormat::JsonArray => {
+            let entries: Vec<String> = entries
+                .iter()
+                .map(|entry| match entry {
+                    Entry::Message(value) => value.to_string(),
+                    Entry::Unparsed(payload) => Value::from(payload.as_str()).to_string(),
+                })
+                .collect();
+            output = format!("[\n{}\n]\n", entries.join(",\n"));
+        }
+    }
+
+    fs::write(path, output).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Converts a log between formats. Formats default to the ones implied
+/// by the file extensions.
+pub(crate) fn convert(
+    input: &Path,
+    output: &Path,
+    from: Option<LogFormat>,
+    to: Option<LogFormat>,
+) -> Result<()> {
+    let Some(from) = from.or_else(|| LogFormat::from_path(input)) else {
+        bail!("Can't tell the format of {}, use --from", input.display());
+    };
+    let Some(to) = to.or_else(|| LogFormat::from_path(output)) else {
+        bail!("Can't tell the format of {}, use --to", output.display());
+    };
+
+    let entries = read_log(input, from)?;
+    write_log(output, to, &entries)?;
+    println!(
+        "Converted {} entries from {} to {}",
+        entries.len(),
+        input.display(),
+        output.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::tests::temp_path;
+    use serde_json::json;
+
+    fn entries() -> Vec<Entry> {
+        vec![
+            Entry::Message(json!({"jsonrpc": "2.0", "method": "initialized"})),
+            Entry::Message(json!({"_empty": true})),
+            Entry::Unparsed("not json".to_string()),
+        ]
+    }
+
+    #[test]
+    fn converts_between_every_format() {
+        let raw = temp_path("convert.log");
+        let json_lines = temp_path("convert.jsonl");
+        let json_array = temp_path("convert.json");
+        let back = temp_path("convert_back.log");
+        write_log(&raw, LogFormat::Raw, &entries()).unwrap();
+
+        convert(&raw, &json_lines, None, None).unwrap();
+        assert_eq!(
+            fs::read_to_string(&json_lines).unwrap(),
+            "{\"jsonrpc\":\"2.0\",\"method\":\"initialized\"}\n{\"_empty\":true}\nnot json\n"
+        );
+        convert(&json_lines, &json_array, None, None).unwrap();
+        assert_eq!(
+            read_log(&json_array, LogFormat::JsonArray).unwrap(),
+            entries()
+        );
+        convert(&json_array, &back, None, None).unwrap();
+        assert_eq!(fs::read(&back).unwrap(), fs::read(&raw).unwrap());
+
+        for path in [&raw, &json_lines, &json_array, &back] {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn needs_a_format_for_unknown_extensions() {
+        let input = temp_path("convert_input.txt");
+        let output = temp_path("convert_output.jsonl");
+        write_log(&input, LogFormat::Raw, &entries()).unwrap();
+
+        let error = convert(&input, &output, None, None).unwrap_err();
+        assert!(error.to_string().ends_with("use --from"));
+        convert(&input, &output, Some(LogFormat::Raw), None).unwrap();
+        assert_eq!(read_log(&output, LogFormat::JsonLines).unwrap(), entries());
+
+        let _ = fs::remove_file(&input);
+        let _ = fs::remove_file(&output);
+    }
+
+    #[test]
+    fn drops_capture_headers_from_raw_logs() {
+        let path = temp_path("convert_header.log");
+        let mut with_header = vec![Entry::Message(json!({"_meta": {"server": "clangd"}}))];
+        with_header.extend(entries());
+        write_log(&path, LogFormat::Raw, &with_header).unwrap();
+        assert_eq!(read_log(&path, LogFormat::Raw).unwrap(), entries());
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::VecDeque;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::LspMessageParser;
+
+/// Hash of a complete frame, headers included
+pub(crate) fn frame_checksum(frame: &[u8]) -> u64 {
+    xxh3_64(frame)
+}
+
+/// Checks that the frames written to the destination are byte-for-byte
+/// the frames read from the source
+pub(crate) struct FrameChecker {
+    /// Sequence numbers and checksums of frames read but not yet seen
+    /// in the output
+    expected: VecDeque<(usize, u64)>,
+    /// Splits the forwarded bytes back into frames
+    output: LspMessageParser,
+}
+
+impl FrameChecker {
+    pub(crate) fn new() -> Self {
+        Self {
+            expected: VecDeque::new(),
+            output: LspMessageParser::new(),
+        }
+    }
+
+    /// Record a frame read from the source
+    pub(crate) fn expect(&mut self, seq: usize, checksum: u64) {
+        self.expected.push_back((seq, checksum));
+    }
+
+    /// Check bytes that were written to the destination, returning a
+    /// description of each frame that doesn't match what was read
+    pub(crate) fn check_output(&mut self, data: &[u8]) -> Vec<String> {
+        let mut problems = vec![];
+        self.output.add_data(data);
+
+        while let Some((frame, _)) = self.output.try_parse_message() {
+            let actual = frame_checksum(&frame);
+            match self.expected.pop_front() {
+                Some((_, expected)) if expected == actual => {}
+                Some((seq, expected)) => problems.push(format!(
+                    "frame {} was altered by the proxy (read {:016x}, wrote {:016x})",
+                    seq, expected, actual
+                )),
+                None => problems.push(format!(
+                    "proxy wrote a frame that was never read ({:016x})",
+                    actual
+                )),
+            }
+        }
+
+        problems
+    }
+}
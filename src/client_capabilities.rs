@@ -0,0 +1,86 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Requests a server may only send if the editor advertised the
+/// capability, as a JSON pointer under `capabilities`
+const REQUIRED_CAPABILITIES: &[(&str, &str)] = &[
+    ("workspace/applyEdit", "/workspace/applyEdit"),
+    ("workspace/configuration", "/workspace/configuration"),
+    ("workspace/workspaceFolders", "/workspace/workspaceFolders"),
+    ("window/workDoneProgress/create", "/window/workDoneProgress"),
+    ("window/showDocument", "/window/showDocument/support"),
+    (
+        "workspace/semanticTokens/refresh",
+        "/workspace/semanticTokens/refreshSupport",
+    ),
+    (
+        "workspace/codeLens/refresh",
+        "/workspace/codeLens/refreshSupport",
+    ),
+    (
+        "workspace/inlayHint/refresh",
+        "/workspace/inlayHint/refreshSupport",
+    ),
+    (
+        "workspace/inlineValue/refresh",
+        "/workspace/inlineValue/refreshSupport",
+    ),
+    (
+        "workspace/diagnostic/refresh",
+        "/workspace/diagnostics/refreshSupport",
+    ),
+    (
+        "workspace/foldingRange/refresh",
+        "/workspace/foldingRange/refreshSupport",
+    ),
+];
+
+/// The capabilities the editor advertised in initialize, to catch a
+/// server sending requests the editor never said it supports
+#[derive(Default)]
+pub(crate) struct ClientCapabilities {
+    /// None until the editor's initialize is seen
+    capabilities: Option<Value>,
+    /// How many requests for each method weren't advertised
+    unadvertised: BTreeMap<String, usize>,
+}
+
+impl ClientCapabilities {
+    pub(crate) fn observe_initialize(&mut self, params: Option<&Value>) {
+        let capabilities = params
+            .and_then(|params| params.get("capabilities"))
+            .cloned()
+            .unwrap_or(Value::Null);
+        self.capabilities = Some(capabilities);
+    }
+
+    /// Check a request from the server, returning a warning the first
+    /// time it sends a method whose capability wasn't advertised
+    pub(crate) fn check_request(&mut self, method: &str) -> Option<String> {
+        let capabilities = self.capabilities.as_ref()?;
+        let (_, pointer) = REQUIRED_CAPABILITIES
+            .iter()
+            .find(|(required_by, _)| *required_by == method)?;
+        let advertised = capabilities.pointer(pointer);
+        if !matches!(
+            advertised,
+            None | Some(Value::Null) | Some(Value::Bool(false))
+        ) {
+            return None;
+        }
+
+        let count = self.unadvertised.entry(method.to_string()).or_default();
+        *count += 1;
+        (*count == 1).then(|| {
+            format!(
+                "server sent {}, but the editor didn't advertise capabilities{}",
+                method,
+                pointer.replace('/', ".")
+            )
+        })
+    }
+
+    pub(crate) fn summary_json(&self) -> Value {
+        serde_json::json!(self.unadvertised)
+    }
+}
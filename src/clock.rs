@@ -0,0 +1,169 @@
+use chrono::{DateTime, Local};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::time::Instant;
+
+#[cfg(any(test, feature = "fake-clock"))]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(any(test, feature = "fake-clock"))]
+use std::time::Duration;
+
+/// How far `SteppingClock` moves on each timestamp, in milliseconds
+#[cfg(any(test, feature = "fake-clock"))]
+const STEP_MILLIS: u64 = 1;
+
+pub(crate) type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Where timestamps, latencies and timers take the time from
+pub(crate) trait Clock: Send + Sync {
+    /// The wall clock time, for timestamps
+    fn now(&self) -> DateTime<Local>;
+    /// A monotonic time, for measuring latencies and setting deadlines
+    fn instant(&self) -> Instant;
+    /// Waits until `deadline`, as measured by `instant`
+    fn sleep_until(&self, deadline: Instant) -> Sleep;
+}
+
+pub(crate) type SharedClock = Arc<dyn Clock>;
+
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        Box::pin(tokio::time::sleep_until(deadline))
+    }
+}
+
+/// A clock that starts at a given time and moves forward a step each
+/// time a timestamp is taken, so the same traffic always gets the same
+/// timestamps and latencies. Reading the monotonic time doesn't move
+/// it, as that's also done on timers. A timer waits in real time, then
+/// moves the clock to its deadline if it's behind.
+#[cfg(any(test, feature = "fake-clock"))]
+pub(crate) struct SteppingClock {
+    start: DateTime<Local>,
+    base: Instant,
+    steps: Arc<AtomicU64>,
+}
+
+#[cfg(any(test, feature = "fake-clock"))]
+impl SteppingClock {
+    pub(crate) fn new(start: DateTime<Local>) -> Self {
+        Self {
+            start,
+            base: Instant::now(),
+            steps: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+/// How long `steps` steps take. Panics rather than wrapping, which
+/// would take far more timestamps than a session could log.
+#[cfg(any(test, feature = "fake-clock"))]
+fn step_duration(steps: u64) -> Duration {
+    let millis = steps
+        .checked_mul(STEP_MILLIS)
+        .expect("fake clock overflowed");
+    Duration::from_millis(millis)
+}
+
+#[cfg(any(test, feature = "fake-clock"))]
+impl Clock for SteppingClock {
+    fn now(&self) -> DateTime<Local> {
+        let steps = self.steps.fetch_add(1, Ordering::Relaxed) + 1;
+        self.start + step_duration(steps)
+    }
+
+    fn instant(&self) -> Instant {
+        self.base + step_duration(self.steps.load(Ordering::Relaxed))
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        let wait = deadline.saturating_duration_since(self.instant());
+        let elapsed = deadline.saturating_duration_since(self.base).as_millis();
+        // Round up, so the clock reaches the deadline.
+        let steps = u64::try_from(elapsed.div_ceil(u128::from(STEP_MILLIS)))
+            .expect("fake clock overflowed");
+        let clock_steps = self.steps.clone();
+        Box::pin(async move {
+            tokio::time::sleep(wait).await;
+            clock_steps.fetch_max(steps, Ordering::Relaxed);
+        })
+    }
+}
+
+/// The system clock, or a `SteppingClock` starting at the RFC 3339
+/// time in `LSP_FAKE_CLOCK` when built with the `fake-clock` feature
+pub(crate) fn from_env() -> SharedClock {
+    let start = match std::env::var("LSP_FAKE_CLOCK") {
+        Ok(start) if !start.is_empty() => start,
+        _ => return Arc::new(SystemClock),
+    };
+    #[cfg(feature = "fake-clock")]
+    match DateTime::parse_from_rfc3339(&start) {
+        Ok(start) => Arc::new(SteppingClock::new(start.with_timezone(&Local))),
+        Err(e) => {
+            eprintln!("Ignoring LSP_FAKE_CLOCK: {}", e);
+            Arc::new(SystemClock)
+        }
+    }
+    #[cfg(not(feature = "fake-clock"))]
+    {
+        eprintln!(
+            "Ignoring LSP_FAKE_CLOCK={}: lsp-fiddle was built without the fake-clock feature",
+            start
+        );
+        Arc::new(SystemClock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start() -> DateTime<Local> {
+        DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Local)
+    }
+
+    #[test]
+    fn steps_on_each_timestamp() {
+        let clock = SteppingClock::new(start());
+        let base = clock.instant();
+        assert_eq!(clock.now(), start() + Duration::from_millis(1));
+        assert_eq!(clock.now(), start() + Duration::from_millis(2));
+        assert_eq!(clock.instant() - base, Duration::from_millis(2));
+        // Reading the monotonic time doesn't move the clock.
+        assert_eq!(clock.instant() - base, Duration::from_millis(2));
+    }
+
+    #[test]
+    fn keeps_counting_past_u32_steps() {
+        let clock = SteppingClock::new(start());
+        clock.steps.store(u64::from(u32::MAX), Ordering::Relaxed);
+        // Casting the steps to a u32 used to wrap this back to the start.
+        assert_eq!(
+            clock.now(),
+            start() + Duration::from_millis(u64::from(u32::MAX) + 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn moves_to_a_timer_deadline() {
+        let clock = SteppingClock::new(start());
+        let deadline = clock.instant() + Duration::from_millis(5);
+        clock.sleep_until(deadline).await;
+        assert_eq!(clock.instant(), deadline);
+        assert_eq!(clock.now(), start() + Duration::from_millis(6));
+    }
+}
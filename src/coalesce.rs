@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Collapses bursts of notifications with the same method into a
+/// single log entry, so e.g. a `textDocument/didChange` per keystroke
+/// doesn't swamp the log. Notifications about different documents are
+/// separate bursts. Only the last notification of each burst is kept,
+/// along with a count of how many were received. Bursts are
+/// logged in the order they started, and before any later message that
+/// isn't coalesced, so the log keeps the order messages were sent in.
+pub(crate) struct Coalescer {
+    methods: Vec<String>,
+    window: Duration,
+    pending: HashMap<BurstKey, Burst>,
+    /// How many bursts have started, to order them
+    bursts: u64,
+}
+
+/// The method of a burst's notifications, and the document they're
+/// about if they have a `textDocument`
+type BurstKey = (String, Option<String>);
+
+struct Burst {
+    last: serde_json::Value,
+    count: usize,
+    deadline: Instant,
+    /// Which burst this is, counting from 0
+    order: u64,
+}
+
+impl Coalescer {
+    pub(crate) fn new(methods: Vec<String>, window: Duration) -> Self {
+        Self {
+            methods,
+            window,
+            pending: HashMap::new(),
+            bursts: 0,
+        }
+    }
+
+    /// Hold `message` back if it's a notification we're coalescing.
+    /// Returns the entries to log now: any other message, after the
+    /// bursts that were held back before it.
+    pub(crate) fn add(
+        &mut self,
+        message: serde_json::Value,
+        now: Instant,
+    ) -> Vec<serde_json::Value> {
+        let method = message.get("method").and_then(|m| m.as_str());
+        let coalesced = method.filter(|method| {
+            message.get("id").is_none() && self.methods.iter().any(|m| m == method)
+        });
+        let Some(method) = coalesced else {
+            let mut entries = self.take_all();
+            entries.push(message);
+            return entries;
+        };
+
+        let deadline = now + self.window;
+        let order = self.bursts;
+        let uri = message
+            .pointer("/params/textDocument/uri")
+            .and_then(|uri| uri.as_str())
+            .map(|uri| uri.to_string());
+        let burst = self
+            .pending
+            .entry((method.to_string(), uri))
+            .or_insert_with(|| Burst {
+                last: serde_json::Value::Null,
+                count: 0,
+                deadline,
+                order,
+            });
+        if burst.count == 0 {
+            self.bursts += 1;
+        }
+        burst.last = message;
+        burst.count += 1;
+
+        vec![]
+    }
+
+    /// When the earliest pending burst should be logged
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|burst| burst.deadline).min()
+    }
+
+    /// Remove the bursts whose window has elapsed, returning their log
+    /// entries
+    pub(crate) fn take_expired(&mut self, now: Instant) -> Vec<serde_json::Value> {
+        let expired: Vec<BurstKey> = self
+            .pending
+            .iter()
+            .filter(|(_, burst)| burst.deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        let bursts = expired
+            .into_iter()
+            .filter_map(|key| self.pending.remove(&key))
+            .collect();
+        in_order(bursts)
+    }
+
+    /// Remove all pending bursts, e.g. when the stream ends
+    pub(crate) fn take_all(&mut self) -> Vec<serde_json::Value> {
+        in_order(self.pending.drain().map(|(_, burst)| burst).collect())
+    }
+}
+
+/// The log entries for `bursts`, in the order they started
+fn in_order(mut bursts: Vec<Burst>) -> Vec<serde_json::Value> {
+    bursts.sort_by_key(|burst| burst.order);
+    bursts.into_iter().map(Burst::into_entry).collect()
+}
+
+impl Burst {
+    fn into_entry(self) -> serde_json::Value {
+        let mut entry = self.last;
+        if self.count > 1 {
+            if let Some(object) = entry.as_object_mut() {
+                object.insert("_coalesced".to_string(), self.count.into());
+            }
+        }
+        entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn did_change(version: u64) -> serde_json::Value {
+        json!({"method": "textDocument/didChange", "params": {"version": version}})
+    }
+
+    #[test]
+    fn logs_a_burst_before_a_later_message() {
+        let mut coalescer = Coalescer::new(
+            vec![
+                "textDocument/didChange".to_string(),
+                "$/progress".to_string(),
+            ],
+            Duration::from_secs(60),
+        );
+        let now = Instant::now();
+        assert!(coalescer.add(did_change(1), now).is_empty());
+        assert!(coalescer
+            .add(json!({"method": "$/progress"}), now)
+            .is_empty());
+        assert!(coalescer.add(did_change(2), now).is_empty());
+
+        let hover = json!({"id": 1, "method": "textDocument/hover"});
+        let entries = coalescer.add(hover.clone(), now);
+        let mut burst = did_change(2);
+        burst["_coalesced"] = 2.into();
+        assert_eq!(entries, vec![burst, json!({"method": "$/progress"}), hover]);
+        assert!(coalescer.take_all().is_empty());
+    }
+
+    #[test]
+    fn keeps_the_last_change_to_each_document() {
+        let mut coalescer = Coalescer::new(
+            vec!["textDocument/didChange".to_string()],
+            Duration::from_secs(60),
+        );
+        let change = |uri: &str, version: u64| {
+            json!({
+                "method": "textDocument/didChange",
+                "params": {"textDocument": {"uri": uri, "version": version}},
+            })
+        };
+        let now = Instant::now();
+        for message in [
+            change("file:///a.rs", 1),
+            change("file:///b.rs", 1),
+            change("file:///a.rs", 2),
+            change("file:///b.rs", 2),
+            change("file:///b.rs", 3),
+        ] {
+            assert!(coalescer.add(message, now).is_empty());
+        }
+
+        let mut a = change("file:///a.rs", 2);
+        a["_coalesced"] = 2.into();
+        let mut b = change("file:///b.rs", 3);
+        b["_coalesced"] = 3.into();
+        assert_eq!(
+            coalescer.take_expired(now + Duration::from_secs(60)),
+            vec![a, b]
+        );
+    }
+}
@@ -0,0 +1,862 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock::{self, SharedClock, SystemClock};
+use crate::filter::Filter;
+use crate::line_log::LineFormat;
+use crate::plugin::{Plugin, SharedPlugin};
+use crate::shared_server::SharedServer;
+use crate::syslog::Facility;
+
+/// Settings for a proxy session, read from `LSP_*` environment
+/// variables
+#[derive(Clone)]
+pub(crate) struct ProxyConfig {
+    /// Directory to write log files to
+    pub(crate) log_dir: PathBuf,
+    /// How long to keep retrying if the log directory can't be created
+    pub(crate) log_dir_retry: Option<Duration>,
+    /// How messages are written to the stdin and stdout logs
+    pub(crate) log_format: LogFormat,
+    /// Permissions for the log files and directory we create
+    pub(crate) log_mode: LogMode,
+    /// Notification methods whose bursts are collapsed into one log line
+    pub(crate) coalesce_methods: Vec<String>,
+    /// How long a burst of coalesced notifications can last
+    pub(crate) coalesce_window: Duration,
+    /// Address of a TCP collector to also send JSON lines to
+    pub(crate) tee_addr: Option<String>,
+    /// Only send JSON lines to the collector, without local log files
+    pub(crate) tee_only: bool,
+    /// Address to serve the session's live status on as JSON
+    pub(crate) status_addr: Option<String>,
+    /// How many lines to buffer while the collector is unreachable
+    pub(crate) tee_buffer_lines: usize,
+    /// A named pipe to stream JSON log lines to
+    pub(crate) log_fifo: Option<PathBuf>,
+    /// What to call the editor-to-server and server-to-editor streams
+    /// in lines sent to the collector and FIFO
+    pub(crate) dir_labels: Option<(String, String)>,
+    /// How long the server can take to answer initialize before we
+    /// warn about it
+    pub(crate) initialize_timeout: Duration,
+    /// Deliberately send incorrect Content-Length headers to the editor
+    pub(crate) corrupt_framing: Option<CorruptFraming>,
+    /// Checksum each frame, writing the checksums to an index file and
+    /// verifying that frames are forwarded unchanged
+    pub(crate) checksum: bool,
+    /// Start JSON Lines logs with a `_meta` line describing the capture
+    pub(crate) capture_header: bool,
+    /// Servers to try, in order, if the requested server isn't found
+    pub(crate) server_fallbacks: Vec<String>,
+    /// Pipes to an already running server, used instead of spawning one
+    pub(crate) server_fds: Option<ServerFds>,
+    /// Write a binary index of the frames in the raw logs
+    pub(crate) binary_index: bool,
+    /// Also log a line per message, formatted with this template
+    pub(crate) line_format: Option<LineFormat>,
+    /// Write a CSV of every request and its response
+    pub(crate) csv_summary: bool,
+    /// Write a Chrome trace of the requests and notifications
+    pub(crate) trace: bool,
+    /// Rebuild each document from the editor's sync notifications,
+    /// writing out the final text
+    pub(crate) reconstruct_documents: bool,
+    /// Record diagnostic counts from each publishDiagnostics
+    pub(crate) diagnostics_timeline: bool,
+    /// Count traffic before and after the handshake separately
+    pub(crate) phase_metrics: bool,
+    /// Start labelled phases at milestones in the traffic, marking
+    /// each in the logs
+    pub(crate) phase_markers: bool,
+    /// FIFO to read manual phase labels from
+    pub(crate) phase_fifo: Option<PathBuf>,
+    /// FIFO to read annotations from
+    pub(crate) annotation_fifo: Option<PathBuf>,
+    /// File holding the text of the annotation written on SIGUSR1
+    pub(crate) annotation_file: Option<PathBuf>,
+    /// SQLite database to also add every message to
+    pub(crate) sqlite: Option<PathBuf>,
+    /// Also send a summary of each message to syslog
+    pub(crate) syslog: Option<Facility>,
+    /// Also log each method's messages to a file of their own
+    pub(crate) split_by_method: bool,
+    /// Add the time since the previous message in the same stream to
+    /// each JSON log entry
+    pub(crate) log_gaps: bool,
+    /// Add how long the server took to answer to each response in the
+    /// stdout JSON log
+    pub(crate) log_latency: bool,
+    /// Check that nothing comes between the server's messages
+    pub(crate) strict_length: bool,
+    /// Warn about message bodies that aren't valid UTF-8
+    pub(crate) strict_utf8: bool,
+    /// Write each message to the editor with one write, rather than
+    /// forwarding what was read from the server as it arrives
+    pub(crate) write_whole_messages: bool,
+    /// Only read the method, id and error of bodies larger than this,
+    /// when nothing logs the rest
+    pub(crate) metadata_only_bytes: Option<usize>,
+    /// Add each message's headers to JSON log entries
+    pub(crate) log_headers: bool,
+    /// Mask source text in logged didOpen and didChange notifications
+    pub(crate) anonymize_text: bool,
+    /// A command to run the server under, e.g. a profiler, with the
+    /// server and its arguments appended
+    pub(crate) server_wrapper: Vec<String>,
+    /// A command to run before starting the server
+    pub(crate) pre_hook: Vec<String>,
+    /// A command to run once the session's logs are written
+    pub(crate) post_hook: Vec<String>,
+    /// Record the server's environment in the summary, with secrets
+    /// redacted
+    pub(crate) record_env: bool,
+    /// Only record these environment variables, as they are
+    pub(crate) record_env_names: Vec<String>,
+    /// Delete logs smaller than this many bytes at the end of the
+    /// session
+    pub(crate) min_log_bytes: Option<u64>,
+    /// Log request ids as 1, 2, 3... rather than as sent
+    pub(crate) renumber_ids: bool,
+    /// Warn about identical messages sent twice within this long
+    pub(crate) dedup_window: Option<Duration>,
+    /// Don't log anything until a message with this method is sent
+    pub(crate) log_after_method: Option<String>,
+    /// Stop logging after a message with this method is sent
+    pub(crate) log_until_method: Option<String>,
+    /// Only log messages matching this predicate
+    pub(crate) filter: Option<Filter>,
+    /// Start new stdin and stdout logs at each clock boundary
+    pub(crate) rotate: Option<Rotation>,
+    /// Leave messages out of the logs rather than log faster than this
+    pub(crate) log_max_bytes_per_sec: Option<u64>,
+    /// Reopen the stdin, stdout and stderr logs on SIGHUP, for logrotate
+    pub(crate) reopen_on_sighup: bool,
+    /// Hold back didChange notifications sent within this long of the
+    /// previous change to the same document, merging them
+    pub(crate) throttle_did_change: Option<Duration>,
+    /// Warn if the editor hasn't read our stdout for this long
+    pub(crate) stdout_write_timeout: Option<Duration>,
+    /// End the session, rather than just warning, when the editor
+    /// hasn't read our stdout within the timeout
+    pub(crate) stdout_write_timeout_exit: bool,
+    /// Warn if the server hasn't read its stdin for this long
+    pub(crate) server_write_timeout: Option<Duration>,
+    /// How long to keep forwarding server output after the editor
+    /// closes stdin
+    pub(crate) stdout_drain_timeout: Duration,
+    /// End the session after this long, shutting down the server
+    pub(crate) max_duration: Option<Duration>,
+    /// Keep the session going until the editor has closed stdin and
+    /// the server has closed stdout, rather than until either does
+    pub(crate) wait_for_both_eof: bool,
+    /// Send each line of server stderr to the editor as a
+    /// `window/logMessage` notification
+    pub(crate) stderr_as_log_message: bool,
+    /// Send the server an initialize handshake if the editor's first
+    /// message isn't initialize
+    pub(crate) auto_initialize: bool,
+    /// JSON pointers under the editor's initialize capabilities that
+    /// must be advertised
+    pub(crate) require_capabilities: Vec<String>,
+    /// Answer an initialize missing required capabilities with an
+    /// error, rather than forwarding it
+    pub(crate) reject_missing_capabilities: bool,
+    /// Restart the server this many times if it crashes
+    pub(crate) respawn: usize,
+    /// Stop restarting the server after this many crashes within
+    /// `flap_window`
+    pub(crate) flap_threshold: Option<usize>,
+    pub(crate) flap_window: Duration,
+    /// Traffic levels to warn about
+    pub(crate) alert_thresholds: AlertThresholds,
+    /// Library given each message, which can drop or rewrite it
+    pub(crate) plugin: Option<SharedPlugin>,
+    /// How many plugin calls may run at once on each stream. Nothing
+    /// else about a message is processed concurrently.
+    pub(crate) plugin_concurrency: Option<usize>,
+    /// Address of a remote server to connect to instead of spawning
+    /// one, set by the connect subcommand
+    pub(crate) server_addr: Option<String>,
+    /// A server shared with other connections to use instead of
+    /// spawning one, set by listen with `--shared-server`
+    pub(crate) shared_server: Option<SharedServer>,
+    /// PEM certificate chain and key for accepting TLS connections in
+    /// listen mode
+    pub(crate) tls_cert: Option<PathBuf>,
+    pub(crate) tls_key: Option<PathBuf>,
+    /// PEM CA certificates for verifying the server in connect mode,
+    /// which enables TLS
+    pub(crate) tls_ca: Option<PathBuf>,
+    /// Where timestamps and latencies come from
+    pub(crate) clock: SharedClock,
+}
+
+/// Settings as if no `LSP_*` variables were set, with every optional
+/// feature off
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            log_dir: PathBuf::from("/tmp/lsp-fiddle"),
+            log_dir_retry: None,
+            log_format: LogFormat::Raw,
+            log_mode: LogMode::default(),
+            coalesce_methods: vec![],
+            coalesce_window: Duration::from_millis(500),
+            tee_addr: None,
+            tee_only: false,
+            status_addr: None,
+            tee_buffer_lines: 100_000,
+            log_fifo: None,
+            dir_labels: None,
+            initialize_timeout: Duration::from_secs(10),
+            corrupt_framing: None,
+            checksum: false,
+            capture_header: false,
+            server_fallbacks: vec![],
+            server_fds: None,
+            binary_index: false,
+            line_format: None,
+            csv_summary: false,
+            trace: false,
+            reconstruct_documents: false,
+            diagnostics_timeline: false,
+            phase_metrics: false,
+            phase_markers: false,
+            phase_fifo: None,
+            annotation_fifo: None,
+            annotation_file: None,
+            sqlite: None,
+            syslog: None,
+            split_by_method: false,
+            log_gaps: false,
+            log_latency: false,
+            strict_length: false,
+            strict_utf8: false,
+            write_whole_messages: false,
+            metadata_only_bytes: None,
+            log_headers: false,
+            anonymize_text: false,
+            server_wrapper: vec![],
+            pre_hook: vec![],
+            post_hook: vec![],
+            record_env: false,
+            record_env_names: vec![],
+            min_log_bytes: None,
+            renumber_ids: false,
+            dedup_window: None,
+            log_after_method: None,
+            log_until_method: None,
+            filter: None,
+            rotate: None,
+            log_max_bytes_per_sec: None,
+            reopen_on_sighup: false,
+            throttle_did_change: None,
+            stdout_write_timeout: None,
+            stdout_write_timeout_exit: false,
+            server_write_timeout: None,
+            stdout_drain_timeout: Duration::from_secs(2),
+            max_duration: None,
+            wait_for_both_eof: false,
+            stderr_as_log_message: false,
+            auto_initialize: false,
+            require_capabilities: vec![],
+            reject_missing_capabilities: false,
+            respawn: 0,
+            flap_threshold: None,
+            flap_window: Duration::from_secs(60),
+            alert_thresholds: AlertThresholds {
+                did_change_per_sec: None,
+                outstanding_requests: None,
+                payload_bytes: None,
+                backlog_messages: None,
+            },
+            plugin: None,
+            plugin_concurrency: None,
+            server_addr: None,
+            shared_server: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_ca: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl ProxyConfig {
+    pub(crate) fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            log_dir: env::var_os("LSP_LOG_DIR")
+                .map(PathBuf::from)
+                .unwrap_or(defaults.log_dir),
+            log_dir_retry: env_millis("LSP_LOG_DIR_RETRY_MS"),
+            log_format: LogFormat::from_env(),
+            log_mode: LogMode::from_env(),
+            coalesce_methods: env_list("LSP_COALESCE_METHODS"),
+            coalesce_window: env_millis("LSP_COALESCE_WINDOW_MS")
+                .unwrap_or(defaults.coalesce_window),
+            tee_addr: env::var("LSP_TEE_ADDR")
+                .ok()
+                .filter(|addr| !addr.is_empty()),
+            tee_only: env_flag("LSP_TEE_ONLY"),
+            status_addr: env::var("LSP_STATUS_ADDR")
+                .ok()
+                .filter(|addr| !addr.is_empty()),
+            tee_buffer_lines: env_number("LSP_TEE_BUFFER_LINES")
+                .unwrap_or(defaults.tee_buffer_lines),
+            log_fifo: env_path("LSP_LOG_FIFO"),
+            dir_labels: env_dir_labels(),
+            initialize_timeout: env_millis("LSP_INITIALIZE_TIMEOUT_MS")
+                .unwrap_or(defaults.initialize_timeout),
+            corrupt_framing: CorruptFraming::from_env(),
+            checksum: env_flag("LSP_CHECKSUM"),
+            capture_header: env_flag("LSP_HEADER"),
+            server_fallbacks: env_list("LSP_SERVER_FALLBACKS"),
+            server_fds: ServerFds::from_env(),
+            binary_index: env_flag("LSP_BINARY_INDEX"),
+            line_format: env::var("LSP_LINE_FORMAT")
+                .ok()
+                .filter(|format| !format.is_empty())
+                .map(|format| LineFormat::parse(&format)),
+            csv_summary: env_flag("LSP_CSV_SUMMARY"),
+            trace: env_flag("LSP_TRACE"),
+            reconstruct_documents: env_flag("LSP_RECONSTRUCT_DOCUMENTS"),
+            diagnostics_timeline: env_flag("LSP_DIAGNOSTICS_TIMELINE"),
+            phase_metrics: env_flag("LSP_PHASE_METRICS"),
+            phase_markers: env_flag("LSP_PHASE_MARKERS"),
+            phase_fifo: env_path("LSP_PHASE_FIFO"),
+            annotation_fifo: env_path("LSP_ANNOTATION_FIFO"),
+            annotation_file: env_path("LSP_ANNOTATION_FILE"),
+            sqlite: env_path("LSP_SQLITE"),
+            syslog: Facility::from_env(),
+            split_by_method: env_flag("LSP_SPLIT_BY_METHOD"),
+            log_gaps: env_flag("LSP_LOG_GAPS"),
+            log_latency: env_flag("LSP_LOG_LATENCY"),
+            strict_length: env_flag("LSP_STRICT_LENGTH"),
+            strict_utf8: env_flag("LSP_STRICT_UTF8"),
+            write_whole_messages: env_flag("LSP_WRITE_WHOLE_MESSAGES"),
+            metadata_only_bytes: env_number("LSP_METADATA_ONLY_BYTES"),
+            log_headers: env_flag("LSP_LOG_HEADERS"),
+            anonymize_text: env_flag("LSP_ANONYMIZE_TEXT"),
+            renumber_ids: env_flag("LSP_RENUMBER_IDS"),
+            min_log_bytes: env_number("LSP_MIN_LOG_BYTES"),
+            server_wrapper: env_command("LSP_SERVER_WRAPPER"),
+            pre_hook: env_command("LSP_PRE_HOOK"),
+            post_hook: env_command("LSP_POST_HOOK"),
+            record_env: env_flag("LSP_RECORD_ENV"),
+            record_env_names: env_list("LSP_RECORD_ENV_NAMES"),
+            dedup_window: env_millis("LSP_DEDUP_WINDOW_MS"),
+            log_after_method: env::var("LSP_LOG_AFTER_METHOD")
+                .ok()
+                .filter(|method| !method.is_empty()),
+            log_until_method: env::var("LSP_LOG_UNTIL_METHOD")
+                .ok()
+                .filter(|method| !method.is_empty()),
+            filter: Filter::from_env(),
+            rotate: Rotation::from_env(),
+            log_max_bytes_per_sec: env_number("LSP_LOG_MAX_BYTES_PER_SEC")
+                .filter(|&bytes_per_sec: &u64| bytes_per_sec > 0),
+            reopen_on_sighup: env_flag("LSP_REOPEN_ON_SIGHUP"),
+            throttle_did_change: env_millis("LSP_THROTTLE_DID_CHANGE_MS"),
+            stdout_write_timeout: env_millis("LSP_STDOUT_WRITE_TIMEOUT_MS"),
+            stdout_write_timeout_exit: env_flag("LSP_STDOUT_WRITE_TIMEOUT_EXIT"),
+            server_write_timeout: env_millis("LSP_SERVER_WRITE_TIMEOUT_MS"),
+            stdout_drain_timeout: env_millis("LSP_STDOUT_DRAIN_TIMEOUT_MS")
+                .unwrap_or(defaults.stdout_drain_timeout),
+            max_duration: env_number("LSP_MAX_DURATION_SECS").map(Duration::from_secs),
+            wait_for_both_eof: env_flag("LSP_WAIT_FOR_BOTH_EOF"),
+            stderr_as_log_message: env_flag("LSP_STDERR_AS_LOGMESSAGE"),
+            auto_initialize: env_flag("LSP_AUTO_INITIALIZE"),
+            // The leading slash of each pointer is optional.
+            require_capabilities: env_list("LSP_REQUIRE_CAPABILITIES")
+                .into_iter()
+                .map(capability_pointer)
+                .collect(),
+            reject_missing_capabilities: env_flag("LSP_REJECT_MISSING_CAPABILITIES"),
+            respawn: env_number("LSP_RESPAWN").unwrap_or(defaults.respawn),
+            flap_threshold: env_number("LSP_FLAP_THRESHOLD").filter(|&crashes: &usize| crashes > 0),
+            flap_window: env_number("LSP_FLAP_WINDOW_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.flap_window),
+            alert_thresholds: AlertThresholds {
+                did_change_per_sec: env_number("LSP_ALERT_DID_CHANGE_PER_SEC"),
+                outstanding_requests: env_number("LSP_ALERT_OUTSTANDING_REQUESTS"),
+                payload_bytes: env_number("LSP_ALERT_PAYLOAD_BYTES"),
+                backlog_messages: env_number("LSP_ALERT_BACKLOG_MESSAGES"),
+            },
+            plugin: Plugin::from_env(),
+            plugin_concurrency: env_number("LSP_PLUGIN_CONCURRENCY")
+                .filter(|&concurrency: &usize| concurrency > 1),
+            server_addr: None,
+            shared_server: None,
+            tls_cert: env_path("LSP_TLS_CERT"),
+            tls_key: env_path("LSP_TLS_KEY"),
+            tls_ca: env_path("LSP_TLS_CA"),
+            clock: clock::from_env(),
+        }
+    }
+}
+
+/// Unix permissions for new log files, from `LSP_LOG_MODE`. Logs hold
+/// source code and paths, so they're only readable by the user unless
+/// configured otherwise.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct LogMode(pub(crate) u32);
+
+impl Default for LogMode {
+    fn default() -> Self {
+        Self(0o600)
+    }
+}
+
+impl LogMode {
+    fn from_env() -> Self {
+        let Ok(mode) = env::var("LSP_LOG_MODE") else {
+            return Self::default();
+        };
+        Self::parse(&mode).unwrap_or_else(|e| {
+            eprintln!("Ignoring LSP_LOG_MODE={:?}: {}", mode, e);
+            Self::default()
+        })
+    }
+
+    /// Parses octal permissions, as used by `LSP_LOG_MODE`
+    fn parse(mode: &str) -> Result<Self, String> {
+        match u32::from_str_radix(mode.trim().trim_start_matches("0o"), 8) {
+            Ok(bits) if bits <= 0o777 => Ok(Self(bits)),
+            _ => Err("expected octal permissions, e.g. 600 or 640".to_string()),
+        }
+    }
+
+    /// Permissions for directories, which can be entered by anyone who
+    /// can read the files
+    pub(crate) fn dir_mode(self) -> u32 {
+        self.0 | (self.0 & 0o444) >> 2
+    }
+}
+
+/// How messages are written to the stdin and stdout logs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum LogFormat {
+    /// The exact bytes sent, including headers
+    Raw,
+    /// One JSON message per line
+    JsonLines,
+    /// A single JSON array of messages
+    JsonArray,
+}
+
+impl LogFormat {
+    /// Reads `LSP_FORMAT`, falling back to `LSP_JSON_LINES`
+    fn from_env() -> Self {
+        match env::var("LSP_FORMAT").as_deref() {
+            Ok(name) if !name.is_empty() => Self::from_name(name).unwrap_or_else(|e| {
+                eprintln!("Ignoring LSP_FORMAT={:?}: {}", name, e);
+                Self::from_json_lines_flag()
+            }),
+            _ => Self::from_json_lines_flag(),
+        }
+    }
+
+    /// Parses a format name, as used by `LSP_FORMAT`
+    pub(crate) fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "raw" => Ok(LogFormat::Raw),
+            "jsonl" | "json-lines" => Ok(LogFormat::JsonLines),
+            "json-array" => Ok(LogFormat::JsonArray),
+            _ => Err("expected raw, jsonl or json-array".to_string()),
+        }
+    }
+
+    /// The format of a log, from its file extension
+    pub(crate) fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "log" => Some(LogFormat::Raw),
+            "jsonl" => Some(LogFormat::JsonLines),
+            "json" => Some(LogFormat::JsonArray),
+            _ => None,
+        }
+    }
+
+    fn from_json_lines_flag() -> Self {
+        if env_flag("LSP_JSON_LINES") {
+            LogFormat::JsonLines
+        } else {
+            LogFormat::Raw
+        }
+    }
+
+    /// Whether messages are parsed and logged as JSON
+    pub(crate) fn is_json(self) -> bool {
+        self != LogFormat::Raw
+    }
+
+    pub(crate) fn file_extension(self) -> &'static str {
+        match self {
+            LogFormat::Raw => "log",
+            LogFormat::JsonLines => "jsonl",
+            LogFormat::JsonArray => "json",
+        }
+    }
+}
+
+/// How often to start new stdin and stdout logs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Rotation {
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    /// Reads `LSP_ROTATE`
+    fn from_env() -> Option<Self> {
+        match env::var("LSP_ROTATE").as_deref() {
+            Ok(name) if !name.is_empty() => match Self::from_name(name) {
+                Ok(rotation) => Some(rotation),
+                Err(e) => {
+                    eprintln!("Ignoring LSP_ROTATE={:?}: {}", name, e);
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+
+    fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            _ => Err("expected hourly or daily".to_string()),
+        }
+    }
+}
+
+/// Limits on traffic that suggest the editor or server is misbehaving.
+/// Each alert is disabled unless its limit is set.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct AlertThresholds {
+    /// didChange notifications the editor can send in one second
+    pub(crate) did_change_per_sec: Option<usize>,
+    /// Editor requests that can be waiting for a response at once
+    pub(crate) outstanding_requests: Option<usize>,
+    /// Largest message body, in bytes
+    pub(crate) payload_bytes: Option<usize>,
+    /// Complete messages that can be waiting to be forwarded at once
+    pub(crate) backlog_messages: Option<usize>,
+}
+
+/// Inherited file descriptors connected to a server that another
+/// process manages
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ServerFds {
+    /// Written to, connected to the server's stdin
+    pub(crate) input: i32,
+    /// Read from, connected to the server's stdout
+    pub(crate) output: i32,
+}
+
+impl ServerFds {
+    /// Reads `LSP_SERVER_FD_IN` and `LSP_SERVER_FD_OUT`, which must be
+    /// set together
+    fn from_env() -> Option<Self> {
+        Self::new(
+            env_number("LSP_SERVER_FD_IN"),
+            env_number("LSP_SERVER_FD_OUT"),
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("Ignoring LSP_SERVER_FD_IN/LSP_SERVER_FD_OUT: {}", e);
+            None
+        })
+    }
+
+    fn new(input: Option<u16>, output: Option<u16>) -> Result<Option<Self>, String> {
+        match (input, output) {
+            (Some(input), Some(output)) => Ok(Some(ServerFds {
+                input: input.into(),
+                output: output.into(),
+            })),
+            (None, None) => Ok(None),
+            _ => Err("both must be set".to_string()),
+        }
+    }
+}
+
+/// Fault injection for testing how editors handle framing errors:
+/// the Content-Length of selected messages is off by `delta` bytes
+#[derive(Clone, Debug)]
+pub(crate) struct CorruptFraming {
+    delta: i64,
+    /// Methods to corrupt, including responses to requests with these
+    /// methods. All messages are corrupted if this is empty.
+    methods: Vec<String>,
+}
+
+impl CorruptFraming {
+    fn from_env() -> Option<Self> {
+        let delta = env::var("LSP_CORRUPT_FRAMING").ok()?;
+        match Self::new(&delta, env_list("LSP_CORRUPT_FRAMING_METHODS")) {
+            Ok(corrupt_framing) => Some(corrupt_framing),
+            Err(e) => {
+                eprintln!("Ignoring LSP_CORRUPT_FRAMING={:?}: {}", delta, e);
+                None
+            }
+        }
+    }
+
+    fn new(delta: &str, methods: Vec<String>) -> Result<Self, String> {
+        match delta.trim().trim_start_matches('+').parse::<i64>() {
+            Ok(delta) if delta != 0 => Ok(Self { delta, methods }),
+            _ => Err("expected a non-zero number of bytes, e.g. -5 or +5".to_string()),
+        }
+    }
+
+    pub(crate) fn applies_to(&self, method: Option<&str>) -> bool {
+        if self.methods.is_empty() {
+            return true;
+        }
+        method.is_some_and(|method| self.methods.iter().any(|m| m == method))
+    }
+
+    pub(crate) fn content_length(&self, actual_length: usize) -> usize {
+        (actual_length as i64 + self.delta).max(0) as usize
+    }
+}
+
+/// Whether an environment variable is set to `1` or `true`
+pub(crate) fn env_flag(name: &str) -> bool {
+    env::var(name).is_ok_and(|v| is_true(&v))
+}
+
+fn is_true(value: &str) -> bool {
+    value == "1" || value.to_lowercase() == "true"
+}
+
+/// Reads a comma-separated environment variable, ignoring empty items
+pub(crate) fn env_list(name: &str) -> Vec<String> {
+    env::var(name).map(|v| split_list(&v)).unwrap_or_default()
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| item.to_string())
+        .collect()
+}
+
+/// A JSON pointer into the server's capabilities, for
+/// `LSP_REQUIRE_CAPABILITIES`, where the leading slash is optional
+fn capability_pointer(pointer: String) -> String {
+    match pointer.starts_with('/') {
+        true => pointer,
+        false => format!("/{}", pointer),
+    }
+}
+
+/// Reads `LSP_DIR_LABELS`, a label for each direction separated by a
+/// comma
+fn env_dir_labels() -> Option<(String, String)> {
+    let value = env::var("LSP_DIR_LABELS").ok()?;
+    match dir_labels(&value) {
+        Ok(labels) => Some(labels),
+        Err(e) => {
+            eprintln!("Ignoring LSP_DIR_LABELS={:?}: {}", value, e);
+            None
+        }
+    }
+}
+
+fn dir_labels(value: &str) -> Result<(String, String), String> {
+    match split_list(value).as_slice() {
+        [to_server, to_editor] if to_server != to_editor => {
+            Ok((to_server.clone(), to_editor.clone()))
+        }
+        _ => Err("expected two different labels, e.g. client2server,server2client".to_string()),
+    }
+}
+
+/// Reads an environment variable holding a command and its arguments,
+/// split into words like a shell would: quotes group words, and a
+/// backslash escapes the next character outside single quotes
+pub(crate) fn env_command(name: &str) -> Vec<String> {
+    let Ok(value) = env::var(name) else {
+        return vec![];
+    };
+    match split_words(&value) {
+        Some(words) => words,
+        None => {
+            eprintln!("Ignoring {}: unterminated quote in {:?}", name, value);
+            vec![]
+        }
+    }
+}
+
+/// Split `text` into shell words, or None if a quote isn't closed
+fn split_words(text: &str) -> Option<Vec<String>> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), c) => word.get_or_insert_with(String::new).push(c),
+            (_, '\\') => {
+                let escaped = chars.next()?;
+                word.get_or_insert_with(String::new).push(escaped);
+            }
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return None;
+    }
+    words.extend(word);
+    Some(words)
+}
+
+/// Reads an environment variable holding a path, ignoring it if empty
+pub(crate) fn env_path(name: &str) -> Option<PathBuf> {
+    env::var_os(name)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Reads an environment variable holding a non-negative number
+pub(crate) fn env_number<T: FromStr>(name: &str) -> Option<T> {
+    let value = env::var(name).ok()?;
+    match value.trim().parse() {
+        Ok(number) => Some(number),
+        Err(_) => {
+            eprintln!("Ignoring {}: expected a number, got {:?}", name, value);
+            None
+        }
+    }
+}
+
+/// Reads an environment variable holding a number of milliseconds
+pub(crate) fn env_millis(name: &str) -> Option<Duration> {
+    env_number(name).map(Duration::from_millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_log_modes() {
+        assert_eq!(LogMode::parse("640").unwrap().0, 0o640);
+        assert_eq!(LogMode::parse(" 0o600 ").unwrap().0, 0o600);
+        assert!(LogMode::parse("1000").is_err());
+        assert!(LogMode::parse("rw-r-----").is_err());
+        assert_eq!(LogMode(0o640).dir_mode(), 0o750);
+        assert_eq!(LogMode::default().dir_mode(), 0o700);
+    }
+
+    #[test]
+    fn parses_log_formats() {
+        assert_eq!(LogFormat::from_name("raw"), Ok(LogFormat::Raw));
+        assert_eq!(LogFormat::from_name("jsonl"), Ok(LogFormat::JsonLines));
+        assert_eq!(LogFormat::from_name("json-lines"), Ok(LogFormat::JsonLines));
+        assert_eq!(LogFormat::from_name("json-array"), Ok(LogFormat::JsonArray));
+        assert!(LogFormat::from_name("json").is_err());
+
+        for format in [LogFormat::Raw, LogFormat::JsonLines, LogFormat::JsonArray] {
+            let path = PathBuf::from(format!("stdin.{}", format.file_extension()));
+            assert_eq!(LogFormat::from_path(&path), Some(format));
+        }
+        assert_eq!(LogFormat::from_path(Path::new("stdin.txt")), None);
+        assert_eq!(LogFormat::from_path(Path::new("stdin")), None);
+    }
+
+    #[test]
+    fn parses_rotations() {
+        assert_eq!(Rotation::from_name("hourly"), Ok(Rotation::Hourly));
+        assert_eq!(Rotation::from_name("daily"), Ok(Rotation::Daily));
+        assert!(Rotation::from_name("weekly").is_err());
+    }
+
+    #[test]
+    fn needs_both_server_fds() {
+        let fds = ServerFds::new(Some(3), Some(4)).unwrap().unwrap();
+        assert_eq!((fds.input, fds.output), (3, 4));
+        assert!(ServerFds::new(None, None).unwrap().is_none());
+        assert!(ServerFds::new(Some(3), None).is_err());
+        assert!(ServerFds::new(None, Some(4)).is_err());
+    }
+
+    #[test]
+    fn corrupts_the_framing_of_chosen_methods() {
+        let corrupt = CorruptFraming::new("+5", vec![]).unwrap();
+        assert_eq!(corrupt.content_length(10), 15);
+        assert!(corrupt.applies_to(None));
+
+        let corrupt = CorruptFraming::new(" -20", vec!["initialize".to_string()]).unwrap();
+        assert_eq!(corrupt.content_length(10), 0);
+        assert!(corrupt.applies_to(Some("initialize")));
+        assert!(!corrupt.applies_to(Some("shutdown")));
+        assert!(!corrupt.applies_to(None));
+
+        assert!(CorruptFraming::new("0", vec![]).is_err());
+        assert!(CorruptFraming::new("five", vec![]).is_err());
+    }
+
+    #[test]
+    fn parses_lists() {
+        assert!(is_true("1"));
+        assert!(is_true("TRUE"));
+        assert!(!is_true("yes"));
+        assert!(!is_true(""));
+
+        assert_eq!(split_list(" a, ,b ,"), vec!["a", "b"]);
+        assert!(split_list("").is_empty());
+        assert_eq!(
+            capability_pointer("hoverProvider".to_string()),
+            "/hoverProvider"
+        );
+        assert_eq!(
+            capability_pointer("/workspace/fileOperations".to_string()),
+            "/workspace/fileOperations"
+        );
+    }
+
+    #[test]
+    fn needs_two_different_dir_labels() {
+        assert_eq!(
+            dir_labels("client2server, server2client"),
+            Ok(("client2server".to_string(), "server2client".to_string()))
+        );
+        assert!(dir_labels("in,in").is_err());
+        assert!(dir_labels("in").is_err());
+        assert!(dir_labels("in,out,err").is_err());
+    }
+
+    #[test]
+    fn splits_commands_like_a_shell() {
+        assert_eq!(
+            split_words(r#"nice -n 10  "my server" 'a "b"' c\ d e"f"g"#),
+            Some(
+                ["nice", "-n", "10", "my server", r#"a "b""#, "c d", "efg"]
+                    .map(String::from)
+                    .to_vec()
+            )
+        );
+        assert_eq!(
+            split_words(r#"'' """#),
+            Some(vec![String::new(), String::new()])
+        );
+        assert_eq!(split_words("  "), Some(vec![]));
+        assert_eq!(split_words("'unterminated"), None);
+        assert_eq!(split_words("trailing\\"), None);
+    }
+}
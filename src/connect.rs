@@ -0,0 +1,35 @@
+use anyhow::{Context, Result};
+use tokio::net::TcpStream;
+
+use crate::config::ProxyConfig;
+use crate::tls::{self, Connection};
+use crate::{run_session, ServerInput, ServerOutput};
+
+/// Proxies the editor on stdio to an LSP server already listening on
+/// `addr`, rather than spawning one
+pub(crate) async fn run_connect(addr: String) -> Result<()> {
+    let mut config = ProxyConfig::from_env();
+    config.server_addr = Some(addr.clone());
+
+    run_session(&addr, &[], config, tokio::io::stdin(), tokio::io::stdout()).await?;
+    Ok(())
+}
+
+/// Opens a connection to a remote server, using TLS if `LSP_TLS_CA` is
+/// set
+pub(crate) async fn connect_to_server(
+    addr: &str,
+    config: &ProxyConfig,
+) -> Result<(ServerInput, ServerOutput)> {
+    let stream = TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("Failed to connect to LSP server at {}", addr))?;
+
+    let connection: Box<dyn Connection> = match &config.tls_ca {
+        Some(ca) => tls::connect(&tls::connector(ca)?, addr, stream).await?,
+        None => Box::new(stream),
+    };
+
+    let (output, input) = tokio::io::split(connection);
+    Ok((Box::new(input), Box::new(output)))
+}
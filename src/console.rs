@@ -0,0 +1,47 @@
+use std::io::IsTerminal;
+use std::sync::OnceLock;
+
+/// ANSI colors for messages on stderr
+#[derive(Clone, Copy)]
+pub(crate) enum Color {
+    Red,
+    Yellow,
+    /// Faint text, for output that's passed through from the server
+    Dim,
+}
+
+impl Color {
+    fn code(self) -> &'static str {
+        match self {
+            Color::Red => "31",
+            Color::Yellow => "33",
+            Color::Dim => "2",
+        }
+    }
+}
+
+/// Whether to color what we print to stderr: only when it's a
+/// terminal, so redirected output stays plain, and never when
+/// `NO_COLOR` is set
+fn color_stderr() -> bool {
+    static COLOR: OnceLock<bool> = OnceLock::new();
+    *COLOR.get_or_init(|| {
+        std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+    })
+}
+
+/// `text` in `color`, if stderr is colored
+pub(crate) fn paint(text: &str, color: Color) -> String {
+    if color_stderr() {
+        format!("\x1b[{}m{}\x1b[0m", color.code(), text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Whether stdout is a terminal, so JSON printed there can be
+/// indented for reading rather than kept to one line for scripts
+pub(crate) fn pretty_stdout() -> bool {
+    static PRETTY: OnceLock<bool> = OnceLock::new();
+    *PRETTY.get_or_init(|| std::io::stdout().is_terminal())
+}
@@ -0,0 +1,247 @@
+use anyhow::Result;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use crate::capture::{self, Entry};
+use crate::config::LogFormat;
+
+/// Totals for one method, split by stream
+#[derive(Default)]
+struct MethodCount {
+    stdin: usize,
+    stdout: usize,
+}
+
+/// Message counts across one or more captures
+#[derive(Default)]
+struct Counts {
+    stdin: usize,
+    stdout: usize,
+    requests: usize,
+    notifications: usize,
+    responses: usize,
+    errors: usize,
+    empty: usize,
+    unparseable: usize,
+    methods: BTreeMap<String, MethodCount>,
+    /// Methods of requests, keyed by the stream they were sent on and
+    /// their id, so responses can be attributed
+    requests_by_id: HashMap<(&'static str, String), String>,
+}
+
+impl Counts {
+    /// Remember a request's method, for counting its response. Requests
+    /// are recorded before counting, as a request from the server is
+    /// in the stdout log but its response is in the stdin log.
+    fn record_request(&mut self, stream: &'static str, message: &Value) {
+        if let (Some(method), Some(id)) = (
+            message.get("method").and_then(|m| m.as_str()),
+            message.get("id"),
+        ) {
+            self.requests_by_id
+                .insert((stream, id.to_string()), method.to_string());
+        }
+    }
+
+    fn add(&mut self, stream: &'static str, message: &Value) {
+        match stream {
+            "stdin" => self.stdin += 1,
+            _ => self.stdout += 1,
+        }
+
+        if message.get("_empty").is_some() {
+            self.empty += 1;
+            return;
+        }
+
+        let id = message.get("id").map(|id| id.to_string());
+        let method = match (message.get("method").and_then(|m| m.as_str()), id) {
+            (Some(method), Some(_)) => {
+                self.requests += 1;
+                method.to_string()
+            }
+            (Some(method), None) => {
+                self.notifications += 1;
+                method.to_string()
+            }
+            (None, Some(id)) => {
+                self.responses += 1;
+                if message.get("error").is_some() {
+                    self.errors += 1;
+                }
+                // Responses travel in the opposite direction to their
+                // request.
+                let request_stream = if stream == "stdin" { "stdout" } else { "stdin" };
+                self.requests_by_id
+                    .get(&(request_stream, id))
+                    .cloned()
+                    .unwrap_or_else(|| "(unknown request)".to_string())
+            }
+            (None, None) => {
+                self.unparseable += 1;
+                return;
+            }
+        };
+
+        let count = self.methods.entry(method).or_default();
+        match stream {
+            "stdin" => count.stdin += 1,
+            _ => count.stdout += 1,
+        }
+    }
+
+    fn print(&self) {
+        println!(
+            "Messages: {} (stdin {}, stdout {})",
+            self.stdin + self.stdout,
+            self.stdin,
+            self.stdout
+        );
+        println!(
+            "Requests: {}, responses: {}, notifications: {}, errors: {}",
+            self.requests, self.responses, self.notifications, self.errors
+        );
+        if self.empty > 0 {
+            println!("Empty: {}", self.empty);
+        }
+        if self.unparseable > 0 {
+            println!("Unparseable: {}", self.unparseable);
+        }
+
+        let mut methods: Vec<_> = self.methods.iter().collect();
+        methods.sort_by_key(|(_, count)| std::cmp::Reverse(count.stdin + count.stdout));
+
+        println!();
+        println!("{:>8} {:>8} {:>8}  Method", "Total", "stdin", "stdout");
+        for (method, count) in methods {
+            println!(
+                "{:>8} {:>8} {:>8}  {}",
+                count.stdin + count.stdout,
+                count.stdin,
+                count.stdout,
+                method
+            );
+        }
+    }
+}
+
+/// Prints message counts for captures, by method and stream. Accepts
+/// logs in any `LSP_FORMAT`, and JSON Lines files whose lines are
+/// `{"stream":..,"message":..}`, such as method logs and tee output.
+pub(crate) fn count(paths: &[impl AsRef<Path>]) -> Result<()> {
+    count_logs(paths)?.print();
+    Ok(())
+}
+
+fn count_logs(paths: &[impl AsRef<Path>]) -> Result<Counts> {
+    let mut counts = Counts::default();
+    let mut messages = vec![];
+
+    for path in paths {
+        let path = path.as_ref();
+        let stream = capture::stream_from_file_name(path);
+        let format = LogFormat::from_path(path).unwrap_or(LogFormat::Raw);
+
+        for entry in capture::read_log(path, format)? {
+            match entry {
+                Entry::Message(entry) => messages.extend(capture::unwrap_entry(stream, entry)),
+                Entry::Unparsed(_) => counts.unparseable += 1,
+            }
+        }
+    }
+
+    for (stream, message) in &messages {
+        counts.record_request(stream, message);
+    }
+    for (stream, message) in &messages {
+        counts.add(stream, message);
+    }
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::tests::temp_path;
+
+    #[test]
+    fn attributes_responses_to_requests_from_either_stream() {
+        let stdin = temp_path("count_stdin.jsonl");
+        let stdout = temp_path("count_stdout.jsonl");
+        std::fs::write(
+            &stdin,
+            [
+                r#"{"_meta":{"server":"rust-analyzer"}}"#,
+                r#"{"jsonrpc":"2.0","id":1,"method":"initialize"}"#,
+                r#"{"jsonrpc":"2.0","method":"initialized"}"#,
+                r#"{"jsonrpc":"2.0","id":"r1","result":null}"#,
+                "not json",
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+        std::fs::write(
+            &stdout,
+            [
+                r#"{"jsonrpc":"2.0","id":1,"result":{}}"#,
+                r#"{"jsonrpc":"2.0","id":"r1","method":"client/registerCapability"}"#,
+                r#"{"jsonrpc":"2.0","id":2,"error":{"code":-32601}}"#,
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+
+        let counts = count_logs(&[&stdin, &stdout]).unwrap();
+        assert_eq!((counts.stdin, counts.stdout), (3, 3));
+        assert_eq!(
+            (
+                counts.requests,
+                counts.notifications,
+                counts.responses,
+                counts.errors,
+                counts.unparseable,
+            ),
+            (2, 1, 3, 1, 1)
+        );
+        let methods: Vec<_> = counts
+            .methods
+            .iter()
+            .map(|(method, count)| (method.as_str(), count.stdin, count.stdout))
+            .collect();
+        assert_eq!(
+            methods,
+            vec![
+                ("(unknown request)", 0, 1),
+                ("client/registerCapability", 1, 1),
+                ("initialize", 1, 1),
+                ("initialized", 1, 0),
+            ]
+        );
+
+        let _ = std::fs::remove_file(&stdin);
+        let _ = std::fs::remove_file(&stdout);
+    }
+
+    #[test]
+    fn counts_empty_messages_in_raw_logs() {
+        let path = temp_path("count_raw.log");
+        std::fs::write(
+            &path,
+            format!(
+                "{}{}",
+                crate::format_lsp_message(""),
+                crate::format_lsp_message(r#"{"jsonrpc":"2.0","method":"exit"}"#)
+            ),
+        )
+        .unwrap();
+
+        let counts = count_logs(&[&path]).unwrap();
+        assert_eq!(
+            (counts.stdout, counts.empty, counts.notifications),
+            (2, 1, 1)
+        );
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::stream::Direction;
+
+/// Notifications that are legitimately sent with identical payloads,
+/// e.g. the same progress report or diagnostics published again
+const REPEATABLE_NOTIFICATIONS: &[&str] = &[
+    "$/progress",
+    "$/logTrace",
+    "window/logMessage",
+    "window/showMessage",
+    "telemetry/event",
+    "textDocument/publishDiagnostics",
+];
+
+/// Spots identical messages sent twice in quick succession, which
+/// usually means a double-send bug in the editor or server
+pub(crate) struct DuplicateDetector {
+    window: Duration,
+    /// When each recent payload was last sent
+    recent: HashMap<(Direction, u64), Instant>,
+    last_pruned: Instant,
+}
+
+impl DuplicateDetector {
+    pub(crate) fn new(window: Duration, now: Instant) -> Self {
+        Self {
+            window,
+            recent: HashMap::new(),
+            last_pruned: now,
+        }
+    }
+
+    /// Record a message, returning true if the same payload was sent in
+    /// the same direction within the window
+    pub(crate) fn is_duplicate(
+        &mut self,
+        direction: Direction,
+        message: &serde_json::Value,
+        payload: &str,
+        now: Instant,
+    ) -> bool {
+        if message.get("id").is_none() {
+            let method = message.get("method").and_then(|m| m.as_str());
+            if method.is_some_and(|method| REPEATABLE_NOTIFICATIONS.contains(&method)) {
+                return false;
+            }
+        }
+
+        if now.duration_since(self.last_pruned) > self.window {
+            self.recent
+                .retain(|_, sent| now.duration_since(*sent) <= self.window);
+            self.last_pruned = now;
+        }
+
+        let key = (direction, xxh3_64(payload.as_bytes()));
+        match self.recent.insert(key, now) {
+            Some(sent) => now.duration_since(sent) <= self.window,
+            None => false,
+        }
+    }
+}
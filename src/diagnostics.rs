@@ -0,0 +1,113 @@
+use chrono::{DateTime, Local};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Diagnostic counts for one document, by severity
+type Counts = BTreeMap<&'static str, usize>;
+
+/// One `textDocument/publishDiagnostics` notification
+struct Published {
+    time: DateTime<Local>,
+    uri: String,
+    version: Option<i64>,
+    counts: Counts,
+    /// The editor's most recent change before this, as a URI and
+    /// version
+    after_change: Option<(String, Option<i64>)>,
+}
+
+/// How many diagnostics of each severity the server has published for
+/// each document over the session
+#[derive(Default)]
+pub(crate) struct DiagnosticsTimeline {
+    published: Vec<Published>,
+    last_change: Option<(String, Option<i64>)>,
+}
+
+impl DiagnosticsTimeline {
+    /// Remember the editor's latest `didChange`, so diagnostics can be
+    /// tied to the edit that caused them
+    pub(crate) fn observe_change(&mut self, params: Option<&Value>) {
+        let Some(uri) = params
+            .and_then(|params| params.pointer("/textDocument/uri"))
+            .and_then(|uri| uri.as_str())
+        else {
+            return;
+        };
+        let version = params
+            .and_then(|params| params.pointer("/textDocument/version"))
+            .and_then(|version| version.as_i64());
+        self.last_change = Some((uri.to_string(), version));
+    }
+
+    pub(crate) fn observe_publish(&mut self, params: Option<&Value>, time: DateTime<Local>) {
+        let Some(uri) = params
+            .and_then(|params| params.get("uri"))
+            .and_then(|uri| uri.as_str())
+        else {
+            return;
+        };
+        let mut counts = Counts::new();
+        let diagnostics = params
+            .and_then(|params| params.get("diagnostics"))
+            .and_then(|diagnostics| diagnostics.as_array())
+            .map(|diagnostics| diagnostics.as_slice())
+            .unwrap_or_default();
+        for diagnostic in diagnostics {
+            let severity = severity(diagnostic.get("severity").and_then(|s| s.as_u64()));
+            *counts.entry(severity).or_default() += 1;
+        }
+        self.published.push(Published {
+            time,
+            uri: uri.to_string(),
+            version: params
+                .and_then(|params| params.get("version"))
+                .and_then(|version| version.as_i64()),
+            counts,
+            after_change: self.last_change.clone(),
+        });
+    }
+
+    /// A JSON line per notification, in the order they were sent
+    pub(crate) fn to_json_lines(&self) -> String {
+        self.published
+            .iter()
+            .map(|published| {
+                let after_change = published
+                    .after_change
+                    .as_ref()
+                    .map(|(uri, version)| serde_json::json!({ "uri": uri, "version": version }));
+                let line = serde_json::json!({
+                    "timestamp": published.time.to_rfc3339(),
+                    "uri": published.uri,
+                    "version": published.version,
+                    "counts": published.counts,
+                    "after_change": after_change,
+                });
+                format!("{}\n", line)
+            })
+            .collect()
+    }
+
+    /// The counts last published for each document. Each notification
+    /// replaces all earlier diagnostics for its document.
+    pub(crate) fn summary_json(&self) -> Value {
+        let mut latest: BTreeMap<&str, &Counts> = BTreeMap::new();
+        for published in &self.published {
+            latest.insert(&published.uri, &published.counts);
+        }
+        serde_json::json!(latest)
+    }
+}
+
+/// The name of a `DiagnosticSeverity`. The spec leaves diagnostics
+/// without one to the editor to interpret.
+fn severity(severity: Option<u64>) -> &'static str {
+    match severity {
+        Some(1) => "error",
+        Some(2) => "warning",
+        Some(3) => "information",
+        Some(4) => "hint",
+        _ => "unspecified",
+    }
+}
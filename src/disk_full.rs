@@ -0,0 +1,26 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set once a log write has failed because the disk is full
+static DISK_FULL: AtomicBool = AtomicBool::new(false);
+
+/// Whether logging has stopped because the log disk filled up.
+/// Messages are still proxied.
+pub(crate) fn logging_disabled() -> bool {
+    DISK_FULL.load(Ordering::Relaxed)
+}
+
+/// Report a failed write to `log`. A full disk stops all logging with
+/// a single message, rather than every later write failing too.
+pub(crate) fn log_write_failed(log: &str, e: &io::Error) {
+    if e.kind() != io::ErrorKind::StorageFull {
+        eprintln!("Failed to write to {}: {}", log, e);
+        return;
+    }
+    if !DISK_FULL.swap(true, Ordering::Relaxed) {
+        eprintln!(
+            "Log disk is full (writing to {}), disabling logging and continuing to proxy",
+            log
+        );
+    }
+}
@@ -0,0 +1,375 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// How `Position.character` counts, as negotiated by the server's
+/// `positionEncoding` capability
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PositionEncoding {
+    Utf8,
+    Utf16,
+    Utf32,
+}
+
+impl PositionEncoding {
+    fn units(self, c: char) -> usize {
+        match self {
+            PositionEncoding::Utf8 => c.len_utf8(),
+            PositionEncoding::Utf16 => c.len_utf16(),
+            PositionEncoding::Utf32 => 1,
+        }
+    }
+}
+
+struct Document {
+    text: String,
+    language_id: String,
+    version: Option<i64>,
+    open: bool,
+    /// Set when a change couldn't be applied, after which the text no
+    /// longer matches the editor's and later changes are ignored
+    diverged: bool,
+}
+
+/// The text of each document the editor has opened, rebuilt by applying
+/// each `didChange` to the `didOpen` text. Changes that don't apply
+/// cleanly, or saves that don't match, point at editor sync bugs.
+pub(crate) struct DocumentState {
+    documents: BTreeMap<String, Document>,
+    encoding: PositionEncoding,
+}
+
+impl DocumentState {
+    pub(crate) fn new() -> Self {
+        Self {
+            documents: BTreeMap::new(),
+            encoding: PositionEncoding::Utf16,
+        }
+    }
+
+    /// Read the position encoding the server chose from its
+    /// initialize response. Servers that don't say use UTF-16.
+    pub(crate) fn observe_initialize_response(&mut self, response: &Value) {
+        self.encoding = match response
+            .pointer("/result/capabilities/positionEncoding")
+            .and_then(|encoding| encoding.as_str())
+        {
+            Some("utf-8") => PositionEncoding::Utf8,
+            Some("utf-32") => PositionEncoding::Utf32,
+            _ => PositionEncoding::Utf16,
+        };
+    }
+
+    /// Apply a notification from the editor, returning warnings about
+    /// changes that are inconsistent with the text we have
+    pub(crate) fn observe(&mut self, method: &str, params: Option<&Value>) -> Vec<String> {
+        let Some(params) = params else {
+            return vec![];
+        };
+        let Some(uri) = params
+            .pointer("/textDocument/uri")
+            .and_then(|uri| uri.as_str())
+        else {
+            return vec![];
+        };
+        let version = params
+            .pointer("/textDocument/version")
+            .and_then(|v| v.as_i64());
+
+        match method {
+            "textDocument/didOpen" => self.open(uri, params, version),
+            "textDocument/didChange" => self.change(uri, params, version),
+            "textDocument/didSave" => self.save(uri, params),
+            "textDocument/didClose" => {
+                if let Some(document) = self.documents.get_mut(uri) {
+                    document.open = false;
+                }
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    fn open(&mut self, uri: &str, params: &Value, version: Option<i64>) -> Vec<String> {
+        let mut warnings = vec![];
+        if self
+            .documents
+            .get(uri)
+            .is_some_and(|document| document.open)
+        {
+            warnings.push(format!("editor opened {} while it was already open", uri));
+        }
+        let text = params
+            .pointer("/textDocument/text")
+            .and_then(|text| text.as_str())
+            .unwrap_or_default();
+        let language_id = params
+            .pointer("/textDocument/languageId")
+            .and_then(|language_id| language_id.as_str())
+            .unwrap_or_default();
+        self.documents.insert(
+            uri.to_string(),
+            Document {
+                text: text.to_string(),
+                language_id: language_id.to_string(),
+                version,
+                open: true,
+                diverged: false,
+            },
+        );
+        warnings
+    }
+
+    fn change(&mut self, uri: &str, params: &Value, version: Option<i64>) -> Vec<String> {
+        let Some(document) = self.documents.get_mut(uri).filter(|document| document.open) else {
+            return vec![format!("editor changed {} without opening it", uri)];
+        };
+        if document.diverged {
+            return vec![];
+        }
+
+        let mut warnings = vec![];
+        if let (Some(previous), Some(version)) = (document.version, version) {
+            if version <= previous {
+                warnings.push(format!(
+                    "editor changed {} to version {}, but it was already at version {}",
+                    uri, version, previous
+                ));
+            }
+        }
+        document.version = version.or(document.version);
+
+        let changes = params
+            .get("contentChanges")
+            .and_then(|changes| changes.as_array())
+            .map(|changes| changes.as_slice())
+            .unwrap_or_default();
+        for (i, change) in changes.iter().enumerate() {
+            if let Err(e) = apply_change(&mut document.text, change, self.encoding) {
+                warnings.push(format!(
+                    "change {} of {} to {} (version {}) doesn't apply: {}; no longer reconstructing it",
+                    i + 1,
+                    changes.len(),
+                    uri,
+                    version.map(|v| v.to_string()).unwrap_or("unknown".to_string()),
+                    e
+                ));
+                document.diverged = true;
+                break;
+            }
+        }
+        warnings
+    }
+
+    /// Compare the reconstructed text with what was saved, either the
+    /// text included with the notification or the file on disk
+    fn save(&self, uri: &str, params: &Value) -> Vec<String> {
+        let Some(document) = self
+            .documents
+            .get(uri)
+            .filter(|document| !document.diverged)
+        else {
+            return vec![];
+        };
+        let (saved, source) = match params.get("text").and_then(|text| text.as_str()) {
+            Some(text) => (text.to_string(), "the text sent with didSave"),
+            None => {
+                let Some(text) = file_path(uri).and_then(|path| std::fs::read_to_string(path).ok())
+                else {
+                    return vec![];
+                };
+                (text, "the file on disk")
+            }
+        };
+        if saved == document.text {
+            return vec![];
+        }
+        vec![format!(
+            "reconstructed text of {} differs from {} after saving, first at byte {}",
+            uri,
+            source,
+            first_difference(&saved, &document.text)
+        )]
+    }
+
+    /// A `didOpen` for each document that's still open, with its
+    /// current text, to bring a new server up to date. Documents whose
+    /// changes didn't apply are returned separately, as their text
+    /// isn't known.
+    pub(crate) fn reopen(&self) -> (Vec<Value>, Vec<&str>) {
+        let mut did_opens = vec![];
+        let mut diverged = vec![];
+        for (uri, document) in self.documents.iter().filter(|(_, document)| document.open) {
+            if document.diverged {
+                diverged.push(uri.as_str());
+                continue;
+            }
+            did_opens.push(serde_json::json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didOpen",
+                "params": {
+                    "textDocument": {
+                        "uri": uri,
+                        "languageId": document.language_id,
+                        "version": document.version.unwrap_or(0),
+                        "text": document.text,
+                    }
+                }
+            }));
+        }
+        (did_opens, diverged)
+    }
+
+    /// The reconstructed text of every document seen, by URI
+    pub(crate) fn texts(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.documents
+            .iter()
+            .map(|(uri, document)| (uri.as_str(), document.text.as_str()))
+    }
+
+    pub(crate) fn summary_json(&self) -> Value {
+        let documents: BTreeMap<&str, Value> = self
+            .documents
+            .iter()
+            .map(|(uri, document)| {
+                (
+                    uri.as_str(),
+                    serde_json::json!({
+                        "version": document.version,
+                        "open": document.open,
+                        "diverged": document.diverged,
+                        "bytes": document.text.len(),
+                    }),
+                )
+            })
+            .collect();
+        serde_json::json!(documents)
+    }
+}
+
+/// Apply one `TextDocumentContentChangeEvent`
+fn apply_change(
+    text: &mut String,
+    change: &Value,
+    encoding: PositionEncoding,
+) -> Result<(), String> {
+    let new_text = change
+        .get("text")
+        .and_then(|text| text.as_str())
+        .ok_or("missing text")?;
+    let Some(range) = change.get("range") else {
+        *text = new_text.to_string();
+        return Ok(());
+    };
+
+    let start = offset(text, range.get("start"), encoding)?;
+    let end = offset(text, range.get("end"), encoding)?;
+    if start > end {
+        return Err(format!("range starts after it ends: {}", range));
+    }
+    text.replace_range(start..end, new_text);
+    Ok(())
+}
+
+/// The byte offset of a `Position` in `text`
+fn offset(
+    text: &str,
+    position: Option<&Value>,
+    encoding: PositionEncoding,
+) -> Result<usize, String> {
+    let field = |name| {
+        position
+            .and_then(|position| position.get(name))
+            .and_then(|n| n.as_u64())
+            .map(|n| n as usize)
+            .ok_or_else(|| format!("invalid position {}", position.unwrap_or(&Value::Null)))
+    };
+    let (line, character) = (field("line")?, field("character")?);
+
+    let mut line_start = 0;
+    for _ in 0..line {
+        line_start = next_line_start(text, line_start).ok_or_else(|| {
+            format!(
+                "line {} is past the end of the document, which has {} lines",
+                line,
+                text.lines().count()
+            )
+        })?;
+    }
+
+    let line_text = &text[line_start..];
+    let line_end = line_text.find(['\r', '\n']).unwrap_or(line_text.len());
+    let mut units = 0;
+    for (i, c) in line_text[..line_end].char_indices() {
+        if units == character {
+            return Ok(line_start + i);
+        }
+        units += encoding.units(c);
+        if units > character {
+            return Err(format!(
+                "character {} on line {} is inside {:?}",
+                character, line, c
+            ));
+        }
+    }
+    // The spec says characters past the end of the line mean the end
+    // of the line.
+    Ok(line_start + line_end)
+}
+
+/// Where the line after the one starting at `start` begins. Lines end
+/// with `\n`, `\r\n` or `\r`.
+fn next_line_start(text: &str, start: usize) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let end = start
+        + bytes[start..]
+            .iter()
+            .position(|&b| b == b'\n' || b == b'\r')?;
+    if bytes[end] == b'\r' && bytes.get(end + 1) == Some(&b'\n') {
+        Some(end + 2)
+    } else {
+        Some(end + 1)
+    }
+}
+
+fn first_difference(a: &str, b: &str) -> usize {
+    a.bytes()
+        .zip(b.bytes())
+        .position(|(a, b)| a != b)
+        .unwrap_or(a.len().min(b.len()))
+}
+
+/// The local path of a `file://` URI
+fn file_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(PathBuf::from(String::from_utf8(decoded).ok()?))
+}
+
+/// A file name for a document's reconstructed text, e.g.
+/// `file:///src/main.rs` becomes `src_main.rs`
+pub(crate) fn file_name(uri: &str) -> String {
+    let path = uri.split_once("://").map_or(uri, |(_, path)| path);
+    path.trim_start_matches('/')
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,437 @@
+use serde_json::Value;
+use std::env;
+
+/// A predicate on message contents, from `LSP_FILTER_JQ`, deciding
+/// which messages are logged. It's a small subset of JSONPath:
+///
+/// - a path such as `$.params.textDocument.uri`, `$.params.items[0]`,
+///   `$.params.diagnostics[*].severity` or `$..uri` (any depth) matches
+///   when it leads to a value other than `null` or `false`
+/// - `path == literal`, `!=`, `<`, `<=`, `>` and `>=` compare with a
+///   JSON literal, and `path contains "text"` looks for a substring
+/// - `&&` and `||` combine predicates, with `&&` binding tighter
+///
+/// A path that leads to several values matches when any of them do.
+#[derive(Clone, Debug)]
+pub(crate) struct Filter {
+    /// Predicates combined with `||`, each a list combined with `&&`
+    any_of: Vec<Vec<Predicate>>,
+}
+
+#[derive(Clone, Debug)]
+struct Predicate {
+    path: Vec<Segment>,
+    test: Option<(Op, Value)>,
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Field(String),
+    Index(usize),
+    Wildcard,
+    /// `..name`, a field at any depth
+    Descendant(String),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+}
+
+impl Filter {
+    /// Reads `LSP_FILTER_JQ`, ignoring it if it doesn't parse
+    pub(crate) fn from_env() -> Option<Self> {
+        let expression = env::var("LSP_FILTER_JQ").ok()?;
+        if expression.trim().is_empty() {
+            return None;
+        }
+        match Filter::parse(&expression) {
+            Ok(filter) => Some(filter),
+            Err(e) => {
+                eprintln!("Ignoring LSP_FILTER_JQ: {}", e);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn parse(expression: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            rest: expression.trim(),
+        };
+        let mut any_of = vec![];
+        loop {
+            let mut all_of = vec![parser.predicate()?];
+            while parser.eat("&&") {
+                all_of.push(parser.predicate()?);
+            }
+            any_of.push(all_of);
+            if !parser.eat("||") {
+                break;
+            }
+        }
+        if !parser.rest.is_empty() {
+            return Err(format!("unexpected {:?}", parser.rest));
+        }
+        Ok(Self { any_of })
+    }
+
+    pub(crate) fn matches(&self, message: &Value) -> bool {
+        self.any_of
+            .iter()
+            .any(|all_of| all_of.iter().all(|predicate| predicate.matches(message)))
+    }
+}
+
+impl Predicate {
+    fn matches(&self, message: &Value) -> bool {
+        let mut values = vec![message];
+        for segment in &self.path {
+            values = values
+                .into_iter()
+                .flat_map(|value| segment.select(value))
+                .collect();
+        }
+
+        values.into_iter().any(|value| match &self.test {
+            None => !matches!(value, Value::Null | Value::Bool(false)),
+            Some((op, literal)) => op.test(value, literal),
+        })
+    }
+}
+
+impl Segment {
+    fn select<'a>(&self, value: &'a Value) -> Vec<&'a Value> {
+        match self {
+            Segment::Field(name) => value.get(name).into_iter().collect(),
+            Segment::Index(index) => value.get(index).into_iter().collect(),
+            Segment::Wildcard => match value {
+                Value::Array(items) => items.iter().collect(),
+                Value::Object(fields) => fields.values().collect(),
+                _ => vec![],
+            },
+            Segment::Descendant(name) => {
+                let mut found = vec![];
+                descendants(value, name, &mut found);
+                found
+            }
+        }
+    }
+}
+
+fn descendants<'a>(value: &'a Value, name: &str, found: &mut Vec<&'a Value>) {
+    match value {
+        Value::Object(fields) => {
+            if let Some(field) = fields.get(name) {
+                found.push(field);
+            }
+            for field in fields.values() {
+                descendants(field, name, found);
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                descendants(item, name, found);
+            }
+        }
+        _ => {}
+    }
+}
+
+impl Op {
+    /// Compare a value with a literal. Values of different types never
+    /// match, except with `!=`. Numbers compare by value, so `1500`
+    /// equals `1.5e3`.
+    fn test(self, value: &Value, literal: &Value) -> bool {
+        let equal = || match (value, literal) {
+            (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+            _ => value == literal,
+        };
+        match self {
+            Op::Eq => equal(),
+            Op::Ne => !equal(),
+            Op::Contains => match (value, literal) {
+                (Value::String(value), Value::String(text)) => value.contains(text.as_str()),
+                (Value::Array(items), literal) => items.contains(literal),
+                _ => false,
+            },
+            Op::Lt | Op::Le | Op::Gt | Op::Ge => {
+                let ordering = match (value, literal) {
+                    (Value::Number(a), Value::Number(b)) => a.as_f64().partial_cmp(&b.as_f64()),
+                    (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+                    _ => None,
+                };
+                let Some(ordering) = ordering else {
+                    return false;
+                };
+                match self {
+                    Op::Lt => ordering.is_lt(),
+                    Op::Le => ordering.is_le(),
+                    Op::Gt => ordering.is_gt(),
+                    _ => ordering.is_ge(),
+                }
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl Parser<'_> {
+    /// Consume `token` if it's next, skipping whitespace
+    fn eat(&mut self, token: &str) -> bool {
+        let rest = self.rest.trim_start();
+        match rest.strip_prefix(token) {
+            Some(rest) => {
+                self.rest = rest.trim_start();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn predicate(&mut self) -> Result<Predicate, String> {
+        let path = self.path()?;
+        let op = [
+            ("==", Op::Eq),
+            ("!=", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+            ("contains", Op::Contains),
+        ]
+        .into_iter()
+        .find(|(token, _)| self.eat(token));
+        let test = match op {
+            Some((_, op)) => Some((op, self.literal()?)),
+            None => None,
+        };
+        Ok(Predicate { path, test })
+    }
+
+    fn path(&mut self) -> Result<Vec<Segment>, String> {
+        if !self.eat("$") {
+            return Err(format!(
+                "expected a path starting with $, got {:?}",
+                self.rest
+            ));
+        }
+        let mut path = vec![];
+        loop {
+            if let Some(rest) = self.rest.strip_prefix("..") {
+                self.rest = rest;
+                path.push(Segment::Descendant(self.name()?));
+            } else if let Some(rest) = self.rest.strip_prefix(".*") {
+                self.rest = rest;
+                path.push(Segment::Wildcard);
+            } else if let Some(rest) = self.rest.strip_prefix('.') {
+                self.rest = rest;
+                path.push(Segment::Field(self.name()?));
+            } else if let Some(rest) = self.rest.strip_prefix('[') {
+                let Some((inside, rest)) = rest.split_once(']') else {
+                    return Err("missing ]".to_string());
+                };
+                self.rest = rest;
+                let inside = inside.trim();
+                path.push(if inside == "*" {
+                    Segment::Wildcard
+                } else if let Ok(index) = inside.parse() {
+                    Segment::Index(index)
+                } else if let Some(name) = inside
+                    .strip_prefix('\'')
+                    .and_then(|inside| inside.strip_suffix('\''))
+                {
+                    Segment::Field(name.to_string())
+                } else {
+                    return Err(format!("unsupported [{}]", inside));
+                });
+            } else {
+                break;
+            }
+        }
+        self.rest = self.rest.trim_start();
+        Ok(path)
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let end = self
+            .rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+            .unwrap_or(self.rest.len());
+        if end == 0 {
+            return Err(format!("expected a field name, got {:?}", self.rest));
+        }
+        let (name, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        Ok(name.to_string())
+    }
+
+    /// A JSON literal. Strings end at their closing quote, and other
+    /// literals at whitespace or the next `&&` or `||`.
+    fn literal(&mut self) -> Result<Value, String> {
+        let end = if self.rest.starts_with('"') {
+            let mut stream = serde_json::Deserializer::from_str(self.rest).into_iter::<Value>();
+            match stream.next() {
+                Some(Ok(_)) => stream.byte_offset(),
+                _ => self.rest.len(),
+            }
+        } else {
+            self.rest
+                .find(|c: char| c.is_whitespace() || c == '&' || c == '|')
+                .unwrap_or(self.rest.len())
+        };
+        let (literal, rest) = self.rest.split_at(end);
+        match serde_json::from_str::<Value>(literal) {
+            Ok(value) if !value.is_object() && !value.is_array() => {
+                self.rest = rest.trim_start();
+                Ok(value)
+            }
+            _ => Err(format!(
+                "expected a string, number, true, false or null, got {:?}",
+                self.rest
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn matches(expression: &str, message: &Value) -> bool {
+        Filter::parse(expression).unwrap().matches(message)
+    }
+
+    fn hover() -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": 7,
+            "method": "textDocument/hover",
+            "params": {
+                "textDocument": {"uri": "file:///src/main.rs"},
+                "position": {"line": 3, "character": 10},
+                "items": ["first", "second"],
+                "diagnostics": [{"severity": 1}, {"severity": 2}],
+                "done": false,
+                "missing": null,
+                "a.b": true,
+            }
+        })
+    }
+
+    #[test]
+    fn matches_paths_to_truthy_values() {
+        assert!(matches("$", &hover()));
+        assert!(matches("$.params.textDocument.uri", &hover()));
+        assert!(matches("$.params.items[0]", &hover()));
+        assert!(matches("$.params['a.b']", &hover()));
+        assert!(!matches("$.params.items[2]", &hover()));
+        assert!(!matches("$.params.done", &hover()));
+        assert!(!matches("$.params.missing", &hover()));
+        assert!(!matches("$.result", &hover()));
+    }
+
+    #[test]
+    fn matches_wildcards_and_descendants() {
+        assert!(matches("$.params.diagnostics[*].severity == 2", &hover()));
+        assert!(!matches("$.params.diagnostics[*].severity == 3", &hover()));
+        assert!(matches("$.params.position.* == 10", &hover()));
+        assert!(matches("$..uri == \"file:///src/main.rs\"", &hover()));
+        assert!(matches("$..severity", &hover()));
+        assert!(!matches("$..version", &hover()));
+    }
+
+    #[test]
+    fn compares_with_literals() {
+        assert!(matches("$.method == \"textDocument/hover\"", &hover()));
+        assert!(matches("$.method!=\"exit\"", &hover()));
+        assert!(matches("$.id == 7", &hover()));
+        assert!(matches("$.id == 7.0", &hover()));
+        assert!(!matches("$.id != 7e0", &hover()));
+        assert!(matches("$.params.done == false", &hover()));
+        assert!(matches("$.params.missing == null", &hover()));
+        assert!(matches("$.params.position.line < 4", &hover()));
+        assert!(matches("$.params.position.line <= 3", &hover()));
+        assert!(!matches("$.params.position.line > 3", &hover()));
+        assert!(matches("$.params.position.line >= 3", &hover()));
+        assert!(matches("$.method > \"textDocument/a\"", &hover()));
+        // Values of different types only match with !=.
+        assert!(!matches("$.id == \"7\"", &hover()));
+        assert!(!matches("$.id < \"8\"", &hover()));
+        assert!(matches("$.id != \"7\"", &hover()));
+    }
+
+    #[test]
+    fn matches_contains() {
+        assert!(matches(
+            "$.params.textDocument.uri contains \"src/\"",
+            &hover()
+        ));
+        assert!(!matches(
+            "$.params.textDocument.uri contains \"tests/\"",
+            &hover()
+        ));
+        assert!(matches("$.params.items contains \"second\"", &hover()));
+        assert!(!matches("$.id contains 7", &hover()));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let message = json!({"a": true, "b": false, "c": false});
+        // `$.a || ($.b && $.c)`, not `($.a || $.b) && $.c`
+        assert!(matches("$.a || $.b && $.c", &message));
+        assert!(!matches("$.b && $.c || $.b", &message));
+        assert!(matches("$.b && $.c || $.a", &message));
+        let message = json!({"a": false, "b": true, "c": false});
+        assert!(!matches("$.a || $.b && $.c", &message));
+        assert!(matches("$.a || $.b && $.c || $.b", &message));
+    }
+
+    #[test]
+    fn tokenises_literals() {
+        // Operators inside strings are part of the string.
+        let message = json!({"text": "a && b || c", "n": -1.5e3});
+        assert!(matches("$.text == \"a && b || c\"", &message));
+        assert!(matches("$.text == \"a && b || c\" && $.n", &message));
+        assert!(matches("$.text contains \"\\u0026\\u0026\"", &message));
+        // Other literals end at whitespace, `&&` or `||`.
+        assert!(matches("$.n == -1.5e3&&$.text", &message));
+        assert!(matches("$.n==-1500||$.missing", &message));
+        assert!(matches("$.missing == true || $.n < 0", &message));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for expression in [
+            "",
+            "method == \"x\"",
+            "$.",
+            "$.items[0",
+            "$.items[-1]",
+            "$.items[\"a\"]",
+            "$.id ==",
+            "$.id == seven",
+            "$.id == {}",
+            "$.id == [1]",
+            "$.id == \"unterminated",
+            "$.a &&",
+            "$.a || || $.b",
+            "$.a $.b",
+        ] {
+            assert!(
+                Filter::parse(expression).is_err(),
+                "{:?} should not parse",
+                expression
+            );
+        }
+    }
+}
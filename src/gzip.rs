@@ -0,0 +1,452 @@
+//! Just enough gzip to read message bodies sent with
+//! `Content-Encoding: gzip`: the gzip container (RFC 1952) around a
+//! DEFLATE stream (RFC 1951).
+
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order code length code lengths are sent in
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// The most bytes a body may decompress to. A few bytes of gzip can
+/// expand to gigabytes, so output stops here rather than growing
+/// without bound.
+pub(crate) const MAX_DECODED: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Error {
+    /// The body decompresses to more than the limit
+    TooLarge(usize),
+    Corrupt(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::TooLarge(limit) => write!(f, "decompresses to more than {} bytes", limit),
+            Error::Corrupt(problem) => write!(f, "{}", problem),
+        }
+    }
+}
+
+impl From<&str> for Error {
+    fn from(problem: &str) -> Self {
+        Error::Corrupt(problem.to_string())
+    }
+}
+
+impl From<String> for Error {
+    fn from(problem: String) -> Self {
+        Error::Corrupt(problem)
+    }
+}
+
+/// Decompresses a gzip member, checking its length and CRC, and that
+/// it isn't larger than `MAX_DECODED`
+pub(crate) fn decompress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    decompress_limited(data, MAX_DECODED)
+}
+
+fn decompress_limited(data: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+    if data.len() < 18 || data[0] != 0x1f || data[1] != 0x8b {
+        return Err("not gzip data".into());
+    }
+    if data[2] != 8 {
+        return Err(format!("unsupported compression method {}", data[2]).into());
+    }
+    let flags = data[3];
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let len = u16::from_le_bytes([byte(data, pos)?, byte(data, pos + 1)?]) as usize;
+        pos += 2 + len;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let end = data
+                .get(pos..)
+                .and_then(|rest| rest.iter().position(|&b| b == 0))
+                .ok_or("truncated gzip header")?;
+            pos += end + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+
+    let mut reader = BitReader::new(data.get(pos..).ok_or("truncated gzip header")?);
+    let output = inflate(&mut reader, limit)?;
+
+    let trailer = reader.remaining();
+    if trailer.len() < 8 {
+        return Err("missing gzip trailer".into());
+    }
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if size != output.len() as u32 {
+        return Err("decompressed length doesn't match the gzip trailer".into());
+    }
+    if crc != crc32(&output) {
+        return Err("CRC doesn't match the gzip trailer".into());
+    }
+    Ok(output)
+}
+
+fn byte(data: &[u8], pos: usize) -> Result<u8, Error> {
+    data.get(pos)
+        .copied()
+        .ok_or_else(|| "truncated gzip header".into())
+}
+
+/// Reads bits least significant first, as DEFLATE packs them
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u64,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    fn bits(&mut self, n: u32) -> Result<u32, Error> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos).ok_or("truncated deflate data")?;
+            self.buffer |= (byte as u64) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+        let value = (self.buffer & ((1 << n) - 1)) as u32;
+        self.buffer >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Skip to the next byte boundary
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+
+    /// The bytes after the last one read from
+    fn remaining(&self) -> &'a [u8] {
+        &self.data[self.pos..]
+    }
+}
+
+/// A canonical Huffman code, stored as the number of codes of each
+/// length and the symbols in code order
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut symbols = vec![];
+        for length in 1..16 {
+            for (symbol, _) in lengths
+                .iter()
+                .enumerate()
+                .filter(|(_, &l)| l as usize == length)
+            {
+                symbols.push(symbol as u16);
+            }
+        }
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, Error> {
+        // The first code of each length, and where its symbols start
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+        for length in 1..16 {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("invalid Huffman code".into())
+    }
+}
+
+fn inflate(reader: &mut BitReader, limit: usize) -> Result<Vec<u8>, Error> {
+    let mut output = vec![];
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => stored_block(reader, &mut output, limit)?,
+            1 => {
+                let (literals, distances) = fixed_codes();
+                compressed_block(reader, &literals, &distances, &mut output, limit)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(reader)?;
+                compressed_block(reader, &literals, &distances, &mut output, limit)?;
+            }
+            _ => return Err("invalid deflate block type".into()),
+        }
+        if last {
+            reader.align();
+            return Ok(output);
+        }
+    }
+}
+
+fn stored_block(reader: &mut BitReader, output: &mut Vec<u8>, limit: usize) -> Result<(), Error> {
+    reader.align();
+    let len = reader.bits(16)?;
+    let inverse = reader.bits(16)?;
+    if len != !inverse & 0xffff {
+        return Err("corrupt stored block length".into());
+    }
+    let start = reader.pos;
+    let end = start + len as usize;
+    let bytes = reader
+        .data
+        .get(start..end)
+        .ok_or("truncated stored block")?;
+    if output.len() + bytes.len() > limit {
+        return Err(Error::TooLarge(limit));
+    }
+    output.extend_from_slice(bytes);
+    reader.pos = end;
+    Ok(())
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    for (symbol, length) in lengths.iter_mut().enumerate() {
+        *length = match symbol {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), Error> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[symbol] = reader.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths);
+
+    let mut lengths = vec![];
+    while lengths.len() < literal_count + distance_count {
+        let symbol = code_length_code.decode(reader)?;
+        let (length, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths.last().ok_or("repeat with no previous length")?;
+                (previous, 3 + reader.bits(2)?)
+            }
+            17 => (0, 3 + reader.bits(3)?),
+            _ => (0, 11 + reader.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(length, repeat as usize));
+    }
+    if lengths.len() > literal_count + distance_count {
+        return Err("too many code lengths".into());
+    }
+
+    let (literal_lengths, distance_lengths) = lengths.split_at(literal_count);
+    Ok((
+        Huffman::new(literal_lengths),
+        Huffman::new(distance_lengths),
+    ))
+}
+
+fn compressed_block(
+    reader: &mut BitReader,
+    literals: &Huffman,
+    distances: &Huffman,
+    output: &mut Vec<u8>,
+    limit: usize,
+) -> Result<(), Error> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        match symbol {
+            0..=255 if output.len() >= limit => return Err(Error::TooLarge(limit)),
+            0..=255 => output.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let index = symbol - 257;
+                let (&base, &extra) = LENGTH_BASE
+                    .get(index)
+                    .zip(LENGTH_EXTRA.get(index))
+                    .ok_or("invalid length symbol")?;
+                let length = base as usize + reader.bits(extra as u32)? as usize;
+
+                let index = distances.decode(reader)? as usize;
+                let (&base, &extra) = DISTANCE_BASE
+                    .get(index)
+                    .zip(DISTANCE_EXTRA.get(index))
+                    .ok_or("invalid distance symbol")?;
+                let distance = base as usize + reader.bits(extra as u32)? as usize;
+                if distance > output.len() {
+                    return Err("distance before the start of the output".into());
+                }
+                if output.len() + length > limit {
+                    return Err(Error::TooLarge(limit));
+                }
+
+                // Copies can overlap what they're writing, so go a byte
+                // at a time.
+                let start = output.len() - distance;
+                for i in 0..length {
+                    output.push(output[start + i]);
+                }
+            }
+        }
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// A gzip member holding a short JSON-RPC notification
+#[cfg(test)]
+pub(crate) const SAMPLE: &[u8] = &[
+    0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xab, 0x56, 0xca, 0x2a, 0xce, 0xcf,
+    0x2b, 0x2a, 0x48, 0x56, 0xb2, 0x52, 0x32, 0xd2, 0x33, 0x50, 0xd2, 0x51, 0xca, 0x4d, 0x2d, 0xc9,
+    0xc8, 0x4f, 0x01, 0x72, 0x33, 0xf3, 0x32, 0x4b, 0x32, 0x13, 0x73, 0x32, 0xab, 0x52, 0x53, 0x80,
+    0xc2, 0x05, 0x89, 0x45, 0x89, 0xb9, 0xc5, 0x4a, 0x56, 0xd5, 0xb5, 0xb5, 0x00, 0x54, 0x54, 0xe4,
+    0xd0, 0x34, 0x00, 0x00, 0x00,
+];
+
+#[cfg(test)]
+pub(crate) const SAMPLE_DECODED: &str = r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#;
+
+/// A gzip member that decompresses to at least `size` zero bytes: one
+/// literal, then back references of 258 bytes at 13 bits each. Its
+/// trailer is zeros, as the limit should stop decoding first.
+#[cfg(test)]
+pub(crate) fn bomb(size: usize) -> Vec<u8> {
+    let mut bits = vec![];
+    let value = |value: u32, n: u32, bits: &mut Vec<u8>| {
+        for i in 0..n {
+            bits.push((value >> i) as u8 & 1);
+        }
+    };
+    // Huffman codes are packed starting with their most significant bit.
+    let code = |code: u32, n: u32, bits: &mut Vec<u8>| {
+        for i in (0..n).rev() {
+            bits.push((code >> i) as u8 & 1);
+        }
+    };
+    // The last block, with fixed codes
+    value(1, 1, &mut bits);
+    value(1, 2, &mut bits);
+    // Literal 0
+    code(0x30, 8, &mut bits);
+    for _ in 0..size.div_ceil(258) {
+        // Length 258, then distance 1
+        code(0xc5, 8, &mut bits);
+        code(0, 5, &mut bits);
+    }
+    // End of block
+    code(0, 7, &mut bits);
+
+    let mut data = vec![0x1f, 0x8b, 0x08, 0, 0, 0, 0, 0, 0, 0xff];
+    data.extend(bits.chunks(8).map(|byte| {
+        byte.iter()
+            .enumerate()
+            .fold(0u8, |acc, (i, bit)| acc | (bit << i))
+    }));
+    data.extend([0; 8]);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompresses_a_gzip_member() {
+        assert_eq!(decompress(SAMPLE).unwrap(), SAMPLE_DECODED.as_bytes());
+    }
+
+    #[test]
+    fn rejects_corrupt_data() {
+        let mut corrupt = SAMPLE.to_vec();
+        // The CRC in the trailer
+        corrupt[SAMPLE.len() - 8] ^= 0xff;
+        assert_eq!(
+            decompress(&corrupt),
+            Err(Error::Corrupt("CRC doesn't match the gzip trailer".into()))
+        );
+        assert!(matches!(decompress(&SAMPLE[..30]), Err(Error::Corrupt(_))));
+        assert!(matches!(decompress(b"{}"), Err(Error::Corrupt(_))));
+    }
+
+    #[test]
+    fn stops_at_the_limit() {
+        assert_eq!(decompress_limited(SAMPLE, 10), Err(Error::TooLarge(10)));
+        assert_eq!(
+            decompress_limited(SAMPLE, SAMPLE_DECODED.len()).unwrap(),
+            SAMPLE_DECODED.as_bytes()
+        );
+    }
+
+    #[test]
+    fn stops_a_gzip_bomb() {
+        let bomb = bomb(MAX_DECODED + 1);
+        assert!(bomb.len() < MAX_DECODED / 100);
+        assert_eq!(decompress(&bomb), Err(Error::TooLarge(MAX_DECODED)));
+    }
+}
@@ -0,0 +1,35 @@
+use std::path::Path;
+use std::process::Stdio;
+use tokio::process::Command;
+
+/// Runs a `LSP_PRE_HOOK` or `LSP_POST_HOOK` command and waits for it.
+/// The hook is told where this session's logs are through environment
+/// variables, and its output goes to our stderr so it can't corrupt
+/// the editor's stdout. A hook failing doesn't stop the session.
+pub(crate) async fn run_hook(
+    name: &str,
+    command: &[String],
+    log_dir: &Path,
+    session_id: &str,
+    summary_path: &Path,
+) {
+    let Some((program, args)) = command.split_first() else {
+        return;
+    };
+    eprintln!("Running {}: {}", name, command.join(" "));
+
+    let status = Command::new(program)
+        .args(args)
+        .env("LSP_LOG_DIR", log_dir)
+        .env("LSP_SESSION_ID", session_id)
+        .env("LSP_SUMMARY", summary_path)
+        .stdin(Stdio::null())
+        .stdout(std::io::stderr())
+        .status()
+        .await;
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("{} failed with status: {}", name, status),
+        Err(e) => eprintln!("Failed to run {} {:?}: {}", name, program, e),
+    }
+}
@@ -0,0 +1,189 @@
+use anyhow::{Context, Result};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+use crate::config::ProxyConfig;
+use crate::{format_lsp_message, run_session, LspMessageParser};
+
+/// JSON-RPC error code for requests the receiver doesn't handle
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// State shared between the HTTP handlers and the task reading
+/// messages from the server
+struct Bridge {
+    /// Our end of the editor connection that the proxy session reads
+    /// requests from
+    requests: tokio::sync::Mutex<WriteHalf<DuplexStream>>,
+    /// HTTP handlers waiting for a response, keyed by request id
+    pending: Mutex<HashMap<String, oneshot::Sender<serde_json::Value>>>,
+}
+
+impl Bridge {
+    async fn send(&self, message: &str) -> std::io::Result<()> {
+        let mut requests = self.requests.lock().await;
+        requests
+            .write_all(format_lsp_message(message).as_bytes())
+            .await?;
+        requests.flush().await
+    }
+}
+
+/// Serves JSON-RPC over HTTP on `addr`: each POST body is forwarded to
+/// the LSP server, and the response to it is returned as the HTTP
+/// response body.
+pub(crate) async fn run_http_bridge(
+    addr: &str,
+    lsp_server: String,
+    server_args: Vec<String>,
+) -> Result<()> {
+    let config = ProxyConfig::from_env();
+
+    // The proxy session sees the bridge as an ordinary editor
+    // connection, so messages are logged as usual.
+    let (editor, proxy) = tokio::io::duplex(64 * 1024);
+    let (responses, requests) = tokio::io::split(editor);
+    let (proxy_input, proxy_output) = tokio::io::split(proxy);
+
+    let bridge = Arc::new(Bridge {
+        requests: tokio::sync::Mutex::new(requests),
+        pending: Mutex::new(HashMap::new()),
+    });
+    tokio::spawn(route_responses(responses, bridge.clone()));
+
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+    eprintln!("Listening for JSON-RPC requests on http://{}/", addr);
+
+    let app = Router::new()
+        .route("/", post(handle_post))
+        .with_state(bridge);
+
+    tokio::select! {
+        result = axum::serve(listener, app) => {
+            result.context("HTTP server failed")?;
+        }
+        result = run_session(&lsp_server, &server_args, config, proxy_input, proxy_output) => {
+            result?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_post(State(bridge): State<Arc<Bridge>>, body: String) -> Response {
+    let message = match serde_json::from_str::<serde_json::Value>(&body) {
+        Ok(message) if message.is_object() => message,
+        Ok(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Expected a single JSON-RPC message (batches aren't supported)",
+            )
+                .into_response()
+        }
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("Invalid JSON: {}", e)).into_response(),
+    };
+
+    // Notifications don't get a response.
+    let Some(id) = message.get("id") else {
+        return match bridge.send(&body).await {
+            Ok(()) => StatusCode::NO_CONTENT.into_response(),
+            Err(e) => server_unavailable(e),
+        };
+    };
+
+    let key = id.to_string();
+    let (sender, receiver) = oneshot::channel();
+    {
+        let mut pending = bridge.pending.lock().unwrap();
+        if pending.contains_key(&key) {
+            return (
+                StatusCode::CONFLICT,
+                format!("A request with id {} is already in progress", key),
+            )
+                .into_response();
+        }
+        pending.insert(key.clone(), sender);
+    }
+
+    if let Err(e) = bridge.send(&body).await {
+        bridge.pending.lock().unwrap().remove(&key);
+        return server_unavailable(e);
+    }
+
+    match receiver.await {
+        Ok(response) => Json(response).into_response(),
+        Err(_) => (
+            StatusCode::BAD_GATEWAY,
+            "LSP server exited without responding",
+        )
+            .into_response(),
+    }
+}
+
+fn server_unavailable(e: std::io::Error) -> Response {
+    (
+        StatusCode::BAD_GATEWAY,
+        format!("Failed to send to LSP server: {}", e),
+    )
+        .into_response()
+}
+
+/// Reads messages from the server, handing each response to the HTTP
+/// handler waiting for it
+async fn route_responses(mut responses: ReadHalf<DuplexStream>, bridge: Arc<Bridge>) {
+    let mut buffer = vec![0u8; 8192];
+    let mut parser = LspMessageParser::new();
+
+    loop {
+        match responses.read(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => parser.add_data(&buffer[..n]),
+        }
+
+        while let Some((_, json_payload)) = parser.try_parse_message() {
+            let Ok(message) = serde_json::from_str::<serde_json::Value>(&json_payload) else {
+                continue;
+            };
+            let id = message.get("id");
+
+            match (message.get("method").and_then(|m| m.as_str()), id) {
+                (None, Some(id)) => {
+                    let waiting = bridge.pending.lock().unwrap().remove(&id.to_string());
+                    match waiting {
+                        Some(waiting) => {
+                            let _ = waiting.send(message);
+                        }
+                        None => eprintln!("Dropping response to unknown request {}", id),
+                    }
+                }
+                (Some(method), Some(id)) => {
+                    // HTTP clients can't answer requests, so tell the
+                    // server rather than leaving it waiting.
+                    let error = serde_json::json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": {
+                            "code": METHOD_NOT_FOUND,
+                            "message": format!("{} isn't supported over the HTTP bridge", method),
+                        },
+                    });
+                    let _ = bridge.send(&error.to_string()).await;
+                }
+                // Notifications are only logged.
+                _ => {}
+            }
+        }
+    }
+
+    // The session has ended, so fail any requests still waiting.
+    bridge.pending.lock().unwrap().clear();
+}
@@ -0,0 +1,112 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+
+use crate::capture::{self, Entry};
+use crate::config::LogFormat;
+use crate::Headers;
+
+/// Converts LSP traffic captured by other means, e.g. with strace, as
+/// the raw framed bytes of one stream into a log. Bytes at the end that
+/// aren't a complete message are reported rather than logged.
+pub(crate) fn import_raw(input: &Path, output: &Path) -> Result<()> {
+    let contents =
+        fs::read(input).with_context(|| format!("Failed to read {}", input.display()))?;
+    let (entries, rest) = capture::parse_raw(&contents);
+
+    let format = LogFormat::from_path(output).unwrap_or(LogFormat::JsonLines);
+    capture::write_log(output, format, &entries)?;
+
+    let unparsed = entries
+        .iter()
+        .filter(|entry| matches!(entry, Entry::Unparsed(_)))
+        .count();
+    println!(
+        "Imported {} messages from {} to {}",
+        entries.len(),
+        input.display(),
+        output.display()
+    );
+    if unparsed > 0 {
+        println!(
+            "{} message bodies weren't valid JSON, and are logged as they were",
+            unparsed
+        );
+    }
+    if !rest.is_empty() {
+        println!(
+            "Ignored {} bytes at offset {}: {}",
+            rest.len(),
+            contents.len() - rest.len(),
+            describe_rest(&rest)
+        );
+    }
+    Ok(())
+}
+
+/// Why the bytes at the end of a capture couldn't be parsed
+fn describe_rest(rest: &[u8]) -> String {
+    let Some(header_end) = rest.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return "the headers of a message, without the blank line ending them".to_string();
+    };
+    let headers = String::from_utf8_lossy(&rest[..header_end]);
+    match Headers::parse(&headers) {
+        Some(headers) => format!(
+            "a message with Content-Length {}, but only {} bytes of its body",
+            headers.content_length,
+            rest.len() - header_end - 4
+        ),
+        None => format!(
+            "headers without a valid Content-Length: {:?}",
+            headers.chars().take(80).collect::<String>()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format_lsp_message;
+    use crate::stream::tests::temp_path;
+
+    #[test]
+    fn imports_complete_messages() {
+        let input = temp_path("import_raw.bin");
+        let output = temp_path("import_raw.jsonl");
+        let initialized = r#"{"jsonrpc":"2.0","method":"initialized"}"#;
+        fs::write(
+            &input,
+            format!(
+                "{}{}Content-Length: 100\r\n\r\n{{",
+                format_lsp_message(initialized),
+                format_lsp_message("not json"),
+            ),
+        )
+        .unwrap();
+
+        import_raw(&input, &output).unwrap();
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            format!("{}\nnot json\n", initialized)
+        );
+
+        let _ = fs::remove_file(&input);
+        let _ = fs::remove_file(&output);
+    }
+
+    #[test]
+    fn describes_what_was_left_over() {
+        assert_eq!(
+            describe_rest(b"Content-Length: 10"),
+            "the headers of a message, without the blank line ending them"
+        );
+        assert_eq!(
+            describe_rest(b"Content-Length: 10\r\n\r\n{}"),
+            "a message with Content-Length 10, but only 2 bytes of its body"
+        );
+        assert_eq!(
+            describe_rest(b"Content-Type: text\r\n\r\n"),
+            "headers without a valid Content-Length: \"Content-Type: text\""
+        );
+    }
+}
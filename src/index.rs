@@ -0,0 +1,205 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::config::LogMode;
+use crate::stream::Direction;
+
+/// Start of every binary index file, so we can reject other files and
+/// change the layout later
+const MAGIC: &[u8; 8] = b"LSPIDX01";
+
+/// offset: u64, length: u32, direction: u8, method id: u16
+const RECORD_SIZE: usize = 8 + 4 + 1 + 2;
+
+/// Method id for messages without a method, e.g. responses to
+/// requests we didn't see
+const NO_METHOD: u16 = u16::MAX;
+
+/// Writes a fixed-width record for every frame in the raw stdin and
+/// stdout logs, so large captures can be searched without parsing
+/// them. Method names are stored once, in a separate string table.
+pub(crate) struct BinaryIndex {
+    records: BufWriter<File>,
+    methods: BufWriter<File>,
+    method_ids: HashMap<String, u16>,
+}
+
+pub(crate) type SharedBinaryIndex = Arc<Mutex<BinaryIndex>>;
+
+impl BinaryIndex {
+    pub(crate) fn create(index_path: &Path, mode: LogMode) -> Result<Self> {
+        let mut records = BufWriter::new(
+            create_file(index_path, mode)
+                .with_context(|| format!("Failed to create {}", index_path.display()))?,
+        );
+        records.write_all(MAGIC)?;
+
+        let methods_path = methods_path(index_path);
+        let methods = BufWriter::new(
+            create_file(&methods_path, mode)
+                .with_context(|| format!("Failed to create {}", methods_path.display()))?,
+        );
+
+        Ok(Self {
+            records,
+            methods,
+            method_ids: HashMap::new(),
+        })
+    }
+
+    /// Record a frame at `offset` in the log for `direction`
+    pub(crate) fn record(
+        &mut self,
+        direction: Direction,
+        offset: u64,
+        length: usize,
+        method: Option<&str>,
+    ) -> std::io::Result<()> {
+        let method_id = match method {
+            Some(method) => self.method_id(method)?,
+            None => NO_METHOD,
+        };
+
+        let mut record = [0u8; RECORD_SIZE];
+        record[..8].copy_from_slice(&offset.to_le_bytes());
+        record[8..12].copy_from_slice(&u32::try_from(length).unwrap_or(u32::MAX).to_le_bytes());
+        record[12] = direction_byte(direction);
+        record[13..].copy_from_slice(&method_id.to_le_bytes());
+        self.records.write_all(&record)
+    }
+
+    /// Look up a method in the string table, adding it if it's new
+    fn method_id(&mut self, method: &str) -> std::io::Result<u16> {
+        if let Some(&id) = self.method_ids.get(method) {
+            return Ok(id);
+        }
+
+        let id = self.method_ids.len() as u16;
+        if id == NO_METHOD {
+            // The table is full, which only happens with thousands of
+            // distinct methods.
+            return Ok(NO_METHOD);
+        }
+        writeln!(self.methods, "{}", method)?;
+        self.method_ids.insert(method.to_string(), id);
+        Ok(id)
+    }
+
+    pub(crate) fn flush(&mut self) -> std::io::Result<()> {
+        self.methods.flush()?;
+        self.records.flush()
+    }
+}
+
+fn create_file(path: &Path, mode: LogMode) -> std::io::Result<File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode.0);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    options.open(path)
+}
+
+/// The string table is stored next to the index, one method per line
+fn methods_path(index_path: &Path) -> PathBuf {
+    index_path.with_extension("methods")
+}
+
+fn direction_byte(direction: Direction) -> u8 {
+    match direction {
+        Direction::ToServer => 0,
+        Direction::ToEditor => 1,
+    }
+}
+
+/// Prints the messages in a capture, optionally only those with the
+/// given method, by reading the index and seeking to each message in
+/// the raw logs
+pub(crate) fn query(index_path: &Path, method: Option<&str>) -> Result<()> {
+    let mut index = Vec::new();
+    File::open(index_path)
+        .and_then(|mut file| file.read_to_end(&mut index))
+        .with_context(|| format!("Failed to read {}", index_path.display()))?;
+    let Some(records) = index.strip_prefix(MAGIC) else {
+        bail!("{} isn't an lsp-fiddle index", index_path.display());
+    };
+
+    let methods_path = methods_path(index_path);
+    let methods = BufReader::new(
+        File::open(&methods_path)
+            .with_context(|| format!("Failed to read {}", methods_path.display()))?,
+    )
+    .lines()
+    .collect::<std::io::Result<Vec<String>>>()?;
+
+    // Only look up the method once, rather than comparing strings for
+    // every record.
+    let wanted_id = match method {
+        Some(method) => match methods.iter().position(|m| m == method) {
+            Some(id) => Some(id as u16),
+            None => return Ok(()),
+        },
+        None => None,
+    };
+
+    // `<timestamp>_index.bin` sits next to `<timestamp>_stdin.log`
+    // and `<timestamp>_stdout.log`.
+    let file_name = index_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    let Some(timestamp) = file_name.strip_suffix("_index.bin") else {
+        bail!("Expected an index file named <timestamp>_index.bin");
+    };
+    let log_path =
+        |stream: &str| index_path.with_file_name(format!("{}_{}.log", timestamp, stream));
+    let mut logs = [
+        File::open(log_path("stdin")).context("Failed to open stdin log")?,
+        File::open(log_path("stdout")).context("Failed to open stdout log")?,
+    ];
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    let mut frame = vec![];
+
+    for record in records.chunks_exact(RECORD_SIZE) {
+        let offset = u64::from_le_bytes(record[..8].try_into().unwrap());
+        let length = u32::from_le_bytes(record[8..12].try_into().unwrap());
+        let direction = record[12];
+        let method_id = u16::from_le_bytes(record[13..].try_into().unwrap());
+
+        if wanted_id.is_some_and(|id| id != method_id) {
+            continue;
+        }
+
+        let (stream, log) = match direction {
+            0 => ("stdin", &mut logs[0]),
+            _ => ("stdout", &mut logs[1]),
+        };
+        frame.resize(length as usize, 0);
+        log.seek(SeekFrom::Start(offset))?;
+        log.read_exact(&mut frame)
+            .with_context(|| format!("{} log is shorter than its index", stream))?;
+
+        // Skip the headers, the index covers the whole frame.
+        let body = match frame.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(header_end) => &frame[header_end + 4..],
+            None => &frame[..],
+        };
+        let message = match body {
+            b"" => "{\"_empty\":true}".into(),
+            body => String::from_utf8_lossy(body),
+        };
+        writeln!(out, "{{\"stream\":\"{}\",\"message\":{}}}", stream, message)?;
+    }
+
+    Ok(())
+}
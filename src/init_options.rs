@@ -0,0 +1,72 @@
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+/// The `initializationOptions` an earlier session in the same log
+/// directory sent, for spotting configuration changes between launches
+pub(crate) struct PreviousOptions {
+    /// The summary the options were read from
+    pub(crate) summary: PathBuf,
+    pub(crate) options: Value,
+}
+
+/// Finds the most recent session summary written before
+/// `current_summary` that recorded initialize options. Summaries sort
+/// by name, as they start with the session's timestamp.
+pub(crate) fn previous_options(log_dir: &Path, current_summary: &Path) -> Option<PreviousOptions> {
+    let current_name = current_summary.file_name()?;
+    let mut summaries: Vec<PathBuf> = std::fs::read_dir(log_dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name().is_some_and(|name| {
+                name < current_name && name.to_string_lossy().ends_with("_summary.json")
+            })
+        })
+        .collect();
+    summaries.sort();
+
+    summaries.into_iter().rev().find_map(|summary| {
+        let json = std::fs::read_to_string(&summary).ok()?;
+        let json: Value = serde_json::from_str(&json).ok()?;
+        let options = json.pointer("/initialize/initialization_options")?.clone();
+        Some(PreviousOptions { summary, options })
+    })
+}
+
+/// Describes each difference between two sets of options, one line per
+/// changed JSON pointer, e.g. `/cargo/features: "all" -> []`
+pub(crate) fn diff(old: &Value, new: &Value) -> Vec<String> {
+    let mut changes = vec![];
+    diff_at("", old, new, &mut changes);
+    changes
+}
+
+fn diff_at(path: &str, old: &Value, new: &Value, changes: &mut Vec<String>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for (key, old_value) in old {
+                let key_path = format!("{}/{}", path, escape(key));
+                match new.get(key) {
+                    Some(new_value) => diff_at(&key_path, old_value, new_value, changes),
+                    None => changes.push(format!("{}: removed (was {})", key_path, old_value)),
+                }
+            }
+            for (key, new_value) in new {
+                if !old.contains_key(key) {
+                    changes.push(format!("{}/{}: added {}", path, escape(key), new_value));
+                }
+            }
+        }
+        _ if old != new => {
+            let path = if path.is_empty() { "/" } else { path };
+            changes.push(format!("{}: {} -> {}", path, old, new));
+        }
+        _ => {}
+    }
+}
+
+/// Escapes a key for use in a JSON pointer
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
@@ -0,0 +1,56 @@
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// The initialize request from `--initialize-from`, sent in place of
+/// the minimal one
+static TEMPLATE: OnceLock<Value> = OnceLock::new();
+
+/// Read and check an initialize request, e.g. one copied from an
+/// editor's capture, to send whenever lsp-fiddle initializes a server
+/// itself
+pub(crate) fn load(path: &Path) -> Result<()> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let request: Value = serde_json::from_str(&contents)
+        .with_context(|| format!("{} isn't valid JSON", path.display()))?;
+    check(&request)
+        .with_context(|| format!("{} isn't a well-formed initialize request", path.display()))?;
+    let _ = TEMPLATE.set(request);
+    Ok(())
+}
+
+/// The initialize request from `--initialize-from`, if given
+pub(crate) fn template() -> Option<&'static Value> {
+    TEMPLATE.get()
+}
+
+fn check(request: &Value) -> Result<()> {
+    if !request.is_object() {
+        bail!("expected a JSON object");
+    }
+    if let Some(version) = request.get("jsonrpc") {
+        if version != "2.0" {
+            bail!("jsonrpc is {}, expected \"2.0\"", version);
+        }
+    }
+    match request.get("method").and_then(|method| method.as_str()) {
+        Some("initialize") => {}
+        Some(method) => bail!("method is {:?}, expected \"initialize\"", method),
+        None => bail!("missing method"),
+    }
+    let Some(params) = request.get("params").filter(|params| params.is_object()) else {
+        bail!("params must be an object");
+    };
+    if !params.get("capabilities").is_some_and(|c| c.is_object()) {
+        bail!("params.capabilities must be an object");
+    }
+    if let Some(process_id) = params.get("processId") {
+        if !process_id.is_null() && !process_id.is_i64() {
+            bail!("params.processId must be an integer or null");
+        }
+    }
+    Ok(())
+}
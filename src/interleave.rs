@@ -0,0 +1,158 @@
+/// How many interleaving diagnostics to print for each stream
+const MAX_REPORTS: usize = 3;
+
+/// Bytes shown before where the second message seems to start
+const DUMP_CONTEXT: usize = 64;
+
+/// The most bytes shown in a dump
+const DUMP_BYTES: usize = 256;
+
+/// Looks for the symptoms of two messages written at once, e.g. by a
+/// server writing to stdout from several threads without a lock. The
+/// parser can't untangle them, but saying so points at the likely bug
+/// rather than leaving a bare JSON parse error.
+#[derive(Default)]
+pub(crate) struct InterleaveCheck {
+    reported: usize,
+    /// Where the last message started, if its body wasn't JSON
+    last_unparseable: Option<usize>,
+}
+
+impl InterleaveCheck {
+    /// Check a message read at `offset` in the stream. `stray` and
+    /// `junk` are the bytes skipped before its headers. Returns a
+    /// diagnostic and a dump of the bytes concerned.
+    pub(crate) fn observe(
+        &mut self,
+        message: &[u8],
+        unparseable: bool,
+        stray: usize,
+        junk: &[u8],
+        offset: usize,
+    ) -> Option<(String, String)> {
+        let last_unparseable = self.last_unparseable.take();
+        if unparseable {
+            self.last_unparseable = Some(offset);
+        }
+
+        let body_start = message
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map_or(0, |end| end + 4);
+        if unparseable {
+            if let Some((at, what)) = second_message_in(&message[body_start..]) {
+                let at = body_start + at;
+                return self.report(
+                    format!(
+                        "its body isn't JSON and contains {} at byte {}",
+                        what,
+                        offset + at
+                    ),
+                    message,
+                    offset,
+                    at,
+                );
+            }
+        }
+
+        // A body cut short by another message's bytes leaves the rest
+        // of it before the next headers.
+        if let (Some(previous), true) = (last_unparseable, stray + junk.len() > 0) {
+            return self.report(
+                format!(
+                    "the message at byte {} isn't JSON and {} bytes of junk follow it",
+                    previous,
+                    stray + junk.len()
+                ),
+                message,
+                offset,
+                stray + junk.len(),
+            );
+        }
+        None
+    }
+
+    /// Check headers at `offset` that can't be parsed, which stop the
+    /// stream
+    pub(crate) fn observe_invalid_headers(
+        &mut self,
+        headers: &[u8],
+        offset: usize,
+    ) -> Option<(String, String)> {
+        let previous = self.last_unparseable.take()?;
+        self.report(
+            format!(
+                "the message at byte {} isn't JSON and the headers after it are invalid",
+                previous
+            ),
+            headers,
+            offset,
+            0,
+        )
+    }
+
+    /// A diagnostic for `symptom`, dumping `bytes` from around `at`.
+    /// `bytes` start at `offset` in the stream.
+    fn report(
+        &mut self,
+        symptom: String,
+        bytes: &[u8],
+        offset: usize,
+        at: usize,
+    ) -> Option<(String, String)> {
+        self.reported += 1;
+        if self.reported > MAX_REPORTS {
+            return None;
+        }
+        let start = at.saturating_sub(DUMP_CONTEXT);
+        let end = bytes.len().min(start + DUMP_BYTES);
+        Some((
+            format!(
+                "Possible interleaved writes or stream corruption: {}. The sender may be writing messages from several threads without a lock",
+                symptom
+            ),
+            hex_dump(&bytes[start..end], offset + start),
+        ))
+    }
+}
+
+/// Where a body that isn't JSON seems to hold the start of another
+/// message, and what was found there
+fn second_message_in(body: &[u8]) -> Option<(usize, &'static str)> {
+    let lowercase = body.to_ascii_lowercase();
+    if let Some(at) = find(&lowercase, b"content-length:") {
+        return Some((at, "another message's headers"));
+    }
+    let first = find(body, b"\"jsonrpc\"")?;
+    let second = find(&body[first + 1..], b"\"jsonrpc\"")?;
+    Some((first + 1 + second, "the start of another message"))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Lines of 16 bytes as hex and ASCII, numbered from `offset`
+fn hex_dump(bytes: &[u8], offset: usize) -> String {
+    let mut dump = String::new();
+    for (i, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        dump.push_str(&format!(
+            "  {:08x}  {:<47}  {}\n",
+            offset + i * 16,
+            hex.join(" "),
+            ascii
+        ));
+    }
+    dump
+}
@@ -0,0 +1,108 @@
+use serde_json::Value;
+
+/// Top level fields kept by `scan`, which are small enough to parse
+const KEPT_FIELDS: &[&str] = &["jsonrpc", "id", "method", "error"];
+
+/// Reads the envelope of a message, e.g. its method and id, without
+/// building the rest of it. For huge bodies like semantic tokens this
+/// avoids allocating a `Value` several times the size of the body.
+/// Other fields are skipped by matching brackets and quotes, so aren't
+/// fully validated. Returns None if the body isn't a JSON object.
+pub(crate) fn scan(payload: &str) -> Option<Value> {
+    let bytes = payload.as_bytes();
+    let mut pos = skip_whitespace(bytes, 0);
+    if bytes.get(pos) != Some(&b'{') {
+        return None;
+    }
+    pos = skip_whitespace(bytes, pos + 1);
+
+    let mut head = serde_json::Map::new();
+    if bytes.get(pos) != Some(&b'}') {
+        loop {
+            if bytes.get(pos) != Some(&b'"') {
+                return None;
+            }
+            let key_end = skip_string(bytes, pos)?;
+            let key: String = serde_json::from_str(&payload[pos..key_end]).ok()?;
+            pos = skip_whitespace(bytes, key_end);
+            if bytes.get(pos) != Some(&b':') {
+                return None;
+            }
+            pos = skip_whitespace(bytes, pos + 1);
+            let value_end = skip_value(bytes, pos)?;
+            if KEPT_FIELDS.contains(&key.as_str()) {
+                head.insert(key, serde_json::from_str(&payload[pos..value_end]).ok()?);
+            }
+
+            pos = skip_whitespace(bytes, value_end);
+            match bytes.get(pos)? {
+                b',' => pos = skip_whitespace(bytes, pos + 1),
+                b'}' => break,
+                _ => return None,
+            }
+        }
+    }
+
+    if skip_whitespace(bytes, pos + 1) != bytes.len() {
+        return None;
+    }
+    Some(Value::Object(head))
+}
+
+fn skip_whitespace(bytes: &[u8], mut pos: usize) -> usize {
+    while matches!(bytes.get(pos), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+        pos += 1;
+    }
+    pos
+}
+
+/// The end of the string that starts at `pos`
+fn skip_string(bytes: &[u8], pos: usize) -> Option<usize> {
+    let mut i = pos + 1;
+    loop {
+        match bytes.get(i)? {
+            b'\\' => i += 2,
+            b'"' => return Some(i + 1),
+            _ => i += 1,
+        }
+    }
+}
+
+/// The end of the value that starts at `pos`
+fn skip_value(bytes: &[u8], pos: usize) -> Option<usize> {
+    match bytes.get(pos)? {
+        b'"' => skip_string(bytes, pos),
+        b'{' | b'[' => {
+            let mut depth = 0usize;
+            let mut i = pos;
+            loop {
+                match bytes.get(i)? {
+                    b'"' => {
+                        i = skip_string(bytes, i)?;
+                        continue;
+                    }
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Some(i + 1);
+                        }
+                    }
+                    _ => {}
+                }
+                i += 1;
+            }
+        }
+        // Numbers, true, false and null end at the next delimiter.
+        _ => {
+            let mut i = pos;
+            while !matches!(
+                bytes.get(i),
+                None | Some(b',' | b'}' | b']' | b' ' | b'\t' | b'\n' | b'\r')
+            ) {
+                i += 1;
+            }
+            (i > pos).then_some(i)
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Buckets per power of two, so each bucket is within about 1% of the
+/// latencies in it
+const SUB_BUCKETS: u64 = 64;
+
+/// The percentiles reported for each method
+const PERCENTILES: [(&str, f64); 3] = [("p50_ms", 0.5), ("p95_ms", 0.95), ("p99_ms", 0.99)];
+
+/// A histogram of latencies with logarithmic buckets, so memory stays
+/// bounded however many requests are answered
+#[derive(Default)]
+struct Histogram {
+    /// Samples per bucket, by bucket index
+    buckets: BTreeMap<u64, u64>,
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl Histogram {
+    fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        *self.buckets.entry(bucket(micros)).or_default() += 1;
+        self.count += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+
+    /// The latency in milliseconds that `fraction` of samples are at
+    /// or below, to bucket precision
+    fn percentile(&self, fraction: f64) -> f64 {
+        let rank = ((self.count as f64 * fraction).ceil() as u64).max(1);
+        if rank >= self.count {
+            return millis(self.max.as_micros() as u64);
+        }
+        let mut seen = 0;
+        for (&index, &samples) in &self.buckets {
+            seen += samples;
+            if seen >= rank {
+                // Never report more than the slowest request.
+                let micros = bucket_midpoint(index).min(self.max.as_micros() as u64);
+                return millis(micros);
+            }
+        }
+        millis(self.max.as_micros() as u64)
+    }
+
+    fn summary_json(&self) -> Value {
+        let mut summary = serde_json::json!({
+            "count": self.count,
+            "mean_ms": millis(self.total.as_micros() as u64 / self.count.max(1)),
+            "max_ms": millis(self.max.as_micros() as u64),
+        });
+        for (name, fraction) in PERCENTILES {
+            summary[name] = self.percentile(fraction).into();
+        }
+        summary
+    }
+}
+
+/// Which bucket a latency falls in. Latencies under `SUB_BUCKETS`
+/// microseconds get a bucket each; above that, each power of two is
+/// split into `SUB_BUCKETS` buckets.
+fn bucket(micros: u64) -> u64 {
+    if micros < SUB_BUCKETS {
+        return micros;
+    }
+    let magnitude = 63 - micros.leading_zeros() as u64;
+    let shift = magnitude - SUB_BUCKETS.trailing_zeros() as u64;
+    let sub_bucket = (micros >> shift) - SUB_BUCKETS;
+    (shift + 1) * SUB_BUCKETS + sub_bucket
+}
+
+/// The middle of the latencies in a bucket, in microseconds
+fn bucket_midpoint(index: u64) -> u64 {
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let shift = index / SUB_BUCKETS - 1;
+    let start = (SUB_BUCKETS + index % SUB_BUCKETS) << shift;
+    start + (1 << shift) / 2
+}
+
+/// Microseconds as milliseconds, to a tenth of a millisecond
+fn millis(micros: u64) -> f64 {
+    (micros as f64 / 100.0).round() / 10.0
+}
+
+/// How long the server took to answer the editor's requests, by method
+#[derive(Default)]
+pub(crate) struct Latencies {
+    methods: BTreeMap<String, Histogram>,
+}
+
+impl Latencies {
+    pub(crate) fn record(&mut self, method: &str, latency: Duration) {
+        self.methods
+            .entry(method.to_string())
+            .or_default()
+            .record(latency);
+    }
+
+    pub(crate) fn summary_json(&self) -> Value {
+        let methods: BTreeMap<&str, Value> = self
+            .methods
+            .iter()
+            .map(|(method, histogram)| (method.as_str(), histogram.summary_json()))
+            .collect();
+        serde_json::json!(methods)
+    }
+}
@@ -0,0 +1,153 @@
+use chrono::{DateTime, Local};
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+
+use crate::disk_full;
+use crate::stream::Direction;
+
+/// A template for one line per message, e.g.
+/// `{ts} {dir} {method} {id} {bytes}`
+#[derive(Clone, Debug)]
+pub(crate) struct LineFormat {
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Field {
+    /// When the message was read, in local time
+    Timestamp,
+    /// `stdin` or `stdout`
+    Direction,
+    /// The method, or for a response, the method of its request
+    Method,
+    Id,
+    /// Length of the message body
+    Bytes,
+    /// `request`, `notification`, `response` or `error`
+    Kind,
+}
+
+impl LineFormat {
+    /// Parses a template. Unknown placeholders are kept as literal text.
+    pub(crate) fn parse(template: &str) -> Self {
+        let mut parts = vec![];
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+                break;
+            };
+            let field = match &rest[start + 1..end] {
+                "ts" => Field::Timestamp,
+                "dir" => Field::Direction,
+                "method" => Field::Method,
+                "id" => Field::Id,
+                "bytes" => Field::Bytes,
+                "kind" => Field::Kind,
+                other => {
+                    eprintln!("Unknown placeholder {{{}}} in LSP_LINE_FORMAT", other);
+                    parts.push(Part::Literal(rest[..end + 1].to_string()));
+                    rest = &rest[end + 1..];
+                    continue;
+                }
+            };
+            parts.push(Part::Literal(rest[..start].to_string()));
+            parts.push(Part::Field(field));
+            rest = &rest[end + 1..];
+        }
+        parts.push(Part::Literal(rest.to_string()));
+        Self { parts }
+    }
+
+    /// Fill in the template for a message. Fields that don't apply,
+    /// such as the id of a notification, are empty.
+    pub(crate) fn render(
+        &self,
+        timestamp: DateTime<Local>,
+        direction: Direction,
+        method: Option<&str>,
+        message: Option<&Value>,
+        bytes: usize,
+    ) -> String {
+        let mut line = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => line.push_str(text),
+                Part::Field(Field::Timestamp) => {
+                    line.push_str(&timestamp.format("%Y-%m-%dT%H:%M:%S%.3f").to_string())
+                }
+                Part::Field(Field::Direction) => line.push_str(direction.stream_name()),
+                Part::Field(Field::Method) => line.push_str(method.unwrap_or_default()),
+                Part::Field(Field::Id) => match message.and_then(|message| message.get("id")) {
+                    Some(Value::String(id)) => line.push_str(id),
+                    Some(id) => line.push_str(&id.to_string()),
+                    None => {}
+                },
+                Part::Field(Field::Bytes) => line.push_str(&bytes.to_string()),
+                Part::Field(Field::Kind) => line.push_str(message.map(kind).unwrap_or_default()),
+            }
+        }
+        line.push('\n');
+        line
+    }
+}
+
+fn kind(message: &Value) -> &'static str {
+    match (message.get("method"), message.get("id")) {
+        (Some(_), Some(_)) => "request",
+        (Some(_), None) => "notification",
+        (None, _) if message.get("error").is_some() => "error",
+        (None, Some(_)) => "response",
+        (None, None) => "",
+    }
+}
+
+/// A log with one line per message in both directions, formatted with
+/// `LSP_LINE_FORMAT`
+pub(crate) struct LineLog {
+    format: LineFormat,
+    file: File,
+}
+
+pub(crate) type SharedLineLog = Arc<tokio::sync::Mutex<LineLog>>;
+
+impl LineLog {
+    pub(crate) fn new(format: LineFormat, file: File) -> Self {
+        Self { format, file }
+    }
+
+    pub(crate) async fn write(
+        &mut self,
+        timestamp: DateTime<Local>,
+        direction: Direction,
+        method: Option<&str>,
+        message: Option<&Value>,
+        bytes: usize,
+    ) {
+        let line = self
+            .format
+            .render(timestamp, direction, method, message, bytes);
+        if disk_full::logging_disabled() {
+            return;
+        }
+        if let Err(e) = self.file.write_all(line.as_bytes()).await {
+            disk_full::log_write_failed("lines log", &e);
+        }
+    }
+
+    pub(crate) async fn flush(&mut self) {
+        if disk_full::logging_disabled() {
+            return;
+        }
+        if let Err(e) = self.file.flush().await {
+            disk_full::log_write_failed("lines log", &e);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use anyhow::{bail, Context, Result};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::process::Child;
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::config::ProxyConfig;
+use crate::resolve;
+use crate::shared_server::SharedServer;
+use crate::tls::{self, Connection};
+use crate::transport::ServerTransport;
+use crate::{run_session, server_command, shutdown_signal, SERVER_EXIT_TIMEOUT};
+
+/// How long to wait for open connections to finish their sessions
+/// when the listener is stopped
+const CONNECTION_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Accepts connections on a TCP address or, with a `unix:` prefix, a
+/// Unix socket
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    pub(crate) async fn bind(addr: &str) -> Result<Self> {
+        if let Some(path) = addr.strip_prefix("unix:") {
+            #[cfg(unix)]
+            {
+                let listener = tokio::net::UnixListener::bind(path)
+                    .with_context(|| format!("Failed to listen on {}", addr))?;
+                return Ok(Listener::Unix(listener));
+            }
+            #[cfg(not(unix))]
+            bail!("Unix sockets aren't supported on this platform: {}", path);
+        }
+
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to listen on {}", addr))?;
+        Ok(Listener::Tcp(listener))
+    }
+
+    /// Wait for an editor to connect, returning the connection and a
+    /// description of the peer
+    pub(crate) async fn accept(&self) -> std::io::Result<(Box<dyn Connection>, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((Box::new(stream), peer.to_string()))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), "unix socket".to_string()))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        // Remove the socket file, otherwise binding to it again fails.
+        #[cfg(unix)]
+        if let Listener::Unix(listener) = self {
+            if let Some(path) = listener
+                .local_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(|path| path.to_path_buf()))
+            {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// Starts the server for `--shared-server`, with its stderr going to
+/// ours, as it doesn't belong to any one connection
+fn spawn_shared_server(
+    config: &ProxyConfig,
+    lsp_server: &str,
+    server_args: &[String],
+) -> Result<(Child, SharedServer, JoinHandle<()>)> {
+    if !matches!(
+        ServerTransport::from_args(server_args)?,
+        ServerTransport::Stdio
+    ) {
+        bail!("--shared-server requires a server using stdio");
+    }
+    let server_path = resolve::resolve_server(lsp_server, &config.server_fallbacks)?;
+    eprintln!(
+        "Starting {} for every connection to share",
+        server_path.display()
+    );
+    let mut child = server_command(&config.server_wrapper, &server_path, server_args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .context("Failed to spawn LSP server")?;
+    let input = child.stdin.take().context("Failed to open child stdin")?;
+    let output = child.stdout.take().context("Failed to open child stdout")?;
+    let (shared_server, router) = SharedServer::start(Box::new(input), Box::new(output));
+    Ok((child, shared_server, router))
+}
+
+/// Accepts editor connections on `addr`, running a separate proxy
+/// session with its own log directory for each. Each session gets its
+/// own server process, unless `shared` is set, when they all share one.
+pub(crate) async fn run_listener(
+    addr: &str,
+    shared: bool,
+    lsp_server: String,
+    server_args: Vec<String>,
+) -> Result<()> {
+    let mut config = ProxyConfig::from_env();
+    if config.server_fds.is_some() {
+        // Each connection needs its own server.
+        bail!("LSP_SERVER_FD_IN and LSP_SERVER_FD_OUT can't be used with listen");
+    }
+    let acceptor = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::acceptor(cert, key)?),
+        (None, None) => None,
+        _ => bail!("LSP_TLS_CERT and LSP_TLS_KEY must be set together"),
+    };
+
+    let mut shared_server = None;
+    let mut router = None;
+    if shared {
+        let (child, server, routing) = spawn_shared_server(&config, &lsp_server, &server_args)?;
+        config.shared_server = Some(server.clone());
+        shared_server = Some((child, server));
+        router = Some(routing);
+    }
+
+    let listener = Listener::bind(addr).await?;
+    eprintln!(
+        "Listening for editor connections on {}{}",
+        addr,
+        if acceptor.is_some() { " (TLS)" } else { "" }
+    );
+
+    let lsp_server = Arc::new(lsp_server);
+    let server_args = Arc::new(server_args);
+    let mut sessions = JoinSet::new();
+    let mut connection_number = 0;
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (connection, peer) = match accepted {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        eprintln!("Failed to accept connection: {}", e);
+                        continue;
+                    }
+                };
+
+                connection_number += 1;
+                eprintln!("Connection {} from {}", connection_number, peer);
+
+                // Keep each connection's logs separate.
+                let mut config = config.clone();
+                config.log_dir = config
+                    .log_dir
+                    .join(format!("connection_{}", connection_number));
+
+                let lsp_server = lsp_server.clone();
+                let server_args = server_args.clone();
+                let number = connection_number;
+                let acceptor = acceptor.clone();
+                sessions.spawn(async move {
+                    // Handshake in the session's task, so a slow client
+                    // doesn't hold up other connections.
+                    let connection = match &acceptor {
+                        Some(acceptor) => tls::accept(acceptor, connection).await,
+                        None => Ok(connection),
+                    };
+                    let result = match connection {
+                        Ok(connection) => {
+                            let (editor_input, editor_output) = tokio::io::split(connection);
+                            run_session(&lsp_server, &server_args, config, editor_input, editor_output)
+                                .await
+                        }
+                        Err(e) => Err(e),
+                    };
+                    match result {
+                        Ok(_) => eprintln!("Connection {} closed", number),
+                        Err(e) => eprintln!("Connection {} failed: {:#}", number, e),
+                    }
+                });
+            }
+            Some(_) = sessions.join_next(), if !sessions.is_empty() => {}
+            Some(_) = async { Some(router.as_mut()?.await) } => {
+                eprintln!("The shared LSP server closed its output, no longer accepting connections");
+                break;
+            }
+            signal = shutdown_signal() => {
+                eprintln!("Received {}, no longer accepting connections", signal);
+                break;
+            }
+        }
+    }
+
+    // Sessions handle the signal themselves, so give them a chance to
+    // finish writing their logs.
+    let all_finished = async { while sessions.join_next().await.is_some() {} };
+    if tokio::time::timeout(CONNECTION_SHUTDOWN_TIMEOUT, all_finished)
+        .await
+        .is_err()
+    {
+        eprintln!("Timed out waiting for connections to close");
+    }
+
+    if let Some((mut child, server)) = shared_server {
+        server.stop();
+        match tokio::time::timeout(SERVER_EXIT_TIMEOUT, child.wait()).await {
+            Ok(Ok(status)) => eprintln!("Shared LSP server exited with {}", status),
+            Ok(Err(e)) => eprintln!("Failed to wait for the shared LSP server: {}", e),
+            Err(_) => {
+                eprintln!("Shared LSP server didn't exit, killing it");
+                let _ = child.kill().await;
+            }
+        }
+    }
+
+    Ok(())
+}
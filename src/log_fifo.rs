@@ -0,0 +1,150 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::unix::pipe;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::tee::Backlog;
+
+/// How often to check whether a reader has opened the FIFO
+const RETRY_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Lines kept while no reader is connected
+const MAX_BUFFERED_LINES: usize = 10_000;
+
+/// How long to spend writing buffered lines when the session ends
+const FINISH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Streams log lines to a named pipe for another local process to read.
+/// Lines are buffered until a reader opens the FIFO, and if the reader
+/// goes away we stop writing rather than failing the session.
+pub(crate) struct LogFifo {
+    sender: mpsc::UnboundedSender<String>,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl LogFifo {
+    pub(crate) fn spawn(path: PathBuf) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        let task = tokio::spawn(run_log_fifo(path, receiver, shutdown_receiver));
+
+        Self {
+            sender,
+            shutdown,
+            task,
+        }
+    }
+
+    pub(crate) fn sender(&self) -> mpsc::UnboundedSender<String> {
+        self.sender.clone()
+    }
+
+    /// Write any buffered lines if a reader is connected, then stop
+    pub(crate) async fn finish(self) {
+        let _ = self.shutdown.send(());
+        if tokio::time::timeout(FINISH_TIMEOUT, self.task)
+            .await
+            .is_err()
+        {
+            eprintln!("Timed out writing remaining log lines to LSP_LOG_FIFO");
+        }
+    }
+}
+
+async fn run_log_fifo(
+    path: PathBuf,
+    mut receiver: mpsc::UnboundedReceiver<String>,
+    mut shutdown: oneshot::Receiver<()>,
+) {
+    let mut backlog = Backlog::new(MAX_BUFFERED_LINES);
+    let mut fifo: Option<pipe::Sender> = None;
+    let mut waiting = false;
+    let mut finishing = false;
+
+    loop {
+        if !finishing && backlog.lines.is_empty() {
+            tokio::select! {
+                line = receiver.recv() => match line {
+                    Some(line) => backlog.push(line),
+                    None => finishing = true,
+                },
+                _ = &mut shutdown => finishing = true,
+            }
+        }
+        while let Ok(line) = receiver.try_recv() {
+            backlog.push(line);
+        }
+
+        let dropped = backlog.take_dropped();
+        if dropped > 0 {
+            eprintln!(
+                "No reader on LSP_LOG_FIFO {}, dropped {} log lines",
+                path.display(),
+                dropped
+            );
+        }
+
+        if fifo.is_none() {
+            // Opening without blocking fails with ENXIO until something
+            // opens the FIFO for reading.
+            match pipe::OpenOptions::new().open_sender(&path) {
+                Ok(sender) => {
+                    if waiting {
+                        eprintln!("Reader connected to LSP_LOG_FIFO {}", path.display());
+                    }
+                    fifo = Some(sender);
+                }
+                Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
+                    if finishing {
+                        eprintln!(
+                            "No reader connected to LSP_LOG_FIFO {}, {} log lines were not written",
+                            path.display(),
+                            backlog.lines.len()
+                        );
+                        return;
+                    }
+                    if !waiting {
+                        eprintln!("Waiting for a reader on LSP_LOG_FIFO {}", path.display());
+                        waiting = true;
+                    }
+
+                    // Keep accepting lines while we wait to retry.
+                    tokio::select! {
+                        _ = tokio::time::sleep(RETRY_INTERVAL) => {}
+                        _ = &mut shutdown => finishing = true,
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Ignoring LSP_LOG_FIFO: can't write to {} ({}), create a FIFO with mkfifo",
+                        path.display(),
+                        e
+                    );
+                    return;
+                }
+            }
+        }
+
+        if let Some(fifo) = fifo.as_mut() {
+            while let Some(line) = backlog.lines.front() {
+                if let Err(e) = fifo.write_all(line.as_bytes()).await {
+                    eprintln!(
+                        "LSP_LOG_FIFO reader disconnected ({}), no longer writing to {}",
+                        e,
+                        path.display()
+                    );
+                    return;
+                }
+                backlog.lines.pop_front();
+            }
+        }
+
+        if finishing && backlog.lines.is_empty() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+const WAITING: u8 = 0;
+const LOGGING: u8 = 1;
+const STOPPED: u8 = 2;
+
+/// Only logs the messages between a start and stop method, so a
+/// capture can skip startup traffic. Both directions share the window:
+/// it opens at the first message with the start method, whichever way
+/// it's sent, and closes after the first message with the stop method.
+pub(crate) struct LogWindow {
+    after: Option<String>,
+    until: Option<String>,
+    state: AtomicU8,
+}
+
+pub(crate) type SharedLogWindow = Arc<LogWindow>;
+
+impl LogWindow {
+    pub(crate) fn new(after: Option<String>, until: Option<String>) -> Self {
+        let state = if after.is_some() { WAITING } else { LOGGING };
+        Self {
+            after,
+            until,
+            state: AtomicU8::new(state),
+        }
+    }
+
+    /// Whether to log a message, given its method if it's a request or
+    /// notification. The start and stop messages are both logged.
+    pub(crate) fn should_log(&self, method: Option<&str>) -> bool {
+        let is = |trigger: &Option<String>| method.is_some() && trigger.as_deref() == method;
+
+        if is(&self.after)
+            && self
+                .state
+                .compare_exchange(WAITING, LOGGING, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        {
+            eprintln!("Saw {}, started logging", method.unwrap_or_default());
+        }
+
+        if self.state.load(Ordering::SeqCst) != LOGGING {
+            return false;
+        }
+        if is(&self.until)
+            && self
+                .state
+                .compare_exchange(LOGGING, STOPPED, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        {
+            eprintln!("Saw {}, stopped logging", method.unwrap_or_default());
+        }
+        true
+    }
+}
@@ -1,22 +1,142 @@
 use anyhow::{Context, Result};
-use chrono::Local;
-use clap::{Parser, Subcommand};
+use chrono::{DateTime, Local};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 use std::env;
-use std::path::PathBuf;
-use std::process::Stdio;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::process::Command;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStderr, Command};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+mod alerts;
+mod annotations;
+mod anonymize;
+mod capture;
+mod checksum;
+mod client_capabilities;
+mod clock;
+mod coalesce;
+mod config;
+mod connect;
+mod console;
+mod count;
+mod dedup;
+mod diagnostics;
+mod disk_full;
+mod document_state;
+mod filter;
+mod gzip;
+mod hooks;
+mod http_bridge;
+mod import_raw;
+mod index;
+mod init_options;
+mod initialize_from;
+mod interleave;
+mod json_head;
+mod latency;
+mod line_log;
+mod listen;
+#[cfg(unix)]
+mod log_fifo;
+mod log_window;
+mod merge;
+mod method_logs;
+mod phase_markers;
+mod phases;
+mod plugin;
+mod probe;
+mod progress;
+mod rate_limit;
+mod record_env;
+mod registrations;
+mod renumber;
+mod repair;
+mod repl;
+mod replay;
+mod requests_csv;
+mod require_capabilities;
+mod resolve;
+mod respawn;
+mod rotate;
+mod selftest;
+mod server_messages;
+mod session;
+mod shared_server;
+mod split_by_uri;
+mod sqlite;
+mod stages;
+mod status;
+mod stream;
+mod strict_length;
+mod strict_utf8;
+mod syslog;
+mod tee;
+mod throttle;
+mod tls;
+mod trace;
+mod transport;
+mod validate;
+
+#[cfg(unix)]
+use annotations::annotate_on_sigusr1;
+use annotations::{read_annotation_fifo, Annotations};
+use clock::SharedClock;
+use config::{LogFormat, LogMode, ProxyConfig, ServerFds};
+use console::{paint, Color};
+use index::BinaryIndex;
+use line_log::LineLog;
+#[cfg(unix)]
+use log_fifo::LogFifo;
+use log_window::LogWindow;
+use method_logs::MethodLogs;
+use phase_markers::{read_phase_fifo, PhaseMarkers};
+use rate_limit::LogRateLimit;
+use renumber::SharedIdRenumbering;
+use respawn::{FlapLimit, Respawn};
+use rotate::LogRotation;
+use session::{watch_initialize, Session, SharedSession};
+use sqlite::SqliteLog;
+use status::{serve_status, SharedServerStatus};
+use stream::{proxy_stream, Direction, SharedLogs, StreamControl, StreamEnd, StreamLog};
+use strict_utf8::InvalidUtf8;
+use tee::Tee;
+use transport::ServerTransport;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// How long the server has to exit after we ask it to shut down
+const SERVER_EXIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The id of the shutdown request sent when we end the session, which
+/// can't clash with the editor's numeric ids
+const SHUTDOWN_ID: &str = "lsp-fiddle-shutdown";
+
+/// `MessageType.Log`, the least severe `window/logMessage` type
+const LOG_MESSAGE_TYPE_LOG: u8 = 4;
+
+/// How long to wait for the stream tasks to finish their logs when a
+/// session ends
+const STREAM_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
 /// lsp-fiddle provides helper tools for testing and debugging LSP
 /// servers.
 #[derive(Parser)]
 #[command(version = VERSION)]
 struct Cli {
+    /// A JSON file holding the initialize request to send when
+    /// lsp-fiddle initializes a server itself, e.g. one copied from an
+    /// editor's capture, instead of a minimal one
+    #[arg(long, global = true, env = "LSP_INITIALIZE_FROM")]
+    initialize_from: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -25,7 +145,54 @@ struct Cli {
 enum Commands {
     /// Proxy an LSP server and log all communication
     Proxy {
-        /// Path to the LSP server executable
+        /// Send requests typed at a prompt instead of proxying stdin
+        #[arg(long)]
+        repl: bool,
+
+        /// Path to the LSP server executable. Taken from `LSP_SERVER`
+        /// if not given; a server given here takes precedence.
+        #[arg(env = "LSP_SERVER")]
+        lsp_server: String,
+
+        /// Arguments to pass to the LSP server
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        lsp_args: Vec<String>,
+    },
+    /// Accept editor connections on a socket, proxying each to its own
+    /// LSP server process, or to one they share
+    Listen {
+        /// Address to listen on, either `host:port` for TCP or
+        /// `unix:/path/to/socket`
+        addr: String,
+
+        /// Start one LSP server for every connection to share, rather
+        /// than one per connection
+        #[arg(long)]
+        shared_server: bool,
+
+        /// Path to the LSP server executable. Taken from `LSP_SERVER`
+        /// if not given; a server given here takes precedence.
+        #[arg(env = "LSP_SERVER")]
+        lsp_server: String,
+
+        /// Arguments to pass to the LSP server
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        lsp_args: Vec<String>,
+    },
+    /// Proxy an LSP server that is already listening on a TCP port
+    Connect {
+        /// Address of the LSP server, e.g. `localhost:9257`
+        addr: String,
+    },
+    /// Serve JSON-RPC over HTTP, forwarding each POSTed request to an
+    /// LSP server and responding with its reply
+    HttpBridge {
+        /// Address to serve HTTP on, e.g. `127.0.0.1:8080`
+        addr: String,
+
+        /// Path to the LSP server executable. Taken from `LSP_SERVER`
+        /// if not given; a server given here takes precedence.
+        #[arg(env = "LSP_SERVER")]
         lsp_server: String,
 
         /// Arguments to pass to the LSP server
@@ -34,30 +201,200 @@ enum Commands {
     },
     /// Send initialize and shutdown requests to stdout
     Minimal,
+    /// Check that the proxy forwards and logs a large random stream of
+    /// messages exactly, using a built in echo server
+    Selftest {
+        /// How many messages to send
+        #[arg(long, default_value_t = 2000)]
+        messages: usize,
+
+        /// Seed for the random messages, to reproduce a failure
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+    /// Write stdin back to stdout, as the server for `selftest`
+    #[command(hide = true)]
+    EchoServer,
+    /// Close the JSON array in a log from an interrupted session
+    Repair {
+        /// The `.json` log file to repair in place
+        file: PathBuf,
+    },
+    /// Print messages from a raw capture using its binary index
+    Query {
+        /// The `_index.bin` file written with `LSP_BINARY_INDEX=1`
+        index: PathBuf,
+
+        /// Only print messages with this method, including responses
+        #[arg(long)]
+        method: Option<String>,
+    },
+    /// Convert a stdin or stdout log to another log format
+    Convert {
+        /// Format of the input: raw, jsonl or json-array. Defaults to
+        /// the format implied by its extension.
+        #[arg(long, value_parser = LogFormat::from_name)]
+        from: Option<LogFormat>,
+
+        /// Format to write, defaulting to the format implied by the
+        /// output's extension
+        #[arg(long, value_parser = LogFormat::from_name)]
+        to: Option<LogFormat>,
+
+        input: PathBuf,
+        output: PathBuf,
+    },
+    /// Interleave the stdin and stdout logs of a session into one log,
+    /// in the order messages were read
+    Merge {
+        /// The `_stdin` log, a JSON log written with `LSP_LOG_GAPS=1`
+        stdin: PathBuf,
+
+        /// The `_stdout` log from the same session
+        stdout: PathBuf,
+
+        /// Where to write the merged log, as JSON Lines (`.jsonl`) or
+        /// a JSON array (`.json`)
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Convert raw framed LSP bytes captured by another tool, e.g.
+    /// strace, into a log
+    ImportRaw {
+        /// The bytes of one stream, with their Content-Length headers
+        input: PathBuf,
+
+        /// Where to write the log, as JSON Lines (`.jsonl`) or a JSON
+        /// array (`.json`)
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Split a capture into one log per document URI, plus one for
+    /// messages that aren't about a document
+    SplitByUri {
+        /// A merged log, or the `_stdin` and `_stdout` logs of a
+        /// session so responses go with their requests
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        /// The directory to write the logs to
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Print message counts by method and stream for captured logs
+    Count {
+        /// Log files from any `LSP_FORMAT`, e.g. both `_stdin` and
+        /// `_stdout` logs of a session so responses can be matched
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Check captures for truncation and corruption, exiting with an
+    /// error if there are any problems
+    Validate {
+        /// Log files from any `LSP_FORMAT`. Give both the `_stdin` and
+        /// `_stdout` logs of a session to match responses to requests.
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Send the editor's messages from a capture to a new server, e.g.
+    /// to find which message crashes it
+    Replay {
+        /// Only send these messages, numbered from 0, e.g. `10..25`.
+        /// The initialize handshake is sent first if it's before the
+        /// range.
+        #[arg(long, value_parser = replay::parse_range)]
+        range: Option<std::ops::Range<usize>>,
+
+        /// Seconds to wait for the server to answer the requests sent
+        #[arg(long, default_value_t = 5)]
+        timeout: u64,
+
+        /// A stdin log, or a log of both streams such as a method log
+        capture: PathBuf,
+
+        /// Path to the LSP server executable. Taken from `LSP_SERVER`
+        /// if not given; a server given here takes precedence.
+        #[arg(env = "LSP_SERVER")]
+        lsp_server: String,
+
+        /// Arguments to pass to the LSP server
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        lsp_args: Vec<String>,
+    },
+    /// Check which unusual message framings an LSP server accepts
+    Probe {
+        /// Seconds to wait for a response to each probe
+        #[arg(long, default_value_t = 5)]
+        timeout: u64,
+
+        /// Path to the LSP server executable. Taken from `LSP_SERVER`
+        /// if not given; a server given here takes precedence.
+        #[arg(env = "LSP_SERVER")]
+        lsp_server: String,
+
+        /// Arguments to pass to the LSP server
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        lsp_args: Vec<String>,
+    },
 }
 
 /// Formats a JSON message as an LSP message with Content-Length header
 fn format_lsp_message(json: &str) -> String {
-    format!("Content-Length: {}\r\n\r\n{}", json.len(), json)
+    format_lsp_message_with_length(json, json.len())
 }
 
-/// Prints a minimal LSP session (initialize + shutdown) to stdout
-fn print_minimal_session() {
+/// Formats a JSON message as an LSP message, using the given
+/// Content-Length even if it's wrong
+fn format_lsp_message_with_length(json: &str, content_length: usize) -> String {
+    format!("Content-Length: {}\r\n\r\n{}", content_length, json)
+}
+
+/// Builds an initialize request rooted at the current directory
+fn initialize_request(id: u64) -> serde_json::Value {
+    if let Some(template) = initialize_from::template() {
+        let mut initialize = template.clone();
+        initialize["jsonrpc"] = "2.0".into();
+        initialize["id"] = id.into();
+        return initialize;
+    }
+
     // Get current working directory and convert to file URI
     let root_uri = env::current_dir()
         .ok()
         .and_then(|path| path.to_str().map(|s| format!("file://{}", s)));
 
-    let initialize = serde_json::json!({
+    serde_json::json!({
         "jsonrpc": "2.0",
-        "id": 1,
+        "id": id,
         "method": "initialize",
         "params": {
             "processId": null,
             "rootUri": root_uri,
             "capabilities": {}
         }
+    })
+}
+
+/// The id of the initialize request sent for `LSP_AUTO_INITIALIZE`,
+/// which can't clash with the editor's numeric ids
+const AUTO_INITIALIZE_ID: &str = "lsp-fiddle-initialize";
+
+/// Initialize and initialized messages, for editors (or captures)
+/// that start mid-session
+fn auto_initialize_handshake() -> [serde_json::Value; 2] {
+    let mut initialize = initialize_request(0);
+    initialize["id"] = AUTO_INITIALIZE_ID.into();
+    let initialized = serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": "initialized",
+        "params": {}
     });
+    [initialize, initialized]
+}
+
+/// Prints a minimal LSP session (initialize + shutdown) to stdout
+fn print_minimal_session() {
+    let initialize = initialize_request(1);
 
     let shutdown = serde_json::json!({
         "jsonrpc": "2.0",
@@ -80,11 +417,30 @@ fn print_minimal_session() {
 /// Parses LSP messages from a buffer and extracts JSON payloads
 struct LspMessageParser {
     buffer: Vec<u8>,
+    /// Whether we've warned about bytes before a header, which is only
+    /// done once as some peers send them before every message
+    warned_stray_bytes: bool,
+    /// Stray bytes skipped since the last complete message
+    stray_bytes: usize,
+    /// Stray bytes skipped before the last complete message
+    stray_before_message: usize,
+    /// Where the body of the last complete message stops being UTF-8
+    invalid_utf8: Option<InvalidUtf8>,
+    /// Why the last complete message can't be read, though it could be
+    /// framed
+    framing_error: Option<String>,
 }
 
 impl LspMessageParser {
     fn new() -> Self {
-        Self { buffer: Vec::new() }
+        Self {
+            buffer: Vec::new(),
+            warned_stray_bytes: false,
+            stray_bytes: 0,
+            stray_before_message: 0,
+            invalid_utf8: None,
+            framing_error: None,
+        }
     }
 
     /// Add data to the buffer and try to extract complete messages
@@ -95,12 +451,15 @@ impl LspMessageParser {
     /// Try to extract one complete LSP message from the buffer
     /// Returns (headers_and_body, json_payload) if successful
     fn try_parse_message(&mut self) -> Option<(Vec<u8>, String)> {
+        self.skip_stray_bytes();
+
         // Look for the header separator (\r\n\r\n)
         let header_end = self.find_header_end()?;
 
         // Parse headers to get Content-Length
         let headers = String::from_utf8_lossy(&self.buffer[..header_end]);
-        let content_length = self.parse_content_length(&headers)?;
+        let headers = Headers::parse(&headers)?;
+        let content_length = headers.content_length;
 
         // Check if we have the complete message body
         let body_start = header_end + 4; // Skip \r\n\r\n
@@ -113,305 +472,2012 @@ impl LspMessageParser {
 
         // Extract the complete message (headers + body)
         let complete_message = self.buffer.drain(..body_end).collect::<Vec<u8>>();
+        self.stray_before_message = std::mem::take(&mut self.stray_bytes);
 
-        // Extract just the JSON body
+        // Extract just the JSON body. A compressed body is forwarded
+        // as it is, but decompressed for logging.
         let json_bytes = &complete_message[body_start..];
-        let json_str = String::from_utf8_lossy(json_bytes).to_string();
+        self.framing_error = None;
+        let decompressed = match headers.content_encoding.as_deref() {
+            Some(encoding) if encoding.eq_ignore_ascii_case("gzip") => {
+                match gzip::decompress(json_bytes) {
+                    Ok(json) => Some(json),
+                    Err(e @ gzip::Error::TooLarge(_)) => {
+                        self.framing_error = Some(format!("its gzip body {}", e));
+                        None
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to decompress gzip message body: {}", e);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        let body = decompressed.as_deref().unwrap_or(json_bytes);
+        let json_str = match std::str::from_utf8(body) {
+            Ok(json) => {
+                self.invalid_utf8 = None;
+                json.to_string()
+            }
+            Err(e) => {
+                self.invalid_utf8 = Some(InvalidUtf8::new(body, &e));
+                String::from_utf8_lossy(body).to_string()
+            }
+        };
 
         Some((complete_message, json_str))
     }
 
+    /// Drop a UTF-8 BOM or blank lines before the headers, which some
+    /// servers send, as otherwise the headers are never found
+    fn skip_stray_bytes(&mut self) {
+        let mut stray = 0;
+        loop {
+            let rest = &self.buffer[stray..];
+            if rest.starts_with(UTF8_BOM) {
+                stray += UTF8_BOM.len();
+            } else if rest.first().is_some_and(|b| b.is_ascii_whitespace()) {
+                stray += 1;
+            } else {
+                break;
+            }
+        }
+        if stray == 0 {
+            return;
+        }
+        self.stray_bytes += stray;
+
+        if !self.warned_stray_bytes {
+            self.warned_stray_bytes = true;
+            eprintln!(
+                "{} skipping stray bytes before a message header: {:?}",
+                paint("WARNING:", Color::Yellow),
+                String::from_utf8_lossy(&self.buffer[..stray])
+            );
+        }
+        self.buffer.drain(..stray);
+    }
+
+    /// How many stray bytes were skipped before the message returned
+    /// last by `try_parse_message`
+    fn stray_bytes_before_message(&self) -> usize {
+        self.stray_before_message
+    }
+
+    /// Where the body of the message returned last by
+    /// `try_parse_message` stops being valid UTF-8, if it does
+    fn take_invalid_utf8(&mut self) -> Option<InvalidUtf8> {
+        self.invalid_utf8.take()
+    }
+
+    /// Why the body of the message returned last by `try_parse_message`
+    /// can't be read, e.g. a gzip body over `gzip::MAX_DECODED`
+    fn take_framing_error(&mut self) -> Option<String> {
+        self.framing_error.take()
+    }
+
+    /// Headers that are complete but have no valid Content-Length, so
+    /// no more messages can be parsed
+    fn invalid_headers(&self) -> Option<&[u8]> {
+        let header_end = self.find_header_end()?;
+        let headers = &self.buffer[..header_end];
+        match Headers::parse(&String::from_utf8_lossy(headers)) {
+            Some(_) => None,
+            None => Some(headers),
+        }
+    }
+
+    /// Whether part of a message has been read but not parsed yet
+    fn has_partial_message(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+
+    /// The bytes read of a message that isn't complete yet
+    fn partial_message(&self) -> &[u8] {
+        &self.buffer
+    }
+
     fn find_header_end(&self) -> Option<usize> {
         self.buffer.windows(4).position(|w| w == b"\r\n\r\n")
     }
+}
+
+/// The bytes at the start of a message's headers that aren't a header,
+/// e.g. the end of a body that was longer than its Content-Length
+fn junk_before_headers(message: &[u8]) -> &[u8] {
+    let header_end = message
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .unwrap_or(message.len());
+    let headers = &message[..header_end];
+
+    let mut line_start = 0;
+    for line in headers.split(|&b| b == b'\n') {
+        if is_header_line(line) {
+            return &message[..line_start];
+        }
+        line_start += line.len() + 1;
+    }
+    // A header run into the junk before it, e.g. `}Content-Length: 2`
+    let lowercase = headers.to_ascii_lowercase();
+    let start = lowercase
+        .windows(b"content-length".len())
+        .position(|w| w == b"content-length")
+        .unwrap_or(header_end);
+    &message[..start]
+}
+
+/// Whether a line of headers is `Name: value`
+fn is_header_line(line: &[u8]) -> bool {
+    let Some(colon) = line.iter().position(|&b| b == b':') else {
+        return false;
+    };
+    let name = line[..colon].trim_ascii_end();
+    !name.is_empty()
+        && name
+            .iter()
+            .all(|&b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
 
-    fn parse_content_length(&self, headers: &str) -> Option<usize> {
+/// The headers of a message that we use
+struct Headers {
+    content_length: usize,
+    /// How the body is compressed, e.g. `gzip`
+    content_encoding: Option<String>,
+}
+
+impl Headers {
+    /// Header names are case-insensitive, and some clients pad them
+    /// with whitespace, e.g. `content-length :`. Returns `None` without
+    /// a valid Content-Length.
+    fn parse(headers: &str) -> Option<Self> {
+        let mut content_length = None;
+        let mut content_encoding = None;
         for line in headers.lines() {
-            if let Some(value) = line.strip_prefix("Content-Length:") {
-                return value.trim().parse().ok();
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("Content-Length") && content_length.is_none() {
+                content_length = Some(value.trim().parse().ok()?);
+            } else if name.eq_ignore_ascii_case("Content-Encoding") {
+                content_encoding = Some(value.trim().to_string());
+            }
+        }
+        Some(Self {
+            content_length: content_length?,
+            content_encoding,
+        })
+    }
+}
+
+/// Every header of a message as a JSON object, with names as sent,
+/// for `LSP_LOG_HEADERS`. Content-Length is a number if it parses. A
+/// header sent more than once has an array of its values.
+fn header_fields(message: &[u8]) -> serde_json::Value {
+    let end = message
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .unwrap_or(message.len());
+    let mut fields = serde_json::Map::new();
+    for line in String::from_utf8_lossy(&message[..end]).lines() {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let (name, value) = (name.trim(), value.trim());
+        let value = match value.parse::<u64>() {
+            Ok(length) if name.eq_ignore_ascii_case("Content-Length") => length.into(),
+            _ => serde_json::Value::from(value),
+        };
+        match fields.get_mut(name) {
+            Some(serde_json::Value::Array(values)) => values.push(value),
+            Some(previous) => *previous = serde_json::json!([previous.take(), value]),
+            None => {
+                fields.insert(name.to_string(), value);
+            }
+        }
+    }
+    serde_json::Value::Object(fields)
+}
+
+/// Details of a proxy session, written alongside the logs when the
+/// session ends
+struct SessionSummary {
+    server: String,
+    args: Vec<String>,
+    started: DateTime<Local>,
+    exit_status: Option<ExitStatus>,
+    /// What ended the session
+    shutdown_reason: Option<String>,
+    /// Absolute path of the server executable we ran
+    resolved_server: Option<PathBuf>,
+    clock: SharedClock,
+    /// How each server that was restarted with `LSP_RESPAWN` exited
+    crashes: Option<Vec<ExitStatus>>,
+    /// The working directory and environment we started the server
+    /// with, from `LSP_RECORD_ENV`
+    environment: Option<serde_json::Value>,
+}
+
+impl SessionSummary {
+    fn new(server: &str, args: &[String], clock: SharedClock) -> Self {
+        Self {
+            server: server.to_string(),
+            args: args.to_vec(),
+            started: clock.now(),
+            clock,
+            exit_status: None,
+            shutdown_reason: None,
+            resolved_server: None,
+            crashes: None,
+            environment: None,
+        }
+    }
+
+    /// Combine the session details with what was observed of the
+    /// traffic, from `Session::summary_json`
+    fn to_json(&self, session_summary: serde_json::Value) -> serde_json::Value {
+        let mut json = serde_json::json!({
+            "server": self.server,
+            "args": self.args,
+            "resolved_server": self.resolved_server,
+            "started": self.started.to_rfc3339(),
+            "ended": self.clock.now().to_rfc3339(),
+            "exit": self.exit_status.map(exit_status_json),
+            "shutdown_reason": self.shutdown_reason,
+            "crashes": self.crashes.as_ref().map(|crashes| {
+                crashes.iter().copied().map(exit_status_json).collect::<Vec<_>>()
+            }),
+        });
+        if let Some(environment) = &self.environment {
+            json["environment"] = environment.clone();
+        }
+        if let (Some(json), serde_json::Value::Object(session_summary)) =
+            (json.as_object_mut(), session_summary)
+        {
+            json.extend(session_summary);
+        }
+        json
+    }
+
+    /// The first line of a JSON Lines log when `LSP_HEADER` is set, so
+    /// consumers can tell what produced the capture
+    fn capture_header(&self) -> serde_json::Value {
+        serde_json::json!({
+            "_meta": {
+                "lspproxy_version": VERSION,
+                "format": "jsonl-v1",
+                "server": self.server,
+                "started": self.started.to_rfc3339(),
             }
+        })
+    }
+
+    async fn write(
+        &self,
+        path: &Path,
+        session_summary: serde_json::Value,
+        mode: LogMode,
+    ) -> Result<()> {
+        let json = serde_json::to_string_pretty(&self.to_json(session_summary))?;
+        write_log(path, (json + "\n").as_bytes(), mode).await?;
+        Ok(())
+    }
+}
+
+/// Describes how the LSP server terminated. On Unix this distinguishes
+/// a normal exit from being killed by a signal (e.g. a segfault).
+fn exit_status_json(status: ExitStatus) -> serde_json::Value {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        if let Some(signal) = status.signal() {
+            return serde_json::json!({
+                "reason": "signal",
+                "signal": signal,
+                "signal_name": signal_name(signal),
+                "core_dumped": status.core_dumped(),
+            });
         }
-        None
     }
+
+    serde_json::json!({
+        "reason": "exited",
+        "code": status.code(),
+    })
+}
+
+#[cfg(unix)]
+fn signal_name(signal: i32) -> Option<&'static str> {
+    let name = match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Asks the stream tasks to reopen their logs on each SIGHUP
+#[cfg(unix)]
+async fn reopen_on_sighup(reopen: watch::Sender<()>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(e) => {
+            eprintln!("Failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while sighup.recv().await.is_some() {
+        eprintln!("Received SIGHUP, reopening logs");
+        reopen.send_replace(());
+    }
+}
+
+/// Waits for a signal asking the proxy to terminate, returning its name
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        if let Ok(mut sigterm) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => return "SIGINT",
+                _ = sigterm.recv() => return "SIGTERM",
+            }
+        }
+    }
+
+    let _ = tokio::signal::ctrl_c().await;
+    "SIGINT"
+}
+
+async fn open_log(path: &Path, name: &str, mode: LogMode) -> Result<File> {
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    options.mode(mode.0);
+    #[cfg(not(unix))]
+    let _ = mode;
+    options
+        .open(path)
+        .await
+        .with_context(|| format!("Failed to create {} log file", name))
+}
+
+async fn create_log_dir(path: &Path, mode: LogMode) -> std::io::Result<()> {
+    let mut builder = tokio::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    builder.mode(mode.dir_mode());
+    #[cfg(not(unix))]
+    let _ = mode;
+    builder.create(path).await
+}
+
+/// The first wait before retrying to create the log directory
+const LOG_DIR_MIN_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Creates the log directory, retrying with backoff for up to `retry`
+/// if it fails, e.g. because another process is still setting up its
+/// parent
+async fn create_log_dir_with_retry(
+    path: &Path,
+    mode: LogMode,
+    retry: Option<Duration>,
+) -> std::io::Result<()> {
+    let deadline = retry.map(|retry| tokio::time::Instant::now() + retry);
+    let mut backoff = LOG_DIR_MIN_BACKOFF;
+    loop {
+        let e = match create_log_dir(path, mode).await {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        let Some(deadline) = deadline else {
+            return Err(e);
+        };
+        let now = tokio::time::Instant::now();
+        if now >= deadline {
+            return Err(e);
+        }
+        if backoff == LOG_DIR_MIN_BACKOFF {
+            eprintln!(
+                "Failed to create log directory {}, retrying: {}",
+                path.display(),
+                e
+            );
+        }
+        tokio::time::sleep(backoff.min(deadline - now)).await;
+        backoff *= 2;
+    }
+}
+
+/// Writes a whole log file at the end of a session, replacing any
+/// previous contents
+async fn write_log(path: &Path, contents: &[u8], mode: LogMode) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(mode.0);
+    #[cfg(not(unix))]
+    let _ = mode;
+    let mut file = options.open(path).await?;
+    file.write_all(contents).await?;
+    file.flush().await
+}
+
+/// Delete the files in `paths` that are smaller than `min_bytes`,
+/// returning how many were deleted. Files that don't exist are skipped.
+async fn remove_small_logs(paths: &[&PathBuf], min_bytes: u64) -> usize {
+    let mut removed = 0;
+    for path in paths {
+        let Ok(metadata) = tokio::fs::metadata(path).await else {
+            continue;
+        };
+        if !metadata.is_file() || metadata.len() >= min_bytes {
+            continue;
+        }
+        match tokio::fs::remove_file(path).await {
+            Ok(()) => removed += 1,
+            Err(e) => eprintln!("Failed to remove {}: {}", path.display(), e),
+        }
+    }
+    removed
+}
+
+/// Writes each reconstructed document to a file in `dir`, named after
+/// its URI
+async fn write_documents(dir: &Path, documents: &[(String, String)], mode: LogMode) -> Result<()> {
+    create_log_dir(dir, mode).await?;
+    for (uri, text) in documents {
+        let path = dir.join(document_state::file_name(uri));
+        write_log(&path, text.as_bytes(), mode)
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Where the stderr log is, so it can be reopened on SIGHUP
+struct ReopenStderr {
+    reopen: watch::Receiver<()>,
+    path: PathBuf,
+    mode: LogMode,
+}
+
+/// Copies the server's stderr to the stderr log and our own stderr,
+/// and to the editor as `window/logMessage` notifications if
+/// `log_messages` is set
+async fn log_stderr(
+    child_stderr: ChildStderr,
+    mut stderr_log: File,
+    log_messages: Option<tokio::sync::mpsc::UnboundedSender<serde_json::Value>>,
+    mut reopen: Option<ReopenStderr>,
+) {
+    let mut reader = BufReader::new(child_stderr);
+    // Read bytes rather than a String, as servers may write output that
+    // isn't valid UTF-8, and that shouldn't end the session.
+    let mut line = vec![];
+
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line).await {
+            Ok(0) => {
+                // EOF reached
+                break;
+            }
+            Ok(_) => {
+                // Reopened before writing a line, so lines aren't split
+                // across files.
+                if let Some(reopen) = reopen
+                    .as_mut()
+                    .filter(|reopen| reopen.reopen.has_changed().unwrap_or(false))
+                {
+                    reopen.reopen.mark_unchanged();
+                    match open_log(&reopen.path, "stderr", reopen.mode).await {
+                        Ok(file) => {
+                            let _ = stderr_log.flush().await;
+                            stderr_log = file;
+                            eprintln!("Reopened stderr log at {}", reopen.path.display());
+                        }
+                        Err(e) => eprintln!("Failed to reopen stderr log: {:#}", e),
+                    }
+                }
+                // Log to file, exactly as written
+                if !disk_full::logging_disabled() {
+                    if let Err(e) = stderr_log.write_all(&line).await {
+                        disk_full::log_write_failed("stderr log", &e);
+                    }
+                }
+
+                // Also print to proxy stderr for visibility. Lines are
+                // whole, so multibyte characters are never split. The
+                // last line may not end in a newline.
+                eprint!(
+                    "{} {}",
+                    paint("[LSP stderr]", Color::Dim),
+                    String::from_utf8_lossy(&line)
+                );
+                if !line.ends_with(b"\n") {
+                    eprintln!();
+                }
+
+                if let Some(log_messages) = &log_messages {
+                    let text = String::from_utf8_lossy(&line);
+                    let _ = log_messages.send(serde_json::json!({
+                        "jsonrpc": "2.0",
+                        "method": "window/logMessage",
+                        "params": {
+                            "type": LOG_MESSAGE_TYPE_LOG,
+                            "message": text.trim_end_matches(['\r', '\n']),
+                        },
+                    }));
+                }
+            }
+            Err(e) => {
+                eprintln!("Error reading from LSP server stderr: {}", e);
+                break;
+            }
+        }
+    }
+    // Writes finish in the background, so without this the last line
+    // may not be in the log yet when the session ends.
+    if let Err(e) = stderr_log.flush().await {
+        disk_full::log_write_failed("stderr log", &e);
+    }
+}
+
+/// The command that starts the server, run under `LSP_SERVER_WRAPPER`
+/// if it's set. The wrapper is passed the server and its arguments.
+fn server_command(wrapper: &[String], server_path: &Path, server_args: &[String]) -> Command {
+    let mut command = match wrapper.split_first() {
+        Some((program, wrapper_args)) => {
+            let mut command = Command::new(program);
+            command.args(wrapper_args).arg(server_path);
+            command
+        }
+        None => Command::new(server_path),
+    };
+    command.args(server_args);
+    command
+}
+
+/// Kills the server process, if we spawned it
+fn kill_server(child: &mut Option<Child>) {
+    if let Some(child) = child.as_mut() {
+        if let Err(e) = child.start_kill() {
+            eprintln!("Failed to kill LSP server: {}", e);
+        }
+    }
+}
+
+/// Asks the server to shut down and exit, killing it if it hasn't
+/// exited within `SERVER_EXIT_TIMEOUT`
+async fn stop_server(
+    inject_server: &tokio::sync::mpsc::UnboundedSender<serde_json::Value>,
+    child: &mut Option<Child>,
+) -> Option<ExitStatus> {
+    let shutdown = serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": SHUTDOWN_ID,
+        "method": "shutdown",
+    });
+    let exit = serde_json::json!({ "jsonrpc": "2.0", "method": "exit" });
+    let _ = inject_server.send(shutdown);
+    let _ = inject_server.send(exit);
+
+    match tokio::time::timeout(SERVER_EXIT_TIMEOUT, wait_for_server(child)).await {
+        Ok(status) => status.ok(),
+        Err(_) => {
+            eprintln!(
+                "LSP server didn't exit within {}s of shutdown, killing it",
+                SERVER_EXIT_TIMEOUT.as_secs()
+            );
+            kill_server(child);
+            None
+        }
+    }
+}
+
+/// Waits for the server process to exit, or forever if we didn't spawn
+/// it
+async fn wait_for_server(child: &mut Option<Child>) -> std::io::Result<ExitStatus> {
+    match child {
+        Some(child) => child.wait().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// For `LSP_WAIT_FOR_BOTH_EOF`, waits for both streams to end, so the
+/// capture holds the whole conversation. The maximum duration and
+/// signals still end the session, in which case this returns an error
+/// holding the server's exit status if it had to be stopped.
+async fn wait_for_both_eof(
+    stdin_task: &mut JoinHandle<StreamEnd>,
+    stdout_task: &mut JoinHandle<StreamEnd>,
+    deadline: Option<tokio::time::Instant>,
+    summary: &mut SessionSummary,
+    inject_server: &tokio::sync::mpsc::UnboundedSender<serde_json::Value>,
+    child: &mut Option<Child>,
+) -> Result<(), Option<ExitStatus>> {
+    let clock = summary.clock.clone();
+    let streams = async {
+        for task in [stdin_task, stdout_task] {
+            if !task.is_finished() {
+                let _ = task.await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = streams => {
+            eprintln!("Editor and LSP server have both closed their streams");
+            Ok(())
+        }
+        Some(()) = async { clock.sleep_until(deadline?).await; Some(()) } => {
+            eprintln!(
+                "Session reached LSP_MAX_DURATION_SECS waiting for both streams to close, shutting down the LSP server"
+            );
+            summary.shutdown_reason = Some("reached maximum duration".to_string());
+            Err(stop_server(inject_server, child).await)
+        }
+        signal = shutdown_signal() => {
+            eprintln!("Received {}, shutting down", signal);
+            summary.shutdown_reason = Some(format!("received {}", signal));
+            kill_server(child);
+            Err(None)
+        }
+    }
+}
+
+/// The `shutdown_reason` when both streams were closed with
+/// `LSP_WAIT_FOR_BOTH_EOF`
+const BOTH_STREAMS_CLOSED: &str = "editor and server closed their streams";
+
+type ServerInput = Box<dyn AsyncWrite + Unpin + Send>;
+type ServerOutput = Box<dyn AsyncRead + Unpin + Send>;
+
+/// Takes ownership of the pipes in `LSP_SERVER_FD_IN` and
+/// `LSP_SERVER_FD_OUT`, closing them when the session ends
+#[cfg(unix)]
+fn open_server_fds(fds: ServerFds) -> Result<(ServerInput, ServerOutput)> {
+    use std::os::fd::{FromRawFd, OwnedFd};
+    use tokio::net::unix::pipe;
+
+    for fd in [fds.input, fds.output] {
+        // SAFETY: F_GETFD only inspects the descriptor table.
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            anyhow::bail!("File descriptor {} is not open", fd);
+        }
+    }
+
+    // SAFETY: the descriptors are open, and the parent process handed
+    // them to us to own (see the README). They're only read from the
+    // environment once per proxy session.
+    let (input, output) = unsafe {
+        (
+            OwnedFd::from_raw_fd(fds.input),
+            OwnedFd::from_raw_fd(fds.output),
+        )
+    };
+
+    let input = pipe::Sender::from_owned_fd(input)
+        .with_context(|| format!("LSP_SERVER_FD_IN={} is not a writable pipe", fds.input))?;
+    let output = pipe::Receiver::from_owned_fd(output)
+        .with_context(|| format!("LSP_SERVER_FD_OUT={} is not a readable pipe", fds.output))?;
+    Ok((Box::new(input), Box::new(output)))
+}
+
+#[cfg(not(unix))]
+fn open_server_fds(_fds: ServerFds) -> Result<(ServerInput, ServerOutput)> {
+    anyhow::bail!("LSP_SERVER_FD_IN and LSP_SERVER_FD_OUT are only supported on Unix")
+}
+
+/// Our stdout, for writing to the editor. Rust's stdout is line
+/// buffered, which splits a message into a write for its headers and a
+/// write for its body, so `LSP_WRITE_WHOLE_MESSAGES` writes to a copy
+/// of the descriptor instead.
+fn editor_output(config: &ProxyConfig) -> Box<dyn AsyncWrite + Unpin + Send> {
+    #[cfg(unix)]
+    if config.write_whole_messages {
+        use std::os::fd::AsFd;
+
+        match std::io::stdout().as_fd().try_clone_to_owned() {
+            Ok(fd) => return Box::new(File::from_std(std::fs::File::from(fd))),
+            Err(e) => eprintln!("Failed to write whole messages to stdout: {}", e),
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = config;
+    Box::new(tokio::io::stdout())
 }
 
 async fn run_proxy(lsp_server: String, server_args: Vec<String>) -> Result<()> {
-    let log_dir = env::var("LSP_LOG_DIR").unwrap_or_else(|_| "/tmp/lsp-fiddle".to_string());
-    let log_dir = PathBuf::from(log_dir);
+    let config = ProxyConfig::from_env();
+    let editor_output = editor_output(&config);
+    let exit_status = run_session(
+        &lsp_server,
+        &server_args,
+        config,
+        tokio::io::stdin(),
+        editor_output,
+    )
+    .await?;
+
+    if let Some(exit_status) = exit_status {
+        std::process::exit(exit_status.code().unwrap_or(1));
+    }
+
+    Ok(())
+}
 
-    let json_lines = env::var("LSP_JSON_LINES")
-        .map(|v| v == "1" || v.to_lowercase() == "true")
-        .unwrap_or(false);
+/// Runs an LSP server, proxying messages between it and the editor
+/// until either side finishes. Returns the server's exit status if it
+/// exited during the session.
+async fn run_session<R, W>(
+    lsp_server: &str,
+    server_args: &[String],
+    config: ProxyConfig,
+    editor_input: R,
+    editor_output: W,
+) -> Result<Option<ExitStatus>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let log_dir = &config.log_dir;
 
     // Create log directory if it doesn't exist
-    tokio::fs::create_dir_all(&log_dir)
+    create_log_dir_with_retry(log_dir, config.log_mode, config.log_dir_retry)
         .await
         .context("Failed to create log directory")?;
 
     // Create log file paths with timestamp
-    let timestamp = Local::now().format("%Y_%m_%d_%H:%M:%S");
-    let suffix = if json_lines { "jsonl" } else { "log" };
+    let timestamp = config.clock.now().format("%Y_%m_%d_%H:%M:%S");
+    let suffix = config.log_format.file_extension();
     let stdin_log_path = log_dir.join(format!("{}_stdin.{}", timestamp, suffix));
     let stdout_log_path = log_dir.join(format!("{}_stdout.{}", timestamp, suffix));
     let stderr_log_path = log_dir.join(format!("{}_stderr.log", timestamp));
+    let summary_path = log_dir.join(format!("{}_summary.json", timestamp));
+    let stdin_index_path = log_dir.join(format!("{}_stdin.index.jsonl", timestamp));
+    let stdout_index_path = log_dir.join(format!("{}_stdout.index.jsonl", timestamp));
+    let binary_index_path = log_dir.join(format!("{}_index.bin", timestamp));
+    let lines_log_path = log_dir.join(format!("{}_lines.txt", timestamp));
+    let trace_path = log_dir.join(format!("{}_trace.json", timestamp));
+    let requests_csv_path = log_dir.join(format!("{}_requests.csv", timestamp));
+    let documents_path = log_dir.join(format!("{}_documents", timestamp));
+    let diagnostics_path = log_dir.join(format!("{}_diagnostics.jsonl", timestamp));
+
+    let tee = match &config.tee_addr {
+        Some(addr) if config.log_format.is_json() => {
+            Some(Tee::spawn(addr.clone(), config.tee_buffer_lines))
+        }
+        Some(_) => {
+            eprintln!("Ignoring LSP_TEE_ADDR: requires a JSON log format");
+            None
+        }
+        None => None,
+    };
+    let local_message_logs = !(tee.is_some() && config.tee_only);
+    #[cfg(unix)]
+    let log_fifo = match &config.log_fifo {
+        Some(path) if config.log_format.is_json() => Some(LogFifo::spawn(path.clone())),
+        Some(_) => {
+            eprintln!("Ignoring LSP_LOG_FIFO: requires a JSON log format");
+            None
+        }
+        None => None,
+    };
+    #[cfg(not(unix))]
+    if config.log_fifo.is_some() {
+        eprintln!("Ignoring LSP_LOG_FIFO: requires Unix");
+    }
+
+    if config.anonymize_text && !config.log_format.is_json() {
+        eprintln!("Ignoring LSP_ANONYMIZE_TEXT: requires a JSON log format");
+    }
+    if config.renumber_ids && !config.log_format.is_json() {
+        eprintln!("Ignoring LSP_RENUMBER_IDS: requires a JSON log format");
+    }
+    if config.metadata_only_bytes.is_some()
+        && (config.log_format.is_json() || config.split_by_method || config.filter.is_some())
+    {
+        eprintln!(
+            "Ignoring LSP_METADATA_ONLY_BYTES: requires raw logs, without LSP_SPLIT_BY_METHOD or LSP_FILTER_JQ"
+        );
+    }
+
+    if config.rotate.is_some() && !local_message_logs {
+        eprintln!("Ignoring LSP_ROTATE: requires local stdin and stdout logs");
+    }
+
+    let phase_markers =
+        (config.phase_markers || config.phase_fifo.is_some()) && config.log_format.is_json();
+    if !phase_markers && config.phase_markers {
+        eprintln!("Ignoring LSP_PHASE_MARKERS: requires a JSON log format");
+    }
+    if !phase_markers && config.phase_fifo.is_some() {
+        eprintln!("Ignoring LSP_PHASE_FIFO: requires a JSON log format");
+    }
+    let annotate = config.log_format.is_json();
+    if !annotate && config.annotation_fifo.is_some() {
+        eprintln!("Ignoring LSP_ANNOTATION_FIFO: requires a JSON log format");
+    }
+    if !annotate && config.annotation_file.is_some() {
+        eprintln!("Ignoring LSP_ANNOTATION_FILE: requires a JSON log format");
+    }
+    #[cfg(not(unix))]
+    if config.annotation_file.is_some() {
+        eprintln!("Ignoring LSP_ANNOTATION_FILE: SIGUSR1 is only available on Unix");
+    }
+
+    if config.flap_threshold.is_some() && config.respawn == 0 {
+        eprintln!("Ignoring LSP_FLAP_THRESHOLD: requires LSP_RESPAWN");
+    }
+
+    // Offsets in the binary index are into the raw logs, so they can't
+    // span rotated or reopened logs.
+    let binary_index = config.binary_index
+        && config.log_format == LogFormat::Raw
+        && local_message_logs
+        && config.rotate.is_none()
+        && !config.reopen_on_sighup;
+    if config.binary_index && !binary_index {
+        eprintln!("Ignoring LSP_BINARY_INDEX: requires raw, unrotated stdin and stdout logs");
+    }
+
+    if config.corrupt_framing.is_some() {
+        eprintln!(
+            "{} LSP_CORRUPT_FRAMING is set, the editor will receive messages with incorrect Content-Length headers",
+            paint("WARNING:", Color::Yellow)
+        );
+    }
 
     eprintln!("LSP Server: {} {:?}", lsp_server, server_args);
     eprintln!("Logging to:");
-    eprintln!("  stdin:  {}", stdin_log_path.display());
-    eprintln!("  stdout: {}", stdout_log_path.display());
+    if local_message_logs {
+        eprintln!("  stdin:  {}", stdin_log_path.display());
+        eprintln!("  stdout: {}", stdout_log_path.display());
+    }
+    if let Some(addr) = &config.tee_addr {
+        if tee.is_some() {
+            eprintln!("  collector: {}", addr);
+        }
+    }
+    #[cfg(unix)]
+    if let (Some(path), Some(_)) = (&config.log_fifo, &log_fifo) {
+        eprintln!("  fifo: {}", path.display());
+    }
+    if config.checksum {
+        eprintln!("  stdin index:  {}", stdin_index_path.display());
+        eprintln!("  stdout index: {}", stdout_index_path.display());
+    }
+    if binary_index {
+        eprintln!("  binary index: {}", binary_index_path.display());
+    }
+    if config.line_format.is_some() {
+        eprintln!("  lines: {}", lines_log_path.display());
+    }
+    if config.trace {
+        eprintln!("  trace: {}", trace_path.display());
+    }
+    if config.csv_summary {
+        eprintln!("  requests: {}", requests_csv_path.display());
+    }
+    if config.reconstruct_documents {
+        eprintln!("  documents: {}", documents_path.display());
+    }
+    if config.diagnostics_timeline {
+        eprintln!("  diagnostics: {}", diagnostics_path.display());
+    }
+    if config.syslog.is_some() {
+        eprintln!("  syslog");
+    }
+    if let Some(path) = &config.sqlite {
+        eprintln!("  sqlite: {}", path.display());
+    }
+    if config.split_by_method {
+        eprintln!(
+            "  methods: {}",
+            log_dir
+                .join(format!("{}_method-*.jsonl", timestamp))
+                .display()
+        );
+    }
     eprintln!("  stderr: {}", stderr_log_path.display());
+    eprintln!("  summary: {}", summary_path.display());
 
-    // Open log files
-    let stdin_log = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&stdin_log_path)
-        .await
-        .context("Failed to create stdin log file")?;
-
-    let stdout_log = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&stdout_log_path)
-        .await
-        .context("Failed to create stdout log file")?;
+    let mut summary = SessionSummary::new(lsp_server, server_args, config.clock.clone());
 
-    let stderr_log = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&stderr_log_path)
-        .await
-        .context("Failed to create stderr log file")?;
-
-    // Spawn the LSP server process
-    let mut child = Command::new(&lsp_server)
-        .args(&server_args)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .context("Failed to spawn LSP server")?;
+    // Open log files
+    let (mut stdin_log, mut stdout_log) = if local_message_logs {
+        (
+            Some(open_log(&stdin_log_path, "stdin", config.log_mode).await?),
+            Some(open_log(&stdout_log_path, "stdout", config.log_mode).await?),
+        )
+    } else {
+        (None, None)
+    };
+    let header = match config.log_format {
+        LogFormat::JsonLines if config.capture_header => {
+            Some(format!("{}\n", summary.capture_header()))
+        }
+        _ if config.capture_header => {
+            eprintln!("Ignoring LSP_HEADER: requires JSON Lines logging");
+            None
+        }
+        _ => None,
+    };
+    if let Some(header) = &header {
+        for log in [&mut stdin_log, &mut stdout_log].into_iter().flatten() {
+            log.write_all(header.as_bytes())
+                .await
+                .context("Failed to write capture header")?;
+        }
+    }
+    let [stdin_rotation, stdout_rotation] = [
+        (Direction::ToServer, &stdin_log_path),
+        (Direction::ToEditor, &stdout_log_path),
+    ]
+    .map(|(direction, path)| {
+        if !local_message_logs || (config.rotate.is_none() && !config.reopen_on_sighup) {
+            return None;
+        }
+        Some(LogRotation::new(
+            format!("{}_{}", timestamp, direction.stream_name()),
+            suffix,
+            header.clone(),
+            path.clone(),
+            &config,
+        ))
+    });
+    let (stdin_index, stdout_index) = if config.checksum {
+        (
+            Some(open_log(&stdin_index_path, "stdin index", config.log_mode).await?),
+            Some(open_log(&stdout_index_path, "stdout index", config.log_mode).await?),
+        )
+    } else {
+        (None, None)
+    };
+    let (sqlite, sqlite_writer) = match &config.sqlite {
+        Some(path) => match SqliteLog::open(
+            path,
+            lsp_server,
+            server_args,
+            &summary_path,
+            config.log_mode,
+            config.clock.now(),
+        ) {
+            Ok((log, writer)) => (Some(log), Some(writer)),
+            Err(e) => {
+                eprintln!("Ignoring LSP_SQLITE: {:#}", e);
+                (None, None)
+            }
+        },
+        None => (None, None),
+    };
+    let shared_logs = SharedLogs {
+        binary_index: if binary_index {
+            let index = BinaryIndex::create(&binary_index_path, config.log_mode)?;
+            Some(Arc::new(Mutex::new(index)))
+        } else {
+            None
+        },
+        method_logs: config.split_by_method.then(|| {
+            let logs = MethodLogs::new(log_dir.clone(), timestamp.to_string(), config.log_mode);
+            Arc::new(tokio::sync::Mutex::new(logs))
+        }),
+        line_log: match &config.line_format {
+            Some(format) => {
+                let file = open_log(&lines_log_path, "lines", config.log_mode).await?;
+                Some(Arc::new(tokio::sync::Mutex::new(LineLog::new(
+                    format.clone(),
+                    file,
+                ))))
+            }
+            None => None,
+        },
+        syslog: match config.syslog.map(syslog::Syslog::open) {
+            Some(Ok(syslog)) => Some(Arc::new(syslog)),
+            Some(Err(e)) => {
+                eprintln!("Ignoring LSP_SYSLOG: {:#}", e);
+                None
+            }
+            None => None,
+        },
+        sqlite,
+        window: (config.log_after_method.is_some() || config.log_until_method.is_some()).then(
+            || {
+                Arc::new(LogWindow::new(
+                    config.log_after_method.clone(),
+                    config.log_until_method.clone(),
+                ))
+            },
+        ),
+        rate_limit: config.log_max_bytes_per_sec.map(|bytes_per_sec| {
+            Arc::new(Mutex::new(LogRateLimit::new(
+                bytes_per_sec,
+                config.clock.instant(),
+            )))
+        }),
+        renumber_ids: (config.renumber_ids && config.log_format.is_json())
+            .then(SharedIdRenumbering::default),
+        #[cfg(unix)]
+        fifo: log_fifo.as_ref().map(|fifo| fifo.sender()),
+        #[cfg(not(unix))]
+        fifo: None,
+    };
+    let mut stderr_log = Some(open_log(&stderr_log_path, "stderr", config.log_mode).await?);
+    let (shutdown_sender, shutdown) = watch::channel(false);
+    let (inject_server, inject_server_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let (inject_editor, inject_editor_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let replies = (config.reject_missing_capabilities && !config.require_capabilities.is_empty())
+        .then(|| inject_editor.clone());
+    if config.reject_missing_capabilities && config.require_capabilities.is_empty() {
+        eprintln!("Ignoring LSP_REJECT_MISSING_CAPABILITIES: requires LSP_REQUIRE_CAPABILITIES");
+    }
+    let log_messages = config.stderr_as_log_message.then_some(inject_editor);
+    let (reopen_sender, reopen) = watch::channel(());
+    #[cfg(unix)]
+    let reopen_task = config
+        .reopen_on_sighup
+        .then(|| tokio::spawn(reopen_on_sighup(reopen_sender)));
+    #[cfg(not(unix))]
+    if config.reopen_on_sighup {
+        drop(reopen_sender);
+        eprintln!("Ignoring LSP_REOPEN_ON_SIGHUP: requires Unix");
+    }
 
-    let mut child_stdin = child.stdin.take().context("Failed to open child stdin")?;
-    let child_stdout = child.stdout.take().context("Failed to open child stdout")?;
-    let child_stderr = child.stderr.take().context("Failed to open child stderr")?;
+    if config.respawn > 0
+        && (config.server_addr.is_some()
+            || config.server_fds.is_some()
+            || config.shared_server.is_some())
+    {
+        eprintln!("Ignoring LSP_RESPAWN: requires a server started by lsp-fiddle");
+    }
 
-    let mut proxy_stdin = tokio::io::stdin();
-    let mut proxy_stdout = tokio::io::stdout();
+    let session_id = timestamp.to_string();
+    hooks::run_hook(
+        "LSP_PRE_HOOK",
+        &config.pre_hook,
+        log_dir,
+        &session_id,
+        &summary_path,
+    )
+    .await;
 
-    let json_lines_mode = json_lines;
+    // Spawn the LSP server process, unless we've been given a remote
+    // server or pipes to a server that someone else is managing
+    let server_started = config.clock.instant();
+    let mut supervisor = None;
+    let server_status = SharedServerStatus::default();
+    let (mut child, child_stdin, child_stdout, child_stderr): (
+        Option<Child>,
+        ServerInput,
+        ServerOutput,
+        Option<ChildStderr>,
+    ) = match (
+        &config.shared_server,
+        &config.server_addr,
+        config.server_fds,
+    ) {
+        (Some(shared_server), _, _) => {
+            let (input, output) = shared_server.connect()?;
+            (None, input, output, None)
+        }
+        (None, Some(addr), _) => {
+            let (input, output) = connect::connect_to_server(addr, &config).await?;
+            (None, input, output, None)
+        }
+        (None, None, Some(fds)) => {
+            let (input, output) = open_server_fds(fds)?;
+            (None, input, output, None)
+        }
+        (None, None, None) => {
+            let server_path = resolve::resolve_server(lsp_server, &config.server_fallbacks)?;
+            // Servers launched with `--socket` or `--pipe` connect back
+            // to us rather than using stdio.
+            let listener = ServerTransport::from_args(server_args)?.listen().await?;
+            let stdio = || match listener {
+                Some(_) => Stdio::null(),
+                None => Stdio::piped(),
+            };
+            if config.respawn > 0 && listener.is_some() {
+                eprintln!("Ignoring LSP_RESPAWN: requires a server using stdio");
+            }
 
-    // Task 1: Proxy stdin from editor to LSP server (with logging)
-    let stdin_task = tokio::spawn(async move {
-        let mut stdin_log = stdin_log;
-        let mut buffer = vec![0u8; 8192];
-        let mut parser = LspMessageParser::new();
+            if config.server_wrapper.is_empty() {
+                eprintln!("Starting {}", server_path.display());
+            } else {
+                eprintln!(
+                    "Starting {} under {}",
+                    server_path.display(),
+                    config.server_wrapper.join(" ")
+                );
+            }
+            let mut child = server_command(&config.server_wrapper, &server_path, server_args)
+                .stdin(stdio())
+                .stdout(stdio())
+                .stderr(Stdio::piped())
+                .spawn()
+                .context("Failed to spawn LSP server")?;
+            server_status.lock().unwrap().started(child.id());
 
-        loop {
-            match proxy_stdin.read(&mut buffer).await {
-                Ok(0) => {
-                    // EOF reached
-                    break;
-                }
-                Ok(n) => {
-                    let data = &buffer[..n];
-
-                    if json_lines_mode {
-                        // Parse LSP messages and log as JSON lines
-                        parser.add_data(data);
-
-                        while let Some((_, json_payload)) = parser.try_parse_message() {
-                            // Validate and potentially pretty-print the JSON
-                            match serde_json::from_str::<serde_json::Value>(&json_payload) {
-                                Ok(value) => {
-                                    // Write as compact JSON line
-                                    if let Ok(compact) = serde_json::to_string(&value) {
-                                        let line = format!("{}\n", compact);
-                                        if let Err(e) = stdin_log.write_all(line.as_bytes()).await {
-                                            eprintln!("Failed to write to stdin log: {}", e);
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    eprintln!("Failed to parse JSON from stdin: {}", e);
-                                    // Log the raw payload as fallback
-                                    let line = format!("{}\n", json_payload);
-                                    if let Err(e) = stdin_log.write_all(line.as_bytes()).await {
-                                        eprintln!("Failed to write to stdin log: {}", e);
-                                    }
-                                }
-                            }
-                        }
-                    } else {
-                        // Log raw bytes
-                        if let Err(e) = stdin_log.write_all(data).await {
-                            eprintln!("Failed to write to stdin log: {}", e);
-                        }
-                    }
+            summary.resolved_server = Some(server_path.clone());
+            summary.environment = record_env::launch_environment(&config);
 
-                    // Forward to LSP server
-                    if let Err(e) = child_stdin.write_all(data).await {
-                        eprintln!("Failed to write to LSP server stdin: {}", e);
-                        break;
-                    }
+            match (&listener, stderr_log.take()) {
+                (None, Some(stderr_log)) if config.respawn > 0 => {
+                    // The supervisor owns the server, so it can replace
+                    // it, and the proxy talks to the supervisor.
+                    let (proxy, supervised) = tokio::io::duplex(64 * 1024);
+                    let respawn = Respawn {
+                        server_path,
+                        server_args: server_args.to_vec(),
+                        stderr_log_path: stderr_log_path.clone(),
+                        max_restarts: config.respawn,
+                        log_mode: config.log_mode,
+                        log_messages: log_messages.clone(),
+                        flap_limit: config.flap_threshold.map(|crashes| FlapLimit {
+                            crashes,
+                            window: config.flap_window,
+                        }),
+                        server_status: server_status.clone(),
+                        server_wrapper: config.server_wrapper.clone(),
+                        clock: config.clock.clone(),
+                    };
+                    supervisor = Some(tokio::spawn(respawn::supervise(
+                        respawn,
+                        child,
+                        stderr_log,
+                        supervised,
+                        shutdown.clone(),
+                    )));
+                    summary.crashes = Some(vec![]);
 
-                    // Flush to ensure data is sent
-                    if let Err(e) = child_stdin.flush().await {
-                        eprintln!("Failed to flush LSP server stdin: {}", e);
-                        break;
-                    }
+                    let (output, input) = tokio::io::split(proxy);
+                    (None, Box::new(input), Box::new(output), None)
                 }
-                Err(e) => {
-                    eprintln!("Error reading from proxy stdin: {}", e);
-                    break;
+                (_, unused_log) => {
+                    stderr_log = unused_log;
+                    let (server_input, server_output): (ServerInput, ServerOutput) = match &listener
+                    {
+                        Some(listener) => transport::accept_server(listener, &mut child).await?,
+                        None => (
+                            Box::new(child.stdin.take().context("Failed to open child stdin")?),
+                            Box::new(child.stdout.take().context("Failed to open child stdout")?),
+                        ),
+                    };
+                    let child_stderr =
+                        child.stderr.take().context("Failed to open child stderr")?;
+                    (Some(child), server_input, server_output, Some(child_stderr))
                 }
             }
         }
+    };
+
+    let config = Arc::new(config);
+
+    let previous_options = init_options::previous_options(&config.log_dir, &summary_path);
+    let (stdin_marker_sender, stdin_markers) = mpsc::unbounded_channel();
+    let (stdout_marker_sender, stdout_markers) = mpsc::unbounded_channel();
+    let annotations = Annotations::new(
+        config.clock.clone(),
+        vec![stdin_marker_sender.clone(), stdout_marker_sender.clone()],
+    );
+    let phase_markers = phase_markers.then(|| {
+        PhaseMarkers::new(
+            config.phase_markers,
+            vec![stdin_marker_sender, stdout_marker_sender],
+        )
     });
+    let session: SharedSession = Arc::new(Mutex::new(Session::new(
+        &config,
+        previous_options,
+        server_started,
+        phase_markers,
+    )));
+    let phase_fifo_task = config
+        .phase_fifo
+        .clone()
+        .filter(|_| config.log_format.is_json())
+        .map(|path| tokio::spawn(read_phase_fifo(path, session.clone())));
+    let mut annotation_tasks = vec![];
+    if annotate {
+        if let Some(path) = config.annotation_fifo.clone() {
+            annotation_tasks.push(tokio::spawn(read_annotation_fifo(
+                path,
+                annotations.clone(),
+            )));
+        }
+        #[cfg(unix)]
+        if let Some(path) = config.annotation_file.clone() {
+            annotation_tasks.push(tokio::spawn(annotate_on_sigusr1(path, annotations.clone())));
+        }
+    }
+    drop(annotations);
+    let status_task = config
+        .status_addr
+        .clone()
+        .map(|addr| tokio::spawn(serve_status(addr, session.clone(), server_status.clone())));
+    let initialize_watcher =
+        tokio::spawn(watch_initialize(session.clone(), config.initialize_timeout));
+
+    let tee_sender = tee.as_ref().map(|tee| tee.sender());
+
+    // Task 1: Proxy stdin from editor to LSP server (with logging)
+    let mut stdin_task = tokio::spawn(proxy_stream(
+        Direction::ToServer,
+        editor_input,
+        child_stdin,
+        StreamLog::new(
+            Direction::ToServer,
+            stdin_log,
+            stdin_index,
+            shared_logs.clone(),
+            tee_sender.clone(),
+            stdin_rotation,
+            &config,
+        ),
+        config.clone(),
+        session.clone(),
+        StreamControl {
+            shutdown: shutdown.clone(),
+            inject: inject_server_receiver,
+            reopen: reopen.clone(),
+            markers: stdin_markers,
+            replies,
+        },
+    ));
 
     // Task 2: Proxy stdout from LSP server to editor (with logging)
-    let stdout_task = tokio::spawn(async move {
-        let mut stdout_log = stdout_log;
-        let mut buffer = vec![0u8; 8192];
-        let mut child_stdout = child_stdout;
-        let mut parser = LspMessageParser::new();
+    let mut stdout_task = tokio::spawn(proxy_stream(
+        Direction::ToEditor,
+        child_stdout,
+        editor_output,
+        StreamLog::new(
+            Direction::ToEditor,
+            stdout_log,
+            stdout_index,
+            shared_logs,
+            tee_sender,
+            stdout_rotation,
+            &config,
+        ),
+        config.clone(),
+        session.clone(),
+        StreamControl {
+            shutdown,
+            inject: inject_editor_receiver,
+            reopen: reopen.clone(),
+            markers: stdout_markers,
+            replies: None,
+        },
+    ));
 
-        loop {
-            match child_stdout.read(&mut buffer).await {
-                Ok(0) => {
-                    // EOF reached
-                    break;
-                }
-                Ok(n) => {
-                    let data = &buffer[..n];
-
-                    if json_lines_mode {
-                        // Parse LSP messages and log as JSON lines
-                        parser.add_data(data);
-
-                        while let Some((_, json_payload)) = parser.try_parse_message() {
-                            // Validate and potentially pretty-print the JSON
-                            match serde_json::from_str::<serde_json::Value>(&json_payload) {
-                                Ok(value) => {
-                                    // Write as compact JSON line
-                                    if let Ok(compact) = serde_json::to_string(&value) {
-                                        let line = format!("{}\n", compact);
-                                        if let Err(e) = stdout_log.write_all(line.as_bytes()).await
-                                        {
-                                            eprintln!("Failed to write to stdout log: {}", e);
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    eprintln!("Failed to parse JSON from stdout: {}", e);
-                                    // Log the raw payload as fallback
-                                    let line = format!("{}\n", json_payload);
-                                    if let Err(e) = stdout_log.write_all(line.as_bytes()).await {
-                                        eprintln!("Failed to write to stdout log: {}", e);
-                                    }
-                                }
-                            }
-                        }
-                    } else {
-                        // Log raw bytes
-                        if let Err(e) = stdout_log.write_all(data).await {
-                            eprintln!("Failed to write to stdout log: {}", e);
-                        }
-                    }
+    // Task 3: Log stderr from LSP server
+    let stderr_task = child_stderr
+        .zip(stderr_log)
+        .map(|(child_stderr, stderr_log)| {
+            let reopen = ReopenStderr {
+                reopen,
+                path: stderr_log_path.clone(),
+                mode: config.log_mode,
+            };
+            tokio::spawn(log_stderr(
+                child_stderr,
+                stderr_log,
+                log_messages,
+                Some(reopen),
+            ))
+        });
 
-                    // Forward to proxy stdout
-                    if let Err(e) = proxy_stdout.write_all(data).await {
-                        eprintln!("Failed to write to proxy stdout: {}", e);
-                        break;
-                    }
+    let deadline = config
+        .max_duration
+        .map(|max_duration| config.clock.instant() + max_duration);
 
-                    // Flush to ensure data is sent
-                    if let Err(e) = proxy_stdout.flush().await {
-                        eprintln!("Failed to flush proxy stdout: {}", e);
-                        break;
+    // Wait for any task to complete or the child process to exit
+    let exit_status = tokio::select! {
+        _ = &mut stdin_task => {
+            eprintln!("Stdin task completed");
+            summary.shutdown_reason = Some("editor closed stdin".to_string());
+            if config.wait_for_both_eof {
+                match wait_for_both_eof(
+                    &mut stdin_task,
+                    &mut stdout_task,
+                    deadline,
+                    &mut summary,
+                    &inject_server,
+                    &mut child,
+                )
+                .await {
+                    Ok(()) => {
+                        summary.shutdown_reason = Some(BOTH_STREAMS_CLOSED.to_string());
+                        None
                     }
+                    Err(exit_status) => exit_status,
                 }
-                Err(e) => {
-                    eprintln!("Error reading from LSP server stdout: {}", e);
-                    break;
+            } else {
+                // The server's stdin is closed now, but it may still be
+                // answering the last requests, so let it finish writing.
+                if tokio::time::timeout(config.stdout_drain_timeout, &mut stdout_task)
+                    .await
+                    .is_err()
+                {
+                    eprintln!(
+                        "LSP server didn't close stdout within {}ms of stdin closing",
+                        config.stdout_drain_timeout.as_millis()
+                    );
                 }
+                None
             }
         }
-    });
-
-    // Task 3: Log stderr from LSP server
-    let stderr_task = tokio::spawn(async move {
-        let mut stderr_log = stderr_log;
-        let mut reader = BufReader::new(child_stderr);
-        let mut line = String::new();
-
-        loop {
-            line.clear();
-            match reader.read_line(&mut line).await {
-                Ok(0) => {
-                    // EOF reached
-                    break;
-                }
-                Ok(_) => {
-                    // Log to file
-                    if let Err(e) = stderr_log.write_all(line.as_bytes()).await {
-                        eprintln!("Failed to write to stderr log: {}", e);
+        end = &mut stdout_task => {
+            if let Ok(StreamEnd::EditorDisconnected) = end {
+                // Nobody is listening to the server any more.
+                eprintln!("Editor disconnected, stopping LSP server");
+                summary.shutdown_reason = Some("editor disconnected".to_string());
+                kill_server(&mut child);
+                None
+            } else if let Ok(StreamEnd::EditorStuck) = end {
+                eprintln!("Editor stopped reading, stopping LSP server");
+                summary.shutdown_reason = Some("editor stopped reading".to_string());
+                kill_server(&mut child);
+                None
+            } else {
+                eprintln!("Stdout task completed");
+                summary.shutdown_reason = Some("server closed stdout".to_string());
+                if config.wait_for_both_eof {
+                    // The editor may still be sending, e.g. `exit`.
+                    match wait_for_both_eof(
+                        &mut stdin_task,
+                        &mut stdout_task,
+                        deadline,
+                        &mut summary,
+                        &inject_server,
+                        &mut child,
+                    )
+                    .await {
+                        Ok(()) => {
+                            summary.shutdown_reason = Some(BOTH_STREAMS_CLOSED.to_string());
+                            None
+                        }
+                        Err(exit_status) => exit_status,
                     }
-
-                    // Also print to proxy stderr for visibility
-                    eprint!("[LSP stderr] {}", line);
+                } else {
+                    None
                 }
-                Err(e) => {
-                    eprintln!("Error reading from LSP server stderr: {}", e);
-                    break;
+            }
+        }
+        Some(_) = async { Some(stderr_task?.await) } => {
+            eprintln!("Stderr task completed");
+            summary.shutdown_reason = Some("server closed stderr".to_string());
+            if config.wait_for_both_eof {
+                match wait_for_both_eof(
+                    &mut stdin_task,
+                    &mut stdout_task,
+                    deadline,
+                    &mut summary,
+                    &inject_server,
+                    &mut child,
+                )
+                .await {
+                    Ok(()) => {
+                        summary.shutdown_reason = Some(BOTH_STREAMS_CLOSED.to_string());
+                        None
+                    }
+                    Err(exit_status) => exit_status,
                 }
+            } else {
+                // The server is probably exiting, but may not have
+                // closed stdout yet, so read what it wrote.
+                let _ = tokio::time::timeout(config.stdout_drain_timeout, &mut stdout_task).await;
+                None
             }
         }
-    });
-
-    // Wait for any task to complete or the child process to exit
-    tokio::select! {
-        _ = stdin_task => {
-            eprintln!("Stdin task completed");
+        Some(()) = async { config.clock.sleep_until(deadline?).await; Some(()) } => {
+            eprintln!(
+                "Session reached LSP_MAX_DURATION_SECS ({}s), shutting down the LSP server",
+                config.max_duration.unwrap_or_default().as_secs()
+            );
+            summary.shutdown_reason = Some("reached maximum duration".to_string());
+            stop_server(&inject_server, &mut child).await
         }
-        _ = stdout_task => {
-            eprintln!("Stdout task completed");
+        signal = shutdown_signal() => {
+            eprintln!("Received {}, shutting down", signal);
+            summary.shutdown_reason = Some(format!("received {}", signal));
+            kill_server(&mut child);
+            None
         }
-        _ = stderr_task => {
-            eprintln!("Stderr task completed");
+        Some(supervised) = async { Some(supervisor.as_mut()?.await) } => {
+            supervisor = None;
+            summary.shutdown_reason = Some("server exited".to_string());
+            let supervised = match supervised {
+                Ok(supervised) => supervised,
+                Err(e) => return Err(e).context("LSP server supervisor failed"),
+            };
+            if supervised.flapping {
+                summary.shutdown_reason = Some("server kept crashing".to_string());
+            }
+            summary.crashes = Some(supervised.crashes);
+            // Forward what the supervisor sent last, such as the error
+            // telling the editor why the server stopped.
+            let _ = tokio::time::timeout(config.stdout_drain_timeout, &mut stdout_task).await;
+            supervised.exit_status
         }
-        status = child.wait() => {
-            match status {
-                Ok(exit_status) => {
-                    eprintln!("LSP server exited with status: {}", exit_status);
-                    std::process::exit(exit_status.code().unwrap_or(1));
-                }
-                Err(e) => {
-                    eprintln!("Failed to wait for LSP server: {}", e);
-                    std::process::exit(1);
-                }
+        status = wait_for_server(&mut child) => {
+            summary.shutdown_reason = Some("server exited".to_string());
+            let exit_status = match status {
+                Ok(exit_status) => exit_status,
+                Err(e) => return Err(e).context("Failed to wait for LSP server"),
+            };
+            // Forward and log what the server wrote before it exited,
+            // rather than ending the stream with output unread.
+            if config.wait_for_both_eof {
+                // Its stdout has closed, but the editor may still send
+                // more before it notices.
+                let _ = wait_for_both_eof(
+                    &mut stdin_task,
+                    &mut stdout_task,
+                    deadline,
+                    &mut summary,
+                    &inject_server,
+                    &mut child,
+                )
+                .await;
+            } else {
+                let _ = tokio::time::timeout(config.stdout_drain_timeout, &mut stdout_task).await;
             }
+            Some(exit_status)
         }
+    };
+
+    // The server closing its output streams usually means it has
+    // exited, so give it a moment to be reaped.
+    let exit_status = match exit_status {
+        Some(exit_status) => Some(exit_status),
+        None => tokio::time::timeout(Duration::from_millis(100), wait_for_server(&mut child))
+            .await
+            .ok()
+            .and_then(|status| status.ok()),
+    };
+    if let Some(exit_status) = exit_status {
+        server_status.lock().unwrap().exited(exit_status);
     }
 
-    Ok(())
+    // Let the stream tasks write any buffered entries and close their
+    // logs.
+    let _ = shutdown_sender.send(true);
+    #[cfg(unix)]
+    if let Some(reopen_task) = reopen_task {
+        reopen_task.abort();
+    }
+    for task in [stdin_task, stdout_task] {
+        if !task.is_finished() {
+            let _ = tokio::time::timeout(STREAM_SHUTDOWN_TIMEOUT, task).await;
+        }
+    }
+    if let (Some(bytes), Some(status)) = (
+        session
+            .lock()
+            .unwrap()
+            .truncated_message(Direction::ToEditor),
+        exit_status,
+    ) {
+        if !status.success() {
+            eprintln!(
+                "LSP server crashed {} bytes into a message, which is logged as truncated",
+                bytes
+            );
+            summary.shutdown_reason = Some("server crashed part way through a message".to_string());
+        }
+    }
+
+    let exit_status = match supervisor {
+        Some(supervisor) => match supervisor.await {
+            Ok(supervised) => {
+                summary.crashes = Some(supervised.crashes);
+                supervised.exit_status
+            }
+            Err(_) => exit_status,
+        },
+        None => exit_status,
+    };
+
+    initialize_watcher.abort();
+    if let Some(phase_fifo_task) = phase_fifo_task {
+        phase_fifo_task.abort();
+    }
+    for task in annotation_tasks {
+        task.abort();
+    }
+    if let Some(status_task) = status_task {
+        status_task.abort();
+    }
+
+    if let Some(tee) = tee {
+        tee.finish().await;
+    }
+    #[cfg(unix)]
+    if let Some(log_fifo) = log_fifo {
+        log_fifo.finish().await;
+    }
+    if let Some(sqlite_writer) = sqlite_writer {
+        sqlite_writer.finish(config.clock.now(), exit_status).await;
+    }
+
+    summary.exit_status = exit_status;
+    let session_summary = session.lock().unwrap().summary_json();
+    if let Err(e) = summary
+        .write(&summary_path, session_summary, config.log_mode)
+        .await
+    {
+        eprintln!("Failed to write session summary: {}", e);
+    }
+    let trace = session.lock().unwrap().trace_json();
+    if let Some(trace) = trace {
+        if let Err(e) = write_log(&trace_path, trace.to_string().as_bytes(), config.log_mode).await
+        {
+            eprintln!("Failed to write trace: {}", e);
+        }
+    }
+    let requests_csv = session.lock().unwrap().requests_csv();
+    if let Some(requests_csv) = requests_csv {
+        if let Err(e) =
+            write_log(&requests_csv_path, requests_csv.as_bytes(), config.log_mode).await
+        {
+            eprintln!("Failed to write requests CSV: {}", e);
+        }
+    }
+    let documents = session.lock().unwrap().reconstructed_documents();
+    if let Some(documents) = documents {
+        if let Err(e) = write_documents(&documents_path, &documents, config.log_mode).await {
+            eprintln!("Failed to write reconstructed documents: {}", e);
+        }
+    }
+    let diagnostics = session.lock().unwrap().diagnostics_timeline();
+    if let Some(diagnostics) = diagnostics {
+        if let Err(e) = write_log(&diagnostics_path, diagnostics.as_bytes(), config.log_mode).await
+        {
+            eprintln!("Failed to write diagnostics timeline: {}", e);
+        }
+    }
+
+    if let Some(min_bytes) = config.min_log_bytes {
+        let mut paths = vec![
+            &stdin_log_path,
+            &stdout_log_path,
+            &stdin_index_path,
+            &stdout_index_path,
+            &lines_log_path,
+        ];
+        // The stderr log may explain why the server failed, and may
+        // still be being written to.
+        if exit_status.is_some_and(|status| status.success()) {
+            paths.push(&stderr_log_path);
+        }
+        let removed = remove_small_logs(&paths, min_bytes).await;
+        if removed > 0 {
+            eprintln!(
+                "Removed {} log files smaller than {} bytes (LSP_MIN_LOG_BYTES)",
+                removed, min_bytes
+            );
+        }
+    }
+
+    // Run after everything is written, so the hook can e.g. compress
+    // or upload the whole capture.
+    hooks::run_hook(
+        "LSP_POST_HOOK",
+        &config.post_hook,
+        &config.log_dir,
+        &session_id,
+        &summary_path,
+    )
+    .await;
+
+    if let Some(exit_status) = exit_status {
+        eprintln!("LSP server exited with status: {}", exit_status);
+    }
+
+    Ok(exit_status)
+}
+
+/// Parses the command line, reading the server for `LSP_LANGUAGE` from
+/// its own variable rather than `LSP_SERVER`
+fn parse_cli() -> Cli {
+    let mut command = Cli::command();
+    if let Some(variable) = resolve::select_language_server() {
+        // Argument settings must live as long as the command, which is
+        // the whole run.
+        let variable: &'static str = Box::leak(variable.into_boxed_str());
+        let subcommands: Vec<String> = command
+            .get_subcommands()
+            .filter(|subcommand| {
+                subcommand
+                    .get_arguments()
+                    .any(|arg| arg.get_id() == "lsp_server")
+            })
+            .map(|subcommand| subcommand.get_name().to_string())
+            .collect();
+        for name in subcommands {
+            command = command.mut_subcommand(name, |subcommand| {
+                // mut_arg would move the server after the server's
+                // arguments, which clap rejects.
+                subcommand.mut_args(|arg| {
+                    if arg.get_id() == "lsp_server" {
+                        arg.env(variable)
+                    } else {
+                        arg
+                    }
+                })
+            });
+        }
+    }
+    Cli::from_arg_matches(&command.get_matches()).unwrap_or_else(|e| e.exit())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let cli = Cli::parse();
+    let cli = parse_cli();
+    if let Some(path) = &cli.initialize_from {
+        initialize_from::load(path)?;
+    }
 
     match cli.command {
         Commands::Proxy {
+            repl: true,
+            lsp_server,
+            lsp_args,
+        } => {
+            repl::run_repl(lsp_server, lsp_args).await?;
+        }
+        Commands::Proxy {
+            repl: false,
             lsp_server,
             lsp_args,
         } => {
             run_proxy(lsp_server, lsp_args).await?;
         }
+        Commands::Listen {
+            addr,
+            shared_server,
+            lsp_server,
+            lsp_args,
+        } => {
+            listen::run_listener(&addr, shared_server, lsp_server, lsp_args).await?;
+        }
+        Commands::Connect { addr } => {
+            connect::run_connect(addr).await?;
+        }
+        Commands::HttpBridge {
+            addr,
+            lsp_server,
+            lsp_args,
+        } => {
+            http_bridge::run_http_bridge(&addr, lsp_server, lsp_args).await?;
+        }
         Commands::Minimal => {
             print_minimal_session();
         }
+        Commands::Selftest { messages, seed } => {
+            selftest::run_selftest(messages, seed).await?;
+        }
+        Commands::EchoServer => {
+            selftest::run_echo_server().await?;
+        }
+        Commands::Repair { file } => {
+            repair::repair_json_array(&file)?;
+        }
+        Commands::Query { index, method } => {
+            index::query(&index, method.as_deref())?;
+        }
+        Commands::Convert {
+            from,
+            to,
+            input,
+            output,
+        } => {
+            capture::convert(&input, &output, from, to)?;
+        }
+        Commands::Merge {
+            stdin,
+            stdout,
+            output,
+        } => {
+            merge::merge(&stdin, &stdout, &output)?;
+        }
+        Commands::ImportRaw { input, output } => {
+            import_raw::import_raw(&input, &output)?;
+        }
+        Commands::SplitByUri { files, output } => {
+            split_by_uri::split_by_uri(&files, &output)?;
+        }
+        Commands::Count { files } => {
+            count::count(&files)?;
+        }
+        Commands::Validate { files } => {
+            validate::validate(&files)?;
+        }
+        Commands::Replay {
+            range,
+            timeout,
+            capture,
+            lsp_server,
+            lsp_args,
+        } => {
+            replay::run_replay(
+                &capture,
+                range,
+                Duration::from_secs(timeout),
+                &lsp_server,
+                &lsp_args,
+            )
+            .await?;
+        }
+        Commands::Probe {
+            timeout,
+            lsp_server,
+            lsp_args,
+        } => {
+            probe::run_probe(&lsp_server, &lsp_args, Duration::from_secs(timeout)).await?;
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    /// A frame with `headers`, each ending in CRLF, around `body`
+    fn frame(headers: &[&str], body: &[u8]) -> Vec<u8> {
+        let mut frame = vec![];
+        for header in headers {
+            frame.extend_from_slice(header.as_bytes());
+            frame.extend_from_slice(b"\r\n");
+        }
+        frame.extend_from_slice(b"\r\n");
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    fn gzip_frame(body: &[u8]) -> Vec<u8> {
+        frame(
+            &[
+                &format!("Content-Length: {}", body.len()),
+                "Content-Encoding: gzip",
+            ],
+            body,
+        )
+    }
+
+    #[test]
+    fn decompresses_a_gzipped_frame() {
+        let frame = gzip_frame(gzip::SAMPLE);
+        let mut parser = LspMessageParser::new();
+        parser.add_data(&frame);
+        let (message, json) = parser.try_parse_message().unwrap();
+        // The frame is forwarded compressed, but logged decompressed.
+        assert_eq!(message, frame);
+        assert_eq!(json, gzip::SAMPLE_DECODED);
+        assert_eq!(parser.take_framing_error(), None);
+    }
+
+    #[test]
+    fn keeps_a_corrupt_gzipped_frame() {
+        let mut body = gzip::SAMPLE.to_vec();
+        body[20] ^= 0xff;
+        let frame = gzip_frame(&body);
+        let mut parser = LspMessageParser::new();
+        parser.add_data(&frame);
+        parser.add_data(&gzip_frame(gzip::SAMPLE));
+
+        let (message, json) = parser.try_parse_message().unwrap();
+        assert_eq!(message, frame);
+        assert_ne!(json, gzip::SAMPLE_DECODED);
+        assert_eq!(parser.take_framing_error(), None);
+        // The next frame is still read.
+        let (_, json) = parser.try_parse_message().unwrap();
+        assert_eq!(json, gzip::SAMPLE_DECODED);
+    }
+
+    #[test]
+    fn reports_an_oversized_gzipped_frame() {
+        let frame = gzip_frame(&gzip::bomb(gzip::MAX_DECODED + 1));
+        let mut parser = LspMessageParser::new();
+        parser.add_data(&frame);
+        let (message, _) = parser.try_parse_message().unwrap();
+        assert_eq!(message, frame);
+        assert_eq!(
+            parser.take_framing_error(),
+            Some(format!(
+                "its gzip body decompresses to more than {} bytes",
+                gzip::MAX_DECODED
+            ))
+        );
+    }
+
+    fn content_length(headers: &str) -> Option<usize> {
+        Headers::parse(headers).map(|headers| headers.content_length)
+    }
+
+    #[test]
+    fn parses_header_names_in_any_case() {
+        assert_eq!(content_length("content-length: 12"), Some(12));
+        assert_eq!(content_length("CONTENT-LENGTH: 12"), Some(12));
+        assert_eq!(content_length("Content-Length:12"), Some(12));
+        let headers = Headers::parse("Content-Length: 3\r\ncontent-ENCODING: gzip").unwrap();
+        assert_eq!(headers.content_encoding.as_deref(), Some("gzip"));
+    }
+
+    #[test]
+    fn parses_header_names_padded_with_whitespace() {
+        assert_eq!(content_length(" Content-Length : 12 "), Some(12));
+        assert_eq!(content_length("\tContent-Length\t:\t12"), Some(12));
+    }
+
+    #[test]
+    fn uses_the_first_of_a_duplicated_header() {
+        assert_eq!(
+            content_length("Content-Length: 12\r\nContent-Length: 99"),
+            Some(12)
+        );
+        // A later copy isn't checked, so can't make the frame invalid.
+        assert_eq!(
+            content_length("Content-Length: 12\r\nContent-Length: oops"),
+            Some(12)
+        );
+    }
+
+    #[test]
+    fn needs_a_valid_content_length() {
+        assert_eq!(content_length("Content-Type: application/json"), None);
+        assert_eq!(content_length("Content-Length: twelve"), None);
+        assert_eq!(content_length("Content-Length: -1"), None);
+        assert_eq!(content_length("Content-Length-Extra: 12"), None);
+    }
+
+    #[test]
+    fn reads_a_frame_with_padded_lowercase_headers() {
+        let body = br#"{"jsonrpc":"2.0","method":"exit"}"#;
+        let frame = frame(&[&format!("content-length :  {} ", body.len())], body);
+        let mut parser = LspMessageParser::new();
+        parser.add_data(&frame);
+        let (message, json) = parser.try_parse_message().unwrap();
+        assert_eq!(message, frame);
+        assert_eq!(json.as_bytes(), body);
+    }
+
+    fn exit_frame() -> Vec<u8> {
+        let body = br#"{"jsonrpc":"2.0","method":"exit"}"#;
+        frame(&[&format!("Content-Length: {}", body.len())], body)
+    }
+
+    #[test]
+    fn skips_a_bom_before_the_first_frame() {
+        let mut parser = LspMessageParser::new();
+        parser.add_data(UTF8_BOM);
+        parser.add_data(&exit_frame());
+        let (message, _) = parser.try_parse_message().unwrap();
+        assert_eq!(message, exit_frame());
+        assert_eq!(parser.stray_bytes_before_message(), UTF8_BOM.len());
+    }
+
+    #[test]
+    fn skips_crlf_between_frames() {
+        let mut parser = LspMessageParser::new();
+        parser.add_data(&exit_frame());
+        parser.add_data(b"\r\n");
+        parser.add_data(&exit_frame());
+        let (message, _) = parser.try_parse_message().unwrap();
+        assert_eq!(message, exit_frame());
+        assert_eq!(parser.stray_bytes_before_message(), 0);
+        let (message, _) = parser.try_parse_message().unwrap();
+        assert_eq!(message, exit_frame());
+        assert_eq!(parser.stray_bytes_before_message(), 2);
+        assert!(!parser.has_partial_message());
+    }
+
+    #[test]
+    fn skips_a_bom_split_across_reads() {
+        let mut parser = LspMessageParser::new();
+        parser.add_data(&UTF8_BOM[..2]);
+        assert_eq!(parser.try_parse_message(), None);
+        parser.add_data(&UTF8_BOM[2..]);
+        parser.add_data(&exit_frame());
+        let (message, _) = parser.try_parse_message().unwrap();
+        assert_eq!(message, exit_frame());
+        assert_eq!(parser.stray_bytes_before_message(), UTF8_BOM.len());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn forwards_server_output_after_the_editor_closes_stdin() {
+        let response = r#"{"jsonrpc":"2.0","id":1,"result":null}"#;
+        // Answers only once the editor has gone, as a slow server would.
+        let server = format!(
+            "cat > /dev/null; sleep 0.2; printf '%s' '{}'",
+            format_lsp_message(response)
+        );
+        let request = r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#;
+        let editor_input = std::io::Cursor::new(format_lsp_message(request).into_bytes());
+        let (editor_output, mut editor) = tokio::io::duplex(64 * 1024);
+
+        let mut config = stream::tests::test_config();
+        config.log_dir = stream::tests::temp_path("drain");
+        let log_dir = config.log_dir.clone();
+        run_session(
+            "sh",
+            &["-c".to_string(), server],
+            config,
+            editor_input,
+            editor_output,
+        )
+        .await
+        .unwrap();
+
+        let mut forwarded = vec![];
+        editor.read_to_end(&mut forwarded).await.unwrap();
+        assert_eq!(forwarded, format_lsp_message(response).into_bytes());
+        let stdout_log = std::fs::read_dir(&log_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.to_string_lossy().ends_with("_stdout.jsonl"))
+            .unwrap();
+        let logged = std::fs::read_to_string(stdout_log).unwrap();
+        let _ = std::fs::remove_dir_all(&log_dir);
+        let logged: Vec<serde_json::Value> = logged
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            logged,
+            vec![serde_json::from_str::<serde_json::Value>(response).unwrap()]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn logs_partial_and_non_utf8_stderr() {
+        // A whole line, then a partial one with a byte that isn't UTF-8.
+        let mut child = Command::new("sh")
+            .args(["-c", r"printf 'caf\303\251\nhalf \377' >&2"])
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stderr = child.stderr.take().unwrap();
+        let path = stream::tests::temp_path("stderr.log");
+        let stderr_log = File::create(&path).await.unwrap();
+        let (log_messages, mut messages) = tokio::sync::mpsc::unbounded_channel();
+
+        log_stderr(stderr, stderr_log, Some(log_messages), None).await;
+        child.wait().await.unwrap();
+
+        let logged = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(logged, b"caf\xc3\xa9\nhalf \xff");
+        let mut texts = vec![];
+        while let Ok(message) = messages.try_recv() {
+            texts.push(message["params"]["message"].as_str().unwrap().to_string());
+        }
+        assert_eq!(texts, ["café", "half \u{FFFD}"]);
+    }
+}
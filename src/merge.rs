@@ -0,0 +1,251 @@
+use anyhow::{bail, Result};
+use serde_json::Value;
+use std::collections::{HashSet, VecDeque};
+use std::path::Path;
+
+use crate::capture::{self, Entry};
+use crate::config::LogFormat;
+
+/// A message from one of the logs, and when it was read
+struct Timed {
+    /// Milliseconds since the session started
+    ms: u64,
+    stream: &'static str,
+    message: Value,
+}
+
+/// Interleaves the stdin and stdout logs of a session into one log of
+/// `{"stream":...,"message":...}` entries, ordered by when each message
+/// was read. The time of each message is the running total of its
+/// `_dt_ms` gaps, so both logs must have been written with
+/// `LSP_LOG_GAPS`.
+pub(crate) fn merge(stdin: &Path, stdout: &Path, output: &Path) -> Result<()> {
+    let Some(format) = LogFormat::from_path(output) else {
+        bail!(
+            "Can't tell the format of {}, use a .jsonl or .json extension",
+            output.display()
+        );
+    };
+    if format == LogFormat::Raw {
+        bail!("Raw logs can't say which stream each message was sent on, use .jsonl or .json");
+    }
+
+    let timeline = interleave(read_timed(stdin, "stdin")?, read_timed(stdout, "stdout")?);
+
+    let entries: Vec<Entry> = timeline
+        .into_iter()
+        .map(|timed| {
+            Entry::Message(serde_json::json!({
+                "stream": timed.stream,
+                "message": timed.message,
+            }))
+        })
+        .collect();
+    capture::write_log(output, format, &entries)?;
+    println!(
+        "Merged {} messages from {} and {} into {}",
+        entries.len(),
+        stdin.display(),
+        stdout.display(),
+        output.display()
+    );
+    Ok(())
+}
+
+/// Merges the two logs by time, keeping each log's order. Gaps are
+/// whole milliseconds, so the totals drift apart over a session; a
+/// response is never put before the request it answers, whatever
+/// the times say. Messages with the same time put stdin first.
+fn interleave(stdin: Vec<Timed>, stdout: Vec<Timed>) -> Vec<Timed> {
+    let mut streams = [VecDeque::from(stdin), VecDeque::from(stdout)];
+    // The ids of requests sent on each stream that haven't been merged
+    // yet
+    let mut unmerged: [HashSet<String>; 2] =
+        [0, 1].map(|i| streams[i].iter().filter_map(request_id).collect());
+
+    let mut timeline = vec![];
+    loop {
+        let next = match (streams[0].front(), streams[1].front()) {
+            (None, None) => break,
+            (Some(_), None) => 0,
+            (None, Some(_)) => 1,
+            (Some(a), Some(b)) => {
+                let earliest = if b.ms < a.ms { 1 } else { 0 };
+                let other = 1 - earliest;
+                let waiting = response_id(&streams[earliest][0].message)
+                    .is_some_and(|id| unmerged[other].contains(&id));
+                if waiting {
+                    other
+                } else {
+                    earliest
+                }
+            }
+        };
+        let timed = streams[next].pop_front().expect("stream has a message");
+        if let Some(id) = request_id(&timed) {
+            unmerged[next].remove(&id);
+        }
+        timeline.push(timed);
+    }
+    timeline
+}
+
+fn request_id(timed: &Timed) -> Option<String> {
+    timed.message.get("method")?;
+    Some(timed.message.get("id")?.to_string())
+}
+
+fn response_id(message: &Value) -> Option<String> {
+    if message.get("method").is_some() {
+        return None;
+    }
+    Some(message.get("id")?.to_string())
+}
+
+/// Reads a per-stream log, timing each message from the `_dt_ms` gaps.
+/// Entries without a gap, such as unparseable bodies, are given the
+/// time of the entry before.
+fn read_timed(path: &Path, stream: &'static str) -> Result<Vec<Timed>> {
+    let Some(format) = LogFormat::from_path(path) else {
+        bail!(
+            "Can't tell the format of {}, expected a .jsonl or .json log",
+            path.display()
+        );
+    };
+    if format == LogFormat::Raw {
+        bail!(
+            "{} is a raw log, which has no timestamps; capture with LSP_FORMAT=jsonl and LSP_LOG_GAPS=1",
+            path.display()
+        );
+    }
+
+    let mut timed = vec![];
+    let mut ms = 0;
+    let mut has_gaps = false;
+    for entry in capture::read_log(path, format)? {
+        let message = match entry {
+            Entry::Message(value) => value,
+            Entry::Unparsed(payload) => Value::String(payload),
+        };
+        if message.get("_meta").is_some() {
+            continue;
+        }
+        if message.get("stream").is_some() && message.get("message").is_some() {
+            bail!(
+                "{} already has messages from both streams, it doesn't need merging",
+                path.display()
+            );
+        }
+        if let Some(gap) = message.get("_dt_ms").and_then(|gap| gap.as_u64()) {
+            ms += gap;
+            has_gaps = true;
+        }
+        timed.push(Timed {
+            ms,
+            stream,
+            message,
+        });
+    }
+
+    if !timed.is_empty() && !has_gaps {
+        bail!(
+            "{} has no _dt_ms gaps, so its messages can't be put in order; capture with LSP_LOG_GAPS=1",
+            path.display()
+        );
+    }
+    Ok(timed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::tests::temp_path;
+    use serde_json::json;
+
+    #[test]
+    fn orders_messages_by_their_gaps() {
+        let stdin = temp_path("merge_stdin.jsonl");
+        let stdout = temp_path("merge_stdout.jsonl");
+        let output = temp_path("merge_output.jsonl");
+        std::fs::write(
+            &stdin,
+            [
+                r#"{"_meta":{"server":"rust-analyzer"}}"#,
+                r#"{"id":1,"method":"initialize","_dt_ms":0}"#,
+                r#"{"method":"initialized","_dt_ms":20}"#,
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+        std::fs::write(
+            &stdout,
+            [
+                r#"{"id":1,"result":{},"_dt_ms":10}"#,
+                r#"{"method":"window/logMessage","_dt_ms":15}"#,
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+
+        merge(&stdin, &stdout, &output).unwrap();
+        let entries = capture::read_log(&output, LogFormat::JsonLines).unwrap();
+        let streams: Vec<_> = entries
+            .iter()
+            .map(|entry| match entry {
+                Entry::Message(entry) => entry["stream"].as_str().unwrap(),
+                Entry::Unparsed(_) => "unparsed",
+            })
+            .collect();
+        assert_eq!(streams, vec!["stdin", "stdout", "stdin", "stdout"]);
+
+        for path in [&stdin, &stdout, &output] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn never_puts_a_response_before_its_request() {
+        let timed = |ms, stream, message| Timed {
+            ms,
+            stream,
+            message,
+        };
+        // The gaps have drifted, so the response looks earlier.
+        let stdin = vec![
+            timed(0, "stdin", json!({"method": "initialized"})),
+            timed(5, "stdin", json!({"id": 1, "method": "shutdown"})),
+        ];
+        let stdout = vec![timed(4, "stdout", json!({"id": 1, "result": null}))];
+
+        let order: Vec<_> = interleave(stdin, stdout)
+            .into_iter()
+            .map(|timed| timed.message)
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                json!({"method": "initialized"}),
+                json!({"id": 1, "method": "shutdown"}),
+                json!({"id": 1, "result": null}),
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_logs_it_cant_order() {
+        let stdin = temp_path("merge_gapless_stdin.jsonl");
+        let stdout = temp_path("merge_gapless_stdout.jsonl");
+        std::fs::write(&stdin, "{\"method\":\"initialized\"}\n").unwrap();
+        std::fs::write(&stdout, "").unwrap();
+
+        let error = merge(&stdin, &stdout, &temp_path("merge_gapless.jsonl")).unwrap_err();
+        assert!(error.to_string().contains("has no _dt_ms gaps"));
+        let error = merge(&stdin, &stdout, &temp_path("merge_gapless.log")).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Raw logs can't say which stream"));
+
+        let _ = std::fs::remove_file(&stdin);
+        let _ = std::fs::remove_file(&stdout);
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+
+use crate::config::LogMode;
+use crate::disk_full;
+use crate::open_log;
+
+/// A JSON Lines log for each method, holding messages in both
+/// directions, opened the first time we see the method
+pub(crate) struct MethodLogs {
+    log_dir: PathBuf,
+    /// Start of each file name, so method logs sort with the session's
+    /// other logs
+    prefix: String,
+    files: HashMap<String, File>,
+    mode: LogMode,
+}
+
+pub(crate) type SharedMethodLogs = Arc<tokio::sync::Mutex<MethodLogs>>;
+
+impl MethodLogs {
+    pub(crate) fn new(log_dir: PathBuf, prefix: String, mode: LogMode) -> Self {
+        Self {
+            log_dir,
+            prefix,
+            files: HashMap::new(),
+            mode,
+        }
+    }
+
+    pub(crate) async fn write(&mut self, method: &str, stream: &str, message: &serde_json::Value) {
+        if disk_full::logging_disabled() {
+            return;
+        }
+        if !self.files.contains_key(method) {
+            let path =
+                self.log_dir
+                    .join(format!("{}_method-{}.jsonl", self.prefix, sanitize(method)));
+            match open_log(&path, method, self.mode).await {
+                Ok(file) => {
+                    self.files.insert(method.to_string(), file);
+                }
+                Err(e) => {
+                    eprintln!("{:#}", e);
+                    return;
+                }
+            }
+        }
+
+        let Some(file) = self.files.get_mut(method) else {
+            return;
+        };
+        let line = format!("{{\"stream\":\"{}\",\"message\":{}}}\n", stream, message);
+        if let Err(e) = file.write_all(line.as_bytes()).await {
+            disk_full::log_write_failed(&format!("{} log", method), &e);
+        }
+    }
+
+    pub(crate) async fn flush(&mut self) {
+        if disk_full::logging_disabled() {
+            return;
+        }
+        for (method, file) in &mut self.files {
+            if let Err(e) = file.flush().await {
+                disk_full::log_write_failed(&format!("{} log", method), &e);
+            }
+        }
+    }
+}
+
+/// Makes a method name safe to use in a file name, e.g.
+/// `textDocument/completion` becomes `textDocument_completion`
+pub(crate) fn sanitize(method: &str) -> String {
+    method
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,222 @@
+use chrono::{DateTime, Local};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+
+use crate::latency::Latencies;
+use crate::session::SharedSession;
+
+/// The automatic phases, in the order they usually happen
+const HANDSHAKE: &str = "handshake";
+const INDEXING: &str = "indexing";
+const STEADY: &str = "steady";
+
+/// Where a phase boundary came from
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Source {
+    /// A milestone in the traffic, with `LSP_PHASE_MARKERS`
+    Auto,
+    /// A label written to `LSP_PHASE_FIFO`
+    Manual,
+}
+
+impl Source {
+    fn name(self) -> &'static str {
+        match self {
+            Source::Auto => "auto",
+            Source::Manual => "manual",
+        }
+    }
+}
+
+struct Phase {
+    label: String,
+    source: Source,
+    started: DateTime<Local>,
+}
+
+/// Splits a session into labelled phases. Each boundary is written to
+/// the JSON logs of both streams as a marker entry, and the editor's
+/// request latencies are kept per phase.
+pub(crate) struct PhaseMarkers {
+    /// Whether to follow milestones in the traffic
+    auto: bool,
+    phases: Vec<Phase>,
+    /// Tokens of `$/progress` reports that have begun but not ended
+    progress: HashSet<String>,
+    handshake_complete: bool,
+    /// The stream tasks, which write markers to their logs
+    logs: Vec<mpsc::UnboundedSender<Value>>,
+    latencies: BTreeMap<String, Latencies>,
+}
+
+impl PhaseMarkers {
+    pub(crate) fn new(auto: bool, logs: Vec<mpsc::UnboundedSender<Value>>) -> Self {
+        Self {
+            auto,
+            phases: vec![],
+            progress: HashSet::new(),
+            handshake_complete: false,
+            logs,
+            latencies: BTreeMap::new(),
+        }
+    }
+
+    /// The label of the current phase, if one has started
+    pub(crate) fn current(&self) -> Option<&str> {
+        self.phases.last().map(|phase| phase.label.as_str())
+    }
+
+    /// End the current phase and start `label`, writing a marker to the
+    /// logs
+    pub(crate) fn start(&mut self, label: &str, source: Source, now: DateTime<Local>) {
+        if self.current() == Some(label) {
+            return;
+        }
+        eprintln!("Phase {} started ({})", label, source.name());
+        let marker = serde_json::json!({
+            "_phase": label,
+            "_source": source.name(),
+            "_timestamp": now.to_rfc3339(),
+        });
+        for log in &self.logs {
+            let _ = log.send(marker.clone());
+        }
+        self.phases.push(Phase {
+            label: label.to_string(),
+            source,
+            started: now,
+        });
+    }
+
+    /// Follow the milestones that start automatic phases: the editor's
+    /// first message, its `initialized` notification, and the server's
+    /// `$/progress` reports. A phase started manually isn't replaced
+    /// automatically until the next milestone.
+    pub(crate) fn observe(&mut self, from_editor: bool, message: &Value, now: DateTime<Local>) {
+        if !self.auto {
+            return;
+        }
+        if self.phases.is_empty() {
+            self.start(HANDSHAKE, Source::Auto, now);
+        }
+        let method = message.get("method").and_then(|m| m.as_str());
+        match (from_editor, method) {
+            (true, Some("initialized")) if message.get("id").is_none() => {
+                self.handshake_complete = true;
+                self.settle(now);
+            }
+            (false, Some("$/progress")) => {
+                let token = message
+                    .pointer("/params/token")
+                    .map(|token| token.to_string())
+                    .unwrap_or_default();
+                match message
+                    .pointer("/params/value/kind")
+                    .and_then(|kind| kind.as_str())
+                {
+                    Some("begin") => {
+                        self.progress.insert(token);
+                        self.settle(now);
+                    }
+                    Some("end") => {
+                        self.progress.remove(&token);
+                        self.settle(now);
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// After the handshake, the server is indexing while it reports
+    /// progress, and steady otherwise
+    fn settle(&mut self, now: DateTime<Local>) {
+        if !self.handshake_complete {
+            return;
+        }
+        let label = if self.progress.is_empty() {
+            STEADY
+        } else {
+            INDEXING
+        };
+        self.start(label, Source::Auto, now);
+    }
+
+    /// Record how long a request sent during `phase` took
+    pub(crate) fn record_latency(&mut self, phase: &str, method: &str, latency: Duration) {
+        self.latencies
+            .entry(phase.to_string())
+            .or_default()
+            .record(method, latency);
+    }
+
+    pub(crate) fn summary_json(&self) -> Value {
+        let phases: Vec<Value> = self
+            .phases
+            .iter()
+            .map(|phase| {
+                serde_json::json!({
+                    "label": phase.label,
+                    "source": phase.source.name(),
+                    "started": phase.started.to_rfc3339(),
+                })
+            })
+            .collect();
+        let latency: BTreeMap<&str, Value> = self
+            .latencies
+            .iter()
+            .map(|(phase, latencies)| (phase.as_str(), latencies.summary_json()))
+            .collect();
+        serde_json::json!({
+            "phases": phases,
+            "latency": latency,
+        })
+    }
+}
+
+/// Starts a manual phase for each line written to the FIFO at `path`.
+/// The FIFO is opened again whenever its writer closes it, so labels
+/// can be sent with e.g. `echo indexing > phases.fifo`.
+pub(crate) async fn read_phase_fifo(path: PathBuf, session: SharedSession) {
+    // A regular file would be read from the start again at each EOF.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) if metadata.file_type().is_fifo() => {}
+            Ok(_) => {
+                eprintln!(
+                    "Ignoring LSP_PHASE_FIFO: {} isn't a FIFO, create one with mkfifo",
+                    path.display()
+                );
+                return;
+            }
+            Err(e) => {
+                eprintln!("Ignoring LSP_PHASE_FIFO: {}: {}", path.display(), e);
+                return;
+            }
+        }
+    }
+    loop {
+        let file = match tokio::fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("Failed to open LSP_PHASE_FIFO {}: {}", path.display(), e);
+                return;
+            }
+        };
+        let mut lines = BufReader::new(file).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let label = line.trim();
+            if !label.is_empty() {
+                session.lock().unwrap().start_phase(label, Source::Manual);
+            }
+        }
+    }
+}
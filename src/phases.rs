@@ -0,0 +1,69 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::stream::Direction;
+
+/// Traffic in one stream during one phase
+#[derive(Default)]
+struct StreamCounts {
+    messages: usize,
+    bytes: usize,
+    /// Messages by method, with responses under their request's method
+    methods: BTreeMap<String, usize>,
+}
+
+/// Traffic counted separately before and after the handshake, so the
+/// burst at startup can be told apart from steady-state traffic. The
+/// handshake ends with the editor's `initialized` notification.
+#[derive(Default)]
+pub(crate) struct Phases {
+    handshake: BTreeMap<&'static str, StreamCounts>,
+    after_handshake: BTreeMap<&'static str, StreamCounts>,
+    handshake_complete: bool,
+}
+
+impl Phases {
+    pub(crate) fn observe(&mut self, direction: Direction, method: Option<&str>, bytes: usize) {
+        let phase = if self.handshake_complete {
+            &mut self.after_handshake
+        } else {
+            &mut self.handshake
+        };
+        let counts = phase.entry(direction.stream_name()).or_default();
+        counts.messages += 1;
+        counts.bytes += bytes;
+        if let Some(method) = method {
+            *counts.methods.entry(method.to_string()).or_default() += 1;
+        }
+    }
+
+    /// Start counting steady-state traffic. `initialized` itself is
+    /// the last message of the handshake.
+    pub(crate) fn complete_handshake(&mut self) {
+        self.handshake_complete = true;
+    }
+
+    pub(crate) fn summary_json(&self) -> Value {
+        serde_json::json!({
+            "handshake": phase_json(&self.handshake),
+            "after_handshake": phase_json(&self.after_handshake),
+        })
+    }
+}
+
+fn phase_json(phase: &BTreeMap<&'static str, StreamCounts>) -> Value {
+    let streams: BTreeMap<&str, Value> = phase
+        .iter()
+        .map(|(stream, counts)| {
+            (
+                *stream,
+                serde_json::json!({
+                    "messages": counts.messages,
+                    "bytes": counts.bytes,
+                    "methods": counts.methods,
+                }),
+            )
+        })
+        .collect();
+    serde_json::json!(streams)
+}
@@ -0,0 +1,239 @@
+use anyhow::{bail, Context, Result};
+use libloading::Library;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+use crate::stream::Direction;
+
+/// The plugin ABI version this proxy implements. Plugins report the
+/// version they were written for from `lsp_plugin_abi_version`.
+const ABI_VERSION: u32 = 1;
+
+/// Values returned by a plugin's `process_message`
+const ACTION_FORWARD: i32 = 0;
+const ACTION_DROP: i32 = 1;
+const ACTION_REPLACE: i32 = 2;
+const ACTION_LOG_ONLY: i32 = 3;
+
+/// What a plugin wants done with a message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Action {
+    /// Forward and log the message unchanged
+    Forward,
+    /// Neither forward the message nor log it as JSON
+    Drop,
+    /// Forward and log this message body instead
+    Replace(String),
+    /// Log the message without forwarding it
+    LogOnly,
+}
+
+type AbiVersionFn = unsafe extern "C" fn() -> u32;
+type ProcessMessageFn = unsafe extern "C" fn(
+    direction: u8,
+    json: *const u8,
+    len: usize,
+    replacement: *mut *mut u8,
+    replacement_len: *mut usize,
+) -> i32;
+type FreeMessageFn = unsafe extern "C" fn(ptr: *mut u8, len: usize);
+
+/// A dynamic library from `LSP_PLUGIN` that's given each parsed
+/// message, and decides whether it's forwarded. See the README for the
+/// functions it must export.
+pub(crate) struct Plugin {
+    process_message: ProcessMessageFn,
+    free_message: FreeMessageFn,
+    /// Keeps the library loaded while its functions are in use, when
+    /// they came from one
+    _library: Option<Library>,
+}
+
+pub(crate) type SharedPlugin = Arc<Plugin>;
+
+impl Plugin {
+    /// Loads the plugin named by `LSP_PLUGIN`, if any
+    pub(crate) fn from_env() -> Option<SharedPlugin> {
+        let path = crate::config::env_path("LSP_PLUGIN")?;
+        match Plugin::load(&path) {
+            Ok(plugin) => {
+                eprintln!("Loaded plugin {}", path.display());
+                Some(Arc::new(plugin))
+            }
+            Err(e) => {
+                eprintln!("Ignoring LSP_PLUGIN: {:#}", e);
+                None
+            }
+        }
+    }
+
+    fn load(path: &Path) -> Result<Self> {
+        // SAFETY: loading a library runs its initialisers, and we trust
+        // the exported functions to have the documented signatures.
+        // Both are the user's responsibility when setting LSP_PLUGIN.
+        unsafe {
+            let library =
+                Library::new(path).with_context(|| format!("Failed to load {}", path.display()))?;
+
+            let abi_version = *library
+                .get::<AbiVersionFn>(b"lsp_plugin_abi_version\0")
+                .context("Plugin doesn't export lsp_plugin_abi_version")?;
+            let version = abi_version();
+            if version != ABI_VERSION {
+                bail!(
+                    "Plugin was written for ABI version {}, but lsp-fiddle supports version {}",
+                    version,
+                    ABI_VERSION
+                );
+            }
+
+            let process_message = *library
+                .get::<ProcessMessageFn>(b"process_message\0")
+                .context("Plugin doesn't export process_message")?;
+            let free_message = *library
+                .get::<FreeMessageFn>(b"free_message\0")
+                .context("Plugin doesn't export free_message")?;
+
+            Ok(Self {
+                process_message,
+                free_message,
+                _library: Some(library),
+            })
+        }
+    }
+
+    /// Ask the plugin what to do with a message body. Unknown actions
+    /// forward the message, so a buggy plugin doesn't break the session.
+    pub(crate) fn process(&self, direction: Direction, json: &str) -> Action {
+        let direction = match direction {
+            Direction::ToServer => 0,
+            Direction::ToEditor => 1,
+        };
+        let mut replacement = std::ptr::null_mut();
+        let mut replacement_len = 0;
+
+        // SAFETY: the plugin may only read `len` bytes from `json`, which
+        // outlives the call, and only writes through the two pointers.
+        let action = unsafe {
+            (self.process_message)(
+                direction,
+                json.as_ptr(),
+                json.len(),
+                &mut replacement,
+                &mut replacement_len,
+            )
+        };
+
+        match action {
+            ACTION_FORWARD => Action::Forward,
+            ACTION_DROP => Action::Drop,
+            ACTION_LOG_ONLY => Action::LogOnly,
+            ACTION_REPLACE if replacement.is_null() => {
+                eprintln!("Plugin asked to replace a message without a replacement, forwarding it");
+                Action::Forward
+            }
+            ACTION_REPLACE => {
+                // SAFETY: the plugin gave us `replacement_len` bytes at
+                // `replacement`, which stay valid until we free them.
+                let message = unsafe {
+                    let bytes = std::slice::from_raw_parts(replacement, replacement_len);
+                    let message = String::from_utf8_lossy(bytes).into_owned();
+                    (self.free_message)(replacement, replacement_len);
+                    message
+                };
+                Action::Replace(message)
+            }
+            other => {
+                eprintln!(
+                    "Plugin returned unknown action {}, forwarding the message",
+                    other
+                );
+                Action::Forward
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl Plugin {
+    /// A plugin made of functions in this binary, for tests
+    pub(crate) fn from_functions(
+        process_message: ProcessMessageFn,
+        free_message: FreeMessageFn,
+    ) -> SharedPlugin {
+        Arc::new(Self {
+            process_message,
+            free_message,
+            _library: None,
+        })
+    }
+}
+
+/// Runs a plugin on up to `concurrency` messages of a stream at once,
+/// on blocking threads, for plugins that are slow. Actions are given
+/// back in the order the messages were queued, so forwarding order is
+/// kept.
+pub(crate) struct PluginPipeline {
+    plugin: SharedPlugin,
+    direction: Direction,
+    concurrency: usize,
+    /// Bodies waiting for a free slot
+    queued: VecDeque<String>,
+    running: VecDeque<JoinHandle<Action>>,
+}
+
+impl PluginPipeline {
+    pub(crate) fn new(plugin: SharedPlugin, direction: Direction, concurrency: usize) -> Self {
+        Self {
+            plugin,
+            direction,
+            concurrency,
+            queued: VecDeque::new(),
+            running: VecDeque::new(),
+        }
+    }
+
+    /// Queue a message body, starting on it if there's a free slot
+    pub(crate) fn queue(&mut self, json: String) {
+        self.queued.push_back(json);
+        self.fill();
+    }
+
+    fn fill(&mut self) {
+        while self.running.len() < self.concurrency {
+            let Some(json) = self.queued.pop_front() else {
+                break;
+            };
+            let plugin = self.plugin.clone();
+            let direction = self.direction;
+            self.running.push_back(tokio::task::spawn_blocking(move || {
+                plugin.process(direction, &json)
+            }));
+        }
+    }
+
+    pub(crate) fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+
+    /// The action for the oldest queued message, waiting for the plugin
+    /// if it's still running. Safe to cancel, as the message stays at
+    /// the front of the queue.
+    pub(crate) async fn next(&mut self) -> Action {
+        let Some(running) = self.running.front_mut() else {
+            return Action::Forward;
+        };
+        let action = match running.await {
+            Ok(action) => action,
+            Err(e) => {
+                eprintln!("Plugin failed: {}, forwarding the message", e);
+                Action::Forward
+            }
+        };
+        self.running.pop_front();
+        self.fill();
+        action
+    }
+}
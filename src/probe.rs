@@ -0,0 +1,160 @@
+use anyhow::{Context, Result};
+use std::fmt;
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::{ChildStdout, Command};
+
+use crate::{format_lsp_message, initialize_request, LspMessageParser};
+
+/// The request id used for every probe initialize request
+const PROBE_ID: u64 = 1;
+
+/// A framing variant to send to the server
+struct ProbeCase {
+    name: &'static str,
+    frame: fn(&str) -> String,
+}
+
+const PROBE_CASES: &[ProbeCase] = &[
+    ProbeCase {
+        name: "standard",
+        frame: format_lsp_message,
+    },
+    ProbeCase {
+        name: "extra-headers",
+        frame: |json| {
+            format!(
+                "Content-Length: {}\r\nContent-Type: application/vscode-jsonrpc; charset=utf-8\r\nX-Lsp-Fiddle-Probe: 1\r\n\r\n{}",
+                json.len(),
+                json
+            )
+        },
+    },
+    ProbeCase {
+        name: "lowercase-header",
+        frame: |json| format!("content-length: {}\r\n\r\n{}", json.len(), json),
+    },
+    ProbeCase {
+        name: "bare-newlines",
+        frame: |json| format!("Content-Length: {}\n\n{}", json.len(), json),
+    },
+    ProbeCase {
+        name: "zero-length-body",
+        frame: |json| format!("Content-Length: 0\r\n\r\n{}", format_lsp_message(json)),
+    },
+];
+
+/// How the server reacted to a probe
+enum ProbeOutcome {
+    Accepted,
+    Rejected(String),
+    NoResponse(Duration),
+    Exited(Option<ExitStatus>),
+}
+
+impl fmt::Display for ProbeOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeOutcome::Accepted => write!(f, "ok"),
+            ProbeOutcome::Rejected(error) => write!(f, "error response: {}", error),
+            ProbeOutcome::NoResponse(timeout) => {
+                write!(f, "no response within {}s", timeout.as_secs())
+            }
+            ProbeOutcome::Exited(Some(status)) => write!(f, "server exited ({})", status),
+            ProbeOutcome::Exited(None) => write!(f, "server closed its stdout"),
+        }
+    }
+}
+
+/// Sends each framing variant to a fresh server process and prints
+/// which ones the server responds to
+pub(crate) async fn run_probe(
+    lsp_server: &str,
+    server_args: &[String],
+    timeout: Duration,
+) -> Result<()> {
+    println!("Framing conformance for {} {:?}", lsp_server, server_args);
+
+    for case in PROBE_CASES {
+        let outcome = probe_case(lsp_server, server_args, case, timeout).await?;
+        println!("  {:<18} {}", case.name, outcome);
+    }
+
+    Ok(())
+}
+
+async fn probe_case(
+    lsp_server: &str,
+    server_args: &[String],
+    case: &ProbeCase,
+    timeout: Duration,
+) -> Result<ProbeOutcome> {
+    // Use a new process for every case, so a server confused by one
+    // framing doesn't affect the results of the next.
+    let mut child = Command::new(lsp_server)
+        .args(server_args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Failed to spawn LSP server")?;
+
+    let mut child_stdin = child.stdin.take().context("Failed to open child stdin")?;
+    let mut child_stdout = child.stdout.take().context("Failed to open child stdout")?;
+
+    let request = serde_json::to_string(&initialize_request(PROBE_ID))?;
+
+    // A write error means the server has already gone away, which
+    // we'll see when reading its output.
+    let _ = child_stdin
+        .write_all((case.frame)(&request).as_bytes())
+        .await;
+    let _ = child_stdin.flush().await;
+
+    let outcome = match tokio::time::timeout(timeout, read_response(&mut child_stdout)).await {
+        Ok(Some(response)) => match response.get("error") {
+            Some(error) => ProbeOutcome::Rejected(error.to_string()),
+            None => ProbeOutcome::Accepted,
+        },
+        Ok(None) => {
+            let status = tokio::time::timeout(timeout, child.wait()).await;
+            ProbeOutcome::Exited(status.ok().and_then(|status| status.ok()))
+        }
+        Err(_) => ProbeOutcome::NoResponse(timeout),
+    };
+
+    Ok(outcome)
+}
+
+/// Reads server output until a response to the probe request arrives,
+/// returning None if the server closes stdout first
+async fn read_response(child_stdout: &mut ChildStdout) -> Option<serde_json::Value> {
+    let mut parser = LspMessageParser::new();
+    let mut buffer = vec![0u8; 8192];
+
+    loop {
+        while let Some((_, json_payload)) = parser.try_parse_message() {
+            let Ok(value) = serde_json::from_str::<serde_json::Value>(&json_payload) else {
+                continue;
+            };
+
+            // Skip server requests and notifications. Servers that
+            // can't parse a message respond with a null id.
+            if value.get("method").is_some() {
+                continue;
+            }
+            match value.get("id") {
+                Some(serde_json::Value::Null) => return Some(value),
+                Some(id) if id.as_u64() == Some(PROBE_ID) => return Some(value),
+                _ => {}
+            }
+        }
+
+        match child_stdout.read(&mut buffer).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => parser.add_data(&buffer[..n]),
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use chrono::{DateTime, Local};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A work done progress operation, from its `begin` report
+struct Operation {
+    token: Value,
+    title: Option<String>,
+    started: Instant,
+    started_at: DateTime<Local>,
+    reports: usize,
+    /// The last message the server gave, e.g. the file being indexed
+    message: Option<String>,
+    ended: Option<Duration>,
+}
+
+/// The server's `$/progress` operations, matched up by token, so the
+/// summary shows e.g. how long indexing took
+#[derive(Default)]
+pub(crate) struct Progress {
+    operations: Vec<Operation>,
+    /// Operations that have begun but not ended, by token
+    active: HashMap<String, usize>,
+}
+
+impl Progress {
+    pub(crate) fn observe(
+        &mut self,
+        params: Option<&Value>,
+        now: Instant,
+        now_at: DateTime<Local>,
+    ) {
+        let Some(token) = params.and_then(|params| params.get("token")) else {
+            return;
+        };
+        // Partial results are also sent as `$/progress`, but their
+        // value is a page of results rather than a report.
+        let Some(value) = params.and_then(|params| params.get("value")) else {
+            return;
+        };
+        let message = value
+            .get("message")
+            .and_then(|message| message.as_str())
+            .map(|message| message.to_string());
+        let key = token.to_string();
+
+        match value.get("kind").and_then(|kind| kind.as_str()) {
+            Some("begin") => {
+                // A token reused before it ended leaves the earlier
+                // operation unfinished.
+                self.active.insert(key, self.operations.len());
+                self.operations.push(Operation {
+                    token: token.clone(),
+                    title: value
+                        .get("title")
+                        .and_then(|title| title.as_str())
+                        .map(|title| title.to_string()),
+                    started: now,
+                    started_at: now_at,
+                    reports: 0,
+                    message,
+                    ended: None,
+                });
+            }
+            Some("report") => {
+                if let Some(&index) = self.active.get(&key) {
+                    let operation = &mut self.operations[index];
+                    operation.reports += 1;
+                    operation.message = message.or(operation.message.take());
+                }
+            }
+            Some("end") => {
+                if let Some(index) = self.active.remove(&key) {
+                    let operation = &mut self.operations[index];
+                    operation.ended = Some(now.duration_since(operation.started));
+                    operation.message = message.or(operation.message.take());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// Each operation in the order they began. Operations that never
+    /// ended are flagged with `"finished": false` and how long they've
+    /// been running.
+    pub(crate) fn summary_json(&self, now: Instant) -> Value {
+        let operations: Vec<Value> = self
+            .operations
+            .iter()
+            .map(|operation| {
+                let mut json = serde_json::json!({
+                    "token": operation.token,
+                    "title": operation.title,
+                    "started": operation.started_at.to_rfc3339(),
+                    "reports": operation.reports,
+                    "message": operation.message,
+                    "finished": operation.ended.is_some(),
+                });
+                match operation.ended {
+                    Some(duration) => json["duration_ms"] = (duration.as_millis() as u64).into(),
+                    None => {
+                        json["running_ms"] =
+                            (now.duration_since(operation.started).as_millis() as u64).into()
+                    }
+                }
+                json
+            })
+            .collect();
+        let unfinished = self
+            .operations
+            .iter()
+            .filter(|operation| operation.ended.is_none())
+            .count();
+        serde_json::json!({
+            "operations": operations,
+            "unfinished": unfinished,
+        })
+    }
+}
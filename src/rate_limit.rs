@@ -0,0 +1,39 @@
+use std::sync::{Arc, Mutex};
+use tokio::time::Instant;
+
+/// Caps how fast messages are logged, so a slow disk doesn't hold up
+/// forwarding. A token bucket holding up to a second of bytes: messages
+/// that arrive when it's empty aren't logged.
+pub(crate) struct LogRateLimit {
+    bytes_per_sec: f64,
+    /// Bytes that can be logged now. Negative after a message bigger
+    /// than the bucket, which is let through when the bucket is full.
+    tokens: f64,
+    refilled: Instant,
+}
+
+pub(crate) type SharedLogRateLimit = Arc<Mutex<LogRateLimit>>;
+
+impl LogRateLimit {
+    pub(crate) fn new(bytes_per_sec: u64, now: Instant) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec as f64,
+            tokens: bytes_per_sec as f64,
+            refilled: now,
+        }
+    }
+
+    /// Whether a message of `bytes` can be logged at `now`
+    pub(crate) fn admit(&mut self, bytes: usize, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+        self.refilled = now;
+
+        let bytes = bytes as f64;
+        if self.tokens < bytes.min(self.bytes_per_sec) {
+            return false;
+        }
+        self.tokens -= bytes;
+        true
+    }
+}
@@ -0,0 +1,52 @@
+use serde_json::Value;
+use std::env;
+
+use crate::config::ProxyConfig;
+
+/// Parts of a variable name that suggest its value is a secret
+const SECRET_NAME_PARTS: &[&str] = &["TOKEN", "KEY", "SECRET", "PASSWORD"];
+
+const REDACTED: &str = "<redacted>";
+
+fn looks_secret(name: &str) -> bool {
+    let name = name.to_ascii_uppercase();
+    SECRET_NAME_PARTS.iter().any(|part| name.contains(part))
+}
+
+/// The working directory and environment the server is started with,
+/// for the summary, if `LSP_RECORD_ENV` or `LSP_RECORD_ENV_NAMES` is
+/// set. The server inherits our environment, so this is ours.
+pub(crate) fn launch_environment(config: &ProxyConfig) -> Option<Value> {
+    if !config.record_env && config.record_env_names.is_empty() {
+        return None;
+    }
+
+    let mut variables = serde_json::Map::new();
+    let mut redacted = vec![];
+    if config.record_env_names.is_empty() {
+        for (name, value) in env::vars_os() {
+            let name = name.to_string_lossy().into_owned();
+            let value = if looks_secret(&name) {
+                redacted.push(name.clone());
+                REDACTED.into()
+            } else {
+                value.to_string_lossy().into()
+            };
+            variables.insert(name, value);
+        }
+    } else {
+        // Naming a variable explicitly allows recording it, even if it
+        // looks secret.
+        for name in &config.record_env_names {
+            let value = env::var_os(name).map(|value| value.to_string_lossy().into_owned());
+            variables.insert(name.clone(), value.into());
+        }
+    }
+    redacted.sort();
+
+    Some(serde_json::json!({
+        "cwd": env::current_dir().ok(),
+        "variables": variables,
+        "redacted": redacted,
+    }))
+}
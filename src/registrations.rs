@@ -0,0 +1,86 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Capabilities the server has registered dynamically with
+/// `client/registerCapability`, to catch registration bugs
+#[derive(Default)]
+pub(crate) struct Registrations {
+    /// Method of each registration that's still active, by id
+    active: BTreeMap<String, String>,
+    registered: usize,
+    unregistered: usize,
+}
+
+impl Registrations {
+    /// Record a `client/registerCapability` request, returning warnings
+    /// about ids that are already registered
+    pub(crate) fn register(&mut self, params: Option<&Value>) -> Vec<String> {
+        let mut warnings = vec![];
+        for (id, method) in registration_list(params, &["registrations"]) {
+            self.registered += 1;
+            match self.active.insert(id.clone(), method.clone()) {
+                Some(previous) if previous != method => warnings.push(format!(
+                    "server registered capability {:?} for {}, but it was already registered for {}",
+                    id, method, previous
+                )),
+                Some(_) => warnings.push(format!(
+                    "server registered capability {:?} ({}) twice",
+                    id, method
+                )),
+                None => {}
+            }
+        }
+        warnings
+    }
+
+    /// Record a `client/unregisterCapability` request, returning
+    /// warnings about ids that aren't registered
+    pub(crate) fn unregister(&mut self, params: Option<&Value>) -> Vec<String> {
+        let mut warnings = vec![];
+        // The spec misspells this field, and keeps the misspelling for
+        // compatibility.
+        for (id, method) in registration_list(params, &["unregisterations", "unregistrations"]) {
+            self.unregistered += 1;
+            match self.active.remove(&id) {
+                Some(registered) if registered != method => warnings.push(format!(
+                    "server unregistered capability {:?} for {}, but it was registered for {}",
+                    id, method, registered
+                )),
+                Some(_) => {}
+                None => warnings.push(format!(
+                    "server unregistered capability {:?} ({}), which was never registered",
+                    id, method
+                )),
+            }
+        }
+        warnings
+    }
+
+    pub(crate) fn summary_json(&self) -> Value {
+        let active: Vec<Value> = self
+            .active
+            .iter()
+            .map(|(id, method)| serde_json::json!({ "id": id, "method": method }))
+            .collect();
+        serde_json::json!({
+            "registered": self.registered,
+            "unregistered": self.unregistered,
+            "active": active,
+        })
+    }
+}
+
+/// The ids and methods in a registration or unregistration request
+fn registration_list(params: Option<&Value>, fields: &[&str]) -> Vec<(String, String)> {
+    let list = fields
+        .iter()
+        .find_map(|field| params?.get(field)?.as_array());
+    list.into_iter()
+        .flatten()
+        .filter_map(|registration| {
+            let id = registration.get("id")?.as_str()?;
+            let method = registration.get("method")?.as_str()?;
+            Some((id.to_string(), method.to_string()))
+        })
+        .collect()
+}
@@ -0,0 +1,82 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::stream::Direction;
+
+/// The number given to a request id
+struct Renumbered {
+    number: u64,
+    request_seen: bool,
+    response_seen: bool,
+}
+
+/// Replaces request ids in logged messages with 1, 2, 3..., so
+/// captures are easy to follow by eye. A response gets the same number
+/// as its request, and so does a `$/cancelRequest` for it.
+#[derive(Default)]
+pub(crate) struct IdRenumbering {
+    /// Keyed by the direction the request travelled and its original id
+    ids: HashMap<(Direction, String), Renumbered>,
+    last: u64,
+}
+
+pub(crate) type SharedIdRenumbering = Arc<Mutex<IdRenumbering>>;
+
+impl IdRenumbering {
+    /// Renumber a message logged in `direction`. The two streams are
+    /// logged independently, so a response may be seen before its
+    /// request.
+    pub(crate) fn renumber(&mut self, direction: Direction, message: &mut Value) {
+        let method = message.get("method").and_then(|m| m.as_str());
+        if method == Some("$/cancelRequest") {
+            if let Some(id) = message.pointer_mut("/params/id") {
+                if let Some(renumbered) = self.ids.get(&(direction, id.to_string())) {
+                    *id = renumbered.number.into();
+                }
+            }
+            return;
+        }
+
+        let is_request = method.is_some();
+        let Some(id) = message.get_mut("id") else {
+            return;
+        };
+        // Errors about unparseable requests have a null id.
+        if id.is_null() {
+            return;
+        }
+        let key = if is_request {
+            (direction, id.to_string())
+        } else {
+            (direction.opposite(), id.to_string())
+        };
+
+        // An id used again after its request was answered is a new
+        // request.
+        let reused = self.ids.get(&key).is_some_and(|renumbered| {
+            if is_request {
+                renumbered.request_seen
+            } else {
+                renumbered.response_seen
+            }
+        });
+        if reused {
+            self.ids.remove(&key);
+        }
+        let renumbered = self.ids.entry(key).or_insert_with(|| {
+            self.last += 1;
+            Renumbered {
+                number: self.last,
+                request_seen: false,
+                response_seen: false,
+            }
+        });
+        if is_request {
+            renumbered.request_seen = true;
+        } else {
+            renumbered.response_seen = true;
+        }
+        *id = renumbered.number.into();
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::Path;
+
+/// Closes the JSON array in a log written with `LSP_FORMAT=json-array`
+/// whose session was interrupted before it could write the final `]`.
+/// Any truncated trailing entry is dropped.
+pub(crate) fn repair_json_array(path: &Path) -> Result<()> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+
+    if serde_json::from_str::<serde_json::Value>(&contents).is_ok() {
+        println!("{} is already valid JSON", path.display());
+        return Ok(());
+    }
+
+    let mut lines = contents.lines();
+    if lines.next().map(str::trim) != Some("[") {
+        bail!("{} doesn't look like a JSON array log", path.display());
+    }
+
+    // Each entry is on its own line, followed by a comma if another
+    // entry came after it.
+    let mut entries = vec![];
+    for line in lines {
+        let entry = line.trim().trim_end_matches(',');
+        if entry.is_empty() || entry == "]" {
+            continue;
+        }
+        if serde_json::from_str::<serde_json::Value>(entry).is_err() {
+            eprintln!("Dropping truncated entry: {}", entry);
+            break;
+        }
+        entries.push(entry);
+    }
+
+    let repaired = format!("[\n{}\n]\n", entries.join(",\n"));
+    fs::write(path, repaired).with_context(|| format!("Failed to write {}", path.display()))?;
+
+    println!("Repaired {} ({} messages)", path.display(), entries.len());
+    Ok(())
+}
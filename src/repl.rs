@@ -0,0 +1,404 @@
+use anyhow::{Context, Result};
+use rustyline::error::ReadlineError;
+use rustyline::{Config, DefaultEditor, ExternalPrinter};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::config::ProxyConfig;
+use crate::console::pretty_stdout;
+use crate::{format_lsp_message, initialize_request, run_session, LspMessageParser};
+
+/// JSON-RPC error code for requests the receiver doesn't handle
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// The id of the initialize request sent when the REPL starts
+const INITIALIZE_ID: u64 = 1;
+
+/// How many lines the prompt's history keeps
+const MAX_HISTORY: usize = 1000;
+
+/// A request typed at the prompt that hasn't been answered yet
+struct SentRequest {
+    method: String,
+    sent: Instant,
+}
+
+/// Runs an LSP server with a prompt in place of the editor. Each line
+/// is a method followed by optional JSON params, which is sent as a
+/// request and its response printed when it arrives. Messages are
+/// logged as for any other proxy session.
+pub(crate) async fn run_repl(lsp_server: String, server_args: Vec<String>) -> Result<()> {
+    let config = ProxyConfig::from_env();
+
+    let (editor, proxy) = tokio::io::duplex(64 * 1024);
+    let (from_server, mut to_server) = tokio::io::split(editor);
+    let (proxy_input, proxy_output) = tokio::io::split(proxy);
+
+    let mut session = tokio::spawn(async move {
+        run_session(&lsp_server, &server_args, config, proxy_input, proxy_output).await
+    });
+    let (messages_tx, mut messages) = mpsc::unbounded_channel();
+    tokio::spawn(read_messages(from_server, messages_tx));
+
+    let mut pending = HashMap::new();
+    let mut next_id = INITIALIZE_ID;
+    let initialize = initialize_request(next_id);
+    send(&mut to_server, &initialize).await?;
+    pending.insert(
+        next_id.to_string(),
+        SentRequest {
+            method: "initialize".to_string(),
+            sent: Instant::now(),
+        },
+    );
+    next_id += 1;
+
+    eprintln!(
+        "Type a method and optional JSON params, e.g. workspace/symbol {{\"query\": \"main\"}}"
+    );
+    eprintln!("Prefix the method with ! to send a notification. Ctrl-D shuts the server down.");
+
+    let (mut prompt, mut lines) = Prompt::new()?;
+    let mut stdin_open = true;
+    // Input waits for the server to initialize, as it would in an
+    // editor.
+    let mut initialized = false;
+    let mut shutdown_id = None;
+
+    loop {
+        tokio::select! {
+                line = lines.recv(), if stdin_open && initialized => {
+                    let Some(line) = line.transpose().context("Failed to read from stdin")? else {
+                        // Shut down politely, and exit once the server
+                        // acknowledges.
+                        stdin_open = false;
+                        println!();
+                        let shutdown = serde_json::json!({
+                            "jsonrpc": "2.0",
+                            "id": next_id,
+                            "method": "shutdown",
+                        });
+                        send(&mut to_server, &shutdown).await?;
+                        shutdown_id = Some(next_id.to_string());
+                        continue;
+                    };
+                    match parse_line(&line) {
+                        Ok(None) => {}
+                        Ok(Some(Input {
+            method,
+            params,
+            notification,
+        })) => {
+                            let mut message = serde_json::json!({
+                                "jsonrpc": "2.0",
+                                "method": method,
+                            });
+                            if let Some(params) = params {
+                                message["params"] = params;
+                            }
+                            if !notification {
+                                message["id"] = next_id.into();
+                                pending.insert(
+                                    next_id.to_string(),
+                                    SentRequest {
+                                        method: method.to_string(),
+                                        sent: Instant::now(),
+                                    },
+                                );
+                                next_id += 1;
+                            }
+                            send(&mut to_server, &message).await?;
+                        }
+                        Err(e) => eprintln!("{}", e),
+                    }
+                    prompt.ready();
+                }
+                message = messages.recv() => {
+                    let Some(message) = message else {
+                        eprintln!("LSP server closed the connection");
+                        break;
+                    };
+                    let id = message.get("id").map(|id| id.to_string());
+                    match message.get("method").and_then(|m| m.as_str()) {
+                        Some(method) => {
+                            prompt.print(server_message(method, &message));
+                            if let Some(id) = message.get("id") {
+                                // Nobody at the prompt can answer, so tell the
+                                // server rather than leaving it waiting.
+                                let error = serde_json::json!({
+                                    "jsonrpc": "2.0",
+                                    "id": id,
+                                    "error": {
+                                        "code": METHOD_NOT_FOUND,
+                                        "message": format!("{} isn't supported in the REPL", method),
+                                    },
+                                });
+                                send(&mut to_server, &error).await?;
+                            }
+                        }
+                        None => {
+                            // A response without an id can't be matched to a request.
+                            if let Some(id) = id {
+                                if shutdown_id.as_ref() == Some(&id) {
+                                    let exit = serde_json::json!({ "jsonrpc": "2.0", "method": "exit" });
+                                    send(&mut to_server, &exit).await?;
+                                    break;
+                                }
+                                match pending.remove(&id) {
+                                    Some(request) => {
+                                        prompt.print(response(&id, &request, &message));
+                                        if id == INITIALIZE_ID.to_string() {
+                                            let notification = serde_json::json!({
+                                                "jsonrpc": "2.0",
+                                                "method": "initialized",
+                                                "params": {},
+                                            });
+                                            send(&mut to_server, &notification).await?;
+                                            initialized = true;
+                                            prompt.ready();
+                                        }
+                                    }
+                                    None => eprintln!("Response to unknown request {}", id),
+                                }
+                            }
+                        }
+                    }
+                }
+                result = &mut session => {
+                    result??;
+                    return Ok(());
+                }
+            }
+    }
+
+    // Closing our side ends the session once the server exits.
+    to_server.shutdown().await?;
+    session.await??;
+    Ok(())
+}
+
+/// A message typed at the prompt
+struct Input<'a> {
+    method: &'a str,
+    params: Option<Value>,
+    notification: bool,
+}
+
+/// Parses a method, optionally prefixed with `!` for a notification,
+/// and its params. Blank lines give `None`.
+fn parse_line(line: &str) -> Result<Option<Input<'_>>, String> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    let (method, params) = match line.split_once(char::is_whitespace) {
+        Some((method, params)) => (method, Some(params.trim())),
+        None => (line, None),
+    };
+    let (method, notification) = match method.strip_prefix('!') {
+        Some(method) => (method, true),
+        None => (method, false),
+    };
+    if method.is_empty() {
+        return Err("Expected a method name".to_string());
+    }
+    let params = params
+        .map(serde_json::from_str)
+        .transpose()
+        .map_err(|e| format!("Invalid JSON params: {}", e))?;
+    Ok(Some(Input {
+        method,
+        params,
+        notification,
+    }))
+}
+
+async fn send<W: AsyncWriteExt + Unpin>(writer: &mut W, message: &Value) -> Result<()> {
+    writer
+        .write_all(format_lsp_message(&message.to_string()).as_bytes())
+        .await
+        .context("Failed to send to the LSP server")?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// A response with the request it answers and how long it took
+fn response(id: &str, request: &SentRequest, response: &Value) -> String {
+    let body = response
+        .get("error")
+        .or_else(|| response.get("result"))
+        .unwrap_or(&Value::Null);
+    let body = if pretty_stdout() {
+        serde_json::to_string_pretty(body).unwrap_or_else(|_| body.to_string())
+    } else {
+        body.to_string()
+    };
+    format!(
+        "<- {} {} ({}ms)\n{}\n",
+        request.method,
+        id,
+        request.sent.elapsed().as_millis(),
+        body
+    )
+}
+
+/// A notification or request from the server, on one line
+fn server_message(method: &str, message: &Value) -> String {
+    let kind = if message.get("id").is_some() {
+        "request"
+    } else {
+        "notification"
+    };
+    let params = message.get("params").map(|p| p.to_string());
+    format!("<- {} {} {}\n", kind, method, params.unwrap_or_default())
+}
+
+/// The prompt lines are read from. Reading happens on a thread of its
+/// own, as rustyline blocks, and each line is read once the last one
+/// has been sent.
+struct Prompt {
+    /// Asks the thread to read another line
+    ready: std::sync::mpsc::Sender<()>,
+    /// Prints above the prompt while a line is being typed, when
+    /// stdin and stdout are terminals
+    printer: Option<Box<dyn ExternalPrinter + Send>>,
+}
+
+impl Prompt {
+    fn new() -> Result<(Self, mpsc::UnboundedReceiver<Result<String, ReadlineError>>)> {
+        let config = Config::builder()
+            .auto_add_history(true)
+            .max_history_size(MAX_HISTORY)?
+            .build();
+        let mut editor = DefaultEditor::with_config(config)?;
+        let printer = if std::io::stdin().is_terminal() && std::io::stdout().is_terminal() {
+            editor
+                .create_external_printer()
+                .ok()
+                .map(|printer| Box::new(printer) as Box<dyn ExternalPrinter + Send>)
+        } else {
+            None
+        };
+
+        let (ready, ready_rx) = std::sync::mpsc::channel();
+        let (lines_tx, lines) = mpsc::unbounded_channel();
+        std::thread::spawn(move || read_lines(editor, ready_rx, lines_tx));
+        Ok((Self { ready, printer }, lines))
+    }
+
+    /// Shows the prompt, ready for the next line
+    fn ready(&self) {
+        let _ = self.ready.send(());
+    }
+
+    fn print(&mut self, text: String) {
+        match &mut self.printer {
+            Some(printer) => {
+                if printer.print(text).is_err() {
+                    eprintln!("Failed to print above the prompt");
+                }
+            }
+            None => print!("{}", text),
+        }
+    }
+}
+
+/// Reads a line each time `ready` is signalled, until input ends.
+/// Ctrl-C discards the line being typed.
+fn read_lines(
+    mut editor: DefaultEditor,
+    ready: std::sync::mpsc::Receiver<()>,
+    lines: mpsc::UnboundedSender<Result<String, ReadlineError>>,
+) {
+    while ready.recv().is_ok() {
+        let line = loop {
+            match editor.readline("> ") {
+                Err(ReadlineError::Interrupted) => continue,
+                line => break line,
+            }
+        };
+        match line {
+            Err(ReadlineError::Eof) => return,
+            line => {
+                let failed = line.is_err();
+                if lines.send(line).is_err() || failed {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Reads messages from the server, passing each to the prompt loop
+async fn read_messages(
+    mut from_server: ReadHalf<DuplexStream>,
+    messages: mpsc::UnboundedSender<Value>,
+) {
+    let mut buffer = vec![0u8; 8192];
+    let mut parser = LspMessageParser::new();
+
+    loop {
+        match from_server.read(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => parser.add_data(&buffer[..n]),
+        }
+
+        while let Some((_, json_payload)) = parser.try_parse_message() {
+            let Ok(message) = serde_json::from_str::<Value>(&json_payload) else {
+                eprintln!("Ignoring a message from the server that isn't JSON");
+                continue;
+            };
+            if messages.send(message).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_requests_and_notifications() {
+        assert!(parse_line("   ").unwrap().is_none());
+
+        let input = parse_line(r#"workspace/symbol {"query": "main"}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(input.method, "workspace/symbol");
+        assert_eq!(input.params, Some(serde_json::json!({"query": "main"})));
+        assert!(!input.notification);
+
+        let input = parse_line("!exit").unwrap().unwrap();
+        assert_eq!(input.method, "exit");
+        assert_eq!(input.params, None);
+        assert!(input.notification);
+
+        assert!(parse_line("!").is_err());
+        assert!(parse_line("shutdown {").is_err());
+    }
+
+    #[test]
+    fn prints_server_messages_on_one_line() {
+        let notification = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "window/logMessage",
+            "params": {"type": 3, "message": "hi"},
+        });
+        assert_eq!(
+            server_message("window/logMessage", &notification),
+            "<- notification window/logMessage {\"message\":\"hi\",\"type\":3}\n"
+        );
+        let request =
+            serde_json::json!({"jsonrpc": "2.0", "id": 4, "method": "workspace/configuration"});
+        assert_eq!(
+            server_message("workspace/configuration", &request),
+            "<- request workspace/configuration \n"
+        );
+    }
+}
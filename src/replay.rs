@@ -0,0 +1,191 @@
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::ops::Range;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::{ChildStdout, Command};
+use tokio::sync::mpsc;
+
+use crate::capture::{self, Entry};
+use crate::config::LogFormat;
+use crate::{format_lsp_message, LspMessageParser};
+
+/// Parses a range of message numbers such as `10..25`, `10..` or `..25`
+pub(crate) fn parse_range(range: &str) -> Result<Range<usize>, String> {
+    let Some((start, end)) = range.split_once("..") else {
+        return Err("expected a range such as 10..25".to_string());
+    };
+    let parse = |number: &str, default: usize| match number {
+        "" => Ok(default),
+        number => number
+            .parse()
+            .map_err(|_| format!("{:?} isn't a message number", number)),
+    };
+    Ok(parse(start, 0)?..parse(end, usize::MAX)?)
+}
+
+/// Sends the editor's messages from a capture to a new server process,
+/// to reproduce a crash. With a range, only those messages are sent,
+/// after the initialize handshake. Fails if the server exits.
+pub(crate) async fn run_replay(
+    capture_path: &Path,
+    range: Option<Range<usize>>,
+    timeout: Duration,
+    lsp_server: &str,
+    server_args: &[String],
+) -> Result<()> {
+    let messages = editor_messages(capture_path)?;
+    let range = range.unwrap_or(0..usize::MAX);
+    let range = range.start.min(messages.len())..range.end.min(messages.len());
+
+    // Servers reject everything before initialize, so send the
+    // handshake first if the range skips it.
+    let mut to_send: Vec<(usize, &Value)> = messages[..range.start]
+        .iter()
+        .enumerate()
+        .filter(|(_, message)| {
+            matches!(
+                message.get("method").and_then(|m| m.as_str()),
+                Some("initialize") | Some("initialized")
+            )
+        })
+        .collect();
+    to_send.extend(range.clone().map(|i| (i, &messages[i])));
+
+    let mut child = Command::new(lsp_server)
+        .args(server_args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Failed to spawn LSP server")?;
+    let mut child_stdin = child.stdin.take().context("Failed to open child stdin")?;
+    let child_stdout = child.stdout.take().context("Failed to open child stdout")?;
+
+    // Both our messages and replies to the server's requests are
+    // written by one task, so they aren't interleaved.
+    let (outgoing, mut outgoing_rx) = mpsc::unbounded_channel::<String>();
+    tokio::spawn(async move {
+        while let Some(json) = outgoing_rx.recv().await {
+            let frame = format_lsp_message(&json);
+            if child_stdin.write_all(frame.as_bytes()).await.is_err()
+                || child_stdin.flush().await.is_err()
+            {
+                break;
+            }
+        }
+    });
+    let (responses, mut responses_rx) = mpsc::unbounded_channel();
+    tokio::spawn(read_output(child_stdout, outgoing.clone(), responses));
+
+    println!(
+        "Replaying messages {}..{} of {} from {}",
+        range.start,
+        range.end,
+        messages.len(),
+        capture_path.display()
+    );
+    let mut unanswered = HashSet::new();
+    for (i, message) in to_send {
+        let method = message.get("method").and_then(|m| m.as_str());
+        println!("  #{} {}", i, method.unwrap_or("(response)"));
+        if let (Some(_), Some(id)) = (method, message.get("id")) {
+            unanswered.insert(id.to_string());
+        }
+        let _ = outgoing.send(message.to_string());
+    }
+
+    // Wait for the server to answer, crash, or go quiet.
+    let finished = async {
+        while !unanswered.is_empty() {
+            match responses_rx.recv().await {
+                Some(id) => {
+                    unanswered.remove(&id);
+                }
+                None => break,
+            }
+        }
+    };
+    tokio::select! {
+        _ = tokio::time::timeout(timeout, finished) => {}
+        status = child.wait() => {
+            bail!("LSP server exited during replay: {}", status?);
+        }
+    }
+    // Give a server that crashed on the last message time to exit.
+    if let Ok(status) = tokio::time::timeout(Duration::from_millis(500), child.wait()).await {
+        bail!("LSP server exited during replay: {}", status?);
+    }
+
+    if unanswered.is_empty() {
+        println!("Server answered every request and is still running");
+    } else {
+        println!(
+            "Server is still running, but didn't answer {} requests within {}s",
+            unanswered.len(),
+            timeout.as_secs()
+        );
+    }
+    Ok(())
+}
+
+/// The messages the editor sent in a capture, numbered from 0.
+/// Capture headers and empty messages aren't counted.
+fn editor_messages(path: &Path) -> Result<Vec<Value>> {
+    let format = LogFormat::from_path(path).unwrap_or(LogFormat::Raw);
+    let stream = capture::stream_from_file_name(path);
+
+    let messages = capture::read_log(path, format)?
+        .into_iter()
+        .filter_map(|entry| match entry {
+            Entry::Message(entry) => capture::unwrap_entry(stream, entry),
+            Entry::Unparsed(_) => None,
+        })
+        .filter(|(stream, message)| *stream == "stdin" && message.get("_empty").is_none())
+        .map(|(_, message)| message)
+        .collect::<Vec<_>>();
+    if messages.is_empty() {
+        bail!(
+            "No editor messages in {}, expected a stdin log",
+            path.display()
+        );
+    }
+    Ok(messages)
+}
+
+/// Reads server output, reporting the ids of responses and answering
+/// the server's own requests with a null result
+async fn read_output(
+    mut child_stdout: ChildStdout,
+    outgoing: mpsc::UnboundedSender<String>,
+    responses: mpsc::UnboundedSender<String>,
+) {
+    let mut parser = LspMessageParser::new();
+    let mut buffer = vec![0u8; 8192];
+
+    loop {
+        match child_stdout.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => parser.add_data(&buffer[..n]),
+        }
+
+        while let Some((_, json_payload)) = parser.try_parse_message() {
+            let Ok(message) = serde_json::from_str::<Value>(&json_payload) else {
+                continue;
+            };
+            match (message.get("method"), message.get("id")) {
+                (Some(_), Some(id)) => {
+                    let reply = serde_json::json!({ "jsonrpc": "2.0", "id": id, "result": null });
+                    let _ = outgoing.send(reply.to_string());
+                }
+                (None, Some(id)) => {
+                    let _ = responses.send(id.to_string());
+                }
+                _ => {}
+            }
+        }
+    }
+}
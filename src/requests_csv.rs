@@ -0,0 +1,80 @@
+use chrono::{DateTime, Local};
+
+use crate::stream::Direction;
+
+/// The columns of the requests CSV
+const HEADER: &str = "id,method,direction,sent,received,latency_ms,response_bytes,error,status";
+
+/// One request and what became of it, for `LSP_CSV_SUMMARY`
+pub(crate) struct RequestRecord {
+    pub(crate) id: String,
+    pub(crate) method: String,
+    /// The direction the request was sent in
+    pub(crate) direction: Direction,
+    pub(crate) sent: DateTime<Local>,
+    /// When the response arrived, if it did
+    pub(crate) received: Option<DateTime<Local>>,
+    pub(crate) response_bytes: Option<usize>,
+    pub(crate) error: bool,
+    /// Whether the sender cancelled the request with `$/cancelRequest`
+    pub(crate) cancelled: bool,
+}
+
+impl RequestRecord {
+    /// `answered`, `error`, `cancelled` or `unanswered`
+    fn status(&self) -> &'static str {
+        match (self.received, self.cancelled, self.error) {
+            (_, true, _) => "cancelled",
+            (None, false, _) => "unanswered",
+            (Some(_), false, true) => "error",
+            (Some(_), false, false) => "answered",
+        }
+    }
+
+    fn to_csv_row(&self) -> String {
+        let latency_ms = self
+            .received
+            .map(|received| (received - self.sent).num_milliseconds().to_string());
+        let fields = [
+            self.id.clone(),
+            self.method.clone(),
+            self.direction.stream_name().to_string(),
+            self.sent.to_rfc3339(),
+            self.received
+                .map(|received| received.to_rfc3339())
+                .unwrap_or_default(),
+            latency_ms.unwrap_or_default(),
+            self.response_bytes
+                .map(|bytes| bytes.to_string())
+                .unwrap_or_default(),
+            self.error.to_string(),
+            self.status().to_string(),
+        ];
+        fields
+            .iter()
+            .map(|field| escape(field))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// Every request in the session as CSV, in the order they were sent
+pub(crate) fn to_csv(records: &mut [RequestRecord]) -> String {
+    records.sort_by_key(|record| record.sent);
+
+    let mut csv = format!("{}\n", HEADER);
+    for record in records.iter() {
+        csv.push_str(&record.to_csv_row());
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Quote a field if it contains anything CSV treats specially
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
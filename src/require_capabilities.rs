@@ -0,0 +1,80 @@
+use serde_json::Value;
+
+use crate::plugin::Action;
+use crate::stages::{Effects, Incoming, Stage};
+
+/// `InvalidParams`, for an initialize missing required capabilities
+const INVALID_PARAMS: i64 = -32602;
+
+/// The capabilities from `LSP_REQUIRE_CAPABILITIES` that the editor's
+/// initialize params don't advertise. Each is a JSON pointer under
+/// `capabilities`, and must be present and neither null nor false.
+fn missing_capabilities<'a>(required: &'a [String], params: Option<&Value>) -> Vec<&'a str> {
+    let capabilities = params.and_then(|params| params.get("capabilities"));
+    required
+        .iter()
+        .filter(|pointer| {
+            let value = capabilities.and_then(|capabilities| capabilities.pointer(pointer));
+            matches!(value, None | Some(Value::Null) | Some(Value::Bool(false)))
+        })
+        .map(|pointer| pointer.as_str())
+        .collect()
+}
+
+/// The error response sent to the editor in place of forwarding an
+/// initialize that's missing capabilities
+fn rejection(id: &Value, missing: &[&str]) -> Value {
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {
+            "code": INVALID_PARAMS,
+            "message": format!(
+                "lsp-fiddle: initialize is missing required capabilities: {}",
+                missing.join(", ")
+            ),
+        },
+    })
+}
+
+/// Warns when the editor's initialize is missing capabilities from
+/// `LSP_REQUIRE_CAPABILITIES`, and with
+/// `LSP_REJECT_MISSING_CAPABILITIES` answers it with an error rather
+/// than forwarding it
+pub(crate) struct RequireCapabilities {
+    required: Vec<String>,
+    reject: bool,
+}
+
+impl RequireCapabilities {
+    pub(crate) fn new(required: Vec<String>, reject: bool) -> Self {
+        Self { required, reject }
+    }
+}
+
+impl Stage for RequireCapabilities {
+    fn process(&mut self, message: &Incoming, effects: &mut Effects) -> Action {
+        let Some(initialize) = message
+            .message
+            .filter(|_| message.method() == Some("initialize"))
+        else {
+            return Action::Forward;
+        };
+        let missing = missing_capabilities(&self.required, initialize.get("params"));
+        if missing.is_empty() {
+            return Action::Forward;
+        }
+        effects.warnings.push(format!(
+            "Editor's initialize is missing required capabilities (LSP_REQUIRE_CAPABILITIES): {}",
+            missing.join(", ")
+        ));
+        match initialize.get("id") {
+            Some(id) if self.reject => {
+                eprintln!("Rejecting initialize (LSP_REJECT_MISSING_CAPABILITIES)");
+                effects.replies.push(rejection(id, &missing));
+                Action::LogOnly
+            }
+            _ => Action::Forward,
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use anyhow::{bail, Result};
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// Finds the server executable, trying each of `fallbacks` in turn if
+/// `server` isn't installed. Names without a `/` are looked up on
+/// PATH. Returns an absolute path, without resolving symlinks as some
+/// servers are installed as symlinks to a proxy like rustup.
+pub(crate) fn resolve_server(server: &str, fallbacks: &[String]) -> Result<PathBuf> {
+    let candidates = std::iter::once(server).chain(fallbacks.iter().map(String::as_str));
+
+    let mut tried = vec![];
+    for candidate in candidates {
+        let Some(path) = find_executable(candidate) else {
+            tried.push(candidate);
+            continue;
+        };
+
+        let path = std::path::absolute(&path).unwrap_or(path);
+        if candidate != server {
+            eprintln!(
+                "{} not found, using fallback {} ({})",
+                server,
+                candidate,
+                path.display()
+            );
+        }
+        return Ok(path);
+    }
+
+    bail!(
+        "Couldn't find an LSP server executable, tried: {}",
+        tried.join(", ")
+    );
+}
+
+/// The `LSP_SERVER_<language>` variable holding the server when
+/// `LSP_LANGUAGE` is set, e.g. `LSP_SERVER_rust` for
+/// `LSP_LANGUAGE=rust`, so one wrapper can front a server per language.
+/// The server argument reads it instead of `LSP_SERVER`, so a server on
+/// the command line still takes precedence.
+pub(crate) fn select_language_server() -> Option<String> {
+    let language = env::var("LSP_LANGUAGE")
+        .ok()
+        .map(|language| language.trim().to_string())
+        .filter(|language| !language.is_empty())?;
+
+    // Allow `LSP_SERVER_RUST` too, as environment variables are
+    // usually upper case.
+    let names = [
+        format!("LSP_SERVER_{}", language),
+        format!("LSP_SERVER_{}", language.to_lowercase()),
+        format!("LSP_SERVER_{}", language.to_uppercase()),
+    ];
+    let server = names.iter().find_map(|name| {
+        env::var(name)
+            .ok()
+            .filter(|server| !server.is_empty())
+            .map(|server| (name, server))
+    });
+    match server {
+        Some(_) if env::var_os("LSP_SERVER").is_some_and(|server| !server.is_empty()) => {
+            eprintln!("Ignoring LSP_LANGUAGE: LSP_SERVER is set, which takes precedence");
+            None
+        }
+        Some((name, server)) => {
+            eprintln!("Using {} for LSP_LANGUAGE={}: {}", name, language, server);
+            Some(name.clone())
+        }
+        None => {
+            eprintln!("Ignoring LSP_LANGUAGE: {} isn't set", names[0]);
+            None
+        }
+    }
+}
+
+fn find_executable(name: &str) -> Option<PathBuf> {
+    let path = Path::new(name);
+    if path.components().count() > 1 {
+        return is_executable(path).then(|| path.to_path_buf());
+    }
+
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| is_executable(path))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    path.metadata()
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
@@ -0,0 +1,533 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::process::{Child, ChildStdout};
+use tokio::sync::{mpsc, watch};
+
+use crate::clock::SharedClock;
+use crate::config::LogMode;
+use crate::document_state::DocumentState;
+use crate::status::SharedServerStatus;
+use crate::{format_lsp_message, log_stderr, open_log, server_command, LspMessageParser};
+
+/// The id of the initialize request replayed to a restarted server
+const REINITIALIZE_ID: &str = "lsp-fiddle-reinitialize";
+
+/// JSON-RPC error code for a request that was valid but failed
+const REQUEST_FAILED: i64 = -32803;
+
+/// `MessageType` of an error shown to the user
+const MESSAGE_TYPE_ERROR: u8 = 1;
+
+/// How many crashes within how long means the server is flapping, and
+/// restarting it again is pointless
+#[derive(Clone, Copy)]
+pub(crate) struct FlapLimit {
+    pub(crate) crashes: usize,
+    pub(crate) window: Duration,
+}
+
+/// How to start the server again after it crashes
+pub(crate) struct Respawn {
+    pub(crate) server_path: PathBuf,
+    pub(crate) server_args: Vec<String>,
+    pub(crate) stderr_log_path: PathBuf,
+    pub(crate) max_restarts: usize,
+    pub(crate) log_mode: LogMode,
+    /// Where to send server stderr as `window/logMessage` notifications
+    pub(crate) log_messages: Option<mpsc::UnboundedSender<Value>>,
+    /// Stop restarting a server that keeps crashing
+    pub(crate) flap_limit: Option<FlapLimit>,
+    /// Updated as servers crash and start, for `LSP_STATUS_ADDR`
+    pub(crate) server_status: SharedServerStatus,
+    /// From `LSP_SERVER_WRAPPER`
+    pub(crate) server_wrapper: Vec<String>,
+    pub(crate) clock: SharedClock,
+}
+
+/// How a supervised server ended
+pub(crate) struct Supervised {
+    pub(crate) exit_status: Option<ExitStatus>,
+    /// How each crashed server exited, in order
+    pub(crate) crashes: Vec<ExitStatus>,
+    /// Restarting stopped because the server hit `LSP_FLAP_THRESHOLD`
+    pub(crate) flapping: bool,
+}
+
+/// What the editor has told the server so far, so a new server can be
+/// brought up to the same point
+struct EditorState {
+    initialize: Option<Value>,
+    initialized: bool,
+    /// The documents the editor has open, with their current text
+    documents: DocumentState,
+    /// Requests the server hasn't answered, by id
+    pending: HashMap<String, Value>,
+    /// The editor asked the server to stop, so exiting isn't a crash
+    shutting_down: bool,
+}
+
+impl EditorState {
+    fn new() -> Self {
+        Self {
+            initialize: None,
+            initialized: false,
+            documents: DocumentState::new(),
+            pending: HashMap::new(),
+            shutting_down: false,
+        }
+    }
+
+    fn observe_editor(&mut self, message: &Value) {
+        let method = message.get("method").and_then(|m| m.as_str());
+        match (method, message.get("id")) {
+            (Some("initialize"), _) => self.initialize = Some(message.clone()),
+            (Some("initialized"), None) => self.initialized = true,
+            (Some("shutdown"), _) | (Some("exit"), None) => self.shutting_down = true,
+            (Some(method), None) => {
+                self.documents.observe(method, message.get("params"));
+            }
+            _ => {}
+        }
+        if let (Some(_), Some(id)) = (method, message.get("id")) {
+            self.pending.insert(id.to_string(), id.clone());
+        }
+    }
+
+    /// Whether the server's message should be forwarded to the editor
+    fn observe_server(&mut self, message: &Value) -> bool {
+        if message.get("method").is_some() {
+            return true;
+        }
+        let Some(id) = message.get("id") else {
+            return true;
+        };
+        let initialize_id = self
+            .initialize
+            .as_ref()
+            .and_then(|initialize| initialize.get("id"));
+        if initialize_id == Some(id) || id.as_str() == Some(REINITIALIZE_ID) {
+            self.documents.observe_initialize_response(message);
+        }
+        if id.as_str() == Some(REINITIALIZE_ID) {
+            if let Some(error) = message.get("error") {
+                eprintln!(
+                    "Restarted LSP server failed to initialize: {}",
+                    error
+                        .get("message")
+                        .and_then(|message| message.as_str())
+                        .unwrap_or("no message")
+                );
+            }
+            return false;
+        }
+        self.pending.remove(&id.to_string());
+        true
+    }
+}
+
+/// A running server, with a task writing the editor's messages to it
+struct Server {
+    child: Child,
+    stdout: ChildStdout,
+    input: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl Server {
+    fn from_child(
+        mut child: Child,
+        stderr_log: File,
+        log_messages: Option<mpsc::UnboundedSender<Value>>,
+    ) -> Result<Self> {
+        let mut stdin = child.stdin.take().context("Failed to open child stdin")?;
+        let stdout = child.stdout.take().context("Failed to open child stdout")?;
+        let stderr = child.stderr.take().context("Failed to open child stderr")?;
+        tokio::spawn(log_stderr(stderr, stderr_log, log_messages, None));
+
+        // Written by a task of its own, so a server that isn't reading
+        // can't stop us forwarding its output.
+        let (input, mut input_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        tokio::spawn(async move {
+            while let Some(frame) = input_rx.recv().await {
+                if stdin.write_all(&frame).await.is_err() || stdin.flush().await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            child,
+            stdout,
+            input,
+        })
+    }
+}
+
+/// Sits between the proxy and the server, starting the server again
+/// (up to `max_restarts` times) if it exits before the editor asked it
+/// to. The new server is sent the editor's initialize handshake and
+/// open documents, and requests the old server didn't answer fail, so
+/// the editor's session stays valid.
+pub(crate) async fn supervise(
+    respawn: Respawn,
+    child: Child,
+    stderr_log: File,
+    proxy: DuplexStream,
+    mut shutdown: watch::Receiver<bool>,
+) -> Supervised {
+    let (mut proxy_read, mut proxy_write) = tokio::io::split(proxy);
+    let (to_editor, mut to_editor_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    let writer = tokio::spawn(async move {
+        while let Some(frame) = to_editor_rx.recv().await {
+            if proxy_write.write_all(&frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut crashes = vec![];
+    // When recent crashes happened, for the flap limit
+    let mut recent_crashes = VecDeque::new();
+    let mut server = match Server::from_child(child, stderr_log, respawn.log_messages.clone()) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            return Supervised {
+                exit_status: None,
+                crashes,
+                flapping: false,
+            };
+        }
+    };
+
+    let mut state = EditorState::new();
+    let mut editor_parser = LspMessageParser::new();
+    let mut server_parser = LspMessageParser::new();
+    let mut editor_buffer = vec![0u8; 8192];
+    let mut server_buffer = vec![0u8; 8192];
+    let mut editor_closed = false;
+    let mut server_closed = false;
+
+    loop {
+        tokio::select! {
+            read = proxy_read.read(&mut editor_buffer), if !editor_closed => {
+                let n = match read {
+                    Ok(0) | Err(_) => {
+                        // Close the server's stdin, so it exits.
+                        editor_closed = true;
+                        let (closed, _) = mpsc::unbounded_channel();
+                        server.input = closed;
+                        continue;
+                    }
+                    Ok(n) => n,
+                };
+                editor_parser.add_data(&editor_buffer[..n]);
+                while let Some((frame, payload)) = editor_parser.try_parse_message() {
+                    if let Ok(message) = serde_json::from_str::<Value>(&payload) {
+                        state.observe_editor(&message);
+                    }
+                    let _ = server.input.send(frame);
+                }
+            }
+            read = server.stdout.read(&mut server_buffer), if !server_closed => {
+                let n = match read {
+                    Ok(0) | Err(_) => {
+                        server_closed = true;
+                        continue;
+                    }
+                    Ok(n) => n,
+                };
+                server_parser.add_data(&server_buffer[..n]);
+                while let Some((frame, payload)) = server_parser.try_parse_message() {
+                    let forward = match serde_json::from_str::<Value>(&payload) {
+                        Ok(message) => state.observe_server(&message),
+                        Err(_) => true,
+                    };
+                    if forward {
+                        let _ = to_editor.send(frame);
+                    }
+                }
+            }
+            status = server.child.wait(), if server_closed => {
+                let status = match status {
+                    Ok(status) => status,
+                    Err(e) => {
+                        eprintln!("Failed to wait for LSP server: {}", e);
+                        return Supervised { exit_status: None, crashes, flapping: false };
+                    }
+                };
+                if editor_closed || state.shutting_down || crashes.len() >= respawn.max_restarts {
+                    return Supervised { exit_status: Some(status), crashes, flapping: false };
+                }
+
+                crashes.push(status);
+                respawn.server_status.lock().unwrap().exited(status);
+                if let Some(limit) = respawn.flap_limit {
+                    let now = respawn.clock.instant();
+                    recent_crashes.push_back(now);
+                    while recent_crashes
+                        .front()
+                        .is_some_and(|&crashed| now.duration_since(crashed) > limit.window)
+                    {
+                        recent_crashes.pop_front();
+                    }
+                    if recent_crashes.len() >= limit.crashes {
+                        let reason = format!(
+                            "LSP server crashed {} times within {}s, so lsp-fiddle stopped restarting it",
+                            recent_crashes.len(),
+                            limit.window.as_secs()
+                        );
+                        eprintln!("{} (LSP_FLAP_THRESHOLD)", reason);
+                        fail_pending(&mut state, &format!("LSP server crashed ({})", status), &to_editor);
+                        let notification = serde_json::json!({
+                            "jsonrpc": "2.0",
+                            "method": "window/showMessage",
+                            "params": {
+                                "type": MESSAGE_TYPE_ERROR,
+                                "message": format!(
+                                    "{}. Language features are unavailable until the server is started again. The server's output is in {}",
+                                    reason,
+                                    respawn.stderr_log_path.display()
+                                ),
+                            },
+                        });
+                        let _ = to_editor.send(format_lsp_message(&notification.to_string()).into_bytes());
+                        // Let the writer send everything before the
+                        // session ends.
+                        drop(to_editor);
+                        let _ = writer.await;
+                        return Supervised { exit_status: Some(status), crashes, flapping: true };
+                    }
+                }
+                eprintln!(
+                    "LSP server crashed ({}), restarting it ({} of {})",
+                    status,
+                    crashes.len(),
+                    respawn.max_restarts
+                );
+                fail_pending(
+                    &mut state,
+                    &format!("LSP server crashed ({}) and was restarted", status),
+                    &to_editor,
+                );
+
+                server = match restart(&respawn, &state).await {
+                    Ok(server) => server,
+                    Err(e) => {
+                        eprintln!("Failed to restart LSP server: {:#}", e);
+                        return Supervised { exit_status: Some(status), crashes, flapping: false };
+                    }
+                };
+                respawn.server_status.lock().unwrap().started(server.child.id());
+                server_parser = LspMessageParser::new();
+                server_closed = false;
+            }
+            _ = shutdown.changed() => {
+                if let Err(e) = server.child.start_kill() {
+                    eprintln!("Failed to kill LSP server: {}", e);
+                }
+                let exit_status = server.child.wait().await.ok();
+                return Supervised { exit_status, crashes, flapping: false };
+            }
+        }
+    }
+}
+
+/// Fail the requests the crashed server never answered, so the editor
+/// isn't left waiting
+fn fail_pending(
+    state: &mut EditorState,
+    message: &str,
+    to_editor: &mpsc::UnboundedSender<Vec<u8>>,
+) {
+    for (_, id) in state.pending.drain() {
+        let response = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {
+                "code": REQUEST_FAILED,
+                "message": message,
+            }
+        });
+        let _ = to_editor.send(format_lsp_message(&response.to_string()).into_bytes());
+    }
+}
+
+/// Start a new server, and replay the editor's initialize handshake and
+/// the documents it has open
+async fn restart(respawn: &Respawn, state: &EditorState) -> Result<Server> {
+    let child = server_command(
+        &respawn.server_wrapper,
+        &respawn.server_path,
+        &respawn.server_args,
+    )
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .context("Failed to spawn LSP server")?;
+    let stderr_log = open_log(&respawn.stderr_log_path, "stderr", respawn.log_mode).await?;
+    let server = Server::from_child(child, stderr_log, respawn.log_messages.clone())?;
+
+    if let Some(initialize) = &state.initialize {
+        let mut initialize = initialize.clone();
+        initialize["id"] = REINITIALIZE_ID.into();
+        let _ = server
+            .input
+            .send(format_lsp_message(&initialize.to_string()).into_bytes());
+        if state.initialized {
+            let initialized = serde_json::json!({
+                "jsonrpc": "2.0",
+                "method": "initialized",
+                "params": {}
+            });
+            let _ = server
+                .input
+                .send(format_lsp_message(&initialized.to_string()).into_bytes());
+
+            let (did_opens, diverged) = state.documents.reopen();
+            for did_open in &did_opens {
+                let _ = server
+                    .input
+                    .send(format_lsp_message(&did_open.to_string()).into_bytes());
+            }
+            if !did_opens.is_empty() {
+                eprintln!(
+                    "Reopened {} documents in the new LSP server",
+                    did_opens.len()
+                );
+            }
+            for uri in diverged {
+                eprintln!(
+                    "Couldn't reopen {} in the new LSP server: its changes didn't apply, so its text isn't known",
+                    uri
+                );
+            }
+        }
+    }
+    Ok(server)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use std::sync::Arc;
+    use tokio::io::DuplexStream;
+    use tokio::process::Command;
+
+    /// Reads messages from the proxy side until one matches `wanted`
+    async fn read_until(
+        editor: &mut DuplexStream,
+        parser: &mut LspMessageParser,
+        wanted: impl Fn(&Value) -> bool,
+    ) -> Value {
+        let mut buffer = vec![0u8; 8192];
+        loop {
+            while let Some((_, payload)) = parser.try_parse_message() {
+                let message: Value = serde_json::from_str(&payload).unwrap();
+                if wanted(&message) {
+                    return message;
+                }
+            }
+            let n = tokio::time::timeout(Duration::from_secs(10), editor.read(&mut buffer))
+                .await
+                .expect("timed out waiting for the server")
+                .unwrap();
+            assert_ne!(n, 0, "supervisor closed the editor's stream");
+            parser.add_data(&buffer[..n]);
+        }
+    }
+
+    fn is_method(method: &'static str) -> impl Fn(&Value) -> bool {
+        move |message| message["method"] == method
+    }
+
+    #[tokio::test]
+    async fn reopens_documents_in_a_restarted_server() {
+        // `cat` echoes like `lsp-fiddle echo-server`, which unit tests
+        // can't run, so the replayed messages come back to the editor.
+        let child = Command::new("cat")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let pid = child.id().unwrap();
+        let stderr_log_path = std::env::temp_dir().join(format!(
+            "lsp-fiddle-test-{}-respawn_stderr.log",
+            std::process::id()
+        ));
+        let stderr_log = File::create(&stderr_log_path).await.unwrap();
+        let respawn = Respawn {
+            server_path: PathBuf::from("cat"),
+            server_args: vec![],
+            stderr_log_path: stderr_log_path.clone(),
+            max_restarts: 1,
+            log_mode: LogMode::default(),
+            log_messages: None,
+            flap_limit: None,
+            server_status: Default::default(),
+            server_wrapper: vec![],
+            clock: Arc::new(SystemClock),
+        };
+        let (proxy, mut editor) = tokio::io::duplex(64 * 1024);
+        let (_shutdown_sender, shutdown) = watch::channel(false);
+        let supervisor = tokio::spawn(supervise(respawn, child, stderr_log, proxy, shutdown));
+
+        let document = serde_json::json!({"uri": "file:///a.rs", "version": 2});
+        for message in [
+            serde_json::json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}}),
+            serde_json::json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}),
+            serde_json::json!({"jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {
+                "textDocument": {"uri": "file:///a.rs", "languageId": "rust", "version": 1, "text": "fn"},
+            }}),
+            serde_json::json!({"jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+                "textDocument": document,
+                "contentChanges": [{"text": "fn main() {}"}],
+            }}),
+        ] {
+            editor
+                .write_all(format_lsp_message(&message.to_string()).as_bytes())
+                .await
+                .unwrap();
+        }
+        let mut parser = LspMessageParser::new();
+        read_until(
+            &mut editor,
+            &mut parser,
+            is_method("textDocument/didChange"),
+        )
+        .await;
+
+        let killed = std::process::Command::new("kill")
+            .args(["-9", &pid.to_string()])
+            .status()
+            .unwrap();
+        assert!(killed.success());
+        let reinitialize = read_until(&mut editor, &mut parser, is_method("initialize")).await;
+        assert_eq!(reinitialize["id"], REINITIALIZE_ID);
+        read_until(&mut editor, &mut parser, is_method("initialized")).await;
+        let did_open =
+            read_until(&mut editor, &mut parser, is_method("textDocument/didOpen")).await;
+        assert_eq!(
+            did_open["params"]["textDocument"],
+            serde_json::json!({
+                "uri": "file:///a.rs",
+                "languageId": "rust",
+                "version": 2,
+                "text": "fn main() {}",
+            })
+        );
+
+        drop(editor);
+        let supervised = supervisor.await.unwrap();
+        assert_eq!(supervised.crashes.len(), 1);
+        let _ = std::fs::remove_file(&stderr_log_path);
+    }
+}
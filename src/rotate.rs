@@ -0,0 +1,112 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration as ChronoDuration, DurationRound, Local, NaiveTime};
+use std::path::PathBuf;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::time::Instant;
+
+use crate::clock::SharedClock;
+use crate::config::{LogMode, ProxyConfig, Rotation};
+use crate::open_log;
+
+/// Opens a new log for one stream at each hourly or daily boundary,
+/// and reopens the current one on SIGHUP. Logs after the first are
+/// named after the period they cover, e.g.
+/// `<timestamp>_stdin_2024_05_01_14.jsonl`.
+pub(crate) struct LogRotation {
+    rotation: Option<Rotation>,
+    log_dir: PathBuf,
+    /// Start of each file name, e.g. `<timestamp>_stdin`
+    prefix: String,
+    extension: &'static str,
+    /// Written at the start of each new log
+    header: Option<String>,
+    mode: LogMode,
+    clock: SharedClock,
+    next: Option<Instant>,
+    /// The log being written to
+    path: PathBuf,
+}
+
+impl LogRotation {
+    pub(crate) fn new(
+        prefix: String,
+        extension: &'static str,
+        header: Option<String>,
+        path: PathBuf,
+        config: &ProxyConfig,
+    ) -> Self {
+        let clock = config.clock.clone();
+        Self {
+            rotation: config.rotate,
+            log_dir: config.log_dir.clone(),
+            prefix,
+            extension,
+            header,
+            mode: config.log_mode,
+            next: config
+                .rotate
+                .map(|rotation| next_boundary(rotation, clock.now(), clock.instant())),
+            clock,
+            path,
+        }
+    }
+
+    /// When the current log should be replaced, if it's rotated
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.next
+    }
+
+    /// Open the log for the current period
+    pub(crate) async fn open_next(&mut self) -> Result<(File, PathBuf)> {
+        let Some(rotation) = self.rotation else {
+            return self.reopen().await;
+        };
+        let now = self.clock.now();
+        self.next = Some(next_boundary(rotation, now, self.clock.instant()));
+
+        let period = match rotation {
+            Rotation::Hourly => now.format("%Y_%m_%d_%H"),
+            Rotation::Daily => now.format("%Y_%m_%d"),
+        };
+        self.path = self
+            .log_dir
+            .join(format!("{}_{}.{}", self.prefix, period, self.extension));
+        self.reopen().await
+    }
+
+    /// Open the current log's path again, creating a new file if it has
+    /// been moved away
+    pub(crate) async fn reopen(&mut self) -> Result<(File, PathBuf)> {
+        let mut file = open_log(&self.path, &self.prefix, self.mode).await?;
+        if let Some(header) = &self.header {
+            file.write_all(header.as_bytes())
+                .await
+                .context("Failed to write capture header")?;
+        }
+        Ok((file, self.path.clone()))
+    }
+}
+
+/// The start of the next hour or day after `now`, in local time, as
+/// an instant after `instant`, the same moment on the monotonic clock
+fn next_boundary(rotation: Rotation, now: DateTime<Local>, instant: Instant) -> Instant {
+    let next = match rotation {
+        Rotation::Hourly => now
+            .duration_trunc(ChronoDuration::hours(1))
+            .ok()
+            .map(|hour| hour + ChronoDuration::hours(1)),
+        Rotation::Daily => now.date_naive().succ_opt().and_then(|tomorrow| {
+            tomorrow
+                .and_time(NaiveTime::MIN)
+                .and_local_timezone(Local)
+                .earliest()
+        }),
+    };
+    // If the boundary can't be represented, e.g. midnight is skipped by
+    // a DST change, try again in an hour.
+    let wait = next
+        .and_then(|next| (next - now).to_std().ok())
+        .unwrap_or(std::time::Duration::from_secs(60 * 60));
+    instant + wait
+}
@@ -0,0 +1,290 @@
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+
+use crate::format_lsp_message;
+
+/// How long to wait for the proxy to echo everything back
+const ROUND_TRIP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A small xorshift generator, so a failing stream can be reproduced
+/// from its seed
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number in `0..n`
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// Runs the proxy against an echo server, checking that a random stream
+/// of messages comes back byte for byte, and that the raw and JSON
+/// Lines logs match what was sent
+pub(crate) async fn run_selftest(messages: usize, seed: Option<u64>) -> Result<()> {
+    let seed =
+        seed.unwrap_or_else(|| chrono::Local::now().timestamp_nanos_opt().unwrap_or(1) as u64);
+    // Xorshift gets stuck at zero.
+    let mut rng = Rng(seed.max(1));
+    let sent: Vec<Value> = (0..messages).map(|i| random_message(&mut rng, i)).collect();
+    let stream: Vec<u8> = sent
+        .iter()
+        .flat_map(|message| format_lsp_message(&message.to_string()).into_bytes())
+        .collect();
+    println!(
+        "Sending {} messages ({} bytes) through the proxy, seed {}",
+        messages,
+        stream.len(),
+        seed
+    );
+
+    let log_dir = env::temp_dir().join(format!("lsp-fiddle-selftest-{}", std::process::id()));
+    let result = async {
+        for json_lines in [false, true] {
+            let format = if json_lines { "JSON Lines" } else { "raw" };
+            let run_dir = log_dir.join(if json_lines { "jsonl" } else { "raw" });
+            round_trip(&stream, &run_dir, json_lines, &mut rng)
+                .await
+                .with_context(|| {
+                    format!("Self test failed with {} logs (seed {})", format, seed)
+                })?;
+            check_logs(&run_dir, &stream, &sent, json_lines).with_context(|| {
+                format!("Self test failed with {} logs (seed {})", format, seed)
+            })?;
+            println!("  {} logs: ok", format);
+        }
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    let _ = fs::remove_dir_all(&log_dir);
+    result?;
+
+    println!("Self test passed");
+    Ok(())
+}
+
+/// A valid message with random content, sized from a few bytes to tens
+/// of kilobytes so messages span reads
+fn random_message(rng: &mut Rng, i: usize) -> Value {
+    let methods = [
+        "textDocument/didChange",
+        "textDocument/hover",
+        "$/progress",
+        "workspace/symbol",
+    ];
+    let method = methods[rng.below(methods.len())];
+
+    let size = match rng.below(10) {
+        0 => rng.below(10_000),
+        1..=3 => rng.below(2_000),
+        _ => rng.below(100),
+    };
+    let text = random_text(rng, size);
+    let params = serde_json::json!({
+        "text": text,
+        "numbers": (0..rng.below(5)).map(|_| rng.next() as i64 % 1_000_000).collect::<Vec<_>>(),
+        "nested": { "flag": rng.below(2) == 1, "empty": null },
+    });
+
+    match rng.below(3) {
+        0 => serde_json::json!({ "jsonrpc": "2.0", "method": method, "params": params }),
+        1 => serde_json::json!({ "jsonrpc": "2.0", "id": i, "method": method, "params": params }),
+        _ => serde_json::json!({ "jsonrpc": "2.0", "id": format!("req-{}", i), "result": params }),
+    }
+}
+
+/// Text mixing ASCII, escapes and multibyte characters, which must
+/// survive byte for byte
+fn random_text(rng: &mut Rng, chars: usize) -> String {
+    let alphabet = [
+        "a",
+        "Z",
+        "0",
+        " ",
+        "\n",
+        "\r\n",
+        "\t",
+        "\"",
+        "\\",
+        "{",
+        "}",
+        "é",
+        "λ",
+        "中",
+        "🦀",
+        "Content-Length: 12\r\n\r\n",
+    ];
+    (0..chars)
+        .map(|_| alphabet[rng.below(alphabet.len())])
+        .collect()
+}
+
+/// Send the stream through a proxy session in random sized writes,
+/// checking the echo comes back unchanged
+async fn round_trip(stream: &[u8], log_dir: &Path, json_lines: bool, rng: &mut Rng) -> Result<()> {
+    let exe = env::current_exe().context("Failed to find the lsp-fiddle executable")?;
+    let mut command = Command::new(&exe);
+    command
+        .args(["proxy", "--"])
+        .arg(&exe)
+        .arg("echo-server")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    // The user's settings could legitimately change the traffic.
+    for (name, _) in env::vars_os() {
+        if name.to_string_lossy().starts_with("LSP_") {
+            command.env_remove(name);
+        }
+    }
+    command.env("LSP_LOG_DIR", log_dir);
+    if json_lines {
+        command.env("LSP_JSON_LINES", "1");
+    }
+    let mut proxy = command.spawn().context("Failed to spawn the proxy")?;
+    let mut proxy_stdin = proxy.stdin.take().context("Failed to open proxy stdin")?;
+    let mut proxy_stdout = proxy.stdout.take().context("Failed to open proxy stdout")?;
+    let mut proxy_stderr = proxy.stderr.take().context("Failed to open proxy stderr")?;
+    let stderr = tokio::spawn(async move {
+        let mut stderr = String::new();
+        let _ = proxy_stderr.read_to_string(&mut stderr).await;
+        stderr
+    });
+
+    let chunks = {
+        let mut chunks = vec![];
+        let mut start = 0;
+        while start < stream.len() {
+            let end = (start + 1 + rng.below(20_000)).min(stream.len());
+            chunks.push(stream[start..end].to_vec());
+            start = end;
+        }
+        chunks
+    };
+    let writer = tokio::spawn(async move {
+        for chunk in chunks {
+            proxy_stdin.write_all(&chunk).await?;
+            proxy_stdin.flush().await?;
+        }
+        // Keep stdin open until the echo is read, as closing it ends
+        // the session.
+        Ok::<_, std::io::Error>(proxy_stdin)
+    });
+
+    let mut received = vec![0; stream.len()];
+    let read =
+        tokio::time::timeout(ROUND_TRIP_TIMEOUT, proxy_stdout.read_exact(&mut received)).await;
+    let proxy_stdin = writer.await?.context("Failed to write to the proxy")?;
+    drop(proxy_stdin);
+
+    let status = tokio::time::timeout(ROUND_TRIP_TIMEOUT, proxy.wait()).await;
+    if status.is_err() {
+        let _ = proxy.start_kill();
+    }
+    let stderr = stderr.await.unwrap_or_default();
+
+    match read {
+        Err(_) => bail!("Timed out waiting for the echo\n{}", stderr),
+        Ok(Err(e)) => bail!("Proxy output ended early: {}\n{}", e, stderr),
+        Ok(Ok(_)) => {}
+    }
+    if let Some(offset) = first_difference(stream, &received) {
+        bail!("Echo differs from the input at byte {}", offset);
+    }
+    match status {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e).context("Failed to wait for the proxy"),
+        Err(_) => bail!("Proxy didn't exit after stdin closed"),
+    }
+}
+
+/// Both logs should hold exactly the stream, as the echo server sends
+/// back what it's given
+fn check_logs(log_dir: &Path, stream: &[u8], sent: &[Value], json_lines: bool) -> Result<()> {
+    for name in ["stdin", "stdout"] {
+        let extension = if json_lines { "jsonl" } else { "log" };
+        let path = find_log(log_dir, &format!("_{}.{}", name, extension))?;
+        let contents =
+            fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+
+        if !json_lines {
+            if let Some(offset) = first_difference(stream, &contents) {
+                bail!(
+                    "{} differs from the input at byte {}",
+                    path.display(),
+                    offset
+                );
+            }
+            continue;
+        }
+
+        let logged: Vec<Value> = String::from_utf8_lossy(&contents)
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()
+            .with_context(|| format!("{} has an invalid line", path.display()))?;
+        if logged.len() != sent.len() {
+            bail!(
+                "{} has {} messages, expected {}",
+                path.display(),
+                logged.len(),
+                sent.len()
+            );
+        }
+        if let Some(i) = (0..sent.len()).find(|&i| logged[i] != sent[i]) {
+            bail!("Message {} in {} differs from the input", i, path.display());
+        }
+    }
+    Ok(())
+}
+
+fn find_log(log_dir: &Path, suffix: &str) -> Result<PathBuf> {
+    let entries =
+        fs::read_dir(log_dir).with_context(|| format!("Failed to read {}", log_dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.to_string_lossy().ends_with(suffix) {
+            return Ok(path);
+        }
+    }
+    bail!("No *{} log in {}", suffix, log_dir.display())
+}
+
+/// Where two byte strings first differ, including one being shorter
+fn first_difference(expected: &[u8], actual: &[u8]) -> Option<usize> {
+    match expected.iter().zip(actual).position(|(a, b)| a != b) {
+        Some(offset) => Some(offset),
+        None if expected.len() != actual.len() => Some(expected.len().min(actual.len())),
+        None => None,
+    }
+}
+
+/// The server used by the self test, which writes back exactly what it
+/// reads
+pub(crate) async fn run_echo_server() -> Result<()> {
+    let mut stdin = tokio::io::stdin();
+    let mut stdout = tokio::io::stdout();
+    let mut buffer = vec![0u8; 8192];
+    loop {
+        let n = stdin.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        stdout.write_all(&buffer[..n]).await?;
+        stdout.flush().await?;
+    }
+}
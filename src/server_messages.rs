@@ -0,0 +1,78 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::console::{paint, Color};
+
+/// How many error messages to keep for the summary
+const MAX_ERRORS: usize = 20;
+
+/// `window/showMessage` and `window/logMessage` notifications from the
+/// server, which carry the errors it wants the user to see
+#[derive(Default)]
+pub(crate) struct ServerMessages {
+    /// How many messages of each severity were sent, by method
+    counts: BTreeMap<String, BTreeMap<&'static str, usize>>,
+    /// The first error messages, in order
+    errors: Vec<String>,
+}
+
+impl ServerMessages {
+    /// Record a notification from the server, printing it if it's
+    /// shown to the user or is an error or warning
+    pub(crate) fn observe(&mut self, method: &str, message: &Value) {
+        let shown = match method {
+            "window/showMessage" => true,
+            "window/logMessage" => false,
+            _ => return,
+        };
+        let severity = severity(message.pointer("/params/type").and_then(|t| t.as_u64()));
+        let text = message
+            .pointer("/params/message")
+            .and_then(|text| text.as_str())
+            .unwrap_or_default();
+
+        *self
+            .counts
+            .entry(method.to_string())
+            .or_default()
+            .entry(severity)
+            .or_default() += 1;
+        if severity == "error" && self.errors.len() < MAX_ERRORS {
+            self.errors.push(text.to_string());
+        }
+
+        if shown || matches!(severity, "error" | "warning") {
+            let kind = if shown { "show" } else { "log" };
+            let label = format!("[LSP {} {}]", kind, severity);
+            eprintln!("{} {}", colored(&label, severity), text);
+        }
+    }
+
+    pub(crate) fn summary_json(&self) -> Value {
+        serde_json::json!({
+            "counts": self.counts,
+            "errors": self.errors,
+        })
+    }
+}
+
+/// The name of a `MessageType`
+fn severity(message_type: Option<u64>) -> &'static str {
+    match message_type {
+        Some(1) => "error",
+        Some(2) => "warning",
+        Some(3) => "info",
+        Some(4) => "log",
+        Some(5) => "debug",
+        _ => "unknown",
+    }
+}
+
+/// Errors in red and warnings in yellow
+fn colored(label: &str, severity: &str) -> String {
+    match severity {
+        "error" => paint(label, Color::Red),
+        "warning" => paint(label, Color::Yellow),
+        _ => label.to_string(),
+    }
+}
@@ -0,0 +1,907 @@
+use chrono::{DateTime, Local};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::alerts::TrafficMonitor;
+use crate::client_capabilities::ClientCapabilities;
+use crate::clock::SharedClock;
+use crate::config::ProxyConfig;
+use crate::console::{paint, Color};
+use crate::dedup::DuplicateDetector;
+use crate::diagnostics::DiagnosticsTimeline;
+use crate::document_state::DocumentState;
+use crate::init_options::{self, PreviousOptions};
+use crate::latency::Latencies;
+use crate::phase_markers::{PhaseMarkers, Source};
+use crate::phases::Phases;
+use crate::progress::Progress;
+use crate::registrations::Registrations;
+use crate::requests_csv::{self, RequestRecord};
+use crate::server_messages::ServerMessages;
+use crate::stream::Direction;
+use crate::strict_length::StrictLength;
+use crate::trace::Trace;
+
+/// What we've seen of the traffic between the editor and the server,
+/// shared between the stream tasks
+pub(crate) struct Session {
+    handshake: Handshake,
+    /// Requests that haven't been answered yet, keyed by the direction
+    /// the request travelled and its id
+    outstanding: HashMap<(Direction, String), PendingRequest>,
+    /// Requests that have been answered, keyed like `outstanding`, to
+    /// tell a duplicate response from one to a request never sent
+    answered_ids: HashSet<(Direction, String)>,
+    /// Where latencies and timestamps are read from
+    clock: SharedClock,
+    /// Responses to requests that had already been answered
+    duplicate_responses: usize,
+    /// Responses to requests that were never sent
+    unknown_responses: usize,
+    /// How much of an unfinished message each stream ended with
+    truncated_messages: BTreeMap<&'static str, usize>,
+    /// Messages left out of the logs by `LSP_LOG_MAX_BYTES_PER_SEC`,
+    /// by stream
+    dropped_log_messages: BTreeMap<&'static str, usize>,
+    /// Problems noticed during the session, reported in the summary
+    warnings: Vec<String>,
+    traffic: TrafficMonitor,
+    /// How many `textDocument/*` messages mentioned each document
+    documents: BTreeMap<String, usize>,
+    duplicates: Option<DuplicateDetector>,
+    /// The `initializationOptions` the editor sent
+    initialization_options: Option<serde_json::Value>,
+    /// Options from the previous session in the log directory, to
+    /// compare against
+    previous_options: Option<PreviousOptions>,
+    /// How the options differ from the previous session's
+    options_changes: Option<Vec<String>>,
+    /// Capabilities the server registered dynamically
+    registrations: Registrations,
+    client_capabilities: ClientCapabilities,
+    trace: Option<Trace>,
+    /// When the server was started, or connected to
+    server_started: Instant,
+    /// How long after the server started it answered initialize
+    initialize_response: Option<Duration>,
+    /// How long after the server started it first published
+    /// diagnostics
+    first_diagnostics: Option<Duration>,
+    /// How long after the server started the editor sent
+    /// `initialized`, completing the handshake
+    handshake_complete: Option<Duration>,
+    /// Traffic before and after the handshake, if `LSP_PHASE_METRICS`
+    /// is set
+    phases: Option<Phases>,
+    /// Labelled phases of the session, from `LSP_PHASE_MARKERS` or
+    /// `LSP_PHASE_FIFO`
+    phase_markers: Option<PhaseMarkers>,
+    /// Messages seen in each stream by method, with responses under
+    /// their request's method
+    message_counts: BTreeMap<&'static str, BTreeMap<String, usize>>,
+    /// How long the server took to send its most recent response, or
+    /// None if it didn't match a request
+    last_response_latency: Option<Duration>,
+    /// The most recent error response, for `LSP_STATUS_ADDR`
+    last_error: Option<serde_json::Value>,
+    /// Messages the server wants the user to see
+    server_messages: ServerMessages,
+    /// The server's `$/progress` operations
+    progress: Progress,
+    /// Junk between the server's messages, if `LSP_STRICT_LENGTH` is
+    /// set
+    strict_length: Option<StrictLength>,
+    /// How long the server took to answer each method
+    latencies: Latencies,
+    /// Requests that have been answered, kept for `LSP_CSV_SUMMARY`
+    answered: Option<Vec<RequestRecord>>,
+    /// The editor's documents, rebuilt from its sync notifications
+    document_state: Option<DocumentState>,
+    diagnostics: Option<DiagnosticsTimeline>,
+}
+
+/// Progress of the initialize request, which must succeed before the
+/// server does anything useful
+enum Handshake {
+    NotStarted,
+    Pending {
+        id: serde_json::Value,
+        sent: Instant,
+        warned_timeout: bool,
+        warned_early_request: bool,
+    },
+    Succeeded {
+        latency: Duration,
+    },
+    Failed {
+        error: serde_json::Value,
+    },
+}
+
+struct PendingRequest {
+    method: String,
+    id: serde_json::Value,
+    sent: Instant,
+    /// `sent` as a wall clock time, for reports
+    sent_at: DateTime<Local>,
+    cancelled: bool,
+    /// The labelled phase the request was sent in
+    phase: Option<String>,
+}
+
+impl PendingRequest {
+    fn record(&self, direction: Direction) -> RequestRecord {
+        RequestRecord {
+            id: match &self.id {
+                serde_json::Value::String(id) => id.clone(),
+                id => id.to_string(),
+            },
+            method: self.method.clone(),
+            direction,
+            sent: self.sent_at,
+            received: None,
+            response_bytes: None,
+            error: false,
+            cancelled: self.cancelled,
+        }
+    }
+}
+
+pub(crate) type SharedSession = Arc<Mutex<Session>>;
+
+impl Session {
+    pub(crate) fn new(
+        config: &ProxyConfig,
+        previous_options: Option<PreviousOptions>,
+        server_started: Instant,
+        phase_markers: Option<PhaseMarkers>,
+    ) -> Self {
+        Self {
+            handshake: Handshake::NotStarted,
+            outstanding: HashMap::new(),
+            answered_ids: HashSet::new(),
+            clock: config.clock.clone(),
+            duplicate_responses: 0,
+            unknown_responses: 0,
+            truncated_messages: BTreeMap::new(),
+            dropped_log_messages: BTreeMap::new(),
+            warnings: vec![],
+            traffic: TrafficMonitor::new(config.alert_thresholds),
+            documents: BTreeMap::new(),
+            duplicates: config
+                .dedup_window
+                .map(|window| DuplicateDetector::new(window, config.clock.instant())),
+            initialization_options: None,
+            previous_options,
+            options_changes: None,
+            registrations: Registrations::default(),
+            client_capabilities: ClientCapabilities::default(),
+            trace: config.trace.then(|| Trace::new(config.clock.instant())),
+            server_started,
+            initialize_response: None,
+            first_diagnostics: None,
+            handshake_complete: None,
+            phases: config.phase_metrics.then(Phases::default),
+            phase_markers,
+            message_counts: BTreeMap::new(),
+            last_response_latency: None,
+            last_error: None,
+            server_messages: ServerMessages::default(),
+            progress: Progress::default(),
+            strict_length: config.strict_length.then(StrictLength::default),
+            latencies: Latencies::default(),
+            answered: config.csv_summary.then(Vec::new),
+            document_state: config.reconstruct_documents.then(DocumentState::new),
+            diagnostics: config
+                .diagnostics_timeline
+                .then(DiagnosticsTimeline::default),
+        }
+    }
+
+    /// How long it's been since `since`, by the session's clock
+    fn elapsed(&self, since: Instant) -> Duration {
+        self.clock.instant().duration_since(since)
+    }
+
+    /// Print a warning and keep it for the summary
+    pub(crate) fn warn(&mut self, warning: String) {
+        eprintln!("{} {}", paint("WARNING:", Color::Yellow), warning);
+        self.warnings.push(warning);
+    }
+
+    /// Update the session with a message passing through the proxy,
+    /// parsed from `payload`. Returns the method of the message, or
+    /// for a response, the method of the request it answers.
+    pub(crate) fn observe(
+        &mut self,
+        direction: Direction,
+        message: &serde_json::Value,
+        payload: &str,
+    ) -> Option<String> {
+        let method = self.observe_message(direction, message, payload);
+
+        let initialized = direction == Direction::ToServer
+            && message.get("id").is_none()
+            && method.as_deref() == Some("initialized");
+        if let Some(phases) = self.phases.as_mut() {
+            phases.observe(direction, method.as_deref(), payload.len());
+        }
+        if let Some(phase_markers) = self.phase_markers.as_mut() {
+            phase_markers.observe(direction == Direction::ToServer, message, self.clock.now());
+        }
+        if initialized {
+            self.observe_initialized();
+        }
+        if let Some(method) = &method {
+            *self
+                .message_counts
+                .entry(direction.stream_name())
+                .or_default()
+                .entry(method.clone())
+                .or_default() += 1;
+        }
+        method
+    }
+
+    fn observe_message(
+        &mut self,
+        direction: Direction,
+        message: &serde_json::Value,
+        payload: &str,
+    ) -> Option<String> {
+        if let Some(alert) = self.traffic.payload(direction.stream_name(), payload.len()) {
+            self.warn(alert);
+        }
+
+        if let Some(duplicates) = self.duplicates.as_mut() {
+            if duplicates.is_duplicate(direction, message, payload, self.clock.instant()) {
+                self.warn(format!(
+                    "{} message sent twice: {}",
+                    direction.stream_name(),
+                    truncate(payload, 200)
+                ));
+            }
+        }
+
+        let method = message.get("method").and_then(|m| m.as_str());
+        let id = message.get("id");
+
+        if method.is_some_and(|method| method.starts_with("textDocument/")) {
+            self.count_document(message);
+        }
+
+        match (method, id) {
+            (Some(method), Some(id)) => {
+                if direction == Direction::ToServer && method == "initialize" {
+                    self.observe_initialization_options(message);
+                    self.client_capabilities
+                        .observe_initialize(message.get("params"));
+                }
+                if direction == Direction::ToEditor {
+                    self.observe_registration(method, message);
+                    if let Some(warning) = self.client_capabilities.check_request(method) {
+                        self.warn(warning);
+                    }
+                }
+                self.observe_request(direction, method, id);
+                Some(method.to_string())
+            }
+            (Some(method), None) => {
+                if let Some(trace) = self.trace.as_mut() {
+                    trace.notification(direction, method, self.clock.instant());
+                }
+                if method == "$/cancelRequest" {
+                    self.observe_cancel(direction, message);
+                }
+                if direction == Direction::ToEditor {
+                    self.server_messages.observe(method, message);
+                    if method == "$/progress" {
+                        self.progress.observe(
+                            message.get("params"),
+                            self.clock.instant(),
+                            self.clock.now(),
+                        );
+                    }
+                    if method == "textDocument/publishDiagnostics" {
+                        self.observe_diagnostics();
+                        if let Some(diagnostics) = self.diagnostics.as_mut() {
+                            diagnostics.observe_publish(message.get("params"), self.clock.now());
+                        }
+                    }
+                }
+                if direction == Direction::ToServer && method == "textDocument/didChange" {
+                    if let Some(alert) = self.traffic.did_change(self.clock.instant()) {
+                        self.warn(alert);
+                    }
+                    if let Some(diagnostics) = self.diagnostics.as_mut() {
+                        diagnostics.observe_change(message.get("params"));
+                    }
+                }
+                if direction == Direction::ToServer {
+                    if let Some(document_state) = self.document_state.as_mut() {
+                        for warning in document_state.observe(method, message.get("params")) {
+                            self.warn(warning);
+                        }
+                    }
+                }
+                Some(method.to_string())
+            }
+            (None, Some(id)) => {
+                // Responses travel in the opposite direction to their
+                // request.
+                let key = (direction.opposite(), id.to_string());
+                let request = self.outstanding.remove(&key);
+                match &request {
+                    Some(_) => {
+                        self.answered_ids.insert(key);
+                    }
+                    // Errors about unparseable requests have a null id.
+                    None if !id.is_null() => self.observe_unmatched_response(direction, &key),
+                    None => {}
+                }
+                if let (Some(trace), Some(request)) = (self.trace.as_mut(), &request) {
+                    let outcome = match message.get("error") {
+                        Some(_) => "error",
+                        None => "result",
+                    };
+                    trace.request(
+                        direction.opposite(),
+                        &request.method,
+                        id,
+                        request.sent,
+                        self.clock.instant(),
+                        outcome,
+                    );
+                }
+                if direction == Direction::ToEditor {
+                    self.last_response_latency =
+                        request.as_ref().map(|request| self.elapsed(request.sent));
+                }
+                // Cancelled requests are often answered straight away
+                // with an error, which would hide how slow they were.
+                if let (Direction::ToEditor, Some(request)) = (direction, &request) {
+                    if !request.cancelled {
+                        let latency = self.elapsed(request.sent);
+                        self.latencies.record(&request.method, latency);
+                        if let (Some(phase_markers), Some(phase)) =
+                            (self.phase_markers.as_mut(), &request.phase)
+                        {
+                            phase_markers.record_latency(phase, &request.method, latency);
+                        }
+                    }
+                }
+                if let (Some(answered), Some(request)) = (self.answered.as_mut(), &request) {
+                    let mut record = request.record(direction.opposite());
+                    record.received = Some(self.clock.now());
+                    record.response_bytes = Some(payload.len());
+                    record.error = message.get("error").is_some();
+                    answered.push(record);
+                }
+                if let Some(error) = message.get("error") {
+                    self.last_error = Some(serde_json::json!({
+                        "stream": direction.stream_name(),
+                        "id": id,
+                        "method": request.as_ref().map(|request| &request.method),
+                        "error": error,
+                        "received": self.clock.now().to_rfc3339(),
+                    }));
+                }
+                if direction == Direction::ToEditor {
+                    self.check_outstanding_requests();
+                }
+
+                if direction == Direction::ToEditor {
+                    self.observe_response(id, message);
+                }
+                request.map(|request| request.method)
+            }
+            (None, None) => None,
+        }
+    }
+
+    /// Start a labelled phase, if phase markers are enabled
+    pub(crate) fn start_phase(&mut self, label: &str, source: Source) {
+        let now = self.clock.now();
+        if let Some(phase_markers) = self.phase_markers.as_mut() {
+            phase_markers.start(label, source, now);
+        }
+    }
+
+    /// Record that a message wasn't logged because logging was over
+    /// `LSP_LOG_MAX_BYTES_PER_SEC`. Warns the first time for each
+    /// stream.
+    pub(crate) fn observe_log_dropped(&mut self, direction: Direction) {
+        let dropped = self
+            .dropped_log_messages
+            .entry(direction.stream_name())
+            .or_default();
+        *dropped += 1;
+        if *dropped == 1 {
+            self.warn(format!(
+                "Logging {} faster than LSP_LOG_MAX_BYTES_PER_SEC, leaving messages out of the logs",
+                direction.stream_name()
+            ));
+        }
+    }
+
+    /// Record that a stream ended `bytes` into a message, e.g. because
+    /// the server crashed while writing it
+    pub(crate) fn observe_truncated(&mut self, direction: Direction, bytes: usize) {
+        self.truncated_messages
+            .insert(direction.stream_name(), bytes);
+        self.warn(format!(
+            "{} ended part way through a message, {} bytes into it",
+            direction.stream_name(),
+            bytes
+        ));
+    }
+
+    /// How much of an unfinished message a stream ended with, if any
+    pub(crate) fn truncated_message(&self, direction: Direction) -> Option<usize> {
+        self.truncated_messages
+            .get(direction.stream_name())
+            .copied()
+    }
+
+    /// Warn about a response whose request isn't outstanding, which
+    /// is a bug in whichever side sent it
+    fn observe_unmatched_response(&mut self, direction: Direction, key: &(Direction, String)) {
+        let sender = match direction {
+            Direction::ToServer => "editor",
+            Direction::ToEditor => "server",
+        };
+        if self.answered_ids.contains(key) {
+            self.duplicate_responses += 1;
+            self.warn(format!(
+                "{} responded to request {} more than once",
+                sender, key.1
+            ));
+        } else {
+            self.unknown_responses += 1;
+            self.warn(format!(
+                "{} responded to request {}, which was never sent",
+                sender, key.1
+            ));
+        }
+    }
+
+    fn observe_request(&mut self, direction: Direction, method: &str, id: &serde_json::Value) {
+        self.outstanding.insert(
+            (direction, id.to_string()),
+            PendingRequest {
+                method: method.to_string(),
+                id: id.clone(),
+                sent: self.clock.instant(),
+                sent_at: self.clock.now(),
+                cancelled: false,
+                phase: self
+                    .phase_markers
+                    .as_ref()
+                    .and_then(|phase_markers| phase_markers.current())
+                    .map(|phase| phase.to_string()),
+            },
+        );
+
+        if direction != Direction::ToServer {
+            return;
+        }
+        self.check_outstanding_requests();
+
+        if method == "initialize" {
+            self.handshake = Handshake::Pending {
+                id: id.clone(),
+                sent: self.clock.instant(),
+                warned_timeout: false,
+                warned_early_request: false,
+            };
+        } else if let Handshake::Pending {
+            warned_early_request,
+            ..
+        } = &mut self.handshake
+        {
+            if !*warned_early_request {
+                *warned_early_request = true;
+                self.warn(format!(
+                    "editor sent {} before initialize was answered",
+                    method
+                ));
+            }
+        }
+    }
+
+    /// Record the options the editor configured the server with, and
+    /// print how they've changed since the previous session
+    fn observe_initialization_options(&mut self, initialize: &serde_json::Value) {
+        let options = initialize
+            .pointer("/params/initializationOptions")
+            .cloned()
+            .unwrap_or(serde_json::Value::Null);
+
+        if let Some(previous) = &self.previous_options {
+            let changes = init_options::diff(&previous.options, &options);
+            if changes.is_empty() {
+                eprintln!(
+                    "initializationOptions unchanged since {}",
+                    previous.summary.display()
+                );
+            } else {
+                eprintln!(
+                    "initializationOptions changed since {}:",
+                    previous.summary.display()
+                );
+                for change in &changes {
+                    eprintln!("  {}", change);
+                }
+            }
+            self.options_changes = Some(changes);
+        }
+        self.initialization_options = Some(options);
+    }
+
+    /// Mark a request as cancelled. Cancellations are sent in the same
+    /// direction as the request.
+    fn observe_cancel(&mut self, direction: Direction, cancel: &serde_json::Value) {
+        let Some(id) = cancel.pointer("/params/id") else {
+            return;
+        };
+        if let Some(request) = self.outstanding.get_mut(&(direction, id.to_string())) {
+            request.cancelled = true;
+        }
+    }
+
+    /// Track the server's dynamic capability registrations, warning
+    /// about duplicate ids and unknown unregistrations
+    fn observe_registration(&mut self, method: &str, request: &serde_json::Value) {
+        let params = request.get("params");
+        let warnings = match method {
+            "client/registerCapability" => self.registrations.register(params),
+            "client/unregisterCapability" => self.registrations.unregister(params),
+            _ => return,
+        };
+        for warning in warnings {
+            self.warn(warning);
+        }
+    }
+
+    fn observe_response(&mut self, id: &serde_json::Value, response: &serde_json::Value) {
+        let Handshake::Pending {
+            id: initialize_id,
+            sent,
+            ..
+        } = &self.handshake
+        else {
+            return;
+        };
+        if id != initialize_id {
+            return;
+        }
+
+        let startup = self.elapsed(self.server_started);
+        eprintln!(
+            "Server startup latency: {}ms from start to initialize response",
+            startup.as_millis()
+        );
+        self.initialize_response = Some(startup);
+
+        match response.get("error") {
+            Some(error) => {
+                let error = error.clone();
+                self.warn(format!("initialize failed: {}", error));
+                self.handshake = Handshake::Failed { error };
+            }
+            None => {
+                self.handshake = Handshake::Succeeded {
+                    latency: self.elapsed(*sent),
+                };
+                if let Some(document_state) = self.document_state.as_mut() {
+                    document_state.observe_initialize_response(response);
+                }
+            }
+        }
+    }
+
+    /// The editor's `initialized` notification ends the handshake
+    fn observe_initialized(&mut self) {
+        match &self.handshake {
+            Handshake::NotStarted => {
+                self.warn("editor sent initialized without sending initialize".to_string())
+            }
+            Handshake::Pending { .. } => {
+                self.warn("editor sent initialized before initialize was answered".to_string())
+            }
+            Handshake::Succeeded { .. } | Handshake::Failed { .. } => {}
+        }
+        if self.handshake_complete.is_some() {
+            self.warn("editor sent initialized more than once".to_string());
+            return;
+        }
+
+        let elapsed = self.elapsed(self.server_started);
+        eprintln!(
+            "Handshake complete {}ms after the server started",
+            elapsed.as_millis()
+        );
+        self.handshake_complete = Some(elapsed);
+        if let Some(phases) = self.phases.as_mut() {
+            phases.complete_handshake();
+        }
+    }
+
+    fn observe_diagnostics(&mut self) {
+        if self.first_diagnostics.is_some() {
+            return;
+        }
+        let elapsed = self.elapsed(self.server_started);
+        eprintln!(
+            "First diagnostics {}ms after the server started",
+            elapsed.as_millis()
+        );
+        self.first_diagnostics = Some(elapsed);
+    }
+
+    /// Record how many complete messages were parsed from one read
+    pub(crate) fn observe_backlog(&mut self, direction: Direction, messages: usize) {
+        if let Some(alert) = self.traffic.backlog(direction, messages) {
+            self.warn(alert);
+        }
+    }
+
+    fn count_document(&mut self, message: &serde_json::Value) {
+        // Most methods have a TextDocumentIdentifier, but
+        // publishDiagnostics just has a uri.
+        let uri = message
+            .pointer("/params/textDocument/uri")
+            .or_else(|| message.pointer("/params/uri"))
+            .and_then(|uri| uri.as_str());
+        if let Some(uri) = uri {
+            *self.documents.entry(uri.to_string()).or_default() += 1;
+        }
+    }
+
+    fn check_outstanding_requests(&mut self) {
+        let count = self
+            .outstanding
+            .keys()
+            .filter(|(direction, _)| *direction == Direction::ToServer)
+            .count();
+        if let Some(alert) = self.traffic.outstanding_requests(count) {
+            self.warn(alert);
+        }
+    }
+
+    /// Warn if initialize has been waiting for a response longer than
+    /// `timeout`
+    pub(crate) fn check_initialize_timeout(&mut self, timeout: Duration) {
+        let now = self.clock.instant();
+        let Handshake::Pending {
+            sent,
+            warned_timeout,
+            ..
+        } = &mut self.handshake
+        else {
+            return;
+        };
+        if *warned_timeout || now.duration_since(*sent) < timeout {
+            return;
+        }
+
+        *warned_timeout = true;
+        self.warn(format!(
+            "initialize has not been answered after {}s",
+            timeout.as_secs_f64()
+        ));
+    }
+
+    /// The session as a Chrome trace, if `LSP_TRACE` is set. Requests
+    /// that are still waiting for a response end now.
+    pub(crate) fn trace_json(&mut self) -> Option<serde_json::Value> {
+        let trace = self.trace.as_mut()?;
+        let now = self.clock.instant();
+        for ((direction, _), request) in &self.outstanding {
+            trace.request(
+                *direction,
+                &request.method,
+                &request.id,
+                request.sent,
+                now,
+                "unanswered",
+            );
+        }
+        Some(trace.to_json())
+    }
+
+    /// Every request in the session as CSV, if `LSP_CSV_SUMMARY` is
+    /// set, including those still waiting for a response
+    pub(crate) fn requests_csv(&mut self) -> Option<String> {
+        let answered = self.answered.as_mut()?;
+        answered.extend(
+            self.outstanding
+                .iter()
+                .map(|((direction, _), request)| request.record(*direction)),
+        );
+        Some(requests_csv::to_csv(answered))
+    }
+
+    /// The reconstructed text of each document, by URI, if
+    /// `LSP_RECONSTRUCT_DOCUMENTS` is set
+    pub(crate) fn reconstructed_documents(&self) -> Option<Vec<(String, String)>> {
+        let document_state = self.document_state.as_ref()?;
+        Some(
+            document_state
+                .texts()
+                .map(|(uri, text)| (uri.to_string(), text.to_string()))
+                .collect(),
+        )
+    }
+
+    /// A line per publishDiagnostics, if `LSP_DIAGNOSTICS_TIMELINE` is
+    /// set
+    pub(crate) fn diagnostics_timeline(&self) -> Option<String> {
+        Some(self.diagnostics.as_ref()?.to_json_lines())
+    }
+
+    /// Record the junk before a message from the server, for
+    /// `LSP_STRICT_LENGTH`
+    pub(crate) fn observe_framing(&mut self, stray_bytes: usize, junk: &[u8]) {
+        let warning = self
+            .strict_length
+            .as_mut()
+            .and_then(|strict_length| strict_length.observe(stray_bytes, junk));
+        if let Some(warning) = warning {
+            self.warn(warning);
+        }
+    }
+
+    /// Record server headers without a valid Content-Length, for
+    /// `LSP_STRICT_LENGTH`
+    pub(crate) fn observe_invalid_headers(&mut self, headers: &[u8]) {
+        let warning = self
+            .strict_length
+            .as_mut()
+            .and_then(|strict_length| strict_length.observe_invalid_headers(headers));
+        if let Some(warning) = warning {
+            self.warn(warning);
+        }
+    }
+
+    /// How long the server took to send the response it sent last, or
+    /// None if it didn't answer a request we saw
+    pub(crate) fn response_latency(&self) -> Option<Duration> {
+        self.last_response_latency
+    }
+
+    /// What's happening right now, for `LSP_STATUS_ADDR`
+    pub(crate) fn status_json(&self) -> serde_json::Value {
+        let mut outstanding: Vec<_> = self
+            .outstanding
+            .iter()
+            .map(|((direction, _), request)| (direction, request))
+            .collect();
+        outstanding.sort_by_key(|(_, request)| request.sent);
+        let outstanding: Vec<_> = outstanding
+            .into_iter()
+            .map(|(direction, request)| {
+                serde_json::json!({
+                    "stream": direction.stream_name(),
+                    "id": request.id,
+                    "method": request.method,
+                    "age_ms": self.elapsed(request.sent).as_millis() as u64,
+                    "cancelled": request.cancelled,
+                })
+            })
+            .collect();
+        let initialize = match &self.handshake {
+            Handshake::NotStarted => "not sent",
+            Handshake::Pending { .. } => "unanswered",
+            Handshake::Succeeded { .. } => "succeeded",
+            Handshake::Failed { .. } => "failed",
+        };
+        serde_json::json!({
+            "uptime_ms": self.elapsed(self.server_started).as_millis() as u64,
+            "initialize": initialize,
+            "messages": self.message_counts,
+            "outstanding": outstanding,
+            "last_error": self.last_error,
+            "warnings": self.warnings.len(),
+        })
+    }
+
+    pub(crate) fn summary_json(&self) -> serde_json::Value {
+        let initialize = match &self.handshake {
+            Handshake::NotStarted => serde_json::json!({ "status": "not sent" }),
+            Handshake::Pending { sent, .. } => serde_json::json!({
+                "status": "unanswered",
+                "waited_ms": self.elapsed(*sent).as_millis() as u64,
+            }),
+            Handshake::Succeeded { latency } => serde_json::json!({
+                "status": "succeeded",
+                "latency_ms": latency.as_millis() as u64,
+            }),
+            Handshake::Failed { error } => serde_json::json!({
+                "status": "failed",
+                "error": error,
+            }),
+        };
+
+        let mut initialize = initialize;
+        if let Some(options) = &self.initialization_options {
+            initialize["initialization_options"] = options.clone();
+        }
+        if let (Some(previous), Some(changes)) = (&self.previous_options, &self.options_changes) {
+            initialize["initialization_options_changes"] = serde_json::json!({
+                "previous_summary": previous.summary,
+                "changes": changes,
+            });
+        }
+
+        let mut summary = serde_json::json!({
+            "initialize": initialize,
+            "warnings": self.warnings,
+            "alerts": self.traffic.triggered(),
+            "documents": self.documents,
+            "registrations": self.registrations.summary_json(),
+            "unadvertised_requests": self.client_capabilities.summary_json(),
+            "unmatched_responses": {
+                "duplicate": self.duplicate_responses,
+                "unknown": self.unknown_responses,
+            },
+            "server_messages": self.server_messages.summary_json(),
+            "latency": self.latencies.summary_json(),
+            "startup": {
+                "initialize_response_ms": self.initialize_response.map(|d| d.as_millis() as u64),
+                "first_diagnostics_ms": self.first_diagnostics.map(|d| d.as_millis() as u64),
+                "handshake_complete_ms": self.handshake_complete.map(|d| d.as_millis() as u64),
+            },
+        });
+        if !self.dropped_log_messages.is_empty() {
+            summary["dropped_log_messages"] = serde_json::json!(self.dropped_log_messages);
+        }
+        if !self.truncated_messages.is_empty() {
+            summary["truncated_messages"] = serde_json::json!(self.truncated_messages);
+        }
+        if let Some(document_state) = &self.document_state {
+            summary["reconstructed_documents"] = document_state.summary_json();
+        }
+        if let Some(phases) = &self.phases {
+            summary["phases"] = phases.summary_json();
+        }
+        if let Some(phase_markers) = &self.phase_markers {
+            summary["phase_markers"] = phase_markers.summary_json();
+        }
+        if let Some(diagnostics) = &self.diagnostics {
+            summary["diagnostics"] = diagnostics.summary_json();
+        }
+        if let Some(strict_length) = &self.strict_length {
+            summary["strict_length"] = strict_length.summary_json();
+        }
+        if !self.progress.is_empty() {
+            summary["progress"] = self.progress.summary_json(self.clock.instant());
+        }
+        if let Some(peak_backlog) = self.traffic.peak_backlog_json() {
+            summary["peak_backlog_messages"] = peak_backlog;
+        }
+        summary
+    }
+}
+
+/// The start of `text`, for quoting messages in warnings. Counts
+/// characters rather than bytes, so multibyte characters are never cut
+/// in half.
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+/// Periodically checks for an initialize request that the server
+/// hasn't answered
+pub(crate) async fn watch_initialize(session: SharedSession, timeout: Duration) {
+    let mut interval = tokio::time::interval(Duration::from_millis(250));
+    loop {
+        interval.tick().await;
+        session.lock().unwrap().check_initialize_timeout(timeout);
+    }
+}
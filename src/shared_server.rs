@@ -0,0 +1,641 @@
+use anyhow::{bail, Result};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::{format_lsp_message, LspMessageParser, ServerInput, ServerOutput, SHUTDOWN_ID};
+
+/// JSON-RPC error code for a request that couldn't be handled
+const INTERNAL_ERROR: i64 = -32603;
+
+/// What the router is told about
+enum Event {
+    Connected(u64, mpsc::UnboundedSender<Value>),
+    FromEditor(u64, Value),
+    Disconnected(u64),
+    FromServer(Value),
+    ServerClosed,
+    /// The listener is stopping, so shut the server down
+    Stop,
+}
+
+/// One LSP server shared by every editor connection in listen mode.
+/// Each connection's session talks to it as if it had a server of its
+/// own: request ids are rewritten so responses go back to the editor
+/// that asked, and only the first editor's initialize reaches the
+/// server.
+#[derive(Clone)]
+pub(crate) struct SharedServer {
+    events: mpsc::UnboundedSender<Event>,
+    next_connection: Arc<AtomicU64>,
+}
+
+impl SharedServer {
+    /// Starts routing messages between editor connections and the
+    /// server on `input` and `output`. The task ends once the server
+    /// closes its output.
+    pub(crate) fn start(input: ServerInput, mut output: ServerOutput) -> (Self, JoinHandle<()>) {
+        let (events, receiver) = mpsc::unbounded_channel();
+        let from_server = events.clone();
+        tokio::spawn(async move {
+            let mut buffer = vec![0u8; 8192];
+            let mut parser = LspMessageParser::new();
+            while let Ok(n @ 1..) = output.read(&mut buffer).await {
+                parser.add_data(&buffer[..n]);
+                while let Some((_, payload)) = parser.try_parse_message() {
+                    match serde_json::from_str(&payload) {
+                        Ok(message) => {
+                            let _ = from_server.send(Event::FromServer(message));
+                        }
+                        Err(_) => eprintln!("Ignoring a message from the server that isn't JSON"),
+                    }
+                }
+            }
+            let _ = from_server.send(Event::ServerClosed);
+        });
+        let router = tokio::spawn(Router::new(input).run(receiver));
+        let server = Self {
+            events,
+            next_connection: Arc::new(AtomicU64::new(1)),
+        };
+        (server, router)
+    }
+
+    /// A new connection to the server, for one editor's session
+    pub(crate) fn connect(&self) -> Result<(ServerInput, ServerOutput)> {
+        let number = self.next_connection.fetch_add(1, Ordering::Relaxed);
+        let (session_side, router_side) = tokio::io::duplex(64 * 1024);
+        let (mut from_session, mut to_session) = tokio::io::split(router_side);
+        let (messages, mut to_send) = mpsc::unbounded_channel();
+        if self
+            .events
+            .send(Event::Connected(number, messages))
+            .is_err()
+        {
+            bail!("The shared LSP server has exited");
+        }
+
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            let mut buffer = vec![0u8; 8192];
+            let mut parser = LspMessageParser::new();
+            while let Ok(n @ 1..) = from_session.read(&mut buffer).await {
+                parser.add_data(&buffer[..n]);
+                while let Some((_, payload)) = parser.try_parse_message() {
+                    match serde_json::from_str(&payload) {
+                        Ok(message) => {
+                            let _ = events.send(Event::FromEditor(number, message));
+                        }
+                        Err(_) => eprintln!(
+                            "Ignoring a message from connection {} that isn't JSON",
+                            number
+                        ),
+                    }
+                }
+            }
+            let _ = events.send(Event::Disconnected(number));
+        });
+        tokio::spawn(async move {
+            while let Some(message) = to_send.recv().await {
+                if write_message(&mut to_session, &message).await.is_err() {
+                    break;
+                }
+            }
+            // The session sees the server close its output, as it would
+            // when a server of its own exits.
+            let _ = to_session.shutdown().await;
+        });
+
+        let (output, input) = tokio::io::split(session_side);
+        Ok((Box::new(input), Box::new(output)))
+    }
+
+    /// Asks the server to shut down and exit
+    pub(crate) fn stop(&self) {
+        let _ = self.events.send(Event::Stop);
+    }
+}
+
+/// An editor connected to the shared server
+struct Connection {
+    messages: mpsc::UnboundedSender<Value>,
+    /// Documents this editor has open
+    open_documents: HashSet<String>,
+}
+
+/// Where the server's initialize has got to
+enum Initialize {
+    NotSent,
+    /// Sent with this id, and these connections' initialize requests
+    /// are waiting for its response, with their own ids
+    Pending(String, Vec<(u64, Value)>),
+    /// The server's result, given to each editor that initializes
+    Done(Value),
+}
+
+struct Router {
+    server: ServerInput,
+    connections: BTreeMap<u64, Connection>,
+    /// Requests forwarded to the server, by the id they were sent with,
+    /// and the connection and id they came from
+    requests: HashMap<String, (u64, Value)>,
+    /// Requests from the server, by id, and the connection asked to
+    /// answer each
+    server_requests: HashMap<String, u64>,
+    initialize: Initialize,
+    sent_initialized: bool,
+    /// How many editors have each document open
+    open_documents: HashMap<String, usize>,
+}
+
+impl Router {
+    fn new(server: ServerInput) -> Self {
+        Self {
+            server,
+            connections: BTreeMap::new(),
+            requests: HashMap::new(),
+            server_requests: HashMap::new(),
+            initialize: Initialize::NotSent,
+            sent_initialized: false,
+            open_documents: HashMap::new(),
+        }
+    }
+
+    async fn run(mut self, mut events: mpsc::UnboundedReceiver<Event>) {
+        while let Some(event) = events.recv().await {
+            let result = match event {
+                Event::Connected(number, messages) => {
+                    self.connections.insert(
+                        number,
+                        Connection {
+                            messages,
+                            open_documents: HashSet::new(),
+                        },
+                    );
+                    Ok(())
+                }
+                Event::FromEditor(number, message) => self.editor_message(number, message).await,
+                Event::Disconnected(number) => self.disconnect(number).await,
+                Event::FromServer(message) => self.server_message(message).await,
+                Event::ServerClosed => break,
+                Event::Stop => self.stop().await,
+            };
+            if let Err(e) = result {
+                eprintln!("Failed to write to the shared LSP server: {}", e);
+                break;
+            }
+        }
+        // Dropping the connections ends every session.
+    }
+
+    async fn send(&mut self, message: &Value) -> std::io::Result<()> {
+        write_message(&mut self.server, message).await
+    }
+
+    fn reply(&self, number: u64, message: Value) {
+        if let Some(connection) = self.connections.get(&number) {
+            let _ = connection.messages.send(message);
+        }
+    }
+
+    async fn editor_message(&mut self, number: u64, mut message: Value) -> std::io::Result<()> {
+        if !self.connections.contains_key(&number) {
+            return Ok(());
+        }
+        let method = message.get("method").and_then(|m| m.as_str());
+        let id = message.get("id").cloned();
+        match (method, id) {
+            (Some("initialize"), Some(id)) => match &mut self.initialize {
+                Initialize::Done(result) => {
+                    let response =
+                        serde_json::json!({"jsonrpc": "2.0", "id": id, "result": result});
+                    self.reply(number, response);
+                }
+                Initialize::Pending(_, waiting) => waiting.push((number, id)),
+                Initialize::NotSent => {
+                    let shared_id = shared_id(number, &id);
+                    self.initialize = Initialize::Pending(shared_id.clone(), vec![(number, id)]);
+                    message["id"] = shared_id.into();
+                    self.send(&message).await?;
+                }
+            },
+            // The server is shut down when the listener stops, not when
+            // an editor is done with it.
+            (Some("shutdown"), Some(id)) => {
+                self.reply(
+                    number,
+                    serde_json::json!({"jsonrpc": "2.0", "id": id, "result": null}),
+                );
+            }
+            (Some(_), Some(id)) => {
+                let shared_id = shared_id(number, &id);
+                self.requests.insert(shared_id.clone(), (number, id));
+                message["id"] = shared_id.into();
+                self.send(&message).await?;
+            }
+            (Some("initialized"), None) => {
+                if !std::mem::replace(&mut self.sent_initialized, true) {
+                    self.send(&message).await?;
+                }
+            }
+            (Some("exit"), None) => self.disconnect(number).await?,
+            (Some("textDocument/didOpen"), None) => {
+                if let Some(uri) = document_uri(&message) {
+                    if self.open(number, uri) {
+                        self.send(&message).await?;
+                    }
+                }
+            }
+            (Some("textDocument/didClose"), None) => {
+                if let Some(uri) = document_uri(&message) {
+                    if self.close(number, &uri) {
+                        self.send(&message).await?;
+                    }
+                }
+            }
+            (Some("$/cancelRequest"), None) => {
+                let Some(id) = message.pointer("/params/id") else {
+                    return Ok(());
+                };
+                let shared_id = shared_id(number, id);
+                if self.requests.contains_key(&shared_id) {
+                    message["params"]["id"] = shared_id.into();
+                    self.send(&message).await?;
+                }
+            }
+            (Some(_), None) => self.send(&message).await?,
+            // A response to one of the server's requests
+            (None, Some(id)) => {
+                self.server_requests.remove(&id.to_string());
+                self.send(&message).await?;
+            }
+            (None, None) => {}
+        }
+        Ok(())
+    }
+
+    async fn server_message(&mut self, mut message: Value) -> std::io::Result<()> {
+        let has_method = message.get("method").is_some();
+        let Some(id) = message.get("id").cloned() else {
+            // Notifications, such as diagnostics and progress, go to
+            // every editor.
+            for connection in self.connections.values() {
+                let _ = connection.messages.send(message.clone());
+            }
+            return Ok(());
+        };
+
+        if has_method {
+            // Ask the longest connected editor to answer the server.
+            match self.connections.keys().next() {
+                Some(&number) => {
+                    self.server_requests.insert(id.to_string(), number);
+                    self.reply(number, message);
+                }
+                None => {
+                    let error = serde_json::json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": {"code": INTERNAL_ERROR, "message": "No editor is connected"},
+                    });
+                    self.send(&error).await?;
+                }
+            }
+            return Ok(());
+        }
+
+        let Some(shared_id) = id.as_str() else {
+            return Ok(());
+        };
+        if let Initialize::Pending(initialize_id, _) = &self.initialize {
+            if initialize_id == shared_id {
+                let Initialize::Pending(_, waiting) =
+                    std::mem::replace(&mut self.initialize, Initialize::NotSent)
+                else {
+                    unreachable!();
+                };
+                // A failed initialize is sent again by the next editor
+                // to connect.
+                if let Some(result) = message.get("result") {
+                    self.initialize = Initialize::Done(result.clone());
+                }
+                for (number, id) in waiting {
+                    message["id"] = id;
+                    self.reply(number, message.clone());
+                }
+                return Ok(());
+            }
+        }
+        if let Some((number, id)) = self.requests.remove(shared_id) {
+            message["id"] = id;
+            self.reply(number, message);
+        }
+        Ok(())
+    }
+
+    /// Records that an editor opened a document, returning whether the
+    /// server should be told
+    fn open(&mut self, number: u64, uri: String) -> bool {
+        let Some(connection) = self.connections.get_mut(&number) else {
+            return false;
+        };
+        if !connection.open_documents.insert(uri.clone()) {
+            return false;
+        }
+        let editors = self.open_documents.entry(uri).or_default();
+        *editors += 1;
+        *editors == 1
+    }
+
+    /// Records that an editor closed a document, returning whether the
+    /// server should be told
+    fn close(&mut self, number: u64, uri: &str) -> bool {
+        let Some(connection) = self.connections.get_mut(&number) else {
+            return false;
+        };
+        if !connection.open_documents.remove(uri) {
+            return false;
+        }
+        let Some(editors) = self.open_documents.get_mut(uri) else {
+            return false;
+        };
+        *editors -= 1;
+        if *editors > 0 {
+            return false;
+        }
+        self.open_documents.remove(uri);
+        true
+    }
+
+    /// Forget an editor, tidying up what it left behind so the server
+    /// and the other editors carry on as before
+    async fn disconnect(&mut self, number: u64) -> std::io::Result<()> {
+        let Some(connection) = self.connections.get(&number) else {
+            return Ok(());
+        };
+        for uri in connection.open_documents.clone() {
+            if self.close(number, &uri) {
+                let did_close = serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "method": "textDocument/didClose",
+                    "params": {"textDocument": {"uri": uri}},
+                });
+                self.send(&did_close).await?;
+            }
+        }
+        self.connections.remove(&number);
+
+        let abandoned: Vec<String> = self
+            .requests
+            .iter()
+            .filter(|(_, (from, _))| *from == number)
+            .map(|(shared_id, _)| shared_id.clone())
+            .collect();
+        for shared_id in abandoned {
+            self.requests.remove(&shared_id);
+            let cancel = serde_json::json!({
+                "jsonrpc": "2.0",
+                "method": "$/cancelRequest",
+                "params": {"id": shared_id},
+            });
+            self.send(&cancel).await?;
+        }
+
+        let unanswered: Vec<String> = self
+            .server_requests
+            .iter()
+            .filter(|(_, asked)| **asked == number)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in unanswered {
+            self.server_requests.remove(&id);
+            let error = serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": serde_json::from_str::<Value>(&id).unwrap_or(Value::Null),
+                "error": {"code": INTERNAL_ERROR, "message": "The editor disconnected"},
+            });
+            self.send(&error).await?;
+        }
+
+        if let Initialize::Pending(_, waiting) = &mut self.initialize {
+            waiting.retain(|(waiting, _)| *waiting != number);
+        }
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> std::io::Result<()> {
+        let shutdown =
+            serde_json::json!({"jsonrpc": "2.0", "id": SHUTDOWN_ID, "method": "shutdown"});
+        let exit = serde_json::json!({"jsonrpc": "2.0", "method": "exit"});
+        self.send(&shutdown).await?;
+        self.send(&exit).await
+    }
+}
+
+/// The id a connection's request is sent to the server with, unique
+/// across connections
+fn shared_id(number: u64, id: &Value) -> String {
+    format!("{}:{}", number, id)
+}
+
+fn document_uri(message: &Value) -> Option<String> {
+    message
+        .pointer("/params/textDocument/uri")
+        .and_then(|uri| uri.as_str())
+        .map(|uri| uri.to_string())
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &Value,
+) -> std::io::Result<()> {
+    writer
+        .write_all(format_lsp_message(&message.to_string()).as_bytes())
+        .await?;
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::io::{AsyncRead, DuplexStream, ReadHalf, WriteHalf};
+
+    /// Reads messages from one side of a connection
+    struct Peer<R> {
+        reader: R,
+        parser: LspMessageParser,
+    }
+
+    impl<R: AsyncRead + Unpin> Peer<R> {
+        fn new(reader: R) -> Self {
+            Self {
+                reader,
+                parser: LspMessageParser::new(),
+            }
+        }
+
+        async fn next(&mut self) -> Option<Value> {
+            let mut buffer = vec![0u8; 8192];
+            loop {
+                if let Some((_, payload)) = self.parser.try_parse_message() {
+                    return Some(serde_json::from_str(&payload).unwrap());
+                }
+                match self.reader.read(&mut buffer).await.unwrap() {
+                    0 => return None,
+                    n => self.parser.add_data(&buffer[..n]),
+                }
+            }
+        }
+    }
+
+    /// A shared server whose other end the test plays
+    fn start() -> (
+        SharedServer,
+        Peer<ReadHalf<DuplexStream>>,
+        WriteHalf<DuplexStream>,
+    ) {
+        let (router_side, test_side) = tokio::io::duplex(64 * 1024);
+        let (output, input) = tokio::io::split(router_side);
+        let (from_router, to_router) = tokio::io::split(test_side);
+        let (server, _) = SharedServer::start(Box::new(input), Box::new(output));
+        (server, Peer::new(from_router), to_router)
+    }
+
+    async fn send<W: AsyncWrite + Unpin>(writer: &mut W, message: Value) {
+        write_message(writer, &message).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn initializes_the_server_once_and_routes_responses() {
+        let (shared, mut server, mut to_server) = start();
+        let (mut first_input, first_output) = shared.connect().unwrap();
+        let (mut second_input, second_output) = shared.connect().unwrap();
+        let mut first = Peer::new(first_output);
+        let mut second = Peer::new(second_output);
+
+        let initialize = json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}});
+        send(&mut first_input, initialize.clone()).await;
+        let sent = server.next().await.unwrap();
+        assert_eq!(sent["method"], "initialize");
+        assert_eq!(sent["id"], "1:1");
+        send(&mut second_input, initialize).await;
+        let result = json!({"capabilities": {"hoverProvider": true}});
+        send(
+            &mut to_server,
+            json!({"jsonrpc": "2.0", "id": "1:1", "result": result}),
+        )
+        .await;
+        assert_eq!(first.next().await.unwrap()["result"], result);
+        // The second editor's initialize is answered from the first's.
+        assert_eq!(second.next().await.unwrap()["result"], result);
+
+        // Both use id 2, and each gets its own answer.
+        for input in [&mut first_input, &mut second_input] {
+            send(
+                input,
+                json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/hover"}),
+            )
+            .await;
+        }
+        let ids = [
+            server.next().await.unwrap()["id"].clone(),
+            server.next().await.unwrap()["id"].clone(),
+        ];
+        assert_eq!(ids, [json!("1:2"), json!("2:2")]);
+        send(
+            &mut to_server,
+            json!({"jsonrpc": "2.0", "id": "2:2", "result": "second"}),
+        )
+        .await;
+        send(
+            &mut to_server,
+            json!({"jsonrpc": "2.0", "id": "1:2", "result": "first"}),
+        )
+        .await;
+        assert_eq!(
+            first.next().await.unwrap(),
+            json!({"jsonrpc": "2.0", "id": 2, "result": "first"})
+        );
+        assert_eq!(
+            second.next().await.unwrap(),
+            json!({"jsonrpc": "2.0", "id": 2, "result": "second"})
+        );
+
+        // Notifications from the server go to both.
+        let diagnostics =
+            json!({"jsonrpc": "2.0", "method": "textDocument/publishDiagnostics", "params": {}});
+        send(&mut to_server, diagnostics.clone()).await;
+        assert_eq!(first.next().await.unwrap(), diagnostics);
+        assert_eq!(second.next().await.unwrap(), diagnostics);
+    }
+
+    #[tokio::test]
+    async fn one_editor_leaving_doesnt_affect_the_others() {
+        let (shared, mut server, mut to_server) = start();
+        let (mut first_input, first_output) = shared.connect().unwrap();
+        let (mut second_input, second_output) = shared.connect().unwrap();
+        let mut first = Peer::new(first_output);
+        let mut second = Peer::new(second_output);
+
+        let did_open = |uri: &str| json!({"jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {"textDocument": {"uri": uri}}});
+        send(&mut first_input, did_open("file:///shared.rs")).await;
+        send(&mut second_input, did_open("file:///shared.rs")).await;
+        send(&mut first_input, did_open("file:///first.rs")).await;
+        assert_eq!(server.next().await.unwrap(), did_open("file:///shared.rs"));
+        assert_eq!(server.next().await.unwrap(), did_open("file:///first.rs"));
+
+        // The first editor shuts down and leaves with a request still
+        // running.
+        send(
+            &mut first_input,
+            json!({"jsonrpc": "2.0", "id": 7, "method": "workspace/symbol"}),
+        )
+        .await;
+        assert_eq!(server.next().await.unwrap()["id"], "1:7");
+        send(
+            &mut first_input,
+            json!({"jsonrpc": "2.0", "id": 8, "method": "shutdown"}),
+        )
+        .await;
+        assert_eq!(
+            first.next().await.unwrap(),
+            json!({"jsonrpc": "2.0", "id": 8, "result": null})
+        );
+        send(
+            &mut first_input,
+            json!({"jsonrpc": "2.0", "method": "exit"}),
+        )
+        .await;
+        assert_eq!(first.next().await, None);
+
+        // Only the document nobody else has open is closed, and the
+        // abandoned request is cancelled.
+        assert_eq!(
+            server.next().await.unwrap(),
+            json!({"jsonrpc": "2.0", "method": "textDocument/didClose", "params": {"textDocument": {"uri": "file:///first.rs"}}})
+        );
+        assert_eq!(
+            server.next().await.unwrap(),
+            json!({"jsonrpc": "2.0", "method": "$/cancelRequest", "params": {"id": "1:7"}})
+        );
+
+        // The server's requests now go to the editor that's left.
+        let configuration = json!({"jsonrpc": "2.0", "id": 3, "method": "workspace/configuration"});
+        send(&mut to_server, configuration.clone()).await;
+        assert_eq!(second.next().await.unwrap(), configuration);
+        send(
+            &mut second_input,
+            json!({"jsonrpc": "2.0", "id": 3, "result": [null]}),
+        )
+        .await;
+        assert_eq!(
+            server.next().await.unwrap(),
+            json!({"jsonrpc": "2.0", "id": 3, "result": [null]})
+        );
+    }
+}
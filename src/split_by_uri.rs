@@ -0,0 +1,190 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::capture::{self, Entry};
+use crate::config::LogFormat;
+use crate::method_logs::sanitize;
+
+/// The file for messages that aren't about a document
+const OTHER: &str = "other";
+
+/// The document a message is about, from `textDocument.uri` or, for
+/// notifications like `publishDiagnostics`, `uri`
+fn document_uri(message: &Value) -> Option<&str> {
+    let params = message.get("params")?;
+    params
+        .pointer("/textDocument/uri")
+        .or_else(|| params.get("uri"))
+        .and_then(|uri| uri.as_str())
+}
+
+/// Writes the messages of a capture to one log per document URI in
+/// `output_dir`, with everything else in `other.jsonl`. Responses go
+/// with their request. Entries are `{"stream":..,"message":..}` lines,
+/// like merged logs, in the order they appear in `paths`.
+pub(crate) fn split_by_uri(paths: &[PathBuf], output_dir: &Path) -> Result<()> {
+    // Bodies that weren't JSON are kept in place, as they can't be
+    // tied to a document.
+    let mut messages = vec![];
+    for path in paths {
+        let stream = capture::stream_from_file_name(path);
+        let format = LogFormat::from_path(path).unwrap_or(LogFormat::Raw);
+        for entry in capture::read_log(path, format)? {
+            match entry {
+                Entry::Message(entry) => messages.extend(
+                    capture::unwrap_entry(stream, entry)
+                        .map(|(stream, message)| (stream, Entry::Message(message))),
+                ),
+                unparsed => messages.push((stream, unparsed)),
+            }
+        }
+    }
+
+    // Requests are found first, as a response from the editor is in
+    // the stdin log but the server's request is in the stdout log.
+    let mut request_uris: HashMap<(&str, String), String> = HashMap::new();
+    for (stream, entry) in &messages {
+        let Entry::Message(message) = entry else {
+            continue;
+        };
+        if let (Some(uri), Some(id), Some(_)) = (
+            document_uri(message),
+            message.get("id"),
+            message.get("method"),
+        ) {
+            request_uris.insert((stream, id.to_string()), uri.to_string());
+        }
+    }
+
+    let mut by_uri: BTreeMap<String, Vec<Entry>> = BTreeMap::new();
+    let mut other = vec![];
+    for (stream, entry) in messages {
+        let Entry::Message(message) = entry else {
+            other.push(entry);
+            continue;
+        };
+        let uri = match (document_uri(&message), message.get("id")) {
+            (Some(uri), _) => Some(uri.to_string()),
+            (None, Some(id)) if message.get("method").is_none() => {
+                let request_stream = if stream == "stdin" { "stdout" } else { "stdin" };
+                request_uris.get(&(request_stream, id.to_string())).cloned()
+            }
+            _ => None,
+        };
+        let entry = Entry::Message(serde_json::json!({
+            "stream": stream,
+            "message": message,
+        }));
+        match uri {
+            Some(uri) => by_uri.entry(uri).or_default().push(entry),
+            None => other.push(entry),
+        }
+    }
+
+    fs::create_dir_all(output_dir)
+        .with_context(|| format!("Failed to create {}", output_dir.display()))?;
+    let mut names = HashSet::from([OTHER.to_string()]);
+    for (uri, entries) in &by_uri {
+        let name = file_name(uri, &mut names);
+        let path = output_dir.join(format!("{}.jsonl", name));
+        capture::write_log(&path, LogFormat::JsonLines, entries)?;
+        println!("{:>8}  {}  {}", entries.len(), path.display(), uri);
+    }
+    let path = output_dir.join(format!("{}.jsonl", OTHER));
+    capture::write_log(&path, LogFormat::JsonLines, &other)?;
+    println!("{:>8}  {}", other.len(), path.display());
+    Ok(())
+}
+
+/// A file name for `uri` that isn't in `names` yet, e.g.
+/// `file:///src/main.rs` becomes `src_main.rs`
+fn file_name(uri: &str, names: &mut HashSet<String>) -> String {
+    let path = uri.split_once("://").map_or(uri, |(_, path)| path);
+    let base = sanitize(path).trim_matches('_').to_string();
+    let mut name = base.clone();
+    let mut n = 1;
+    while names.contains(&name) {
+        n += 1;
+        name = format!("{}-{}", base, n);
+    }
+    names.insert(name.clone());
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::tests::temp_path;
+    use serde_json::json;
+
+    #[test]
+    fn puts_responses_with_their_document() {
+        let stdin = temp_path("split_stdin.jsonl");
+        let stdout = temp_path("split_stdout.jsonl");
+        let output = temp_path("split");
+        let hover = json!({
+            "id": 1,
+            "method": "textDocument/hover",
+            "params": {"textDocument": {"uri": "file:///src/main.rs"}},
+        });
+        let diagnostics = json!({
+            "method": "textDocument/publishDiagnostics",
+            "params": {"uri": "file:///src/lib.rs"},
+        });
+        std::fs::write(
+            &stdin,
+            format!(
+                "{}\n{}\nnot json\n",
+                json!({"id": 0, "method": "shutdown"}),
+                hover
+            ),
+        )
+        .unwrap();
+        std::fs::write(
+            &stdout,
+            format!("{}\n{}\n", json!({"id": 1, "result": null}), diagnostics),
+        )
+        .unwrap();
+
+        split_by_uri(&[stdin.clone(), stdout.clone()], &output).unwrap();
+        let read =
+            |name: &str| capture::read_log(&output.join(name), LogFormat::JsonLines).unwrap();
+        let wrapped = |stream: &str, message: Value| {
+            Entry::Message(json!({"stream": stream, "message": message}))
+        };
+        assert_eq!(
+            read("src_main.rs.jsonl"),
+            vec![
+                wrapped("stdin", hover),
+                wrapped("stdout", json!({"id": 1, "result": null})),
+            ]
+        );
+        assert_eq!(
+            read("src_lib.rs.jsonl"),
+            vec![wrapped("stdout", diagnostics)]
+        );
+        assert_eq!(
+            read("other.jsonl"),
+            vec![
+                wrapped("stdin", json!({"id": 0, "method": "shutdown"})),
+                Entry::Unparsed("not json".to_string()),
+            ]
+        );
+
+        let _ = std::fs::remove_file(&stdin);
+        let _ = std::fs::remove_file(&stdout);
+        let _ = std::fs::remove_dir_all(&output);
+    }
+
+    #[test]
+    fn gives_each_document_its_own_file() {
+        let mut names = HashSet::from([OTHER.to_string()]);
+        assert_eq!(file_name("file:///other", &mut names), "other-2");
+        assert_eq!(file_name("file:///a/b.rs", &mut names), "a_b.rs");
+        assert_eq!(file_name("untitled:a/b.rs", &mut names), "untitled_a_b.rs");
+        assert_eq!(file_name("file:///a_b.rs", &mut names), "a_b.rs-2");
+    }
+}
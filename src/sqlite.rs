@@ -0,0 +1,392 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use rusqlite::{params, Connection};
+use serde_json::Value;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::config::LogMode;
+use crate::stream::Direction;
+
+/// How long to wait for another session writing to the same database
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most messages to insert in one transaction
+const MAX_BATCH: usize = 1000;
+
+const SCHEMA: &str = "
+PRAGMA journal_mode = WAL;
+CREATE TABLE IF NOT EXISTS sessions (
+    id INTEGER PRIMARY KEY,
+    started TEXT NOT NULL,
+    ended TEXT,
+    server TEXT NOT NULL,
+    args TEXT NOT NULL,
+    pid INTEGER NOT NULL,
+    summary_path TEXT,
+    exit_status TEXT
+);
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY,
+    session_id INTEGER NOT NULL REFERENCES sessions(id),
+    ts TEXT NOT NULL,
+    direction TEXT NOT NULL,
+    method TEXT,
+    rpc_id TEXT,
+    payload TEXT NOT NULL,
+    byte_len INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS messages_session ON messages(session_id);
+CREATE INDEX IF NOT EXISTS messages_method ON messages(method);
+";
+
+const INSERT_MESSAGE: &str = "INSERT INTO messages (session_id, ts, direction, method, rpc_id, payload, byte_len) VALUES (?, ?, ?, ?, ?, ?, ?)";
+
+/// What the writer thread is asked to do
+enum Record {
+    Message {
+        ts: String,
+        direction: &'static str,
+        method: Option<String>,
+        rpc_id: Option<String>,
+        payload: String,
+    },
+    /// The session is over, so record how it ended and stop
+    End {
+        ended: String,
+        exit_status: Option<String>,
+    },
+}
+
+/// Sends every message to a SQLite database for `LSP_SQLITE`. Inserts
+/// happen in batches on a separate thread, so forwarding never waits
+/// for the database.
+#[derive(Clone)]
+pub(crate) struct SqliteLog {
+    records: mpsc::UnboundedSender<Record>,
+}
+
+/// The thread writing to the database, which must be finished at the
+/// end of the session so the last messages are committed
+pub(crate) struct SqliteWriter {
+    log: SqliteLog,
+    thread: JoinHandle<()>,
+}
+
+impl SqliteLog {
+    /// Open or create the database at `path`, adding a row for this
+    /// session
+    pub(crate) fn open(
+        path: &Path,
+        server: &str,
+        args: &[String],
+        summary_path: &Path,
+        mode: LogMode,
+        started: DateTime<Local>,
+    ) -> Result<(Self, SqliteWriter)> {
+        // Create the file ourselves so it gets the log permissions.
+        // SQLite gives its journal files the same permissions.
+        let mut options = std::fs::OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(mode.0);
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+        options
+            .open(path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+
+        let mut database =
+            Connection::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        database.busy_timeout(BUSY_TIMEOUT)?;
+        let session_id = add_session(&database, server, args, summary_path, started)?;
+
+        let (records, receiver) = mpsc::unbounded_channel();
+        let thread =
+            tokio::task::spawn_blocking(move || write_records(&mut database, session_id, receiver));
+        let log = Self { records };
+        Ok((log.clone(), SqliteWriter { log, thread }))
+    }
+
+    pub(crate) fn log(
+        &self,
+        timestamp: DateTime<Local>,
+        direction: Direction,
+        method: Option<&str>,
+        message: Option<&Value>,
+        payload: &str,
+    ) {
+        let rpc_id = message
+            .and_then(|message| message.get("id"))
+            .map(|id| match id {
+                Value::String(id) => id.clone(),
+                id => id.to_string(),
+            });
+        let _ = self.records.send(Record::Message {
+            ts: timestamp.to_rfc3339(),
+            direction: direction.stream_name(),
+            method: method.map(|method| method.to_string()),
+            rpc_id,
+            payload: payload.to_string(),
+        });
+    }
+}
+
+impl SqliteWriter {
+    /// Record how the session ended, and wait for everything to be
+    /// committed
+    pub(crate) async fn finish(self, ended: DateTime<Local>, exit_status: Option<ExitStatus>) {
+        let _ = self.log.records.send(Record::End {
+            ended: ended.to_rfc3339(),
+            exit_status: exit_status.map(|status| status.to_string()),
+        });
+        if let Err(e) = self.thread.await {
+            eprintln!("SQLite writer failed: {}", e);
+        }
+    }
+}
+
+/// Create the tables if they're new, and add a row for this session,
+/// returning its id
+fn add_session(
+    database: &Connection,
+    server: &str,
+    args: &[String],
+    summary_path: &Path,
+    started: DateTime<Local>,
+) -> Result<i64> {
+    database
+        .execute_batch(SCHEMA)
+        .context("Failed to create the capture tables")?;
+    database
+        .execute(
+            "INSERT INTO sessions (started, server, args, pid, summary_path) VALUES (?, ?, ?, ?, ?)",
+            params![
+                started.to_rfc3339(),
+                server,
+                serde_json::json!(args).to_string(),
+                std::process::id(),
+                summary_path.to_string_lossy(),
+            ],
+        )
+        .context("Failed to add the session")?;
+    Ok(database.last_insert_rowid())
+}
+
+/// Insert records as they arrive, a batch per transaction, until the
+/// session ends
+fn write_records(
+    database: &mut Connection,
+    session_id: i64,
+    mut receiver: mpsc::UnboundedReceiver<Record>,
+) {
+    while let Some(first) = receiver.blocking_recv() {
+        let mut batch = vec![first];
+        while batch.len() < MAX_BATCH {
+            match receiver.try_recv() {
+                Ok(record) => batch.push(record),
+                Err(_) => break,
+            }
+        }
+
+        // Dropping the transaction on an error rolls it back.
+        if let Err(e) = write_batch(database, session_id, &batch) {
+            eprintln!("Failed to write to SQLite capture: {:#}", e);
+        }
+
+        if batch
+            .iter()
+            .any(|record| matches!(record, Record::End { .. }))
+        {
+            return;
+        }
+    }
+}
+
+/// Insert `batch` in one transaction
+fn write_batch(database: &mut Connection, session_id: i64, batch: &[Record]) -> Result<()> {
+    let transaction = database.transaction()?;
+    {
+        let mut insert = transaction.prepare_cached(INSERT_MESSAGE)?;
+        for record in batch {
+            match record {
+                Record::Message {
+                    ts,
+                    direction,
+                    method,
+                    rpc_id,
+                    payload,
+                } => {
+                    insert.execute(params![
+                        session_id,
+                        ts,
+                        direction,
+                        method,
+                        rpc_id,
+                        payload,
+                        payload.len() as i64,
+                    ])?;
+                }
+                Record::End { ended, exit_status } => {
+                    transaction.execute(
+                        "UPDATE sessions SET ended = ?, exit_status = ? WHERE id = ?",
+                        params![ended, exit_status, session_id],
+                    )?;
+                }
+            }
+        }
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(database: &Connection, sql: &str, columns: usize) -> Vec<Vec<Option<String>>> {
+        let mut statement = database.prepare(sql).unwrap();
+        statement
+            .query_map([], |row| {
+                (0..columns)
+                    .map(|column| {
+                        row.get::<_, rusqlite::types::Value>(column)
+                            .map(|value| match value {
+                                rusqlite::types::Value::Null => None,
+                                rusqlite::types::Value::Integer(n) => Some(n.to_string()),
+                                rusqlite::types::Value::Text(text) => Some(text),
+                                value => Some(format!("{:?}", value)),
+                            })
+                    })
+                    .collect()
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn writes_a_session_to_an_in_memory_database() {
+        let mut database = Connection::open_in_memory().unwrap();
+        let started = Local::now();
+        let session_id = add_session(
+            &database,
+            "rust-analyzer",
+            &["--verbose".to_string()],
+            Path::new("/tmp/summary.json"),
+            started,
+        )
+        .unwrap();
+
+        let (records, receiver) = mpsc::unbounded_channel();
+        let log = SqliteLog { records };
+        let request = serde_json::json!({"jsonrpc": "2.0", "id": 1, "method": "initialize"});
+        let response = serde_json::json!({"jsonrpc": "2.0", "id": 1, "result": {}});
+        log.log(
+            started,
+            Direction::ToServer,
+            Some("initialize"),
+            Some(&request),
+            &request.to_string(),
+        );
+        log.log(
+            started,
+            Direction::ToEditor,
+            None,
+            Some(&response),
+            &response.to_string(),
+        );
+        log.log(started, Direction::ToEditor, None, None, "not json");
+        log.records
+            .send(Record::End {
+                ended: started.to_rfc3339(),
+                exit_status: Some("exit status: 0".to_string()),
+            })
+            .unwrap();
+        write_records(&mut database, session_id, receiver);
+
+        let some = |text: &str| Some(text.to_string());
+        assert_eq!(
+            query(
+                &database,
+                "SELECT direction, method, rpc_id, payload, byte_len FROM messages ORDER BY id",
+                5
+            ),
+            vec![
+                vec![
+                    some("stdin"),
+                    some("initialize"),
+                    some("1"),
+                    some(&request.to_string()),
+                    some(&request.to_string().len().to_string()),
+                ],
+                vec![
+                    some("stdout"),
+                    None,
+                    some("1"),
+                    some(&response.to_string()),
+                    some(&response.to_string().len().to_string()),
+                ],
+                vec![some("stdout"), None, None, some("not json"), some("8")],
+            ]
+        );
+        assert_eq!(
+            query(
+                &database,
+                "SELECT server, args, ended, exit_status FROM sessions",
+                4
+            ),
+            vec![vec![
+                some("rust-analyzer"),
+                some(r#"["--verbose"]"#),
+                some(&started.to_rfc3339()),
+                some("exit status: 0"),
+            ]]
+        );
+    }
+
+    #[tokio::test]
+    async fn sessions_share_a_database_file() {
+        let path = crate::stream::tests::temp_path("capture.sqlite3");
+        let _ = std::fs::remove_file(&path);
+        let started = Local::now();
+        for server in ["first", "second"] {
+            let (log, writer) = SqliteLog::open(
+                &path,
+                server,
+                &[],
+                Path::new("/tmp/summary.json"),
+                LogMode::default(),
+                started,
+            )
+            .unwrap();
+            for _ in 0..3 {
+                log.log(started, Direction::ToServer, None, None, "{}");
+            }
+            writer.finish(started, None).await;
+        }
+
+        let database = Connection::open(&path).unwrap();
+        assert_eq!(
+            query(
+                &database,
+                "SELECT server, count(messages.id), ended IS NOT NULL FROM sessions
+                 JOIN messages ON messages.session_id = sessions.id
+                 GROUP BY sessions.id ORDER BY sessions.id",
+                3
+            ),
+            vec![
+                vec![Some("first".into()), Some("3".into()), Some("1".into())],
+                vec![Some("second".into()), Some("3".into()), Some("1".into())],
+            ]
+        );
+        drop(database);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,419 @@
+use serde_json::Value;
+use tokio::time::Instant;
+
+use crate::config::ProxyConfig;
+use crate::plugin::Action;
+use crate::require_capabilities::RequireCapabilities;
+use crate::stream::Direction;
+use crate::throttle::DidChangeThrottle;
+use crate::{auto_initialize_handshake, AUTO_INITIALIZE_ID};
+
+/// A message read from the source, as each stage sees it
+pub(crate) struct Incoming<'a> {
+    /// None if the payload isn't JSON
+    pub(crate) message: Option<&'a Value>,
+    /// When the message was read
+    pub(crate) now: Instant,
+}
+
+impl Incoming<'_> {
+    pub(crate) fn method(&self) -> Option<&str> {
+        self.message
+            .and_then(|message| message.get("method"))
+            .and_then(|method| method.as_str())
+    }
+}
+
+/// What the stages need done besides forwarding or dropping a message
+#[derive(Default)]
+pub(crate) struct Effects {
+    /// Messages of our own to forward before this one, which the
+    /// session sees as sent
+    pub(crate) inject: Vec<Value>,
+    /// Bodies of earlier messages that were held back, to forward
+    /// after `inject` and before this one
+    pub(crate) release: Vec<String>,
+    /// Responses to send back to the source
+    pub(crate) replies: Vec<Value>,
+    /// Problems to report as session warnings
+    pub(crate) warnings: Vec<String>,
+}
+
+/// One step in deciding what happens to each message read
+pub(crate) trait Stage: Send {
+    /// Forward, replace, log only or drop `message`
+    fn process(&mut self, message: &Incoming, effects: &mut Effects) -> Action;
+
+    /// When a message this stage is holding back is due
+    fn next_deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Takes the body of a message this stage is holding back, once
+    /// it's due or when the stream ends
+    fn release(&mut self, _now: Instant) -> Option<String> {
+        None
+    }
+}
+
+/// A message once every stage has seen it
+pub(crate) struct Processed {
+    pub(crate) action: Action,
+    /// The body to forward and log, which is the replacement if a stage
+    /// replaced it
+    pub(crate) payload: String,
+    pub(crate) parsed: serde_json::Result<Value>,
+    pub(crate) effects: Effects,
+}
+
+/// The stages each message goes through, in order
+pub(crate) struct Stages {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Stages {
+    /// The stages `config` enables for messages travelling in
+    /// `direction`. `can_reply` is whether responses can be sent back
+    /// to the source.
+    pub(crate) fn new(direction: Direction, config: &ProxyConfig, can_reply: bool) -> Self {
+        let mut stages: Vec<Box<dyn Stage>> = vec![];
+        match direction {
+            Direction::ToServer => {
+                if config.auto_initialize {
+                    stages.push(Box::new(AutoInitialize::default()));
+                }
+                if !config.require_capabilities.is_empty() {
+                    stages.push(Box::new(RequireCapabilities::new(
+                        config.require_capabilities.clone(),
+                        can_reply,
+                    )));
+                }
+                // Throttling protects the server from the editor, so
+                // only applies to messages sent to the server.
+                if let Some(window) = config.throttle_did_change {
+                    stages.push(Box::new(DidChangeThrottle::new(window)));
+                }
+            }
+            Direction::ToEditor => {
+                if config.auto_initialize {
+                    stages.push(Box::new(AutoInitializeResponse));
+                }
+            }
+        }
+        Self { stages }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Runs a message through each stage, starting from `action`, as
+    /// decided by a plugin. Later stages see a replacement rather than
+    /// the original, and a message that's dropped or only logged goes
+    /// no further.
+    pub(crate) fn run(
+        &mut self,
+        payload: String,
+        parsed: serde_json::Result<Value>,
+        action: Action,
+        now: Instant,
+    ) -> Processed {
+        let mut processed = Processed {
+            action,
+            payload,
+            parsed,
+            effects: Effects::default(),
+        };
+        let mut replaced = false;
+        let mut stages = self.stages.iter_mut();
+        loop {
+            match std::mem::replace(&mut processed.action, Action::Forward) {
+                Action::Forward => {}
+                Action::Replace(replacement) => {
+                    processed.parsed = serde_json::from_str(&replacement);
+                    processed.payload = replacement;
+                    replaced = true;
+                }
+                action @ (Action::Drop | Action::LogOnly) => {
+                    processed.action = action;
+                    return processed;
+                }
+            }
+            let Some(stage) = stages.next() else {
+                break;
+            };
+            let message = Incoming {
+                message: processed.parsed.as_ref().ok(),
+                now,
+            };
+            processed.action = stage.process(&message, &mut processed.effects);
+        }
+        if replaced {
+            processed.action = Action::Replace(processed.payload.clone());
+        }
+        processed
+    }
+
+    /// When the next held back message is due
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.stages
+            .iter()
+            .filter_map(|stage| stage.next_deadline())
+            .min()
+    }
+
+    /// Takes the bodies of the messages held back, in the order the
+    /// stages run
+    pub(crate) fn release(&mut self, now: Instant) -> Vec<String> {
+        self.stages
+            .iter_mut()
+            .filter_map(|stage| stage.release(now))
+            .collect()
+    }
+}
+
+/// Initializes the server if the editor's first message isn't
+/// initialize, e.g. when it joins a session part way through. The
+/// editor's own initialize and initialized are then logged, but not
+/// forwarded.
+struct AutoInitialize {
+    awaiting_first_message: bool,
+    initialized: bool,
+}
+
+impl Default for AutoInitialize {
+    fn default() -> Self {
+        Self {
+            awaiting_first_message: true,
+            initialized: false,
+        }
+    }
+}
+
+impl Stage for AutoInitialize {
+    fn process(&mut self, message: &Incoming, effects: &mut Effects) -> Action {
+        let method = message.method();
+        if std::mem::take(&mut self.awaiting_first_message) {
+            if method != Some("initialize") {
+                eprintln!("Editor didn't start with initialize, initializing the server");
+                effects.inject.extend(auto_initialize_handshake());
+                self.initialized = true;
+            }
+            return Action::Forward;
+        }
+        match method {
+            Some(method @ ("initialize" | "initialized")) if self.initialized => {
+                effects.warnings.push(format!(
+                    "Not forwarding {} from the editor, the server is already initialized",
+                    method
+                ));
+                Action::LogOnly
+            }
+            _ => Action::Forward,
+        }
+    }
+}
+
+/// Logs the response to the server's initialize from `AutoInitialize`
+/// without forwarding it, as the editor didn't send the request
+struct AutoInitializeResponse;
+
+impl Stage for AutoInitializeResponse {
+    fn process(&mut self, message: &Incoming, _effects: &mut Effects) -> Action {
+        let id = message.message.and_then(|message| message.get("id"));
+        if message.method().is_none() && id == Some(&AUTO_INITIALIZE_ID.into()) {
+            Action::LogOnly
+        } else {
+            Action::Forward
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Runs each body through `stages`, returning the actions and the
+    /// effects
+    fn run(stages: &mut Stages, bodies: &[&str], now: Instant) -> Vec<(Action, Effects)> {
+        bodies
+            .iter()
+            .map(|body| {
+                let processed = stages.run(
+                    body.to_string(),
+                    serde_json::from_str(body),
+                    Action::Forward,
+                    now,
+                );
+                (processed.action, processed.effects)
+            })
+            .collect()
+    }
+
+    fn stages(stages: Vec<Box<dyn Stage>>) -> Stages {
+        Stages { stages }
+    }
+
+    /// A stage that replaces every message with `{"replaced":true}`
+    struct ReplaceAll;
+
+    impl Stage for ReplaceAll {
+        fn process(&mut self, _message: &Incoming, _effects: &mut Effects) -> Action {
+            Action::Replace(r#"{"replaced":true}"#.to_string())
+        }
+    }
+
+    /// A stage that warns with how many messages it saw, and whether
+    /// they were replaced
+    struct Count(usize);
+
+    impl Stage for Count {
+        fn process(&mut self, message: &Incoming, effects: &mut Effects) -> Action {
+            self.0 += 1;
+            let replaced = message.message.and_then(|message| message.get("replaced"));
+            effects.warnings.push(format!("{} {:?}", self.0, replaced));
+            Action::Forward
+        }
+    }
+
+    #[test]
+    fn later_stages_see_replacements() {
+        let mut stages = stages(vec![Box::new(ReplaceAll), Box::new(Count(0))]);
+        let processed = stages.run(
+            "{}".to_string(),
+            serde_json::from_str("{}"),
+            Action::Forward,
+            Instant::now(),
+        );
+        assert_eq!(
+            processed.action,
+            Action::Replace(r#"{"replaced":true}"#.to_string())
+        );
+        assert_eq!(processed.parsed.unwrap()["replaced"], true);
+        assert_eq!(processed.effects.warnings, vec!["1 Some(Bool(true))"]);
+    }
+
+    #[test]
+    fn stops_at_a_message_that_isnt_forwarded() {
+        let mut stages = stages(vec![Box::new(Count(0))]);
+        for action in [Action::Drop, Action::LogOnly] {
+            let processed = stages.run(
+                "{}".to_string(),
+                serde_json::from_str("{}"),
+                action.clone(),
+                Instant::now(),
+            );
+            assert_eq!(processed.action, action);
+            assert!(processed.effects.warnings.is_empty());
+        }
+    }
+
+    #[test]
+    fn initializes_the_server_before_another_first_message() {
+        let mut stages = stages(vec![Box::new(AutoInitialize::default())]);
+        let results = run(
+            &mut stages,
+            &[
+                r#"{"jsonrpc":"2.0","method":"textDocument/didOpen"}"#,
+                r#"{"jsonrpc":"2.0","id":1,"method":"initialize"}"#,
+                r#"{"jsonrpc":"2.0","method":"initialized"}"#,
+            ],
+            Instant::now(),
+        );
+
+        let methods: Vec<&Value> = results[0]
+            .1
+            .inject
+            .iter()
+            .map(|message| &message["method"])
+            .collect();
+        assert_eq!(methods, vec!["initialize", "initialized"]);
+        assert_eq!(results[0].0, Action::Forward);
+        assert_eq!(results[1].0, Action::LogOnly);
+        assert_eq!(results[2].0, Action::LogOnly);
+        assert_eq!(results[2].1.warnings.len(), 1);
+    }
+
+    #[test]
+    fn leaves_an_editor_that_initializes_alone() {
+        let mut stages = stages(vec![Box::new(AutoInitialize::default())]);
+        let results = run(
+            &mut stages,
+            &[
+                r#"{"jsonrpc":"2.0","id":1,"method":"initialize"}"#,
+                r#"{"jsonrpc":"2.0","method":"initialized"}"#,
+            ],
+            Instant::now(),
+        );
+        for (action, effects) in results {
+            assert_eq!(action, Action::Forward);
+            assert!(effects.inject.is_empty() && effects.warnings.is_empty());
+        }
+    }
+
+    #[test]
+    fn hides_the_response_to_our_initialize() {
+        let mut stages = stages(vec![Box::new(AutoInitializeResponse)]);
+        let ours = format!(
+            r#"{{"jsonrpc":"2.0","id":"{}","result":{{}}}}"#,
+            AUTO_INITIALIZE_ID
+        );
+        let results = run(
+            &mut stages,
+            &[&ours, r#"{"jsonrpc":"2.0","id":1,"result":{}}"#],
+            Instant::now(),
+        );
+        assert_eq!(results[0].0, Action::LogOnly);
+        assert_eq!(results[1].0, Action::Forward);
+    }
+
+    #[test]
+    fn rejects_an_initialize_missing_capabilities() {
+        let required = vec!["/workspace/applyEdit".to_string()];
+        let initialize =
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"capabilities":{}}}"#;
+
+        let mut warn_only = stages(vec![Box::new(RequireCapabilities::new(
+            required.clone(),
+            false,
+        ))]);
+        let (action, effects) = run(&mut warn_only, &[initialize], Instant::now())
+            .pop()
+            .unwrap();
+        assert_eq!(action, Action::Forward);
+        assert_eq!(effects.warnings.len(), 1);
+        assert!(effects.replies.is_empty());
+
+        let mut reject = stages(vec![Box::new(RequireCapabilities::new(required, true))]);
+        let (action, effects) = run(&mut reject, &[initialize], Instant::now())
+            .pop()
+            .unwrap();
+        assert_eq!(action, Action::LogOnly);
+        assert_eq!(effects.replies[0]["id"], 1);
+        assert!(effects.replies[0]["error"].is_object());
+    }
+
+    #[test]
+    fn holds_back_rapid_changes_until_they_are_due() {
+        let window = Duration::from_millis(100);
+        let mut stages = stages(vec![Box::new(DidChangeThrottle::new(window))]);
+        let did_change = |version| {
+            format!(
+                r#"{{"jsonrpc":"2.0","method":"textDocument/didChange","params":{{"textDocument":{{"uri":"file:///a.rs","version":{}}},"contentChanges":[]}}}}"#,
+                version
+            )
+        };
+        let now = Instant::now();
+        let results = run(&mut stages, &[&did_change(1), &did_change(2)], now);
+        assert_eq!(results[0].0, Action::Forward);
+        assert_eq!(results[1].0, Action::LogOnly);
+
+        assert_eq!(stages.next_deadline(), Some(now + window));
+        let released = stages.release(now + window);
+        assert_eq!(released.len(), 1);
+        let released: Value = serde_json::from_str(&released[0]).unwrap();
+        assert_eq!(released["params"]["textDocument"]["version"], 2);
+        assert_eq!(stages.next_deadline(), None);
+    }
+}
@@ -0,0 +1,80 @@
+use axum::extract::State;
+use axum::routing::get;
+use axum::{Json, Router};
+use serde_json::Value;
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+
+use crate::exit_status_json;
+use crate::session::SharedSession;
+
+/// The server process, as last seen by the proxy
+#[derive(Default)]
+pub(crate) struct ServerStatus {
+    /// None if we connected to a server rather than starting it
+    pid: Option<u32>,
+    exit: Option<ExitStatus>,
+    /// How many times `LSP_RESPAWN` has started the server again
+    restarts: usize,
+}
+
+pub(crate) type SharedServerStatus = Arc<Mutex<ServerStatus>>;
+
+impl ServerStatus {
+    pub(crate) fn started(&mut self, pid: Option<u32>) {
+        if self.pid.is_some() {
+            self.restarts += 1;
+        }
+        self.pid = pid;
+        self.exit = None;
+    }
+
+    pub(crate) fn exited(&mut self, status: ExitStatus) {
+        self.exit = Some(status);
+    }
+
+    fn to_json(&self) -> Value {
+        serde_json::json!({
+            "pid": self.pid,
+            "status": if self.exit.is_some() { "exited" } else { "running" },
+            "exit": self.exit.map(exit_status_json),
+            "restarts": self.restarts,
+        })
+    }
+}
+
+struct Status {
+    session: SharedSession,
+    server: SharedServerStatus,
+}
+
+/// Serves what the proxy is doing right now as JSON on `addr`, for
+/// `curl` or a live dashboard. Failing to listen doesn't stop the
+/// session.
+pub(crate) async fn serve_status(addr: String, session: SharedSession, server: SharedServerStatus) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!(
+                "Ignoring LSP_STATUS_ADDR: failed to listen on {}: {}",
+                addr, e
+            );
+            return;
+        }
+    };
+    eprintln!("Serving status on http://{}/", addr);
+
+    let app = Router::new()
+        .route("/", get(handle_status))
+        .with_state(Arc::new(Status { session, server }));
+    if let Err(e) = axum::serve(listener, app).await {
+        eprintln!("Status server failed: {}", e);
+    }
+}
+
+async fn handle_status(State(status): State<Arc<Status>>) -> Json<Value> {
+    let mut json = status.session.lock().unwrap().status_json();
+    json["server"] = status.server.lock().unwrap().to_json();
+    Json(json)
+}
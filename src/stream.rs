@@ -0,0 +1,1951 @@
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+
+use crate::anonymize::anonymize_text;
+use crate::checksum::{frame_checksum, FrameChecker};
+use crate::clock::SharedClock;
+use crate::coalesce::Coalescer;
+use crate::config::{CorruptFraming, LogFormat, ProxyConfig};
+use crate::disk_full;
+use crate::filter::Filter;
+use crate::index::SharedBinaryIndex;
+use crate::interleave::InterleaveCheck;
+use crate::json_head;
+use crate::line_log::SharedLineLog;
+use crate::log_window::SharedLogWindow;
+use crate::method_logs::SharedMethodLogs;
+use crate::plugin::{Action, PluginPipeline};
+use crate::rate_limit::SharedLogRateLimit;
+use crate::renumber::SharedIdRenumbering;
+use crate::rotate::LogRotation;
+use crate::session::SharedSession;
+use crate::sqlite::SqliteLog;
+use crate::stages::{Effects, Processed, Stages};
+use crate::strict_utf8::{InvalidUtf8, StrictUtf8};
+use crate::syslog::SharedSyslog;
+use crate::{
+    format_lsp_message, format_lsp_message_with_length, header_fields, junk_before_headers,
+    LspMessageParser,
+};
+
+/// Which way messages are travelling through the proxy
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Direction {
+    /// From the editor to the LSP server, via our stdin
+    ToServer,
+    /// From the LSP server to the editor, via our stdout
+    ToEditor,
+}
+
+impl Direction {
+    /// The stream name used in log file names and messages
+    pub(crate) fn stream_name(self) -> &'static str {
+        match self {
+            Direction::ToServer => "stdin",
+            Direction::ToEditor => "stdout",
+        }
+    }
+
+    pub(crate) fn opposite(self) -> Direction {
+        match self {
+            Direction::ToServer => Direction::ToEditor,
+            Direction::ToEditor => Direction::ToServer,
+        }
+    }
+
+    fn source_name(self) -> &'static str {
+        match self {
+            Direction::ToServer => "proxy stdin",
+            Direction::ToEditor => "LSP server stdout",
+        }
+    }
+
+    fn destination_name(self) -> &'static str {
+        match self {
+            Direction::ToServer => "LSP server stdin",
+            Direction::ToEditor => "proxy stdout",
+        }
+    }
+}
+
+/// Logs that both directions write to
+#[derive(Clone)]
+pub(crate) struct SharedLogs {
+    pub(crate) binary_index: Option<SharedBinaryIndex>,
+    pub(crate) method_logs: Option<SharedMethodLogs>,
+    pub(crate) line_log: Option<SharedLineLog>,
+    pub(crate) syslog: Option<SharedSyslog>,
+    pub(crate) sqlite: Option<SqliteLog>,
+    /// Which messages to log, if not all of them
+    pub(crate) window: Option<SharedLogWindow>,
+    /// How fast messages can be logged
+    pub(crate) rate_limit: Option<SharedLogRateLimit>,
+    /// Request ids to log in place of the ones sent
+    pub(crate) renumber_ids: Option<SharedIdRenumbering>,
+    /// Lines for the `LSP_LOG_FIFO` reader
+    pub(crate) fifo: Option<mpsc::UnboundedSender<String>>,
+}
+
+/// Where the traffic for one direction is logged
+pub(crate) struct StreamLog {
+    file: Option<File>,
+    /// One line per frame, describing where it is in the stream
+    index: Option<File>,
+    shared: SharedLogs,
+    tee: Option<mpsc::UnboundedSender<String>>,
+    rotation: Option<LogRotation>,
+    /// Whether a rotation boundary has passed, so a new log will be
+    /// opened at the end of the current message
+    rotation_due: bool,
+    /// Whether SIGHUP was received, so the log will be reopened at the
+    /// end of the current message
+    reopen_due: bool,
+    direction: Direction,
+    stream: &'static str,
+    /// The stream's label in lines sent to the collector and FIFO, as
+    /// a JSON string
+    stream_label: String,
+    format: LogFormat,
+    coalescer: Coalescer,
+    anonymize_text: bool,
+    /// Whether to add `_dt_ms` to each entry
+    log_gaps: bool,
+    /// Whether to add `_headers` to each entry
+    log_headers: bool,
+    /// Which messages to log, by their contents
+    filter: Option<Filter>,
+    /// When the previous message in this stream was read, or when the
+    /// session started
+    last_message: Instant,
+    /// Where timestamps and gaps between messages are read from
+    clock: SharedClock,
+    /// Whether a message has been written yet, so JSON array entries
+    /// know whether they need a separator
+    written_message: bool,
+}
+
+impl StreamLog {
+    pub(crate) fn new(
+        direction: Direction,
+        file: Option<File>,
+        index: Option<File>,
+        shared: SharedLogs,
+        tee: Option<mpsc::UnboundedSender<String>>,
+        rotation: Option<LogRotation>,
+        config: &ProxyConfig,
+    ) -> Self {
+        Self {
+            file,
+            index,
+            shared,
+            tee,
+            rotation,
+            rotation_due: false,
+            reopen_due: false,
+            direction,
+            stream: direction.stream_name(),
+            stream_label: stream_label(direction, config),
+            format: config.log_format,
+            coalescer: Coalescer::new(config.coalesce_methods.clone(), config.coalesce_window),
+            anonymize_text: config.anonymize_text,
+            log_gaps: config.log_gaps,
+            log_headers: config.log_headers,
+            filter: config.filter.clone(),
+            last_message: config.clock.instant(),
+            clock: config.clock.clone(),
+            written_message: false,
+        }
+    }
+
+    /// Write anything the log format needs before the first message
+    async fn start(&mut self) {
+        if self.format == LogFormat::JsonArray {
+            self.write(b"[\n").await;
+        }
+    }
+
+    /// Write any pending entries and close the log, so it's valid even
+    /// if the session ended abruptly
+    async fn finish(&mut self) {
+        self.log_bursts().await;
+
+        self.close_file().await;
+        if disk_full::logging_disabled() {
+            return;
+        }
+        if let Some(index) = self.index.as_mut() {
+            if let Err(e) = index.flush().await {
+                eprintln!("Failed to flush {} index: {}", self.stream, e);
+            }
+        }
+        if let Some(binary_index) = &self.shared.binary_index {
+            if let Err(e) = binary_index.lock().unwrap().flush() {
+                eprintln!("Failed to flush binary index: {}", e);
+            }
+        }
+        if let Some(method_logs) = &self.shared.method_logs {
+            method_logs.lock().await.flush().await;
+        }
+        if let Some(line_log) = &self.shared.line_log {
+            line_log.lock().await.flush().await;
+        }
+    }
+
+    /// Write anything the log format needs after the last message
+    async fn close_file(&mut self) {
+        if self.format == LogFormat::JsonArray {
+            self.write(b"\n]\n").await;
+        }
+
+        if disk_full::logging_disabled() {
+            return;
+        }
+        if let Some(file) = self.file.as_mut() {
+            if let Err(e) = file.flush().await {
+                disk_full::log_write_failed(&format!("{} log", self.stream), &e);
+            }
+        }
+    }
+
+    /// When the next rotation boundary is, unless one has already
+    /// passed
+    fn next_rotation(&self) -> Option<Instant> {
+        match &self.rotation {
+            Some(rotation) if !self.rotation_due => rotation.deadline(),
+            _ => None,
+        }
+    }
+
+    /// Switch to a new log if a rotation boundary has passed, or reopen
+    /// the log after SIGHUP. Raw logs are only switched between
+    /// messages, so no message is split across two files.
+    async fn rotate_if_due(&mut self, mid_message: bool) {
+        let due = self.rotation_due || self.reopen_due;
+        if disk_full::logging_disabled() {
+            self.rotation_due = false;
+            self.reopen_due = false;
+            return;
+        }
+        if !due || (mid_message && self.format == LogFormat::Raw) {
+            return;
+        }
+        let Some(rotation) = self.rotation.as_mut() else {
+            return;
+        };
+        let (opened, action) = if self.rotation_due {
+            (rotation.open_next().await, "Rotated")
+        } else {
+            (rotation.reopen().await, "Reopened")
+        };
+        self.rotation_due = false;
+        self.reopen_due = false;
+
+        match opened {
+            Ok((file, path)) => {
+                self.close_file().await;
+                eprintln!("{} {} log at {}", action, self.stream, path.display());
+                self.file = Some(file);
+                self.written_message = false;
+                self.start().await;
+            }
+            Err(e) => eprintln!(
+                "Failed to {} {} log: {:#}",
+                action.to_lowercase().trim_end_matches('d'),
+                self.stream,
+                e
+            ),
+        }
+    }
+
+    async fn write(&mut self, data: &[u8]) {
+        if disk_full::logging_disabled() {
+            return;
+        }
+        if let Some(file) = self.file.as_mut() {
+            if let Err(e) = file.write_all(data).await {
+                disk_full::log_write_failed(&format!("{} log", self.stream), &e);
+            }
+        }
+    }
+
+    /// Whether a message is inside the log window and matches the
+    /// filter. Messages that aren't JSON can't be filtered, so are
+    /// logged.
+    fn should_log(&self, parsed: &serde_json::Result<serde_json::Value>) -> bool {
+        if let Some(window) = &self.shared.window {
+            let method = match parsed {
+                Ok(message) => message.get("method").and_then(|m| m.as_str()),
+                Err(_) => None,
+            };
+            if !window.should_log(method) {
+                return false;
+            }
+        }
+        match (&self.filter, parsed) {
+            (Some(filter), Ok(message)) => filter.matches(message),
+            _ => true,
+        }
+    }
+
+    /// Whether a message of `bytes` is within `LSP_LOG_MAX_BYTES_PER_SEC`
+    fn within_rate_limit(&self, bytes: usize) -> bool {
+        match &self.shared.rate_limit {
+            Some(rate_limit) => rate_limit
+                .lock()
+                .unwrap()
+                .admit(bytes, self.clock.instant()),
+            None => true,
+        }
+    }
+
+    /// Record a frame in the index file
+    async fn index(&mut self, entry: serde_json::Value) {
+        if disk_full::logging_disabled() {
+            return;
+        }
+        if let Some(index) = self.index.as_mut() {
+            if let Err(e) = index.write_all(format!("{}\n", entry).as_bytes()).await {
+                disk_full::log_write_failed(&format!("{} index", self.stream), &e);
+            }
+        }
+    }
+
+    /// Record a frame in the binary index
+    fn index_frame(
+        &self,
+        direction: Direction,
+        offset: usize,
+        length: usize,
+        method: Option<&str>,
+    ) {
+        if disk_full::logging_disabled() {
+            return;
+        }
+        if let Some(binary_index) = &self.shared.binary_index {
+            let result =
+                binary_index
+                    .lock()
+                    .unwrap()
+                    .record(direction, offset as u64, length, method);
+            if let Err(e) = result {
+                disk_full::log_write_failed("binary index", &e);
+            }
+        }
+    }
+
+    /// Write a message to the log for its method
+    async fn log_by_method(&self, method: &str, message: &serde_json::Value) {
+        if let Some(method_logs) = &self.shared.method_logs {
+            let mut message = message.clone();
+            if self.anonymize_text {
+                anonymize_text(&mut message);
+            }
+            method_logs
+                .lock()
+                .await
+                .write(method, self.stream, &message)
+                .await;
+        }
+    }
+
+    /// Write a line describing a message to the lines log and syslog,
+    /// and add it to the SQLite capture
+    async fn log_line(
+        &self,
+        direction: Direction,
+        method: Option<&str>,
+        message: Option<&serde_json::Value>,
+        payload: &str,
+    ) {
+        let bytes = payload.len();
+        let timestamp = self.clock.now();
+        if let Some(line_log) = &self.shared.line_log {
+            line_log
+                .lock()
+                .await
+                .write(timestamp, direction, method, message, bytes)
+                .await;
+        }
+        if let Some(syslog) = &self.shared.syslog {
+            syslog.log(timestamp, direction, method, message, bytes);
+        }
+        if let Some(sqlite) = &self.shared.sqlite {
+            sqlite.log(timestamp, direction, method, message, payload);
+        }
+    }
+
+    /// Send a message to the remote collector and the FIFO, tagged with
+    /// its stream as they receive both directions together
+    fn tee(&self, message: &str) {
+        if self.tee.is_none() && self.shared.fifo.is_none() {
+            return;
+        }
+        let line = format!(
+            "{{\"stream\":{},\"message\":{}}}\n",
+            self.stream_label, message
+        );
+        if let Some(fifo) = &self.shared.fifo {
+            let _ = fifo.send(line.clone());
+        }
+        if let Some(tee) = &self.tee {
+            let _ = tee.send(line);
+        }
+    }
+
+    /// Record that a message was read, returning how long it's been
+    /// since the previous one
+    fn mark_message(&mut self) -> Duration {
+        let now = self.clock.instant();
+        let gap = now.duration_since(self.last_message);
+        self.last_message = now;
+        gap
+    }
+
+    /// Log a message body as a single JSON entry, read `gap` after the
+    /// previous message. `complete_message` is the message as read,
+    /// headers included.
+    async fn log_message(
+        &mut self,
+        json_payload: &str,
+        parsed: serde_json::Result<serde_json::Value>,
+        gap: Duration,
+        complete_message: &[u8],
+    ) {
+        // A `Content-Length: 0` message has no body at all, which some
+        // clients send as a keepalive. It isn't a parse failure.
+        if json_payload.is_empty() {
+            let mut value = serde_json::json!({ "_empty": true });
+            self.add_gap(&mut value, gap);
+            self.add_headers(&mut value, complete_message);
+            self.log_bursts().await;
+            self.log_value(&value).await;
+            return;
+        }
+
+        match parsed {
+            Ok(mut value) => {
+                if self.anonymize_text {
+                    anonymize_text(&mut value);
+                }
+                if let Some(renumber_ids) = &self.shared.renumber_ids {
+                    renumber_ids
+                        .lock()
+                        .unwrap()
+                        .renumber(self.direction, &mut value);
+                }
+                self.add_gap(&mut value, gap);
+                self.add_headers(&mut value, complete_message);
+                for value in self.coalescer.add(value, self.clock.instant()) {
+                    self.log_value(&value).await;
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to parse JSON from {}: {}", self.stream, e);
+                self.log_bursts().await;
+                // Log the raw payload as fallback
+                if self.format == LogFormat::JsonArray {
+                    // Keep the array valid JSON by logging a string.
+                    let payload = serde_json::Value::from(json_payload);
+                    self.write_entry(&payload.to_string()).await;
+                } else {
+                    self.write_entry(json_payload).await;
+                }
+                self.tee(&serde_json::Value::from(json_payload).to_string());
+            }
+        }
+    }
+
+    /// Log the bytes of a message the stream ended part way through
+    async fn log_truncated(&mut self, partial: &[u8]) {
+        let value = serde_json::json!({
+            "_truncated": true,
+            "_partial": String::from_utf8_lossy(partial),
+        });
+        self.log_bursts().await;
+        self.log_value(&value).await;
+    }
+
+    /// Log a phase boundary. Only JSON logs have room for one.
+    async fn log_marker(&mut self, marker: &serde_json::Value) {
+        if self.format.is_json() {
+            self.log_bursts().await;
+            self.log_value(marker).await;
+        }
+    }
+
+    /// Add `_dt_ms` to a log entry, if enabled
+    fn add_gap(&self, value: &mut serde_json::Value, gap: Duration) {
+        if !self.log_gaps {
+            return;
+        }
+        if let Some(object) = value.as_object_mut() {
+            object.insert("_dt_ms".to_string(), (gap.as_millis() as u64).into());
+        }
+    }
+
+    /// Add `_headers` to a log entry, if enabled
+    fn add_headers(&self, value: &mut serde_json::Value, complete_message: &[u8]) {
+        if !self.log_headers {
+            return;
+        }
+        if let Some(object) = value.as_object_mut() {
+            object.insert("_headers".to_string(), header_fields(complete_message));
+        }
+    }
+
+    async fn log_value(&mut self, value: &serde_json::Value) {
+        // Write as compact JSON line
+        if let Ok(compact) = serde_json::to_string(value) {
+            self.write_entry(&compact).await;
+            self.tee(&compact);
+        }
+    }
+
+    /// Write a single message, formatted according to the log format
+    async fn write_entry(&mut self, entry: &str) {
+        let formatted = match self.format {
+            LogFormat::JsonArray if self.written_message => format!(",\n{}", entry),
+            LogFormat::JsonArray => entry.to_string(),
+            _ => format!("{}\n", entry),
+        };
+        self.written_message = true;
+        self.write(formatted.as_bytes()).await;
+    }
+
+    /// Log the coalesced bursts still held back, before an entry for a
+    /// later message
+    async fn log_bursts(&mut self) {
+        for value in self.coalescer.take_all() {
+            self.log_value(&value).await;
+        }
+    }
+
+    /// Log any coalesced bursts whose window has elapsed
+    async fn flush_coalesced(&mut self, now: Instant) {
+        for value in self.coalescer.take_expired(now) {
+            self.log_value(&value).await;
+        }
+    }
+}
+
+/// Why a stream stopped being proxied
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum StreamEnd {
+    /// The source closed
+    Eof,
+    /// The session is shutting down
+    Shutdown,
+    /// The editor stopped reading our stdout
+    EditorDisconnected,
+    /// The editor didn't read our stdout within
+    /// `LSP_STDOUT_WRITE_TIMEOUT_MS`
+    EditorStuck,
+    /// Reading or writing failed for some other reason
+    Error,
+}
+
+/// The label for `direction` in lines sent to the collector and FIFO,
+/// `LSP_DIR_LABELS` if set
+fn stream_label(direction: Direction, config: &ProxyConfig) -> String {
+    let label = match (&config.dir_labels, direction) {
+        (Some((to_server, _)), Direction::ToServer) => to_server.as_str(),
+        (Some((_, to_editor)), Direction::ToEditor) => to_editor.as_str(),
+        (None, _) => direction.stream_name(),
+    };
+    serde_json::Value::from(label).to_string()
+}
+
+/// How the session steers a stream task, besides what it reads
+pub(crate) struct StreamControl {
+    /// Set when the session is ending
+    pub(crate) shutdown: watch::Receiver<bool>,
+    /// Messages of our own to send to the destination. They're written
+    /// between forwarded messages, never in the middle of one.
+    pub(crate) inject: mpsc::UnboundedReceiver<serde_json::Value>,
+    /// Changes when the logs should be reopened, e.g. after logrotate
+    /// has moved them away
+    pub(crate) reopen: watch::Receiver<()>,
+    /// Phase markers to write to the log
+    pub(crate) markers: mpsc::UnboundedReceiver<serde_json::Value>,
+    /// Messages to send back to the source, answering requests we
+    /// don't forward
+    pub(crate) replies: Option<mpsc::UnboundedSender<serde_json::Value>>,
+}
+
+/// A frame read from the source, and what the parser noticed about it
+struct Frame {
+    /// The frame as read, headers included
+    bytes: Vec<u8>,
+    payload: String,
+    /// How many bytes were skipped before its headers
+    stray_bytes: usize,
+    invalid_utf8: Option<InvalidUtf8>,
+    framing_error: Option<String>,
+}
+
+/// What the session made of a message read
+struct Observed {
+    method: Option<String>,
+    /// For a response, how long ago its request was sent, if it was
+    latency: Option<Option<Duration>>,
+}
+
+/// Checks on how frames were sent, which report problems but don't
+/// change what's forwarded
+struct FrameChecks {
+    direction: Direction,
+    /// Whether to count bytes outside frames, for `LSP_STRICT_LENGTH`
+    strict_length: bool,
+    strict_utf8: Option<StrictUtf8>,
+    interleave: InterleaveCheck,
+}
+
+impl FrameChecks {
+    fn new(direction: Direction, config: &ProxyConfig) -> Self {
+        Self {
+            direction,
+            strict_length: config.strict_length && direction == Direction::ToEditor,
+            strict_utf8: config.strict_utf8.then(StrictUtf8::default),
+            interleave: InterleaveCheck::default(),
+        }
+    }
+
+    /// Check a frame starting at `offset` in the stream
+    fn observe(
+        &mut self,
+        frame: &Frame,
+        unparseable: bool,
+        offset: usize,
+        session: &SharedSession,
+    ) {
+        let source = self.direction.source_name();
+        if let Some(error) = &frame.framing_error {
+            session.lock().unwrap().warn(format!(
+                "{}: framing error in the message at byte {}: {}, so it's logged compressed",
+                source, offset, error
+            ));
+        }
+        let junk = junk_before_headers(&frame.bytes);
+        if self.strict_length {
+            session
+                .lock()
+                .unwrap()
+                .observe_framing(frame.stray_bytes, junk);
+        }
+        if let (Some(strict_utf8), Some(invalid)) = (self.strict_utf8.as_mut(), &frame.invalid_utf8)
+        {
+            if let Some(warning) = strict_utf8.observe(invalid, offset) {
+                session
+                    .lock()
+                    .unwrap()
+                    .warn(format!("{}: {}", source, warning));
+            }
+        }
+        if let Some((warning, dump)) =
+            self.interleave
+                .observe(&frame.bytes, unparseable, frame.stray_bytes, junk, offset)
+        {
+            session
+                .lock()
+                .unwrap()
+                .warn(format!("{}: {}", source, warning));
+            eprint!("{}", dump);
+        }
+    }
+
+    /// Check headers the parser couldn't read, at `offset` in the stream
+    fn observe_invalid_headers(&mut self, headers: &[u8], offset: usize, session: &SharedSession) {
+        if self.strict_length {
+            session.lock().unwrap().observe_invalid_headers(headers);
+        }
+        if let Some((warning, dump)) = self.interleave.observe_invalid_headers(headers, offset) {
+            session
+                .lock()
+                .unwrap()
+                .warn(format!("{}: {}", self.direction.source_name(), warning));
+            eprint!("{}", dump);
+        }
+    }
+}
+
+/// Whole messages waiting to be forwarded, when frames are rewritten
+/// rather than forwarding the bytes read
+struct FramedOutput {
+    bytes: Vec<u8>,
+    /// Where each message ends in `bytes`, when they're written one at
+    /// a time
+    frame_ends: Option<Vec<usize>>,
+    corrupt_framing: Option<CorruptFraming>,
+}
+
+impl FramedOutput {
+    fn push_body(&mut self, body: &str) {
+        self.bytes
+            .extend_from_slice(format_lsp_message(body).as_bytes());
+    }
+
+    /// Add a frame read from the source. A replaced body needs new
+    /// headers, and `LSP_CORRUPT_FRAMING` may give it the wrong length.
+    fn push_frame(&mut self, frame: &[u8], payload: &str, replaced: bool, method: Option<&str>) {
+        let corruption = self
+            .corrupt_framing
+            .as_ref()
+            .filter(|corruption| corruption.applies_to(method));
+        match (corruption, replaced) {
+            (Some(corruption), _) => {
+                let length = corruption.content_length(payload.len());
+                self.bytes
+                    .extend_from_slice(format_lsp_message_with_length(payload, length).as_bytes());
+            }
+            (None, true) => self.push_body(payload),
+            (None, false) => self.bytes.extend_from_slice(frame),
+        }
+    }
+
+    /// Mark the end of what was added for one message read, which is
+    /// written in one go
+    fn end_message(&mut self) {
+        let len = self.bytes.len();
+        if let Some(frame_ends) = self.frame_ends.as_mut() {
+            if frame_ends.last() != Some(&len) {
+                frame_ends.push(len);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.bytes.clear();
+        if let Some(frame_ends) = self.frame_ends.as_mut() {
+            frame_ends.clear();
+        }
+    }
+}
+
+/// One direction of the proxy, and what it's read so far
+struct StreamProxy<W> {
+    direction: Direction,
+    writer: W,
+    log: StreamLog,
+    config: Arc<ProxyConfig>,
+    session: SharedSession,
+    parser: LspMessageParser,
+    /// Messages to send back to the source, answering requests we
+    /// don't forward
+    replies: Option<mpsc::UnboundedSender<serde_json::Value>>,
+    stages: Stages,
+    /// A slow plugin can process several messages at once, though each
+    /// is still forwarded in order.
+    plugin_pipeline: Option<PluginPipeline>,
+    /// Messages read that the plugin pipeline hasn't finished with, in
+    /// the order they were read
+    awaiting_plugin: VecDeque<Frame>,
+    checks: FrameChecks,
+    /// Checks frames are forwarded intact, for `LSP_CHECKSUM`
+    checker: Option<FrameChecker>,
+    /// Whether only whole messages are forwarded, as they may be
+    /// altered, dropped or held back
+    rewrite_frames: bool,
+    output: FramedOutput,
+    /// Huge bodies only need their envelope read when nothing logs or
+    /// filters on the rest of them.
+    metadata_only: Option<usize>,
+    /// Whether raw logs are written a message at a time, as only some
+    /// messages are logged
+    windowed: bool,
+    write_timeout: Option<Duration>,
+    destination_closed: bool,
+    /// Injected messages waiting for the end of the message being
+    /// forwarded
+    injected: Vec<u8>,
+    /// Whether the last write ended part way through a message
+    mid_message: bool,
+    frames: usize,
+    /// Where the next frame starts in the stream
+    stream_offset: usize,
+    /// Where the next frame will start in the log, which skips frames
+    /// that aren't logged
+    log_offset: usize,
+}
+
+impl<W: AsyncWrite + Unpin> StreamProxy<W> {
+    fn new(
+        direction: Direction,
+        writer: W,
+        log: StreamLog,
+        config: Arc<ProxyConfig>,
+        session: SharedSession,
+        replies: Option<mpsc::UnboundedSender<serde_json::Value>>,
+    ) -> Self {
+        let stages = Stages::new(direction, &config, replies.is_some());
+        // Framing corruption only applies to messages sent to the
+        // editor, as it's for testing editor LSP clients.
+        let corrupt_framing = match direction {
+            Direction::ToEditor => config.corrupt_framing.clone(),
+            Direction::ToServer => None,
+        };
+        // Some editors mishandle a message split across writes, so they
+        // can be sent one message per write.
+        let whole_messages = config.write_whole_messages && direction == Direction::ToEditor;
+        let rewrite_frames = corrupt_framing.is_some()
+            || config.plugin.is_some()
+            || !stages.is_empty()
+            || whole_messages;
+        // A frozen editor stops reading our stdout, which blocks the
+        // server when it writes more output. Likewise a server busy
+        // with its own work stops reading its stdin, which holds up the
+        // editor's requests.
+        let write_timeout = match direction {
+            Direction::ToEditor => config.stdout_write_timeout,
+            Direction::ToServer => config.server_write_timeout,
+        };
+        let plugin_pipeline = match (&config.plugin, config.plugin_concurrency) {
+            (Some(plugin), Some(concurrency)) => {
+                Some(PluginPipeline::new(plugin.clone(), direction, concurrency))
+            }
+            _ => None,
+        };
+        let metadata_only = config.metadata_only_bytes.filter(|_| {
+            !config.log_format.is_json() && !config.split_by_method && config.filter.is_none()
+        });
+        let windowed =
+            log.shared.window.is_some() || log.filter.is_some() || log.shared.rate_limit.is_some();
+        Self {
+            direction,
+            writer,
+            log,
+            session,
+            parser: LspMessageParser::new(),
+            replies,
+            stages,
+            plugin_pipeline,
+            awaiting_plugin: VecDeque::new(),
+            checks: FrameChecks::new(direction, &config),
+            // Frames are deliberately altered when rewriting them, so
+            // there's nothing to verify.
+            checker: (config.checksum && !rewrite_frames).then(FrameChecker::new),
+            rewrite_frames,
+            output: FramedOutput {
+                bytes: vec![],
+                frame_ends: whole_messages.then(Vec::new),
+                corrupt_framing,
+            },
+            metadata_only,
+            windowed,
+            write_timeout,
+            destination_closed: false,
+            injected: vec![],
+            mid_message: false,
+            frames: 0,
+            stream_offset: 0,
+            log_offset: 0,
+            config,
+        }
+    }
+
+    /// Handle bytes read from the source, returning why the stream
+    /// should end if it should
+    async fn read(
+        &mut self,
+        data: &[u8],
+        markers: &mut mpsc::UnboundedReceiver<serde_json::Value>,
+    ) -> Option<StreamEnd> {
+        self.parser.add_data(data);
+
+        let mut frames = vec![];
+        while let Some((bytes, payload)) = self.parser.try_parse_message() {
+            frames.push(Frame {
+                bytes,
+                payload,
+                stray_bytes: self.parser.stray_bytes_before_message(),
+                invalid_utf8: self.parser.take_invalid_utf8(),
+                framing_error: self.parser.take_framing_error(),
+            });
+        }
+        let parsed_messages = frames.len();
+        match self.plugin_pipeline.as_mut() {
+            // The plugin starts on each message straight away, and the
+            // message is processed once its action and those of the
+            // messages before it are back.
+            Some(pipeline) => {
+                for frame in frames {
+                    pipeline.queue(frame.payload.clone());
+                    self.awaiting_plugin.push_back(frame);
+                }
+            }
+            None => {
+                for frame in frames {
+                    let action = self.plugin_action(&frame.payload);
+                    self.process_frame(frame, action, markers).await;
+                }
+            }
+        }
+
+        if self.config.alert_thresholds.backlog_messages.is_some() {
+            self.session
+                .lock()
+                .unwrap()
+                .observe_backlog(self.direction, parsed_messages);
+        }
+        if let Some(headers) = self.parser.invalid_headers() {
+            self.checks
+                .observe_invalid_headers(headers, self.stream_offset, &self.session);
+        }
+
+        if !self.config.log_format.is_json() && !self.windowed {
+            // Log raw bytes
+            self.log.write(data).await;
+        }
+        self.rotate_log_if_due().await;
+
+        // The server has closed its input but may still be writing
+        // responses, so keep reading (and logging) editor input rather
+        // than ending the session.
+        if self.destination_closed {
+            self.output.clear();
+            return None;
+        }
+        self.forward(data).await
+    }
+
+    /// Whether enough messages are waiting for the plugin pipeline that
+    /// reading should wait for it to catch up
+    fn plugin_backlogged(&self) -> bool {
+        self.plugin_pipeline
+            .as_ref()
+            .is_some_and(|pipeline| self.awaiting_plugin.len() >= pipeline.concurrency())
+    }
+
+    /// Waits for the plugin's action on the oldest message awaiting it.
+    /// Safe to cancel, as the message keeps its place.
+    async fn next_plugin_action(&mut self) -> Action {
+        match self.plugin_pipeline.as_mut() {
+            Some(pipeline) => pipeline.next().await,
+            None => Action::Forward,
+        }
+    }
+
+    /// Process and forward the oldest message awaiting the plugin,
+    /// now its action is back, returning why the stream should end if
+    /// it should
+    async fn process_from_plugin(
+        &mut self,
+        action: Action,
+        markers: &mut mpsc::UnboundedReceiver<serde_json::Value>,
+    ) -> Option<StreamEnd> {
+        let frame = self.awaiting_plugin.pop_front()?;
+        self.process_frame(frame, action, markers).await;
+        self.rotate_log_if_due().await;
+        if self.destination_closed {
+            self.output.clear();
+            return None;
+        }
+        // Messages awaiting the plugin are only forwarded whole.
+        self.forward(&[]).await
+    }
+
+    /// Process every message still awaiting the plugin, at the end of
+    /// the stream
+    async fn drain_plugin(
+        &mut self,
+        markers: &mut mpsc::UnboundedReceiver<serde_json::Value>,
+    ) -> Option<StreamEnd> {
+        while !self.awaiting_plugin.is_empty() {
+            let action = self.next_plugin_action().await;
+            if let Some(end) = self.process_from_plugin(action, markers).await {
+                return Some(end);
+            }
+        }
+        None
+    }
+
+    /// Track, check, forward and log a frame
+    async fn process_frame(
+        &mut self,
+        frame: Frame,
+        action: Action,
+        markers: &mut mpsc::UnboundedReceiver<serde_json::Value>,
+    ) {
+        let gap = self.log.mark_message();
+        let head = self
+            .metadata_only
+            .filter(|&limit| frame.payload.len() > limit)
+            .and_then(|_| json_head::scan(&frame.payload));
+        let parsed = match head {
+            Some(head) => Ok(head),
+            None => serde_json::from_str::<serde_json::Value>(&frame.payload),
+        };
+        let unparseable = parsed.is_err() && !frame.payload.is_empty();
+        self.checks
+            .observe(&frame, unparseable, self.stream_offset, &self.session);
+        let observed = self.observe(&parsed, &frame.payload);
+        self.index(&frame.bytes, observed.method.as_deref()).await;
+        let logged = self.admit_to_log(&parsed, frame.bytes.len(), observed.method.as_deref());
+
+        let now = self.config.clock.instant();
+        let Processed {
+            action,
+            payload,
+            parsed,
+            effects,
+        } = self.stages.run(frame.payload, parsed, action, now);
+        self.apply(effects);
+
+        if self.rewrite_frames {
+            if !matches!(action, Action::Drop | Action::LogOnly) {
+                let replaced = matches!(action, Action::Replace(_));
+                self.output.push_frame(
+                    &frame.bytes,
+                    &payload,
+                    replaced,
+                    observed.method.as_deref(),
+                );
+            }
+            self.output.end_message();
+        }
+
+        if !logged {
+            return;
+        }
+        if action == Action::Drop {
+            // Raw logs still record exactly what was read.
+            if !self.config.log_format.is_json() && self.windowed {
+                self.log.write(&frame.bytes).await;
+            }
+            return;
+        }
+        self.log_frame(&frame.bytes, &payload, parsed, &observed, gap, markers)
+            .await;
+    }
+
+    /// Let the session see a message, returning its method and latency
+    fn observe(&self, parsed: &serde_json::Result<serde_json::Value>, payload: &str) -> Observed {
+        let Ok(message) = parsed else {
+            return Observed {
+                method: None,
+                latency: None,
+            };
+        };
+        let mut session = self.session.lock().unwrap();
+        let method = session.observe(self.direction, message, payload);
+        let is_response = message.get("id").is_some() && message.get("method").is_none();
+        let latency =
+            (self.config.log_latency && self.direction == Direction::ToEditor && is_response)
+                .then(|| session.response_latency());
+        Observed { method, latency }
+    }
+
+    /// Record a frame in the index file, and move past it
+    async fn index(&mut self, frame: &[u8], method: Option<&str>) {
+        if self.config.checksum {
+            let checksum = frame_checksum(frame);
+            if let Some(checker) = self.checker.as_mut() {
+                checker.expect(self.frames, checksum);
+            }
+            self.log
+                .index(serde_json::json!({
+                    "seq": self.frames,
+                    "offset": self.stream_offset,
+                    "length": frame.len(),
+                    "method": method,
+                    "checksum": format!("{:016x}", checksum),
+                }))
+                .await;
+        }
+        self.frames += 1;
+        self.stream_offset += frame.len();
+    }
+
+    /// Whether a frame of `length` bytes should be logged, recording
+    /// where it will be in the log if so
+    fn admit_to_log(
+        &mut self,
+        parsed: &serde_json::Result<serde_json::Value>,
+        length: usize,
+        method: Option<&str>,
+    ) -> bool {
+        if !self.log.should_log(parsed) {
+            return false;
+        }
+        if !self.log.within_rate_limit(length) {
+            self.session
+                .lock()
+                .unwrap()
+                .observe_log_dropped(self.direction);
+            return false;
+        }
+        self.log
+            .index_frame(self.direction, self.log_offset, length, method);
+        self.log_offset += length;
+        true
+    }
+
+    /// What the plugin wants done with a message, if there is one and
+    /// it isn't run in a pipeline
+    fn plugin_action(&self, payload: &str) -> Action {
+        match &self.config.plugin {
+            Some(plugin) => plugin.process(self.direction, payload),
+            None => Action::Forward,
+        }
+    }
+
+    /// Do what the stages asked for besides forwarding the message
+    fn apply(&mut self, effects: Effects) {
+        let mut session = self.session.lock().unwrap();
+        for warning in effects.warnings {
+            session.warn(warning);
+        }
+        for message in effects.inject {
+            let payload = message.to_string();
+            session.observe(self.direction, &message, &payload);
+            self.output.push_body(&payload);
+        }
+        for body in &effects.release {
+            self.output.push_body(body);
+        }
+        if let Some(replies) = &self.replies {
+            for reply in effects.replies {
+                let _ = replies.send(reply);
+            }
+        }
+    }
+
+    /// Write a frame to the logs that take the messages themselves
+    async fn log_frame(
+        &mut self,
+        frame: &[u8],
+        payload: &str,
+        mut parsed: serde_json::Result<serde_json::Value>,
+        observed: &Observed,
+        gap: Duration,
+        markers: &mut mpsc::UnboundedReceiver<serde_json::Value>,
+    ) {
+        let method = observed.method.as_deref();
+        if let (Some(method), Ok(message)) = (method, &parsed) {
+            self.log.log_by_method(method, message).await;
+        }
+        self.log
+            .log_line(self.direction, method, parsed.as_ref().ok(), payload)
+            .await;
+
+        if self.config.log_format.is_json() {
+            // A phase this message started is marked before it.
+            while let Ok(marker) = markers.try_recv() {
+                self.log.log_marker(&marker).await;
+            }
+            if let (Some(latency), Ok(serde_json::Value::Object(message))) =
+                (observed.latency, parsed.as_mut())
+            {
+                let latency = latency.map(|latency| latency.as_millis() as u64);
+                message.insert("_latency_ms".to_string(), latency.into());
+            }
+            self.log.log_message(payload, parsed, gap, frame).await;
+        } else if self.windowed {
+            self.log.write(frame).await;
+        }
+    }
+
+    /// Forward what was read, or the frames rewritten from it,
+    /// returning why the stream should end if it should
+    async fn forward(&mut self, data: &[u8]) -> Option<StreamEnd> {
+        // When rewriting frames, only whole messages are forwarded.
+        let data = if self.rewrite_frames {
+            &self.output.bytes[..]
+        } else {
+            data
+        };
+
+        let frame_ends = self.output.frame_ends.as_deref();
+        let writer = &mut self.writer;
+        let write = async {
+            if let Some(frame_ends) = frame_ends {
+                let mut start = 0;
+                for &end in frame_ends {
+                    writer.write_all(&data[start..end]).await?;
+                    writer.flush().await?;
+                    start = end;
+                }
+                return Ok(());
+            }
+            writer.write_all(data).await?;
+            // Flush to ensure data is sent
+            writer.flush().await
+        };
+        tokio::pin!(write);
+        let forwarded = match (self.write_timeout, self.direction) {
+            (Some(timeout), Direction::ToEditor) => {
+                wait_for_editor(&mut write, timeout, &self.config, &self.session).await
+            }
+            (Some(timeout), Direction::ToServer) => {
+                wait_for_server(&mut write, timeout, &self.config, &self.session).await
+            }
+            (None, _) => write.await,
+        };
+
+        if let Err(e) = forwarded {
+            self.output.clear();
+            return self.write_failed(e);
+        }
+
+        if let Some(checker) = self.checker.as_mut() {
+            for problem in checker.check_output(data) {
+                self.session.lock().unwrap().warn(format!(
+                    "{}: {}",
+                    self.direction.stream_name(),
+                    problem
+                ));
+            }
+        }
+        self.output.clear();
+
+        // Raw reads can end part way through a message, in which case
+        // injected messages wait for the rest.
+        self.mid_message = !self.rewrite_frames && self.parser.has_partial_message();
+        if !self.mid_message && !self.injected.is_empty() {
+            self.write_injected().await;
+        }
+        None
+    }
+
+    /// Stop forwarding after a write failed, returning why the stream
+    /// should end if it should
+    fn write_failed(&mut self, e: std::io::Error) -> Option<StreamEnd> {
+        self.destination_closed = true;
+        let direction = self.direction;
+        if direction == Direction::ToServer && e.kind() == ErrorKind::BrokenPipe {
+            eprintln!("LSP server closed its stdin, editor input will no longer be forwarded");
+            return None;
+        }
+        if direction == Direction::ToEditor && e.kind() == ErrorKind::TimedOut {
+            eprintln!("Editor stopped reading: {}", e);
+            return Some(StreamEnd::EditorStuck);
+        }
+        if direction == Direction::ToEditor
+            && matches!(e.kind(), ErrorKind::BrokenPipe | ErrorKind::ConnectionReset)
+        {
+            eprintln!("Editor disconnected: {}", e);
+            return Some(StreamEnd::EditorDisconnected);
+        }
+        eprintln!("Failed to write to {}: {}", direction.destination_name(), e);
+        Some(StreamEnd::Error)
+    }
+
+    /// Send a message of our own, between forwarded messages
+    async fn inject(&mut self, message: serde_json::Value) {
+        let payload = message.to_string();
+        self.session
+            .lock()
+            .unwrap()
+            .observe(self.direction, &message, &payload);
+        self.injected
+            .extend_from_slice(format_lsp_message(&payload).as_bytes());
+        if !self.mid_message && !self.destination_closed {
+            self.write_injected().await;
+        }
+    }
+
+    async fn write_injected(&mut self) {
+        if let Err(e) = write_injected(&mut self.writer, &mut self.injected).await {
+            eprintln!(
+                "Failed to write to {}: {}",
+                self.direction.destination_name(),
+                e
+            );
+            self.destination_closed = true;
+        }
+    }
+
+    /// Forward the messages the stages held back, once they're due or
+    /// when the stream ends
+    async fn release_held(&mut self) {
+        for body in self.stages.release(self.config.clock.instant()) {
+            if self.destination_closed {
+                break;
+            }
+            if let Err(e) = write_message(&mut self.writer, &body).await {
+                eprintln!(
+                    "Failed to write to {}: {}",
+                    self.direction.destination_name(),
+                    e
+                );
+                self.destination_closed = true;
+            }
+        }
+    }
+
+    async fn rotate_log_if_due(&mut self) {
+        self.log
+            .rotate_if_due(self.parser.has_partial_message())
+            .await;
+    }
+
+    /// Log the end of the stream, and close the destination
+    async fn finish(mut self) {
+        // The start of a message that never finished shows how far the
+        // sender got, e.g. which field a server was writing when it
+        // crashed. Raw logs already have the bytes.
+        let partial = self.parser.partial_message();
+        if !partial.is_empty() {
+            self.session
+                .lock()
+                .unwrap()
+                .observe_truncated(self.direction, partial.len());
+            if self.config.log_format.is_json() {
+                self.log.log_truncated(partial).await;
+            }
+        }
+
+        if !self.destination_closed {
+            // Don't lose a change that's still being held back.
+            self.release_held().await;
+            // Close our side cleanly, e.g. so a TLS peer gets a
+            // close_notify.
+            let _ = self.writer.shutdown().await;
+        }
+
+        self.log.finish().await;
+    }
+}
+
+/// Forwards everything read from `reader` to `writer`, logging it on
+/// the way through. Stops at EOF, on an I/O error, or when shutdown is
+/// set.
+pub(crate) async fn proxy_stream<R, W>(
+    direction: Direction,
+    mut reader: R,
+    writer: W,
+    log: StreamLog,
+    config: Arc<ProxyConfig>,
+    session: SharedSession,
+    control: StreamControl,
+) -> StreamEnd
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let StreamControl {
+        mut shutdown,
+        mut inject,
+        mut reopen,
+        mut markers,
+        replies,
+    } = control;
+    let mut proxy = StreamProxy::new(direction, writer, log, config.clone(), session, replies);
+    let mut buffer = vec![0u8; 8192];
+    let mut inject_open = true;
+    let mut reopen_open = true;
+    let mut markers_open = true;
+
+    proxy.log.start().await;
+
+    let end = loop {
+        let next_flush = proxy.log.coalescer.next_deadline();
+        let next_rotation = proxy.log.next_rotation();
+        let next_release = proxy.stages.next_deadline();
+        let plugin_backlogged = proxy.plugin_backlogged();
+        let awaiting_plugin = !proxy.awaiting_plugin.is_empty();
+        let read_result = tokio::select! {
+            result = reader.read(&mut buffer), if !plugin_backlogged => result,
+            action = proxy.next_plugin_action(), if awaiting_plugin => {
+                if let Some(end) = proxy.process_from_plugin(action, &mut markers).await {
+                    break end;
+                }
+                continue;
+            }
+            _ = config.clock.sleep_until(next_flush.unwrap_or_else(Instant::now)), if next_flush.is_some() => {
+                proxy.log.flush_coalesced(config.clock.instant()).await;
+                continue;
+            }
+            _ = config.clock.sleep_until(next_rotation.unwrap_or_else(Instant::now)), if next_rotation.is_some() => {
+                proxy.log.rotation_due = true;
+                proxy.rotate_log_if_due().await;
+                continue;
+            }
+            _ = config.clock.sleep_until(next_release.unwrap_or_else(Instant::now)), if next_release.is_some() => {
+                proxy.release_held().await;
+                continue;
+            }
+            changed = reopen.changed(), if reopen_open => {
+                if changed.is_err() {
+                    reopen_open = false;
+                    continue;
+                }
+                proxy.log.reopen_due = true;
+                proxy.rotate_log_if_due().await;
+                continue;
+            }
+            marker = markers.recv(), if markers_open => {
+                match marker {
+                    Some(marker) => proxy.log.log_marker(&marker).await,
+                    None => markers_open = false,
+                }
+                continue;
+            }
+            message = inject.recv(), if inject_open => {
+                match message {
+                    Some(message) => proxy.inject(message).await,
+                    None => inject_open = false,
+                }
+                continue;
+            }
+            _ = shutdown.changed() => break StreamEnd::Shutdown,
+        };
+
+        match read_result {
+            // EOF reached
+            Ok(0) => {
+                break proxy
+                    .drain_plugin(&mut markers)
+                    .await
+                    .unwrap_or(StreamEnd::Eof)
+            }
+            Ok(n) => {
+                if let Some(end) = proxy.read(&buffer[..n], &mut markers).await {
+                    break end;
+                }
+            }
+            Err(e) => {
+                eprintln!("Error reading from {}: {}", direction.source_name(), e);
+                break StreamEnd::Error;
+            }
+        }
+    };
+
+    proxy.finish().await;
+    end
+}
+
+/// Waits up to `timeout` for `write`, returning None if it's still
+/// going
+async fn write_within<F>(
+    write: &mut F,
+    timeout: Duration,
+    clock: &SharedClock,
+) -> Option<std::io::Result<()>>
+where
+    F: std::future::Future<Output = std::io::Result<()>> + Unpin,
+{
+    tokio::select! {
+        result = &mut *write => Some(result),
+        _ = clock.sleep_until(clock.instant() + timeout) => None,
+    }
+}
+
+/// Waits for a write to the editor, warning if it takes longer than
+/// `timeout`. Fails with `TimedOut` instead if the session should end.
+async fn wait_for_editor<F>(
+    write: &mut F,
+    timeout: Duration,
+    config: &ProxyConfig,
+    session: &SharedSession,
+) -> std::io::Result<()>
+where
+    F: std::future::Future<Output = std::io::Result<()>> + Unpin,
+{
+    let started = config.clock.instant();
+    if let Some(result) = write_within(write, timeout, &config.clock).await {
+        return result;
+    }
+
+    session.lock().unwrap().warn(format!(
+        "editor hasn't read proxy stdout for {}ms, it may be stuck",
+        timeout.as_millis()
+    ));
+    if config.stdout_write_timeout_exit {
+        return Err(std::io::Error::new(
+            ErrorKind::TimedOut,
+            "stdout write timed out",
+        ));
+    }
+
+    let result = write.await;
+    eprintln!(
+        "Editor read proxy stdout again after {}ms",
+        config.clock.instant().duration_since(started).as_millis()
+    );
+    result
+}
+
+/// Waits for a write to the server, warning if it takes longer than
+/// `timeout`
+async fn wait_for_server<F>(
+    write: &mut F,
+    timeout: Duration,
+    config: &ProxyConfig,
+    session: &SharedSession,
+) -> std::io::Result<()>
+where
+    F: std::future::Future<Output = std::io::Result<()>> + Unpin,
+{
+    let started = config.clock.instant();
+    if let Some(result) = write_within(write, timeout, &config.clock).await {
+        return result;
+    }
+
+    session.lock().unwrap().warn(format!(
+        "server slow to read input: it hasn't read its stdin for {}ms, it may be stuck",
+        timeout.as_millis()
+    ));
+    let result = write.await;
+    eprintln!(
+        "LSP server read its stdin again after {}ms",
+        config.clock.instant().duration_since(started).as_millis()
+    );
+    result
+}
+
+/// Write the framed messages in `injected`, emptying it
+async fn write_injected<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    injected: &mut Vec<u8>,
+) -> std::io::Result<()> {
+    writer.write_all(injected).await?;
+    injected.clear();
+    writer.flush().await
+}
+
+/// Forward a single message body, with headers
+async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, json: &str) -> std::io::Result<()> {
+    writer
+        .write_all(format_lsp_message(json).as_bytes())
+        .await?;
+    writer.flush().await
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::clock::SteppingClock;
+    use crate::line_log::{LineFormat, LineLog};
+    use crate::plugin::Plugin;
+    use crate::session::Session;
+    use chrono::{DateTime, Local};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    /// A path for a test's log, unique to this process
+    pub(crate) fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("lsp-fiddle-test-{}-{}", std::process::id(), name))
+    }
+
+    pub(crate) fn frame(body: &str) -> String {
+        format_lsp_message(body)
+    }
+
+    pub(crate) fn start() -> DateTime<Local> {
+        DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Local)
+    }
+
+    /// A config logging JSON lines with a fake clock, so tests see the
+    /// same timestamps
+    pub(crate) fn test_config() -> ProxyConfig {
+        ProxyConfig {
+            log_format: LogFormat::JsonLines,
+            clock: Arc::new(SteppingClock::new(start())),
+            ..ProxyConfig::default()
+        }
+    }
+
+    pub(crate) fn test_session(config: &ProxyConfig) -> SharedSession {
+        Arc::new(Mutex::new(Session::new(
+            config,
+            None,
+            config.clock.instant(),
+            None,
+        )))
+    }
+
+    pub(crate) fn no_shared_logs() -> SharedLogs {
+        SharedLogs {
+            binary_index: None,
+            method_logs: None,
+            line_log: None,
+            syslog: None,
+            sqlite: None,
+            window: None,
+            rate_limit: None,
+            renumber_ids: None,
+            fifo: None,
+        }
+    }
+
+    /// Proxies `input` in `direction` until it ends, returning what was
+    /// forwarded and what was logged
+    pub(crate) async fn run_stream(
+        direction: Direction,
+        input: impl AsyncRead + Unpin,
+        config: &Arc<ProxyConfig>,
+        session: &SharedSession,
+        shared: SharedLogs,
+        name: &str,
+    ) -> (Vec<u8>, String) {
+        let path = temp_path(name);
+        let file = File::create(&path).await.unwrap();
+        let log = StreamLog::new(direction, Some(file), None, shared, None, None, config);
+        let (_shutdown_sender, shutdown) = watch::channel(false);
+        let (_inject_sender, inject) = mpsc::unbounded_channel();
+        let (_reopen_sender, reopen) = watch::channel(());
+        let (_markers_sender, markers) = mpsc::unbounded_channel();
+        let control = StreamControl {
+            shutdown,
+            inject,
+            reopen,
+            markers,
+            replies: None,
+        };
+        let mut forwarded = vec![];
+        proxy_stream(
+            direction,
+            input,
+            &mut forwarded,
+            log,
+            config.clone(),
+            session.clone(),
+            control,
+        )
+        .await;
+        let logged = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        (forwarded, logged)
+    }
+
+    /// Runs a request and its response through the proxy, returning
+    /// the lines log and the stdout log
+    async fn request_and_response(name: &str) -> (String, String) {
+        let mut config = test_config();
+        config.log_gaps = true;
+        config.log_latency = true;
+        let config = Arc::new(config);
+        let session = test_session(&config);
+        let lines_path = temp_path(&format!("{}_lines.txt", name));
+        let line_log = LineLog::new(
+            LineFormat::parse("{ts} {dir} {method}"),
+            File::create(&lines_path).await.unwrap(),
+        );
+        let shared = SharedLogs {
+            line_log: Some(Arc::new(tokio::sync::Mutex::new(line_log))),
+            ..no_shared_logs()
+        };
+
+        let request = r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/hover","params":{}}"#;
+        let notification = r#"{"jsonrpc":"2.0","method":"$/cancelRequest","params":{"id":0}}"#;
+        let input = format!("{}{}", frame(request), frame(notification));
+        run_stream(
+            Direction::ToServer,
+            input.as_bytes(),
+            &config,
+            &session,
+            shared.clone(),
+            &format!("{}_stdin.jsonl", name),
+        )
+        .await;
+        let response = r#"{"jsonrpc":"2.0","id":1,"result":null}"#;
+        let (_, stdout_log) = run_stream(
+            Direction::ToEditor,
+            frame(response).as_bytes(),
+            &config,
+            &session,
+            shared,
+            &format!("{}_stdout.jsonl", name),
+        )
+        .await;
+
+        let lines = std::fs::read_to_string(&lines_path).unwrap();
+        let _ = std::fs::remove_file(&lines_path);
+        (lines, stdout_log)
+    }
+
+    #[tokio::test]
+    async fn fake_clock_gives_the_same_timestamps_and_latencies() {
+        let (lines, stdout_log) = request_and_response("deterministic_first").await;
+        assert_eq!(
+            (lines.clone(), stdout_log.clone()),
+            request_and_response("deterministic_second").await
+        );
+
+        let timestamp = |millis| {
+            (start() + Duration::from_millis(millis))
+                .format("%Y-%m-%dT%H:%M:%S%.3f")
+                .to_string()
+        };
+        let timestamps: Vec<&str> = lines
+            .lines()
+            .map(|line| line.split(' ').next().unwrap())
+            .collect();
+        assert_eq!(timestamps, (2..=4).map(timestamp).collect::<Vec<_>>());
+
+        let response: serde_json::Value =
+            serde_json::from_str(stdout_log.lines().next().unwrap()).unwrap();
+        assert_eq!(response["_latency_ms"], 3);
+    }
+
+    #[tokio::test]
+    async fn logs_a_coalesced_burst_before_a_later_message() {
+        let mut config = test_config();
+        config.coalesce_methods = vec!["textDocument/didChange".to_string()];
+        config.coalesce_window = Duration::from_secs(60);
+        let config = Arc::new(config);
+        let session = test_session(&config);
+
+        let did_change = |version| {
+            format!(
+                r#"{{"jsonrpc":"2.0","method":"textDocument/didChange","params":{{"textDocument":{{"uri":"file:///a.rs","version":{}}},"contentChanges":[]}}}}"#,
+                version
+            )
+        };
+        let hover = r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/hover","params":{}}"#;
+        let input = format!(
+            "{}{}{}",
+            frame(&did_change(1)),
+            frame(&did_change(2)),
+            frame(hover)
+        );
+        let (_, logged) = run_stream(
+            Direction::ToServer,
+            input.as_bytes(),
+            &config,
+            &session,
+            no_shared_logs(),
+            "coalesced_order.jsonl",
+        )
+        .await;
+
+        let entries: Vec<serde_json::Value> = logged
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["params"]["textDocument"]["version"], 2);
+        assert_eq!(entries[0]["_coalesced"], 2);
+        assert_eq!(entries[1]["method"], "textDocument/hover");
+    }
+
+    #[tokio::test]
+    async fn forwards_and_logs_an_empty_body() {
+        let mut config = test_config();
+        config.log_headers = true;
+        let config = Arc::new(config);
+        let session = test_session(&config);
+        let exit = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        let input = format!("Content-Length: 0\r\n\r\n{}", frame(exit));
+
+        let (forwarded, logged) = run_stream(
+            Direction::ToServer,
+            input.as_bytes(),
+            &config,
+            &session,
+            no_shared_logs(),
+            "empty_body.jsonl",
+        )
+        .await;
+
+        assert_eq!(forwarded, input.as_bytes());
+        let entries: Vec<serde_json::Value> = logged
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            entries[0],
+            serde_json::json!({"_empty": true, "_headers": {"Content-Length": 0}})
+        );
+        assert_eq!(entries[1]["method"], "exit");
+        assert_eq!(entries.len(), 2);
+    }
+
+    /// The messages in forwarded bytes
+    fn forwarded_messages(forwarded: &[u8]) -> Vec<serde_json::Value> {
+        let mut parser = LspMessageParser::new();
+        parser.add_data(forwarded);
+        std::iter::from_fn(|| parser.try_parse_message())
+            .map(|(_, payload)| serde_json::from_str(&payload).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn initializes_a_server_joined_mid_session() {
+        let mut config = test_config();
+        config.auto_initialize = true;
+        let config = Arc::new(config);
+        let session = test_session(&config);
+        let did_open = r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{}}"#;
+        let initialize = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#;
+        let input = format!("{}{}", frame(did_open), frame(initialize));
+
+        let (forwarded, logged) = run_stream(
+            Direction::ToServer,
+            input.as_bytes(),
+            &config,
+            &session,
+            no_shared_logs(),
+            "auto_initialize.jsonl",
+        )
+        .await;
+
+        // The editor's late initialize is logged, but not forwarded.
+        let methods: Vec<serde_json::Value> = forwarded_messages(&forwarded)
+            .into_iter()
+            .map(|message| message["method"].clone())
+            .collect();
+        assert_eq!(
+            methods,
+            vec!["initialize", "initialized", "textDocument/didOpen"]
+        );
+        assert_eq!(logged.lines().count(), 2);
+    }
+
+    /// A plugin's `process_message` that drops didOpen and forwards
+    /// everything else
+    unsafe extern "C" fn drop_did_open(
+        _direction: u8,
+        json: *const u8,
+        len: usize,
+        _replacement: *mut *mut u8,
+        _replacement_len: *mut usize,
+    ) -> i32 {
+        // SAFETY: the proxy passes `len` bytes of message body.
+        let json = unsafe { std::slice::from_raw_parts(json, len) };
+        let message: serde_json::Value = serde_json::from_slice(json).unwrap();
+        if message["method"] == "textDocument/didOpen" {
+            1
+        } else {
+            0
+        }
+    }
+
+    unsafe extern "C" fn free_nothing(_ptr: *mut u8, _len: usize) {}
+
+    #[tokio::test]
+    async fn later_stages_dont_see_what_a_plugin_drops() {
+        let mut config = test_config();
+        config.auto_initialize = true;
+        config.plugin = Some(Plugin::from_functions(drop_did_open, free_nothing));
+        let config = Arc::new(config);
+        let session = test_session(&config);
+        let did_open = r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{}}"#;
+        let initialize = r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#;
+        let input = format!("{}{}", frame(did_open), frame(initialize));
+
+        let (forwarded, _) = run_stream(
+            Direction::ToServer,
+            input.as_bytes(),
+            &config,
+            &session,
+            no_shared_logs(),
+            "plugin_drops_first.jsonl",
+        )
+        .await;
+
+        // The dropped didOpen never reaches auto-initialize, so the
+        // editor's initialize is the first message it sees and goes to
+        // the server unchanged.
+        assert_eq!(
+            forwarded_messages(&forwarded),
+            vec![serde_json::from_str::<serde_json::Value>(initialize).unwrap()]
+        );
+    }
+
+    /// How many calls to `slow_forward` are running, and the most
+    /// there have been at once
+    static SLOW_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static MOST_SLOW_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    /// A plugin's `process_message` that takes a while to forward
+    /// each message
+    unsafe extern "C" fn slow_forward(
+        _direction: u8,
+        _json: *const u8,
+        _len: usize,
+        _replacement: *mut *mut u8,
+        _replacement_len: *mut usize,
+    ) -> i32 {
+        let calls = SLOW_CALLS.fetch_add(1, Ordering::SeqCst) + 1;
+        MOST_SLOW_CALLS.fetch_max(calls, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(200));
+        SLOW_CALLS.fetch_sub(1, Ordering::SeqCst);
+        0
+    }
+
+    #[tokio::test]
+    async fn runs_a_slow_plugin_on_messages_from_separate_reads_at_once() {
+        let mut config = test_config();
+        config.plugin = Some(Plugin::from_functions(slow_forward, free_nothing));
+        config.plugin_concurrency = Some(4);
+        let config = Arc::new(config);
+        let session = test_session(&config);
+        let messages: Vec<serde_json::Value> = (1..=4)
+            .map(|id| serde_json::json!({"jsonrpc": "2.0", "id": id, "method": "textDocument/hover"}))
+            .collect();
+
+        // The pipe holds one message, so each arrives in a read of its
+        // own, and the next can only be sent once the last is read.
+        let frames: Vec<String> = messages.iter().map(|m| frame(&m.to_string())).collect();
+        let (mut editor, input) = tokio::io::duplex(frames[0].len());
+        tokio::spawn(async move {
+            for frame in frames {
+                editor.write_all(frame.as_bytes()).await.unwrap();
+            }
+        });
+        let (forwarded, _) = run_stream(
+            Direction::ToServer,
+            input,
+            &config,
+            &session,
+            no_shared_logs(),
+            "slow_plugin.jsonl",
+        )
+        .await;
+
+        assert!(MOST_SLOW_CALLS.load(Ordering::SeqCst) > 1);
+        assert_eq!(forwarded_messages(&forwarded), messages);
+    }
+
+    #[tokio::test]
+    async fn merges_rapid_changes_to_a_document() {
+        let mut config = test_config();
+        config.throttle_did_change = Some(Duration::from_secs(60));
+        let config = Arc::new(config);
+        let session = test_session(&config);
+        let did_change = |version| {
+            format!(
+                r#"{{"jsonrpc":"2.0","method":"textDocument/didChange","params":{{"textDocument":{{"uri":"file:///a.rs","version":{}}},"contentChanges":[{{"text":"{}"}}]}}}}"#,
+                version, version
+            )
+        };
+        let hover = r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/hover","params":{}}"#;
+        let input = format!(
+            "{}{}{}{}",
+            frame(&did_change(1)),
+            frame(&did_change(2)),
+            frame(&did_change(3)),
+            frame(hover)
+        );
+
+        let (forwarded, logged) = run_stream(
+            Direction::ToServer,
+            input.as_bytes(),
+            &config,
+            &session,
+            no_shared_logs(),
+            "throttle.jsonl",
+        )
+        .await;
+
+        // The second and third changes are held back and merged, until
+        // the hover needs to be sent.
+        let forwarded = forwarded_messages(&forwarded);
+        assert_eq!(forwarded.len(), 3);
+        assert_eq!(forwarded[0]["params"]["textDocument"]["version"], 1);
+        assert_eq!(forwarded[1]["params"]["textDocument"]["version"], 3);
+        assert_eq!(forwarded[2]["method"], "textDocument/hover");
+        // Every change is still logged as it was sent.
+        assert_eq!(logged.lines().count(), 4);
+    }
+}
@@ -0,0 +1,78 @@
+use serde_json::Value;
+
+/// How many junk warnings to print before only counting
+const MAX_WARNINGS: usize = 5;
+
+/// Checks that the server's messages follow each other exactly, with
+/// nothing between one body and the next headers. Junk there usually
+/// means the previous Content-Length was too short.
+#[derive(Default)]
+pub(crate) struct StrictLength {
+    messages: usize,
+    messages_after_junk: usize,
+    junk_bytes: usize,
+    /// The largest run of junk before a message
+    max_junk_bytes: usize,
+    warned_invalid_headers: bool,
+}
+
+impl StrictLength {
+    /// Record a message from the server, with the whitespace skipped
+    /// before it and any other junk at the start of its headers.
+    /// Returns a warning for the first few messages after junk.
+    pub(crate) fn observe(&mut self, stray_bytes: usize, junk: &[u8]) -> Option<String> {
+        self.messages += 1;
+        let bytes = stray_bytes + junk.len();
+        if bytes == 0 {
+            return None;
+        }
+        self.messages_after_junk += 1;
+        self.junk_bytes += bytes;
+        self.max_junk_bytes = self.max_junk_bytes.max(bytes);
+        if self.messages_after_junk > MAX_WARNINGS {
+            return None;
+        }
+
+        let place = match self.messages {
+            1 => "before the first message".to_string(),
+            n => format!("between messages {} and {}", n - 1, n),
+        };
+        let mut warning = format!(
+            "LSP_STRICT_LENGTH: server sent {} unexpected bytes {}, so a Content-Length may be too short",
+            bytes, place
+        );
+        if !junk.is_empty() {
+            warning.push_str(&format!(": {:?}", String::from_utf8_lossy(junk)));
+        } else {
+            warning.push_str(" (whitespace)");
+        }
+        if self.messages_after_junk == MAX_WARNINGS {
+            warning.push_str(". Further junk is only counted in the summary");
+        }
+        Some(warning)
+    }
+
+    /// Record headers that can't be parsed, which stop the server's
+    /// output being read. Returns a warning the first time.
+    pub(crate) fn observe_invalid_headers(&mut self, headers: &[u8]) -> Option<String> {
+        if self.warned_invalid_headers {
+            return None;
+        }
+        self.warned_invalid_headers = true;
+        Some(format!(
+            "LSP_STRICT_LENGTH: server sent headers without a valid Content-Length after message {}: {:?}",
+            self.messages,
+            String::from_utf8_lossy(headers)
+        ))
+    }
+
+    pub(crate) fn summary_json(&self) -> Value {
+        serde_json::json!({
+            "messages": self.messages,
+            "messages_after_junk": self.messages_after_junk,
+            "junk_bytes": self.junk_bytes,
+            "max_junk_bytes": self.max_junk_bytes,
+            "invalid_headers": self.warned_invalid_headers,
+        })
+    }
+}
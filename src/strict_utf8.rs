@@ -0,0 +1,77 @@
+use std::fmt;
+use std::str::Utf8Error;
+
+/// How many invalid UTF-8 warnings to print for each stream
+const MAX_WARNINGS: usize = 5;
+
+/// Bytes of context shown on each side of an invalid sequence
+const CONTEXT_BYTES: usize = 8;
+
+/// Where a message body first stops being valid UTF-8. The body is
+/// logged with U+FFFD in place of the invalid bytes, so they can't be
+/// seen in the logs.
+pub(crate) struct InvalidUtf8 {
+    /// Offset of the invalid sequence in the body
+    offset: usize,
+    bytes: Vec<u8>,
+    /// The bytes around it, and how many come before it
+    context: Vec<u8>,
+    context_before: usize,
+}
+
+impl InvalidUtf8 {
+    pub(crate) fn new(body: &[u8], error: &Utf8Error) -> Self {
+        let offset = error.valid_up_to();
+        // A sequence cut off by the end of the body has no length.
+        let len = error.error_len().unwrap_or(body.len() - offset);
+        let start = offset.saturating_sub(CONTEXT_BYTES);
+        let end = body.len().min(offset + len + CONTEXT_BYTES);
+        Self {
+            offset,
+            bytes: body[offset..offset + len].to_vec(),
+            context: body[start..end].to_vec(),
+            context_before: offset - start,
+        }
+    }
+}
+
+impl fmt::Display for InvalidUtf8 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:#04X}", b)).collect();
+        let after = self.context_before + self.bytes.len();
+        let hex =
+            |bytes: &[u8]| -> Vec<String> { bytes.iter().map(|b| format!("{:02x}", b)).collect() };
+        write!(
+            f,
+            "invalid UTF-8 at body offset {}: bytes [{}] (context: {} [{}] {})",
+            self.offset,
+            bytes.join(", "),
+            hex(&self.context[..self.context_before]).join(" "),
+            hex(&self.context[self.context_before..after]).join(" "),
+            hex(&self.context[after..]).join(" "),
+        )
+    }
+}
+
+/// Counts the messages on a stream whose bodies aren't valid UTF-8,
+/// as the LSP requires
+#[derive(Default)]
+pub(crate) struct StrictUtf8 {
+    invalid_messages: usize,
+}
+
+impl StrictUtf8 {
+    /// Record a message at `offset` in the stream whose body isn't
+    /// valid UTF-8. Returns a warning for the first few.
+    pub(crate) fn observe(&mut self, invalid: &InvalidUtf8, offset: usize) -> Option<String> {
+        self.invalid_messages += 1;
+        if self.invalid_messages > MAX_WARNINGS {
+            return None;
+        }
+        let mut warning = format!("the message at byte {} has {}", offset, invalid);
+        if self.invalid_messages == MAX_WARNINGS {
+            warning.push_str(". Further invalid UTF-8 isn't reported");
+        }
+        Some(warning)
+    }
+}
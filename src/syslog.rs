@@ -0,0 +1,197 @@
+#[cfg(not(unix))]
+use anyhow::bail;
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde_json::Value;
+use std::env;
+use std::sync::Arc;
+
+use crate::line_log::LineFormat;
+use crate::stream::Direction;
+
+/// What's sent to syslog for each message. Payloads are too large for
+/// syslog, so only the summary fields are included.
+const SYSLOG_FORMAT: &str = "dir={dir} kind={kind} method={method} id={id} bytes={bytes}";
+
+/// A syslog facility, set with `LSP_SYSLOG`
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Facility(i32);
+
+impl Facility {
+    /// Reads `LSP_SYSLOG`, which is `1` or `true` for the user
+    /// facility, or a facility name such as `daemon` or `local0`
+    pub(crate) fn from_env() -> Option<Self> {
+        let value = env::var("LSP_SYSLOG").ok()?;
+        let name = value.to_lowercase();
+        if matches!(name.as_str(), "" | "0" | "false") {
+            return None;
+        }
+        if !cfg!(unix) {
+            eprintln!("Ignoring LSP_SYSLOG: only supported on Unix");
+            return None;
+        }
+        match facility_from_name(&name) {
+            Some(facility) => Some(Facility(facility)),
+            None => {
+                eprintln!(
+                    "Ignoring LSP_SYSLOG={:?}: expected 1, user, daemon or local0 to local7",
+                    value
+                );
+                None
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn facility_from_name(name: &str) -> Option<i32> {
+    let facility = match name {
+        "1" | "true" | "user" => libc::LOG_USER,
+        "daemon" => libc::LOG_DAEMON,
+        "local0" => libc::LOG_LOCAL0,
+        "local1" => libc::LOG_LOCAL1,
+        "local2" => libc::LOG_LOCAL2,
+        "local3" => libc::LOG_LOCAL3,
+        "local4" => libc::LOG_LOCAL4,
+        "local5" => libc::LOG_LOCAL5,
+        "local6" => libc::LOG_LOCAL6,
+        "local7" => libc::LOG_LOCAL7,
+        _ => return None,
+    };
+    Some(facility)
+}
+
+#[cfg(not(unix))]
+fn facility_from_name(_name: &str) -> Option<i32> {
+    None
+}
+
+/// Sends a one line summary of each message to syslog, e.g.
+/// `dir=stdin kind=request method=textDocument/hover id=3 bytes=120`
+pub(crate) struct Syslog {
+    format: LineFormat,
+}
+
+pub(crate) type SharedSyslog = Arc<Syslog>;
+
+impl Syslog {
+    #[cfg(unix)]
+    pub(crate) fn open(facility: Facility) -> Result<Self> {
+        // SAFETY: the identity is a static string, so it outlives every
+        // later syslog call.
+        unsafe {
+            libc::openlog(c"lsp-fiddle".as_ptr(), libc::LOG_PID, facility.0);
+        }
+        Ok(Self {
+            format: LineFormat::parse(SYSLOG_FORMAT),
+        })
+    }
+
+    /// `Facility::from_env` already refuses `LSP_SYSLOG` off Unix, but
+    /// fail loudly rather than log nothing if one gets here.
+    #[cfg(not(unix))]
+    pub(crate) fn open(_facility: Facility) -> Result<Self> {
+        bail!("syslog is only supported on Unix")
+    }
+
+    /// The line sent to syslog for a message. Ids and methods come from
+    /// the editor and server, so NUL bytes are removed.
+    fn summary_line(
+        &self,
+        timestamp: DateTime<Local>,
+        direction: Direction,
+        method: Option<&str>,
+        message: Option<&Value>,
+        bytes: usize,
+    ) -> String {
+        self.format
+            .render(timestamp, direction, method, message, bytes)
+            .trim_end()
+            .replace('\0', "")
+    }
+
+    pub(crate) fn log(
+        &self,
+        timestamp: DateTime<Local>,
+        direction: Direction,
+        method: Option<&str>,
+        message: Option<&Value>,
+        bytes: usize,
+    ) {
+        let line = self.summary_line(timestamp, direction, method, message, bytes);
+
+        #[cfg(unix)]
+        {
+            let Ok(line) = std::ffi::CString::new(line) else {
+                return;
+            };
+            // SAFETY: both strings are NUL-terminated, and the format
+            // string only reads one string argument.
+            unsafe {
+                libc::syslog(libc::LOG_INFO, c"%s".as_ptr(), line.as_ptr());
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = line;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn summary_line(
+        direction: Direction,
+        method: Option<&str>,
+        message: &Value,
+        bytes: usize,
+    ) -> String {
+        let syslog = Syslog {
+            format: LineFormat::parse(SYSLOG_FORMAT),
+        };
+        syslog.summary_line(Local::now(), direction, method, Some(message), bytes)
+    }
+
+    #[test]
+    fn summarises_a_request() {
+        let request = json!({"jsonrpc": "2.0", "id": 3, "method": "textDocument/hover"});
+        assert_eq!(
+            summary_line(
+                Direction::ToServer,
+                Some("textDocument/hover"),
+                &request,
+                120
+            ),
+            "dir=stdin kind=request method=textDocument/hover id=3 bytes=120"
+        );
+    }
+
+    #[test]
+    fn summarises_a_response_and_a_notification() {
+        let response = json!({"jsonrpc": "2.0", "id": "a", "result": null});
+        assert_eq!(
+            summary_line(
+                Direction::ToEditor,
+                Some("textDocument/hover"),
+                &response,
+                40
+            ),
+            "dir=stdout kind=response method=textDocument/hover id=a bytes=40"
+        );
+        let notification = json!({"jsonrpc": "2.0", "method": "exit"});
+        assert_eq!(
+            summary_line(Direction::ToServer, Some("exit"), &notification, 33),
+            "dir=stdin kind=notification method=exit id= bytes=33"
+        );
+    }
+
+    #[test]
+    fn removes_nul_bytes() {
+        let request = json!({"jsonrpc": "2.0", "id": "a\0b", "method": "x\0"});
+        assert_eq!(
+            summary_line(Direction::ToServer, Some("x\0"), &request, 1),
+            "dir=stdin kind=request method=x id=ab bytes=1"
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// How long to spend sending buffered lines when the session ends
+const FINISH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Streams log lines to a remote collector over TCP. Lines are queued
+/// while the collector is unreachable and sent once we reconnect.
+pub(crate) struct Tee {
+    sender: mpsc::UnboundedSender<String>,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Tee {
+    /// Start a writer task sending lines to `addr`, buffering at most
+    /// `max_buffered` lines during an outage
+    pub(crate) fn spawn(addr: String, max_buffered: usize) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        let task = tokio::spawn(run_tee(addr, receiver, shutdown_receiver, max_buffered));
+
+        Self {
+            sender,
+            shutdown,
+            task,
+        }
+    }
+
+    pub(crate) fn sender(&self) -> mpsc::UnboundedSender<String> {
+        self.sender.clone()
+    }
+
+    /// Make a last attempt to send any buffered lines, then stop
+    pub(crate) async fn finish(self) {
+        let _ = self.shutdown.send(());
+        if tokio::time::timeout(FINISH_TIMEOUT, self.task)
+            .await
+            .is_err()
+        {
+            eprintln!("Timed out sending remaining log lines to collector");
+        }
+    }
+}
+
+/// Lines waiting to be sent, dropping the oldest when full
+pub(crate) struct Backlog {
+    pub(crate) lines: VecDeque<String>,
+    pub(crate) max_lines: usize,
+    dropped: usize,
+}
+
+impl Backlog {
+    pub(crate) fn new(max_lines: usize) -> Self {
+        Self {
+            lines: VecDeque::new(),
+            max_lines,
+            dropped: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, line: String) {
+        if self.lines.len() >= self.max_lines {
+            self.lines.pop_front();
+            self.dropped += 1;
+        }
+        self.lines.push_back(line);
+    }
+
+    pub(crate) fn take_dropped(&mut self) -> usize {
+        std::mem::take(&mut self.dropped)
+    }
+}
+
+async fn run_tee(
+    addr: String,
+    mut receiver: mpsc::UnboundedReceiver<String>,
+    mut shutdown: oneshot::Receiver<()>,
+    max_buffered: usize,
+) {
+    let mut backlog = Backlog::new(max_buffered);
+    let mut connection: Option<TcpStream> = None;
+    let mut backoff = MIN_BACKOFF;
+    let mut finishing = false;
+
+    loop {
+        if !finishing && backlog.lines.is_empty() {
+            tokio::select! {
+                line = receiver.recv() => match line {
+                    Some(line) => backlog.push(line),
+                    None => finishing = true,
+                },
+                _ = &mut shutdown => finishing = true,
+            }
+        }
+        while let Ok(line) = receiver.try_recv() {
+            backlog.push(line);
+        }
+
+        let dropped = backlog.take_dropped();
+        if dropped > 0 {
+            eprintln!(
+                "Collector backlog full, dropped {} log lines (LSP_TEE_BUFFER_LINES={})",
+                dropped, backlog.max_lines
+            );
+        }
+
+        if connection.is_none() {
+            match TcpStream::connect(&addr).await {
+                Ok(stream) => {
+                    eprintln!("Connected to log collector at {}", addr);
+                    connection = Some(stream);
+                    backoff = MIN_BACKOFF;
+                }
+                Err(e) => {
+                    if finishing {
+                        eprintln!(
+                            "Could not reach log collector at {}, {} log lines were not sent",
+                            addr,
+                            backlog.lines.len()
+                        );
+                        return;
+                    }
+                    if backoff == MIN_BACKOFF {
+                        eprintln!("Failed to connect to log collector at {}: {}", addr, e);
+                    }
+
+                    // Keep accepting lines while we wait to retry.
+                    tokio::select! {
+                        _ = tokio::time::sleep(backoff) => {}
+                        _ = &mut shutdown => finishing = true,
+                    }
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    continue;
+                }
+            }
+        }
+
+        if let Some(stream) = connection.as_mut() {
+            while let Some(line) = backlog.lines.front() {
+                if let Err(e) = stream.write_all(line.as_bytes()).await {
+                    eprintln!("Lost connection to log collector at {}: {}", addr, e);
+                    connection = None;
+                    break;
+                }
+                backlog.lines.pop_front();
+            }
+        }
+
+        if finishing && backlog.lines.is_empty() {
+            return;
+        }
+    }
+}
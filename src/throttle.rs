@@ -0,0 +1,152 @@
+use serde_json::Value;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::plugin::Action;
+use crate::stages::{Effects, Incoming, Stage};
+
+/// Slows down `textDocument/didChange` notifications for servers that
+/// struggle with rapid edits. A change arriving within `window` of the
+/// previous change to the same document is held back, and any further
+/// changes to that document are merged into it, until the window ends
+/// or another message needs to be forwarded. Messages are never
+/// reordered.
+pub(crate) struct DidChangeThrottle {
+    window: Duration,
+    /// The document of the last didChange forwarded, and when
+    last_forwarded: Option<(String, Instant)>,
+    pending: Option<PendingChange>,
+}
+
+struct PendingChange {
+    uri: String,
+    message: Value,
+    /// How many notifications have been merged into `message`
+    merged: usize,
+    deadline: Instant,
+}
+
+impl DidChangeThrottle {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            last_forwarded: None,
+            pending: None,
+        }
+    }
+
+    /// Add a message that's about to be forwarded. Returns a merged
+    /// change that must be forwarded before it, and whether the message
+    /// itself should be forwarded now, rather than being held back.
+    pub(crate) fn add(&mut self, message: Option<&Value>, now: Instant) -> (Option<String>, bool) {
+        let uri = message
+            .filter(|message| is_did_change(message))
+            .and_then(|message| {
+                message
+                    .pointer("/params/textDocument/uri")
+                    .and_then(|uri| uri.as_str())
+            });
+        let (Some(message), Some(uri)) = (message, uri) else {
+            return (self.take_pending(now), true);
+        };
+
+        if let Some(pending) = self.pending.as_mut().filter(|pending| pending.uri == uri) {
+            merge(&mut pending.message, message);
+            pending.merged += 1;
+            return (None, false);
+        }
+
+        let flushed = self.take_pending(now);
+        let recently_changed = self
+            .last_forwarded
+            .as_ref()
+            .is_some_and(|(last_uri, sent)| {
+                last_uri == uri && now.duration_since(*sent) < self.window
+            });
+        if recently_changed {
+            self.pending = Some(PendingChange {
+                uri: uri.to_string(),
+                message: message.clone(),
+                merged: 1,
+                deadline: now + self.window,
+            });
+            (flushed, false)
+        } else {
+            self.last_forwarded = Some((uri.to_string(), now));
+            (flushed, true)
+        }
+    }
+
+    /// Remove the held back change, returning the message body to
+    /// forward
+    fn take_pending(&mut self, now: Instant) -> Option<String> {
+        let pending = self.pending.take()?;
+        if pending.merged > 1 {
+            eprintln!(
+                "Merged {} didChange notifications for {}",
+                pending.merged, pending.uri
+            );
+        }
+        self.last_forwarded = Some((pending.uri, now));
+        Some(pending.message.to_string())
+    }
+}
+
+impl Stage for DidChangeThrottle {
+    /// Logs a held back change without forwarding it, as the merged
+    /// change is forwarded later
+    fn process(&mut self, message: &Incoming, effects: &mut Effects) -> Action {
+        let (released, forward) = self.add(message.message, message.now);
+        effects.release.extend(released);
+        if forward {
+            Action::Forward
+        } else {
+            Action::LogOnly
+        }
+    }
+
+    /// When the held back change should be forwarded
+    fn next_deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|pending| pending.deadline)
+    }
+
+    fn release(&mut self, now: Instant) -> Option<String> {
+        self.take_pending(now)
+    }
+}
+
+/// Whether a message is a didChange notification that can be merged
+fn is_did_change(message: &Value) -> bool {
+    message.get("id").is_none()
+        && message.get("method").and_then(|m| m.as_str()) == Some("textDocument/didChange")
+        && message
+            .pointer("/params/contentChanges")
+            .is_some_and(|changes| changes.is_array())
+}
+
+/// Append the changes from `next` to `pending`, which is still valid as
+/// content changes are applied in order. A change without a range
+/// replaces the whole document, so earlier changes are discarded.
+fn merge(pending: &mut Value, next: &Value) {
+    if let Some(version) = next.pointer("/params/textDocument/version") {
+        if let Some(pending_version) = pending.pointer_mut("/params/textDocument/version") {
+            *pending_version = version.clone();
+        }
+    }
+
+    let next_changes = next
+        .pointer("/params/contentChanges")
+        .and_then(|changes| changes.as_array());
+    let Some(changes) = pending
+        .pointer_mut("/params/contentChanges")
+        .and_then(|changes| changes.as_array_mut())
+    else {
+        return;
+    };
+    for change in next_changes.into_iter().flatten() {
+        if change.get("range").is_none() {
+            changes.clear();
+        }
+        changes.push(change.clone());
+    }
+}
@@ -0,0 +1,89 @@
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// How long to wait for the other side to complete a TLS handshake,
+/// so a peer that isn't speaking TLS fails rather than hanging
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A bidirectional connection, e.g. a TCP stream with or without TLS
+pub(crate) trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// Builds an acceptor for listen mode from a PEM certificate chain and
+/// private key
+pub(crate) fn acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow!("Failed to read certificates from LSP_TLS_CERT: {}", e))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| anyhow!("Failed to read private key from LSP_TLS_KEY: {}", e))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate or key")?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Builds a connector for connect mode that only trusts servers signed
+/// by the PEM certificates in `ca_path`
+pub(crate) fn connector(ca_path: &Path) -> Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    let certs = CertificateDer::pem_file_iter(ca_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow!("Failed to read certificates from LSP_TLS_CA: {}", e))?;
+    for cert in certs {
+        roots
+            .add(cert)
+            .context("Invalid CA certificate in LSP_TLS_CA")?;
+    }
+
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Completes the server side of a TLS handshake with an editor
+pub(crate) async fn accept<S>(acceptor: &TlsAcceptor, stream: S) -> Result<Box<dyn Connection>>
+where
+    S: Connection + 'static,
+{
+    let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .context("Timed out waiting for the editor to complete a TLS handshake")?
+        .context("TLS handshake with editor failed")?;
+    Ok(Box::new(stream))
+}
+
+/// Completes the client side of a TLS handshake with the server at
+/// `addr`, verifying its certificate against the host name
+pub(crate) async fn connect<S>(
+    connector: &TlsConnector,
+    addr: &str,
+    stream: S,
+) -> Result<Box<dyn Connection>>
+where
+    S: Connection + 'static,
+{
+    let host = match addr.rsplit_once(':') {
+        Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => addr,
+    };
+    let server_name = ServerName::try_from(host.to_string())
+        .with_context(|| format!("Invalid TLS server name: {}", host))?;
+
+    let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, connector.connect(server_name, stream))
+        .await
+        .context("Timed out waiting for the LSP server to complete a TLS handshake")?
+        .with_context(|| format!("TLS handshake with {} failed", addr))?;
+    Ok(Box::new(stream))
+}
@@ -0,0 +1,98 @@
+use serde_json::Value;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::stream::Direction;
+
+/// Events in Chrome's Trace Event Format, for loading the session into
+/// `about:tracing` or Perfetto. Each request is a duration event on a
+/// track for the direction it was sent in, and each notification is an
+/// instant event.
+pub(crate) struct Trace {
+    started: Instant,
+    events: Vec<Value>,
+}
+
+impl Trace {
+    pub(crate) fn new(started: Instant) -> Self {
+        Self {
+            started,
+            events: vec![],
+        }
+    }
+
+    /// Record a request that was answered, or is still unanswered at
+    /// `end`
+    pub(crate) fn request(
+        &mut self,
+        direction: Direction,
+        method: &str,
+        id: &Value,
+        sent: Instant,
+        end: Instant,
+        outcome: &str,
+    ) {
+        self.events.push(serde_json::json!({
+            "name": method,
+            "cat": "request",
+            "ph": "X",
+            "ts": self.micros(sent),
+            "dur": micros(end.duration_since(sent)),
+            "pid": 1,
+            "tid": track(direction),
+            "args": { "id": id, "outcome": outcome },
+        }));
+    }
+
+    pub(crate) fn notification(&mut self, direction: Direction, method: &str, at: Instant) {
+        self.events.push(serde_json::json!({
+            "name": method,
+            "cat": "notification",
+            "ph": "i",
+            "s": "t",
+            "ts": self.micros(at),
+            "pid": 1,
+            "tid": track(direction),
+        }));
+    }
+
+    /// The whole trace, with names for the tracks
+    pub(crate) fn to_json(&self) -> Value {
+        let mut events: Vec<Value> = [Direction::ToServer, Direction::ToEditor]
+            .into_iter()
+            .map(|direction| {
+                let name = match direction {
+                    Direction::ToServer => "editor -> server",
+                    Direction::ToEditor => "server -> editor",
+                };
+                serde_json::json!({
+                    "name": "thread_name",
+                    "ph": "M",
+                    "pid": 1,
+                    "tid": track(direction),
+                    "args": { "name": name },
+                })
+            })
+            .collect();
+        events.extend(self.events.iter().cloned());
+        serde_json::json!({
+            "traceEvents": events,
+            "displayTimeUnit": "ms",
+        })
+    }
+
+    fn micros(&self, at: Instant) -> u64 {
+        micros(at.saturating_duration_since(self.started))
+    }
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_micros() as u64
+}
+
+fn track(direction: Direction) -> u32 {
+    match direction {
+        Direction::ToServer => 1,
+        Direction::ToEditor => 2,
+    }
+}
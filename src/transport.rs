@@ -0,0 +1,74 @@
+use anyhow::{bail, Context, Result};
+use std::time::Duration;
+use tokio::process::Child;
+
+use crate::listen::Listener;
+use crate::{ServerInput, ServerOutput};
+
+/// How long a server started with `--socket` or `--pipe` has to
+/// connect back to us
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How the server wants to talk to its client, using the arguments
+/// VS Code passes when launching servers
+pub(crate) enum ServerTransport {
+    /// `--stdio`, or no transport argument
+    Stdio,
+    /// `--socket=PORT`: the server connects to the client on this TCP
+    /// port
+    Socket(u16),
+    /// `--pipe=NAME`: the server connects to the client on this named
+    /// pipe, which is a Unix socket on Unix
+    Pipe(String),
+}
+
+impl ServerTransport {
+    pub(crate) fn from_args(args: &[String]) -> Result<Self> {
+        for arg in args {
+            if let Some(port) = arg.strip_prefix("--socket=") {
+                let port = port
+                    .parse()
+                    .with_context(|| format!("Invalid port in {}", arg))?;
+                return Ok(ServerTransport::Socket(port));
+            }
+            if let Some(name) = arg.strip_prefix("--pipe=") {
+                return Ok(ServerTransport::Pipe(name.to_string()));
+            }
+        }
+        Ok(ServerTransport::Stdio)
+    }
+
+    /// Start listening for the server to connect, before it's started
+    pub(crate) async fn listen(&self) -> Result<Option<Listener>> {
+        let addr = match self {
+            ServerTransport::Stdio => return Ok(None),
+            ServerTransport::Socket(port) => format!("127.0.0.1:{}", port),
+            ServerTransport::Pipe(name) => format!("unix:{}", name),
+        };
+        let listener = Listener::bind(&addr).await?;
+        eprintln!("Waiting for LSP server to connect to {}", addr);
+        Ok(Some(listener))
+    }
+}
+
+/// Waits for a server started with `--socket` or `--pipe` to connect
+/// back to us
+pub(crate) async fn accept_server(
+    listener: &Listener,
+    child: &mut Child,
+) -> Result<(ServerInput, ServerOutput)> {
+    let connection = tokio::select! {
+        accepted = tokio::time::timeout(CONNECT_TIMEOUT, listener.accept()) => {
+            let (connection, _) = accepted
+                .context("Timed out waiting for LSP server to connect")?
+                .context("Failed to accept connection from LSP server")?;
+            connection
+        }
+        status = child.wait() => {
+            bail!("LSP server exited before connecting: {}", status?);
+        }
+    };
+
+    let (output, input) = tokio::io::split(connection);
+    Ok((Box::new(input), Box::new(output)))
+}
@@ -0,0 +1,609 @@
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::capture;
+use crate::config::LogFormat;
+use crate::{gzip, Headers};
+
+/// A message read from a capture, with the line it starts on
+struct Logged {
+    line: usize,
+    stream: &'static str,
+    message: Value,
+}
+
+/// Something wrong with a capture, at a line of the file
+struct Problem {
+    line: usize,
+    description: String,
+}
+
+/// What was read from one capture file
+#[derive(Default)]
+struct Capture {
+    messages: Vec<Logged>,
+    problems: Vec<Problem>,
+}
+
+impl Capture {
+    fn problem(&mut self, line: usize, description: String) {
+        self.problems.push(Problem { line, description });
+    }
+}
+
+/// Checks captures for truncation and corruption, printing each problem
+/// with its line number. Fails if there are any problems.
+pub(crate) fn validate(paths: &[PathBuf]) -> Result<()> {
+    let mut captures = vec![];
+    for path in paths {
+        let contents =
+            fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let stream = capture::stream_from_file_name(path);
+        let capture = match LogFormat::from_path(path).unwrap_or(LogFormat::Raw) {
+            LogFormat::Raw => read_raw(&contents, stream),
+            LogFormat::JsonLines => read_json_lines(&contents, stream),
+            LogFormat::JsonArray => read_json_array(&contents, stream),
+        };
+        captures.push((path, capture));
+    }
+
+    // Responses are in the other stream's capture, so match ids across
+    // every file given.
+    let mut messages: Vec<(usize, &Logged)> = vec![];
+    for (i, (_, capture)) in captures.iter().enumerate() {
+        messages.extend(capture.messages.iter().map(|logged| (i, logged)));
+    }
+    let has_both_streams = ["stdin", "stdout"]
+        .iter()
+        .all(|stream| messages.iter().any(|(_, logged)| logged.stream == *stream));
+    if has_both_streams {
+        for (i, line, description) in correlate(&messages) {
+            captures[i].1.problem(line, description);
+        }
+    } else {
+        println!("Only one stream was given, so responses weren't matched to requests");
+    }
+
+    let mut total = 0;
+    for (path, capture) in &mut captures {
+        capture.problems.sort_by_key(|problem| problem.line);
+        for problem in &capture.problems {
+            println!(
+                "{}:{}: {}",
+                path.display(),
+                problem.line,
+                problem.description
+            );
+        }
+        if capture.problems.is_empty() {
+            println!(
+                "{}: {} messages, no problems",
+                path.display(),
+                capture.messages.len()
+            );
+        }
+        total += capture.problems.len();
+    }
+    if total > 0 {
+        bail!("Found {} {}", total, plural(total, "problem", "problems"));
+    }
+    Ok(())
+}
+
+/// Reads a raw capture, checking each message's headers and that its
+/// Content-Length ends it exactly where the next message starts
+fn read_raw(contents: &[u8], stream: &'static str) -> Capture {
+    let mut capture = Capture::default();
+    let line_at = |pos: usize| contents[..pos].iter().filter(|&&b| b == b'\n').count() + 1;
+
+    let mut pos = 0;
+    let mut number = 0;
+    while pos < contents.len() {
+        number += 1;
+        let line = line_at(pos);
+        let Some(header_len) = contents[pos..].windows(4).position(|w| w == b"\r\n\r\n") else {
+            capture.problem(
+                line,
+                format!(
+                    "message {} is truncated before the end of its headers",
+                    number
+                ),
+            );
+            break;
+        };
+        let headers = String::from_utf8_lossy(&contents[pos..pos + header_len]);
+        if let Some(header) = headers.lines().find(|header| !is_header(header)) {
+            capture.problem(
+                line,
+                format!("message {} has an invalid header {:?}", number, header),
+            );
+        }
+        let Some(headers) = Headers::parse(&headers) else {
+            capture.problem(
+                line,
+                format!("message {} has no valid Content-Length header", number),
+            );
+            break;
+        };
+
+        let body_start = pos + header_len + 4;
+        let body_end = body_start + headers.content_length;
+        if body_end > contents.len() {
+            capture.problem(
+                line,
+                format!(
+                    "message {} is truncated: its Content-Length is {} but only {} bytes follow",
+                    number,
+                    headers.content_length,
+                    contents.len() - body_start
+                ),
+            );
+            break;
+        }
+
+        let body = &contents[body_start..body_end];
+        let body = match headers.content_encoding.as_deref() {
+            Some(encoding) if encoding.eq_ignore_ascii_case("gzip") => {
+                match gzip::decompress(body) {
+                    Ok(body) => body,
+                    Err(e) => {
+                        capture.problem(
+                            line,
+                            format!("message {} has a corrupt gzip body: {}", number, e),
+                        );
+                        vec![]
+                    }
+                }
+            }
+            _ => body.to_vec(),
+        };
+        if body.is_empty() {
+            // A keepalive, or a body we've already complained about
+        } else {
+            match serde_json::from_slice(&body) {
+                Ok(message) => capture.messages.push(Logged {
+                    line,
+                    stream,
+                    message,
+                }),
+                Err(e) => {
+                    capture.problem(line, format!("message {} isn't valid JSON: {}", number, e))
+                }
+            }
+        }
+
+        pos = body_end;
+        let rest = &contents[pos..];
+        let next_header = rest.split(|&b| b == b'\n').next().unwrap_or_default();
+        if !rest.is_empty() && !is_header(&String::from_utf8_lossy(next_header)) {
+            capture.problem(
+                line,
+                format!(
+                    "the Content-Length of message {} doesn't match its body, as no header follows it",
+                    number
+                ),
+            );
+            // Carry on from the next message we can find.
+            match find_ignore_case(rest, b"content-length") {
+                Some(offset) => pos += offset,
+                None => break,
+            }
+        }
+    }
+    capture
+}
+
+/// Whether a line looks like `Name: value`
+fn is_header(line: &str) -> bool {
+    let line = line.trim_end_matches('\r');
+    match line.split_once(':') {
+        Some((name, _)) => {
+            let name = name.trim();
+            !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        }
+        None => false,
+    }
+}
+
+fn find_ignore_case(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle))
+}
+
+fn read_json_lines(contents: &[u8], stream: &'static str) -> Capture {
+    let mut capture = Capture::default();
+    let mut parsed = vec![];
+    for (i, entry) in String::from_utf8_lossy(contents).lines().enumerate() {
+        let line = i + 1;
+        if entry.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(entry) {
+            Ok(value) => parsed.push((line, value)),
+            Err(e) => capture.problem(line, format!("isn't valid JSON: {}", e)),
+        }
+    }
+    if !contents.is_empty() && !contents.ends_with(b"\n") {
+        capture.problem(
+            String::from_utf8_lossy(contents).lines().count(),
+            "the last line is unterminated, so the capture may be truncated".to_string(),
+        );
+    }
+    check_entries(&mut capture, stream, parsed);
+    capture
+}
+
+/// Reads a JSON array capture. lsp-fiddle writes each entry on its own
+/// line, which gives each its line number.
+fn read_json_array(contents: &[u8], stream: &'static str) -> Capture {
+    let mut capture = Capture::default();
+    let values: Vec<Value> = match serde_json::from_slice(contents) {
+        Ok(values) => values,
+        Err(e) => {
+            capture.problem(
+                e.line(),
+                format!(
+                    "isn't a JSON array, so the capture may be truncated (try `lsp-fiddle repair`): {}",
+                    e
+                ),
+            );
+            return capture;
+        }
+    };
+
+    let entry_lines: Vec<usize> = String::from_utf8_lossy(contents)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line.trim(), "" | "[" | "]"))
+        .map(|(i, _)| i + 1)
+        .collect();
+    // Arrays formatted some other way are numbered by entry instead.
+    let lines = if entry_lines.len() == values.len() {
+        entry_lines
+    } else {
+        (1..=values.len()).collect()
+    };
+
+    // Bodies that weren't JSON are logged as strings.
+    let mut parsed = vec![];
+    for (line, value) in lines.into_iter().zip(values) {
+        match value {
+            Value::String(_) => capture.problem(line, "body wasn't valid JSON".to_string()),
+            value => parsed.push((line, value)),
+        }
+    }
+    check_entries(&mut capture, stream, parsed);
+    capture
+}
+
+/// Checks the envelope of each JSON log entry, keeping the messages
+fn check_entries(capture: &mut Capture, stream: &'static str, entries: Vec<(usize, Value)>) {
+    let mut wrapped = None;
+    for (i, (line, entry)) in entries.into_iter().enumerate() {
+        if entry.get("_meta").is_some() {
+            if i > 0 {
+                capture.problem(line, "capture header isn't the first entry".to_string());
+            }
+            continue;
+        }
+        if !entry.is_object() {
+            capture.problem(line, format!("isn't a JSON object: {}", entry));
+            continue;
+        }
+
+        // Method logs and tee output wrap each message with its stream.
+        let is_wrapped = entry.get("stream").is_some() && entry.get("message").is_some();
+        match wrapped {
+            None => wrapped = Some(is_wrapped),
+            Some(previous) if previous != is_wrapped => capture.problem(
+                line,
+                "mixes messages wrapped with their stream and unwrapped ones".to_string(),
+            ),
+            Some(_) => {}
+        }
+        if is_wrapped && !matches!(entry["stream"].as_str(), Some("stdin" | "stdout")) {
+            capture.problem(line, format!("has an unknown stream {}", entry["stream"]));
+        }
+
+        let Some((stream, message)) = capture::unwrap_entry(stream, entry) else {
+            continue;
+        };
+        if message.get("_empty").is_some() {
+            continue;
+        }
+        for description in check_message(&message) {
+            capture.problem(line, description);
+        }
+        capture.messages.push(Logged {
+            line,
+            stream,
+            message,
+        });
+    }
+}
+
+/// Problems with a message's JSON-RPC structure
+fn check_message(message: &Value) -> Vec<String> {
+    let Some(object) = message.as_object() else {
+        return vec![format!("message isn't a JSON object: {}", message)];
+    };
+    let mut problems = vec![];
+    if object.get("jsonrpc").and_then(|v| v.as_str()) != Some("2.0") {
+        problems.push("jsonrpc isn't \"2.0\"".to_string());
+    }
+    if let Some(id) = object.get("id") {
+        if !(id.is_string() || id.is_i64() || id.is_u64() || id.is_null()) {
+            problems.push(format!("id {} isn't a string or integer", id));
+        }
+    }
+
+    match object.get("method") {
+        Some(method) => {
+            if !method.is_string() {
+                problems.push(format!("method {} isn't a string", method));
+            }
+            if object.contains_key("result") || object.contains_key("error") {
+                problems.push("has both a method and a result or error".to_string());
+            }
+        }
+        None if !object.contains_key("id") => {
+            problems.push("has neither a method nor an id".to_string());
+        }
+        None => match (object.get("result"), object.get("error")) {
+            (Some(_), Some(_)) => {
+                problems.push("response has both a result and an error".to_string())
+            }
+            (None, None) => problems.push("response has neither a result nor an error".to_string()),
+            (None, Some(error)) => {
+                let code = error.get("code").is_some_and(|code| code.is_i64());
+                let text = error.get("message").is_some_and(|text| text.is_string());
+                if !(code && text) {
+                    problems.push(format!(
+                        "error {} needs an integer code and a string message",
+                        error
+                    ));
+                }
+            }
+            (Some(_), None) => {}
+        },
+    }
+    problems
+}
+
+/// Matches responses to requests by id, returning problems as the
+/// capture index, line and description
+fn correlate(messages: &[(usize, &Logged)]) -> Vec<(usize, usize, String)> {
+    let mut problems = vec![];
+    // The line of each request, by the stream it was sent on and its
+    // id, and whether it's been answered
+    let mut requests: HashMap<(&str, String), (usize, bool)> = HashMap::new();
+
+    // Requests first, as the stdin and stdout logs are separate files.
+    for &(i, logged) in messages {
+        let (Some(_), Some(id)) = (logged.message.get("method"), logged.message.get("id")) else {
+            continue;
+        };
+        let key = (logged.stream, id.to_string());
+        if let Some((first_line, _)) = requests.get(&key) {
+            problems.push((
+                i,
+                logged.line,
+                format!("request id {} was already used on line {}", id, first_line),
+            ));
+            continue;
+        }
+        requests.insert(key, (logged.line, false));
+    }
+
+    for &(i, logged) in messages {
+        let message = &logged.message;
+        if message.get("method").is_some() {
+            continue;
+        }
+        let Some(id) = message.get("id").filter(|id| !id.is_null()) else {
+            continue;
+        };
+        // Responses travel in the opposite direction to their request.
+        let request_stream = if logged.stream == "stdin" {
+            "stdout"
+        } else {
+            "stdin"
+        };
+        match requests.get_mut(&(request_stream, id.to_string())) {
+            None => problems.push((
+                i,
+                logged.line,
+                format!("response to unknown request {}", id),
+            )),
+            Some((_, true)) => problems.push((
+                i,
+                logged.line,
+                format!("request {} was already answered", id),
+            )),
+            Some((_, answered)) => *answered = true,
+        }
+    }
+
+    // The capture may simply have ended before these were answered, so
+    // mention them without failing.
+    let unanswered = requests.values().filter(|(_, answered)| !answered).count();
+    if unanswered > 0 {
+        println!(
+            "{} {} no response",
+            unanswered,
+            plural(unanswered, "request has", "requests have")
+        );
+    }
+    problems
+}
+
+fn plural(n: usize, one: &'static str, many: &'static str) -> &'static str {
+    if n == 1 {
+        one
+    } else {
+        many
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format_lsp_message;
+    use crate::stream::tests::temp_path;
+    use serde_json::json;
+
+    fn problems(capture: &Capture) -> Vec<(usize, &str)> {
+        capture
+            .problems
+            .iter()
+            .map(|problem| (problem.line, problem.description.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn finds_a_wrong_content_length_and_carries_on() {
+        let good = r#"{"jsonrpc":"2.0","method":"initialized"}"#;
+        let contents = format!(
+            "Content-Length: 3\r\n\r\n{}\n{}{}",
+            good,
+            format_lsp_message(good),
+            format_lsp_message(r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#)
+                .trim_end_matches('}'),
+        );
+        let capture = read_raw(contents.as_bytes(), "stdin");
+        assert_eq!(
+            problems(&capture),
+            vec![
+                (
+                    1,
+                    "message 1 isn't valid JSON: EOF while parsing a string at line 1 column 3"
+                ),
+                (
+                    1,
+                    "the Content-Length of message 1 doesn't match its body, as no header follows it"
+                ),
+                (
+                    6,
+                    "message 3 is truncated: its Content-Length is 44 but only 43 bytes follow"
+                ),
+            ]
+        );
+        assert_eq!(capture.messages.len(), 1);
+        assert_eq!(capture.messages[0].line, 4);
+    }
+
+    #[test]
+    fn finds_bad_lines_in_json_lines() {
+        let contents = [
+            r#"{"jsonrpc":"2.0","method":"initialized"}"#,
+            r#"{"_meta":{}}"#,
+            r#"{"jsonrpc":"2.0","id":1,"result":null,"error":null}"#,
+            r#"{"jsonrpc":"2.0","id":2,"error":{"code":"bad"}}"#,
+            r#"{"jsonrpc":"1.0","id":[],"method":3}"#,
+            r#"{"stream":"stdin","message":{"jsonrpc":"2.0","method":"exit"}}"#,
+            r#"{"jsonrpc":"2.0","meth"#,
+        ]
+        .join("\n");
+        let capture = read_json_lines(contents.as_bytes(), "stdout");
+        assert_eq!(
+            problems(&capture),
+            vec![
+                (
+                    7,
+                    "isn't valid JSON: EOF while parsing a string at line 1 column 22"
+                ),
+                (
+                    7,
+                    "the last line is unterminated, so the capture may be truncated"
+                ),
+                (2, "capture header isn't the first entry"),
+                (3, "response has both a result and an error"),
+                (
+                    4,
+                    r#"error {"code":"bad"} needs an integer code and a string message"#
+                ),
+                (5, "jsonrpc isn't \"2.0\""),
+                (5, "id [] isn't a string or integer"),
+                (5, "method 3 isn't a string"),
+                (
+                    6,
+                    "mixes messages wrapped with their stream and unwrapped ones"
+                ),
+            ]
+        );
+        assert_eq!(capture.messages[4].stream, "stdin");
+    }
+
+    #[test]
+    fn numbers_json_array_entries_by_line() {
+        let contents = "[\n{\"jsonrpc\":\"2.0\",\"method\":\"initialized\"},\n\"not json\"\n]\n";
+        let capture = read_json_array(contents.as_bytes(), "stdin");
+        assert_eq!(problems(&capture), vec![(3, "body wasn't valid JSON")]);
+
+        let capture = read_json_array(b"[\n{\"jsonrpc\":\"2.0\"},\n", "stdin");
+        assert_eq!(capture.problems.len(), 1);
+        assert!(capture.problems[0]
+            .description
+            .starts_with("isn't a JSON array, so the capture may be truncated"));
+    }
+
+    #[test]
+    fn matches_responses_across_captures() {
+        let request =
+            |id: Value| json!({"jsonrpc": "2.0", "id": id, "method": "textDocument/hover"});
+        let response = |id: Value| json!({"jsonrpc": "2.0", "id": id, "result": null});
+        let logged = |line, stream, message| Logged {
+            line,
+            stream,
+            message,
+        };
+        let stdin = [
+            logged(1, "stdin", request(json!(1))),
+            logged(2, "stdin", request(json!(1))),
+            logged(3, "stdin", response(json!("r1"))),
+        ];
+        let stdout = [
+            logged(1, "stdout", response(json!(1))),
+            logged(2, "stdout", response(json!(1))),
+            logged(3, "stdout", response(json!(2))),
+        ];
+        let messages: Vec<_> = stdin
+            .iter()
+            .map(|logged| (0, logged))
+            .chain(stdout.iter().map(|logged| (1, logged)))
+            .collect();
+        assert_eq!(
+            correlate(&messages),
+            vec![
+                (0, 2, "request id 1 was already used on line 1".to_string()),
+                (0, 3, "response to unknown request \"r1\"".to_string()),
+                (1, 2, "request 1 was already answered".to_string()),
+                (1, 3, "response to unknown request 2".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn fails_only_when_there_are_problems() {
+        let stdin = temp_path("validate_stdin.log");
+        let stdout = temp_path("validate_stdout.jsonl");
+        std::fs::write(
+            &stdin,
+            format_lsp_message(r#"{"jsonrpc":"2.0","id":1,"method":"initialize"}"#),
+        )
+        .unwrap();
+        std::fs::write(&stdout, "{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":{}}\n").unwrap();
+        validate(&[stdin.clone(), stdout.clone()]).unwrap();
+
+        std::fs::write(&stdout, "{\"jsonrpc\":\"2.0\",\"id\":2,\"result\":{}}\n").unwrap();
+        let error = validate(&[stdin.clone(), stdout.clone()]).unwrap_err();
+        assert_eq!(error.to_string(), "Found 1 problem");
+
+        let _ = std::fs::remove_file(&stdin);
+        let _ = std::fs::remove_file(&stdout);
+    }
+}